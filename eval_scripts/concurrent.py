@@ -1,4 +1,4 @@
-from time import time
+from time import time, time_ns
 import sys
 import threading
 from tqdm import tqdm
@@ -7,6 +7,7 @@ import matplotlib.pyplot as plt
 from matplotlib.ticker import FormatStrFormatter
 import pickle
 import socket
+import struct
 
 
 def run_tests(host, port):
@@ -21,14 +22,15 @@ def run_tests(host, port):
             def tester(message):
                 s = socket.socket()         # Create a socket object
                 s.connect((host, port))
-                s.sendall(message)
+                # Every packet is prefixed with its length as a big endian u32
+                s.sendall(struct.pack('>I', len(message)) + message)
                 s.recv(1024)
                 s.close() 
 
             thread_list = []
             start = time()
             for _ in range(threads):
-                thread_list.append(threading.Thread(target=tester, args=(b"SEND jae;joe;hahaman",)))
+                thread_list.append(threading.Thread(target=tester, args=(b"SEND jae;joe;%d;hahaman" % time_ns(),)))
 
             for i in range(threads):
                 thread_list[i].start()
@@ -42,7 +44,7 @@ def run_tests(host, port):
             thread_list = []
             start = time()
             for _ in range(threads):
-                thread_list.append(threading.Thread(target=tester, args=(b'CACHE jae;joe;hahafool',)))
+                thread_list.append(threading.Thread(target=tester, args=(b"CACHE jae;joe;%d;hahafool" % time_ns(),)))
             
             for i in range(threads):
                 thread_list[i].start()
@@ -1,107 +1,257 @@
-use local_ip_address::local_ip;
-use std::collections::{HashMap, VecDeque};
-use std::io::stdin;
-use std::net::{Shutdown, TcpListener, TcpStream};
-use std::sync::{
-    mpsc::{channel, Receiver, TryRecvError},
-    Arc, Mutex,
+use messaging_protocol::envelope::seal_sender;
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::{
+    new_message_id, Capability, Message, NetworkParams, StoredMessage, ENCRYPTED_VERSION,
 };
-use std::{process, thread};
+use std::collections::HashMap;
+use std::io::stdin;
+use std::net::{Shutdown, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{env, process, thread};
 use threadpool::ThreadPool;
 
-use lib::network_messaging::handlers::{handle_ack, handle_connection, handle_ip_retrieval, handle_main_server_connection, MDIR};
+use lib::network_messaging::cache::BuddyCache;
+use lib::network_messaging::config::{announced_addr, Profile, USAGE};
+use lib::network_messaging::handlers::{
+    handle_ack, handle_bundle, handle_connection, handle_ip_retrieval, handle_key, handle_pending,
+    CacheMap, Connection,
+};
+use lib::network_messaging::keys::{
+    redeem_token, remember_key, seal_message, seal_with_session, sign_message, Contacts,
+};
 use lib::network_messaging::senders::{
-    init_stream, initialize, ip_fetch, send_backups, send_message,
+    bundle_fetch, fetch, hand_off, init_stream, initialize, ip_fetch, key_fetch, leave,
+    publish_prekeys, refresh_prekeys, send_backups, send_message, top_up_tokens, with_token,
+};
+use lib::network_messaging::sessions::start_session;
+use lib::network_messaging::utils::{
+    contact_file, data_dir, delete_file, gateway as gateway_addr, read_file, set_data_dir,
+    set_gateway, write_message, CHAT_LOG,
 };
-use lib::network_messaging::utils::{delete_file, read_file, write_message, Job, RING_SIZE, NUM_FINGERS, MAX_GROUP_SIZE};
 
-const PORT: u16 = 8013;
-const COMMANDS: &str = "Valid commands: chat [username], clear [username], [message], help, exit";
+const COMMANDS: &str =
+    "Valid commands: chat [username], clear [username], [message], fetch, help, exit";
+
+// Where the messages we cache for others are kept, in the data dir
+const CACHE_FILE: &str = "cache.txt";
+
+// How often we drop expired messages and check whether the groups we
+// cache for have changed
+const HANDOFF_INTERVAL: Duration = Duration::from_secs(60);
+
+// How long a peer may keep a connection to us open without sending
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
 
 /*
- * Setup a local server and send a "hello" message to the main server
+ * Setup a local server on the bound listener that answers messages from
+ * other nodes
 */
 
-fn setup_server(recipient: Arc<Mutex<String>>, username: String, event_queue: Arc<Mutex<VecDeque<String>>>, tx: std::sync::mpsc::Sender<Job>) {
+fn setup_server(
+    listener: TcpListener,
+    recipient: Arc<Mutex<String>>,
+    username: String,
+    cache: CacheMap,
+) {
     thread::spawn(move || {
-        // Set up TCP listener
-        let listener = TcpListener::bind(format!("{}:{}", local_ip().unwrap(), PORT)).unwrap();
+        // Set up the thread pool
+        let num_workers = 8;
+        let pool = ThreadPool::new(num_workers);
 
-        // Set up the cache, only to be accessed by the server thread
-        let cache = Arc::new(Mutex::new(HashMap::new()));
+        // Each connection that comes in is passed to the thread pool
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
 
-        // The group index
-        let mut group_index = 0;
+            // A peer that goes quiet can't hold on to a worker forever
+            _ = stream.set_read_timeout(Some(PEER_TIMEOUT));
 
-        // Set up the finger list
-        let mut fingers: Arc<Mutex<[(String, String); NUM_FINGERS]>> = Arc::new(Mutex::new([(, "".to_string()); NUM_FINGERS]));
+            let recip_copy = recipient.clone();
+            let user_copy = username.clone();
+            let mut cache_copy = cache.clone();
 
-        // Set up the vector that stores all group members
-        let mut group_members = Arc::new(Mutex::new(Vec::with_capacity(MAX_GROUP_SIZE)));
+            pool.execute(move || {
+                let recip = recip_copy.lock().unwrap().clone();
+                let mut stream = FramedStream::new(stream);
 
-        // Local list of addresses we have cached
-        let mut cached_addresses = Arc::new(Mutex::new(VecDeque::new()));
+                if let Some(Err(error)) =
+                    handle_connection(&mut stream, &recip, &user_copy, &mut cache_copy)
+                {
+                    println!("{}", error);
+                }
+            });
+        }
+    });
+}
 
-        // Local storage of user structs
-        let mut storage = Arc::new(Mutex::new(HashMap::new()));
+/*
+ * Every so often drop what has been cached for too long and pass the rest
+ * on to whoever is in the recipient's group now
+*/
 
-        // Set up the thread pool
-        let num_workers = 8;
-        let pool = ThreadPool::new(num_workers);
+fn setup_handoff(me: String, gateway: String, cache: CacheMap) {
+    thread::spawn(move || {
+        let mut known_groups = HashMap::new();
 
-        // Each message that comes in is passed to the thread pool
-        for stream in listener.incoming() {
-            let stream = stream.unwrap();
-
-            // Read the message into a buffer
-            let mut buffer = [0; 2048];
-
-            if let Ok(i) = stream.read(&mut buffer) {
-                let as_string = std::str::from_utf8(&buffer[..i]).unwrap();
-        
-                // Handle based on the status code
-                if let Some((code, message)) = as_string.split_once(" ") {
-                    let response: HandlerResult = match code {
-                        "IP_FETCH" => {
-                            let addr_copy = cached_addresses.clone();
-                            let stor_copy = storage.clone();
-                            let f_copy = fingers.clone();
-
-
-                        },
-                        "IP_RETRIEVAL" => {
-
-                        },
-                        "INIT" => {
-                            handle_init(message, cache, &group_index),
-                        },
-                        "SEND" => {
-                            // Check if the message is for us or not, if not propagate to cache
-                            // This can accept ACKS as well
-                            let mut cache_copy = cache.clone();
-                            handle_send(message, recip, user),
-                        },
-                        "CACHE" => {
-                            // Cache this message, do not propagate
-                        },
-                        "UPDATE_FINGERS" => {
-                            // Update the finger list
-                        },
-                        "NEW_FINGER" => {
-                            let group_copy = group_members.clone();
-                        },
-                        "UPDATE_GROUP" => {
-
-                        },
-                        "404" => handle_not_found(message),
-                        _ => handle_error(message),
-                    };
-                };
-            }
+        loop {
+            thread::sleep(HANDOFF_INTERVAL);
+            cache.lock().unwrap().expire();
+            hand_off(&me, &gateway, &cache, &mut known_groups);
         }
     });
 }
 
+/*
+ * Encrypt a message for its recipient. Our session with them is used if
+ * there is one, otherwise one is started from the bundle the gateway hands
+ * out. Contacts that never published prekeys get a body sealed for their
+ * key instead
+*/
+
+fn encrypt_for(
+    recip: &str,
+    server: &mut Connection,
+    contacts: &mut Contacts,
+    message: &StoredMessage,
+) -> Result<StoredMessage, String> {
+    if let Some(sealed) = seal_with_session(recip, message) {
+        return sealed.map_err(|e| e.to_string());
+    }
+
+    bundle_fetch(recip, server);
+    if let Some(bundle) = handle_bundle(server) {
+        remember_key(contacts, recip, bundle.key.clone());
+        start_session(recip, &bundle).map_err(|e| e.to_string())?;
+        if let Some(sealed) = seal_with_session(recip, message) {
+            return sealed.map_err(|e| e.to_string());
+        }
+    }
+
+    let key = recipient_key(recip, server, contacts)?;
+    seal_message(recip, &key, message).map_err(|e| e.to_string())
+}
+
+/*
+ * The key a recipient's messages are sealed for, asking the gateway if we
+ * don't know it yet
+*/
+
+fn recipient_key(
+    recip: &str,
+    server: &mut Connection,
+    contacts: &mut Contacts,
+) -> Result<String, String> {
+    if let Some(key) = contacts.get(recip) {
+        return Ok(key.clone());
+    }
+
+    key_fetch(recip, server);
+    let key = match handle_key(server) {
+        Some((key, _)) => key,
+        None => {
+            return Err(format!(
+                "{} has no encryption key, their client is from before protocol version {}",
+                recip, ENCRYPTED_VERSION
+            ))
+        }
+    };
+    remember_key(contacts, recip, key.clone());
+    Ok(key)
+}
+
+/*
+ * This method takes an input that is supposed to be sent and handles it appropriately.
+ * Only the recipient can read what leaves here: the body is encrypted and
+ * signed with our identity key, then sealed in an envelope with our name
+ * so buddies and the gateway only learn who it is for. Everything about
+ * the message goes over a connection to the gateway of its own that never
+ * signed in, and the delivery token it carries was signed blind, so the
+ * gateway can't tell who sent it either
+*/
+
+fn send_input(
+    recip: &str,
+    server: &mut Connection,
+    gateway: &[Capability],
+    network: &NetworkParams,
+    contacts: &mut Contacts,
+    username: &str,
+    input: &str,
+) -> Result<String, String> {
+    // Give the message an id that stays with it until it is acked
+    let message = StoredMessage {
+        sender: username.to_string(),
+        id: new_message_id().map_err(|e| e.to_string())?,
+        body: input.to_string(),
+        signature: None,
+    };
+
+    // Ask for the ip_address of the recipient
+    let mut relay =
+        init_stream(gateway_addr()).map_err(|e| format!("can't reach the gateway: {}", e))?;
+    ip_fetch(recip, &mut relay);
+
+    // Search for the user, send directly if they are online, otherwise to their cache
+    if let Some(ip_addr) = handle_ip_retrieval(&mut relay) {
+        // Both the gateway and the recipient have to speak a version with
+        // encryption, we never fall back to sending in the clear
+        if !gateway.contains(&Capability::Encrypted) {
+            return Err(
+                "the gateway doesn't hand out keys, so nothing can be encrypted".to_string(),
+            );
+        }
+        let key = recipient_key(recip, &mut relay, contacts)?;
+
+        let signed = sign_message(recip, &encrypt_for(recip, &mut relay, contacts, &message)?);
+        let sealed = seal_sender(recip, &key, &signed).map_err(|e| e.to_string())?;
+
+        if let Ok(mut stream) = init_stream(&ip_addr) {
+            // If we can connect to the user, send the message directly to
+            // them, they know who is connecting without a token
+            send_message(&sealed_send(recip, &sealed).encode(), &mut stream);
+            handle_ack(&mut stream, recip, &message);
+            _ = stream.get_ref().shutdown(Shutdown::Both);
+        } else if gateway.contains(&Capability::BuddyCache) {
+            // Otherwise, send the message to the buddies to be cached. The
+            // token is spent on it first, so no buddy can spend it on
+            // anything else
+            let sealed = with_token(&sealed, server)?;
+            let sent = match redeem_token(recip, &sealed) {
+                Some(Ok(())) => send_backups(recip, &sealed, &mut relay, network.replication),
+                Some(Err(reason)) => Err(reason),
+                None => Err("the gateway didn't spend the delivery token".to_string()),
+            };
+            match sent {
+                Ok(_) => write_message(contact_file(recip, CHAT_LOG), "You", input, None),
+                Err(reason) => println!("Message not sent: {}", reason),
+            };
+        } else {
+            // A gateway without buddies holds on to the message itself
+            let sealed = with_token(&sealed, server)?;
+            send_message(&sealed_send(recip, &sealed).encode(), &mut relay);
+            handle_ack(&mut relay, recip, &message);
+        }
+    } else {
+        // User was not found
+        return Err(format!("{} not found", recip));
+    }
+
+    Ok(String::from("Message Sent"))
+}
+
+fn sealed_send(recip: &str, sealed: &StoredMessage) -> Message {
+    Message::Send {
+        recipient: recip.to_string(),
+        sender: sealed.sender.clone(),
+        id: sealed.id.clone(),
+        body: sealed.body.clone(),
+        signature: sealed.signature.clone(),
+    }
+}
+
 /*
  * This method gets the username from stdin
 */
@@ -109,10 +259,10 @@ fn setup_server(recipient: Arc<Mutex<String>>, username: String, event_queue: Ar
 fn get_username() -> String {
     let mut username = String::from("");
     stdin().read_line(&mut username).unwrap();
-    while username.contains(";") {
+    while username.contains(';') {
         println!("No ';' characters allowed");
-        stdin().read_line(&mut username).unwrap();
         username.clear();
+        stdin().read_line(&mut username).unwrap();
     }
     username.trim().to_string()
 }
@@ -123,36 +273,75 @@ fn get_username() -> String {
 */
 
 fn main() {
-    println!("Please login by entering the username (no ';') you would like to use:");
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    // Pick the profile before anything touches the network or the disk
+    let profile = match Profile::from_args(args) {
+        Ok(profile) => profile,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, USAGE);
+            process::exit(1);
+        }
+    };
+    set_data_dir(&profile.data_dir);
+    set_gateway(&profile.gateway);
+
+    // Listen before registering so the gateway hears the port we really got
+    let listener = TcpListener::bind(profile.listen_addr()).expect("Couldn't listen for peers");
+    let me = announced_addr(listener.local_addr().unwrap());
 
     // Get the username, check that is doesn't have a ; (our delimiter)
-    let username = get_username();
+    let username = match &profile.username {
+        Some(username) => username.clone(),
+        None => {
+            println!("Please login by entering the username (no ';') you would like to use:");
+            get_username()
+        }
+    };
 
-    // Setup listening server once we know who we are
-    let mut server: TcpStream = initialize(&username, &local_ip().unwrap().to_string(), PORT)
+    // Connect to the gateway once we know who we are
+    let (mut server, gateway, network) = initialize(&username, &me, &profile.gateway)
         .expect("Couldn't connect to the gateway server");
-    
-    // Setup shared server vars
-    let recipient = Arc::new(Mutex::new(String::new()));
-    let event_queue = Arc::new(Mutex::new(Vec::new()));
-    let (tx, rx) = channel::<Job>();
 
-    setup_server(recipient.clone(), username.clone(), event_queue.clone(), tx);
+    // Contacts start sessions with us from the prekeys we publish
+    if let Err(error) = publish_prekeys(&username, &mut server) {
+        println!("{}", error);
+    }
+
+    // Sealed messages each spend a delivery token, asked for ahead of
+    // time so the gateway can't tell which message they go with
+    if let Err(error) = top_up_tokens(&mut server) {
+        println!("{}", error);
+    }
+
+    // Setup shared server vars and the listening server, the cache is
+    // shared by its worker threads and the hand-off
+    let recipient = Arc::new(Mutex::new(String::new()));
+    let cache = BuddyCache::open(data_dir().to_owned() + CACHE_FILE, network)
+        .expect("Couldn't open the buddy cache");
+    let cache: CacheMap = Arc::new(Mutex::new(cache));
+    setup_server(listener, recipient.clone(), username.clone(), cache.clone());
+    setup_handoff(me, profile.gateway.clone(), cache);
 
     // Init stdin listener
     println!("{}", COMMANDS);
+    let mut contacts = Contacts::new();
 
     loop {
         let mut buffer = String::new();
-        let input = stdin().read_line(&mut buffer).unwrap();
-        let mut answer_tok = input.split([' ', '\r', '\n']);
+        stdin().read_line(&mut buffer).unwrap();
+        let mut answer_tok = buffer.split([' ', '\r', '\n']);
         let response = match answer_tok.next().unwrap() {
             "chat" => {
                 // Switch the chat to the input user
                 let mut user = answer_tok.collect::<Vec<&str>>().join(" ");
                 user = user.trim().to_string();
 
-                if user != "" {
+                if !user.is_empty() {
                     // Print the record of the chat with that user
                     read_file(&user);
                     *recipient.lock().unwrap() = user;
@@ -165,7 +354,7 @@ fn main() {
             "clear" => {
                 // Find the user based on input
                 let user = answer_tok.collect::<Vec<&str>>().join("");
-                if user != "" {
+                if !user.is_empty() {
                     // Delete file with the record
                     _ = delete_file(&user);
                     Ok(String::from("Wiped chat"))
@@ -174,20 +363,40 @@ fn main() {
                     Err(String::from("Please enter a user"))
                 }
             }
+            "fetch" => {
+                // Pick up whatever the gateway is holding for us
+                if gateway.contains(&Capability::ServerRelay) {
+                    let recip_copy = recipient.lock().unwrap().clone();
+                    fetch(&username, &mut server);
+                    handle_pending(&mut server, &recip_copy, &username);
+                    Ok(String::from("Fetched messages"))
+                } else {
+                    Err(String::from("The gateway does not hold messages"))
+                }
+            }
             "exit" => {
-                // Graceful exit
+                // Graceful exit, letting the gateway know we are gone
+                leave(&username, &mut server);
                 process::exit(0);
             }
             "help" => Err(String::from(COMMANDS)),
             _ => {
                 let recip_copy = recipient.lock().unwrap().clone();
                 // All other strings are interpreted as messages meant to be sent
-                if recip_copy == "" {
+                if recip_copy.is_empty() {
                     // If not in a convo, require that first
                     Err(String::from("Please enter a conversation first"))
                 } else {
                     // Treat the send input as requried by the method
-                    rx.send(Job::new(recip_copy.clone(), username.clone(), input.trim().to_string()));
+                    send_input(
+                        &recip_copy,
+                        &mut server,
+                        &gateway,
+                        &network,
+                        &mut contacts,
+                        &username,
+                        buffer.trim(),
+                    )
                 }
             }
         };
@@ -196,5 +405,13 @@ fn main() {
             Ok(_) => (),
             Err(error) => println!("{}", error),
         }
+
+        // Contacts have used up one-time prekeys in the meantime
+        if let Err(error) = refresh_prekeys(&username, &mut server) {
+            println!("{}", error);
+        }
+        if let Err(error) = top_up_tokens(&mut server) {
+            println!("{}", error);
+        }
     }
-}
\ No newline at end of file
+}
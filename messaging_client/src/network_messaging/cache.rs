@@ -0,0 +1,302 @@
+use messaging_protocol::journal::{now, Journal};
+use messaging_protocol::message::{escape, unescape, NetworkParams, StoredMessage, FIELD_SEP};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+// How many changes go to the journal before the whole cache is rewritten
+// and the journal starts over
+const COMPACT_EVERY: u32 = 256;
+
+/*
+ * The messages a buddy holds for other users, keyed by recipient and then
+ * by message id. When the cache has a file it is kept in two parts: the
+ * file itself holds every message at the last compaction, one per line:
+ *
+ *   cached_at;recipient;sender;id;body[;signature]
+ *
+ * and every change since is appended to a journal next to it as
+ *
+ *   CACHE cached_at;recipient;sender;id;body[;signature]
+ *   ACK recipient;id
+ *
+ * where cached_at is in seconds since the epoch and the other fields are
+ * escaped like they are on the wire. A new message reaches the disk before
+ * insert returns, so a buddy that restarts still has what it was asked to
+ * protect. Acks aren't waited on, losing one to a crash only means the
+ * message is handed out again. Messages older than the network's
+ * cache_ttl are dropped when the cache is opened and whenever expire is
+ * called, and no recipient gets more than the network's quotas
+*/
+
+pub struct BuddyCache {
+    journal: Option<Journal>,
+    network: NetworkParams,
+    entries: HashMap<String, BTreeMap<String, CachedMessage>>,
+}
+
+struct CachedMessage {
+    message: StoredMessage,
+    cached_at: u64,
+}
+
+impl BuddyCache {
+    /*
+     * A cache that only lives in memory
+     */
+
+    pub fn new(network: NetworkParams) -> BuddyCache {
+        BuddyCache {
+            journal: None,
+            network,
+            entries: HashMap::new(),
+        }
+    }
+
+    /*
+     * Load the cache kept in the given file and its journal, creating them
+     * if needed. Lines that can't be read are skipped
+     */
+
+    pub fn open(path: impl Into<PathBuf>, network: NetworkParams) -> io::Result<BuddyCache> {
+        let path = path.into();
+        let journal = Journal::open(&path, journal_path(&path))?;
+        let (snapshot, changes) = journal.replay()?;
+
+        let mut cache = BuddyCache::new(network);
+        for line in snapshot {
+            if let Some((recipient, entry)) = parse_line(&line) {
+                cache.keep(recipient, entry);
+            }
+        }
+        for line in changes {
+            if let Some(line) = line.strip_prefix("CACHE ") {
+                if let Some((recipient, entry)) = parse_line(line) {
+                    cache.keep(recipient, entry);
+                }
+            } else if let Some((recipient, id)) = line.strip_prefix("ACK ").and_then(parse_ack) {
+                cache.drop_ids(&recipient, &[id]);
+            }
+        }
+
+        cache.journal = Some(journal);
+
+        // Start from a fresh copy, which also drops a torn journal line
+        cache.expire();
+        cache.compact()?;
+        Ok(cache)
+    }
+
+    /*
+     * Keep a message for a recipient, a message we already have is only
+     * kept once. Fails with the reason when the recipient is over quota
+     */
+
+    pub fn insert(&mut self, recipient: &str, message: StoredMessage) -> Result<(), String> {
+        let (held, bytes) = match self.entries.get(recipient) {
+            Some(pending) if pending.contains_key(&message.id) => return Ok(()),
+            Some(pending) => (
+                pending.len(),
+                pending.values().map(|e| e.message.size()).sum(),
+            ),
+            None => (0, 0),
+        };
+        self.network.check_quota(recipient, held, bytes, &message)?;
+
+        let entry = CachedMessage {
+            message,
+            cached_at: now(),
+        };
+        let line = format!("CACHE {}\n", format_line(recipient, &entry));
+        self.keep(recipient.to_string(), entry);
+        self.log(&line, true);
+        Ok(())
+    }
+
+    /*
+     * Let go of messages the recipient (or its new buddies) confirmed.
+     * All of them go to the journal in one write
+     */
+
+    pub fn remove(&mut self, recipient: &str, ids: &[String]) {
+        let removed = self.drop_ids(recipient, ids);
+        if removed.is_empty() {
+            return;
+        }
+
+        let lines: String = removed
+            .iter()
+            .map(|id| format!("ACK {}{}{}\n", escape(recipient), FIELD_SEP, escape(id)))
+            .collect();
+        self.log(&lines, false);
+    }
+
+    /*
+     * Up to limit messages for a recipient with ids after the cursor
+     */
+
+    pub fn page(&self, recipient: &str, cursor: &str, limit: usize) -> Vec<StoredMessage> {
+        match self.entries.get(recipient) {
+            Some(pending) => pending
+                .range::<str, _>((Bound::Excluded(cursor), Bound::Unbounded))
+                .take(limit)
+                .map(|(_, entry)| entry.message.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /*
+     * Everything held for a recipient, oldest id first
+     */
+
+    pub fn pending(&self, recipient: &str) -> Vec<StoredMessage> {
+        self.page(recipient, "", usize::MAX)
+    }
+
+    pub fn recipients(&self) -> Vec<String> {
+        self.entries.keys().cloned().collect()
+    }
+
+    /*
+     * Drop every message that has been held for longer than the ttl
+     */
+
+    pub fn expire(&mut self) {
+        let cutoff = now().saturating_sub(self.network.cache_ttl.into());
+        let before: usize = self.entries.values().map(|p| p.len()).sum();
+
+        for pending in self.entries.values_mut() {
+            pending.retain(|_, entry| entry.cached_at >= cutoff);
+        }
+        self.entries.retain(|_, pending| !pending.is_empty());
+
+        let after: usize = self.entries.values().map(|p| p.len()).sum();
+        if after != before {
+            if let Err(e) = self.compact() {
+                println!("Couldn't save the buddy cache: {}", e);
+            }
+        }
+    }
+
+    fn keep(&mut self, recipient: String, entry: CachedMessage) {
+        self.entries
+            .entry(recipient)
+            .or_default()
+            .insert(entry.message.id.clone(), entry);
+    }
+
+    /*
+     * Take the given ids out of a recipient's messages, returning the ones
+     * that were there
+     */
+
+    fn drop_ids(&mut self, recipient: &str, ids: &[String]) -> Vec<String> {
+        let pending = match self.entries.get_mut(recipient) {
+            Some(pending) => pending,
+            None => return Vec::new(),
+        };
+
+        let removed: Vec<String> = ids
+            .iter()
+            .filter(|id| pending.remove(*id).is_some())
+            .cloned()
+            .collect();
+        if pending.is_empty() {
+            self.entries.remove(recipient);
+        }
+        removed
+    }
+
+    /*
+     * Append whole lines to the journal, waiting for them to reach the
+     * disk if sync is set, and compact once enough changes have piled up
+     */
+
+    fn log(&mut self, lines: &str, sync: bool) {
+        let journal = match &mut self.journal {
+            Some(journal) => journal,
+            None => return,
+        };
+
+        let mut result = journal.append(lines, sync);
+        if journal.entries() >= COMPACT_EVERY {
+            result = result.and_then(|_| self.compact());
+        }
+        if let Err(e) = result {
+            println!("Couldn't save the buddy cache: {}", e);
+        }
+    }
+
+    /*
+     * Write the whole cache to a new copy and empty the journal
+     */
+
+    fn compact(&mut self) -> io::Result<()> {
+        let journal = match &mut self.journal {
+            Some(journal) => journal,
+            None => return Ok(()),
+        };
+
+        let lines = self.entries.iter().flat_map(|(recipient, pending)| {
+            pending
+                .values()
+                .map(move |entry| format_line(recipient, entry))
+        });
+        journal.snapshot(lines)
+    }
+}
+
+fn journal_path(path: &Path) -> PathBuf {
+    let mut journal = path.to_path_buf().into_os_string();
+    journal.push(".journal");
+    journal.into()
+}
+
+fn format_line(recipient: &str, entry: &CachedMessage) -> String {
+    let message = &entry.message;
+    let fields = [
+        entry.cached_at.to_string(),
+        escape(recipient),
+        escape(&message.sender),
+        escape(&message.id),
+        escape(&message.body),
+    ];
+    let mut line = fields.join(FIELD_SEP);
+    if let Some(signature) = &message.signature {
+        line = line + FIELD_SEP + &escape(signature);
+    }
+    line
+}
+
+fn parse_ack(fields: &str) -> Option<(String, String)> {
+    let (recipient, id) = fields.split_once(FIELD_SEP)?;
+    Some((unescape(recipient).ok()?, unescape(id).ok()?))
+}
+
+fn parse_line(line: &str) -> Option<(String, CachedMessage)> {
+    let mut fields = line.splitn(6, FIELD_SEP);
+    let cached_at = fields.next()?.parse().ok()?;
+    let recipient = unescape(fields.next()?).ok()?;
+    let sender = unescape(fields.next()?).ok()?;
+    let id = unescape(fields.next()?).ok()?;
+    let body = unescape(fields.next()?).ok()?;
+    // Lines written before messages were signed have no signature
+    let signature = match fields.next() {
+        Some(signature) => Some(unescape(signature).ok()?),
+        None => None,
+    };
+
+    if id.is_empty() {
+        return None;
+    }
+
+    let message = StoredMessage {
+        sender,
+        id,
+        body,
+        signature,
+    };
+    Some((recipient, CachedMessage { message, cached_at }))
+}
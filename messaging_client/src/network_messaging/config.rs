@@ -0,0 +1,205 @@
+use local_ip_address::local_ip;
+use serde::Deserialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use toml::{Table, Value};
+
+// Where the client looks for its profiles
+pub const CONFIG_FILE: &str = "client.toml";
+
+pub const USAGE: &str =
+    "Usage: messaging_client [--config FILE] [--profile NAME] [--SETTING VALUE]...
+
+Settings are gateway, port, bind, data_dir and username, given on the
+command line with dashes in place of underscores. The top of the config
+file holds the defaults and each [profiles.NAME] table changes them for
+one identity, flags win over both:
+
+  messaging_client --profile amy --gateway 127.0.0.1:9000 --port 0";
+
+/*
+ * Where this client connects, listens and keeps its files. A named profile
+ * without a data_dir of its own keeps them in a directory named after it
+ * inside the shared one, so several profiles can run on one machine
+*/
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    // The gateway as host:port
+    pub gateway: String,
+    // The port peers reach us on, 0 lets the system pick one
+    pub port: u16,
+    // The address to listen on, the machine's own address if left out
+    pub bind: Option<String>,
+    // Chat logs and the buddy cache live here
+    pub data_dir: String,
+    // Log in without being asked for a name
+    pub username: Option<String>,
+}
+
+impl Default for Profile {
+    fn default() -> Profile {
+        Profile {
+            gateway: "limia.cs.williams.edu:8013".to_string(),
+            port: 8013,
+            bind: None,
+            data_dir: "./messages/".to_string(),
+            username: None,
+        }
+    }
+}
+
+impl Profile {
+    /*
+     * Build the profile from command line arguments (without the program
+     * name). The file named by --config, or client.toml, is read first,
+     * then the profile picked with --profile and then the other flags
+     */
+
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Profile, String> {
+        let mut path = None;
+        let mut name = None;
+        let mut overrides = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            let setting = match flag.strip_prefix("--") {
+                Some(setting) => setting.replace('-', "_"),
+                None => return Err(format!("unexpected argument {}", flag)),
+            };
+            let value = args.next().ok_or(format!("{} needs a value", flag))?;
+
+            match setting.as_str() {
+                "config" => path = Some(value),
+                "profile" => name = Some(value),
+                _ => overrides.push((setting, value)),
+            }
+        }
+
+        // A file that was asked for by name has to be there
+        let mut table = match &path {
+            Some(path) => read_table(path, true)?,
+            None => read_table(CONFIG_FILE, false)?,
+        };
+        let mut profiles = match table.remove("profiles") {
+            Some(Value::Table(profiles)) => profiles,
+            Some(_) => return Err("profiles must be a table of profiles".to_string()),
+            None => Table::new(),
+        };
+
+        // A profile's files go in a directory of its own under the shared
+        // data_dir unless it names one
+        if let Some(name) = &name {
+            let mut profile = match profiles.remove(name) {
+                Some(Value::Table(profile)) => profile,
+                _ => return Err(format!("there is no profile named {}", name)),
+            };
+            if !profile.contains_key("data_dir") {
+                let base = match table.get("data_dir") {
+                    Some(Value::String(dir)) => dir.trim_end_matches('/').to_string(),
+                    _ => Profile::default()
+                        .data_dir
+                        .trim_end_matches('/')
+                        .to_string(),
+                };
+                profile.insert(
+                    "data_dir".to_string(),
+                    Value::String(format!("{}/{}/", base, name)),
+                );
+            }
+            table.extend(profile);
+        }
+
+        for (setting, value) in overrides {
+            let value = match setting.as_str() {
+                "port" => Value::Integer(
+                    value
+                        .parse()
+                        .map_err(|_| format!("{} is not a port", value))?,
+                ),
+                _ => Value::String(value),
+            };
+            table.insert(setting, value);
+        }
+
+        let mut profile: Profile = table
+            .try_into()
+            .map_err(|e: toml::de::Error| e.to_string())?;
+        profile.validate()?;
+
+        // Files are named by appending to the directory
+        if !profile.data_dir.ends_with('/') {
+            profile.data_dir.push('/');
+        }
+        Ok(profile)
+    }
+
+    /*
+     * Catch settings that can't work before connecting anywhere
+     */
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.gateway.is_empty() {
+            return Err("gateway can't be empty".to_string());
+        }
+        if self.data_dir.is_empty() {
+            return Err("data_dir can't be empty".to_string());
+        }
+        if let Some(bind) = &self.bind {
+            bind.parse::<IpAddr>()
+                .map_err(|_| format!("{} is not an IP address", bind))?;
+        }
+        if let Some(username) = &self.username {
+            if username.is_empty() || username.contains(';') {
+                return Err("username can't be empty or contain ';'".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * The address to listen on for peers
+     */
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        let ip = match self.bind.as_ref().and_then(|bind| bind.parse().ok()) {
+            Some(ip) => ip,
+            None => own_ip(),
+        };
+        SocketAddr::new(ip, self.port)
+    }
+}
+
+/*
+ * The address others reach us on when we listen on the given one. Listening
+ * on every interface means handing out the machine's own address
+*/
+
+pub fn announced_addr(listening: SocketAddr) -> String {
+    let ip = match listening.ip() {
+        ip if ip.is_unspecified() => own_ip(),
+        ip => ip,
+    };
+    SocketAddr::new(ip, listening.port()).to_string()
+}
+
+fn own_ip() -> IpAddr {
+    local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
+
+/*
+ * Read a config file into a table. A missing file is an empty table
+ * unless it was asked for by name
+*/
+
+fn read_table(path: &str, required: bool) -> Result<Table, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound && !required => return Ok(Table::new()),
+        Err(e) => return Err(format!("couldn't read {}: {}", path, e)),
+    };
+
+    toml::from_str(&text).map_err(|e| format!("{}: {}", path, e))
+}
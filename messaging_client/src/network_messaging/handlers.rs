@@ -1,181 +1,466 @@
+// Handlers hand their reply back in the Err of HandlerResult, a message
+// is big but it is written out right away
+#![allow(clippy::result_large_err)]
+
 use chrono::Utc;
-use std::collections::HashMap;
 use linked_hash_set::LinkedHashSet;
-use std::io::{Read, Write};
+use messaging_protocol::crypto::{init_proof, mailbox_proof, random_bytes, to_hex, verify};
+use messaging_protocol::envelope::is_sealed;
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::{
+    Capability, Message, NetworkParams, StoredMessage, MIN_PROTOCOL_VERSION,
+};
+use messaging_protocol::ratchet::PrekeyBundle;
+use std::io::ErrorKind;
 use std::net::TcpStream;
 use std::process::exit;
 use std::sync::{Arc, Mutex};
 
-use super::utils::write_message;
+use super::cache::BuddyCache;
+use super::keys::{
+    check_signature, encryption_key, identity_of, is_plaintext, open_envelope, open_message,
+    redeem_token, sends_in_the_clear, signing_key,
+};
+use super::utils::{contact_file, set_token_key, verified_tag, write_message, CHAT_LOG};
+
+// Ok goes to the main thread, Err is written back to the peer (if there is
+// anything to write) and the connection keeps going
+type HandlerResult = Result<Result<String, String>, Option<Message>>;
+pub type CacheMap = Arc<Mutex<BuddyCache>>;
+pub type Connection = FramedStream<TcpStream>;
 
-type HandlerResult = Result<Result<String, String>, String>;
-pub type CacheMap = Arc<Mutex<HashMap<String, String>>>;
+// How many cached messages a buddy hands out per page, and the most it
+// will hand out however many are asked for
+pub const PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 500;
 
-pub const MDIR: &str = "./messages/";
-pub const DELIMITER: &str = "&&";
+// How many random bytes a buddy challenges a puller with
+const NONCE_LEN: usize = 32;
 
 /*
- * A handler for the initial connection to the main server. Returns
- * a '&&' separated list of ip addresses that the node should try 
- * to connect to the network through.
+ * Who a connection pulling its messages from us says it is, the nonce it
+ * was challenged with and whether it has signed it yet. Only a proven
+ * puller is handed messages or may ack them
 */
 
-pub fn handle_main_server_connection(mut stream: &TcpStream, user: &str,) -> Option<String> {
-    // Read the message into a buffer
-    let mut buffer = [0; 2048];
+#[derive(Default)]
+struct Puller {
+    username: String,
+    nonce: String,
+    proven: bool,
+}
+
+impl Puller {
+    fn is(&self, username: &str) -> bool {
+        self.proven && self.username == username
+    }
+}
 
-    // Split the message into a status line and a body
-    if let Ok(i) = stream.read(&mut buffer) {
-        if i == 0 {
-            println!("Main Server Shutdown");
-            exit(0);
+/*
+ * Read one whole frame from the stream and parse it. Messages the gateway
+ * relays to us and buddy group changes can show up between a request and
+ * its reply, those are handled on the spot before reading on
+*/
+
+fn read_message(stream: &mut Connection) -> Option<Message> {
+    loop {
+        match stream.read_frame() {
+            Ok(frame) => match Message::decode(&frame) {
+                Ok(Message::Send {
+                    recipient,
+                    sender,
+                    id,
+                    body,
+                    signature,
+                }) => {
+                    let message = StoredMessage {
+                        sender,
+                        id,
+                        body,
+                        signature,
+                    };
+                    if deliver(&message, None, &recipient) {
+                        _ = stream.write_frame(
+                            &Message::Ack {
+                                username: recipient,
+                                id: message.id,
+                            }
+                            .encode(),
+                        );
+                    }
+                }
+                Ok(Message::UpdateGroup { members }) => {
+                    println!("Your buddy group changed: {}", members.join(", "));
+                }
+                Ok(message) => return Some(message),
+                Err(e) => {
+                    println!("Invalid message: {}", e);
+                    return None;
+                }
+            },
+            Err(_) => return None,
         }
+    }
+}
 
-        let as_string = std::str::from_utf8(&buffer[..i]).unwrap();
+/*
+ * A handler for the initial connection to the main server. Signs the
+ * gateway's challenge to prove the username is ours, then returns the
+ * features the gateway supports and the network settings it announced,
+ * keeping the gateway's identity key for checking delivery tokens,
+ * along with the list of ip addresses that the node should try to connect
+ * to the network through.
+*/
 
-        // Handle based on the status code
-        if let Some((code, message)) = as_string.split_once(" ") {
-            match code {
-                "BUDDIES" => return Some(message.to_string()),
-                _ => Some("".to_string()),
-            };
+pub fn handle_main_server_connection(
+    stream: &mut Connection,
+    username: &str,
+    addr: &str,
+) -> Option<(Vec<Capability>, NetworkParams, Vec<String>)> {
+    // A gateway that never says its version is a version 1 gateway
+    let mut gateway_capabilities = Capability::legacy();
+    let mut network = NetworkParams::default();
+
+    loop {
+        // Read the whole message off the stream
+        let frame = match stream.read_frame() {
+            Ok(frame) => frame,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                println!("Main Server Shutdown");
+                exit(0);
+            }
+            Err(_) => return None,
         };
-    }
 
-    None
+        // Handle based on the status code
+        match Message::decode(&frame) {
+            Ok(Message::Version {
+                version,
+                capabilities,
+                params,
+                token_key,
+            }) => {
+                if version < MIN_PROTOCOL_VERSION {
+                    println!(
+                        "The gateway speaks protocol version {}, which is too old",
+                        version
+                    );
+                    exit(0);
+                }
+                gateway_capabilities = capabilities;
+                network = params;
+                if let Some(key) = token_key {
+                    set_token_key(&key);
+                }
+            }
+            Ok(Message::Challenge { nonce }) => {
+                let key = encryption_key().public_hex();
+                let identity = signing_key().public_hex();
+                let prove = Message::Prove {
+                    username: username.to_string(),
+                    signature: signing_key()
+                        .sign(&init_proof(&nonce, username, addr, &key, &identity)),
+                };
+                _ = stream.write_frame(&prove.encode());
+            }
+            Ok(Message::Refused { reason }) => {
+                println!("The gateway refused to let us in: {}", reason);
+                exit(0);
+            }
+            Ok(Message::Buddies { buddies, .. }) => {
+                return Some((gateway_capabilities, network, buddies))
+            }
+            _ => return None,
+        }
+    }
 }
 
 /*
  * A general handle connection method that decides which handle to use.
- * This is mainly used for the server.
+ * This is mainly used for the server. A peer may send several requests
+ * on one connection (a cache hand-off does), so keep answering until it
+ * hangs up or something needs the main thread's attention
 */
 
 pub fn handle_connection(
-    mut stream: &TcpStream,
+    stream: &mut Connection,
     recip: &str,
     user: &str,
     cache: &mut CacheMap,
 ) -> Option<Result<String, String>> {
-    // Read the message into a buffer
-    let mut buffer = [0; 2048];
-
-    // Split the message into a status line and a body
-    if let Ok(i) = stream.read(&mut buffer) {
-        if i == 0 {
-            println!("Main Server Shutdown");
-            exit(0);
-        }
+    let mut puller = Puller::default();
+    loop {
+        // Read the message, a peer that hangs up has nothing more to handle
+        let frame = stream.read_frame().ok()?;
 
-        let as_string = std::str::from_utf8(&buffer[..i]).unwrap();
+        if let Some(result) = handle_frame(stream, &frame, recip, user, &mut puller, cache) {
+            return Some(result);
+        }
+    }
+}
 
-        // Handle based on the status code
-        if let Some((code, message)) = as_string.split_once(" ") {
-            let response: HandlerResult = match code {
-                "ACK" => handle_acker(message, recip),
-                "INIT" => handle_init(message, cache),
-                "SEND" => handle_send(message, recip, user),
-                "CACHE" => handle_cache(message, cache),
-                "404" => handle_not_found(message),
-                _ => handle_error(message),
-            };
+/*
+ * Handle a single request, replies are written straight back
+*/
 
-            // Take action based on the result
-            if let Err(reply) = response {
-                stream.write_all(reply.as_bytes()).unwrap();
-                stream.flush().unwrap();
-            } else if let Ok(returner) = response {
-                return Some(returner);
-            }
+fn handle_frame(
+    stream: &mut Connection,
+    frame: &[u8],
+    recip: &str,
+    user: &str,
+    puller: &mut Puller,
+    cache: &mut CacheMap,
+) -> Option<Result<String, String>> {
+    // Handle based on the status code
+    let response: HandlerResult = match Message::decode(frame) {
+        Ok(Message::Ack { username, id }) => handle_acker(&username, &id, puller, cache),
+        Ok(Message::Init { username, .. }) => handle_init(&username, puller),
+        Ok(Message::Prove {
+            username,
+            signature,
+        }) => handle_prove(&username, &signature, puller, cache),
+        Ok(Message::Pull {
+            username,
+            cursor,
+            limit,
+        }) => handle_pull(&username, &cursor, limit, puller, cache),
+        Ok(Message::Send {
+            sender,
+            id,
+            body,
+            signature,
+            ..
+        }) => handle_send(
+            StoredMessage {
+                sender,
+                id,
+                body,
+                signature,
+            },
+            recip,
+            user,
+        ),
+        Ok(Message::Cache {
+            recipient,
+            sender,
+            id,
+            body,
+            signature,
+        }) => handle_cache(
+            recipient,
+            StoredMessage {
+                sender,
+                id,
+                body,
+                signature,
+            },
+            cache,
+        ),
+        Ok(Message::NotFound { reason }) => handle_not_found(&reason),
+        Ok(other) => handle_error(&other.to_string()),
+        Err(e) => handle_error(&e.to_string()),
+    };
 
-            return None;
-        };
+    // Take action based on the result
+    match response {
+        Err(Some(reply)) => _ = stream.write_frame(&reply.encode()),
+        Err(None) => (),
+        Ok(returner) => return Some(returner),
     }
 
     None
 }
 
 /*
- * Handles an ack by writing the message locally (confirmed delivery)
+ * Handles an ack from a recipient pulling its messages, the message with
+ * that id arrived so we can let go of it. Anyone but the proven recipient
+ * is refused, or they could make us drop messages that never arrived
 */
 
-fn handle_acker(message: &str, recip: &str) -> HandlerResult {
-    // Pull the original message out
-    let (username, orig_message) = message.split_once(";").unwrap();
+fn handle_acker(username: &str, id: &str, puller: &Puller, cache: &mut CacheMap) -> HandlerResult {
+    if !puller.is(username) {
+        return Err(Some(not_signed_in(username)));
+    }
+    cache.lock().unwrap().remove(username, &[id.to_string()]);
 
-    // Construct a filename based on directory and username
-    let file_name: String = MDIR.to_owned() + username + ".txt";
+    // No response required
+    Err(None)
+}
 
-    // Write the original message to the appropriate file
-    write_message(file_name, &("You;".to_owned() + orig_message));
+/*
+ * Receive an ip retrieval message from the server
+*/
 
-    // Print to stdout if it matches the current recipt
-    if username == recip {
-        let formatted_t = &Utc::now().to_rfc2822()[..25];
-        println!("{} You -> {}", formatted_t, orig_message);
+pub fn handle_ip_retrieval(stream: &mut Connection) -> Option<String> {
+    match read_message(stream)? {
+        Message::IpRetrieval { addr } => Some(addr),
+        _ => None,
     }
+}
 
-    // No response required
-    Ok(Ok(String::from("")))
+/*
+ * Receive a user's public key from the server, along with the identity
+ * key their name is bound to if they have one
+*/
+
+pub fn handle_key(stream: &mut Connection) -> Option<(String, Option<String>)> {
+    match read_message(stream)? {
+        Message::Key { key, identity, .. } => Some((key, identity)),
+        _ => None,
+    }
 }
 
 /*
- * Receive an ip retrieval message from the server
+ * Receive the answer to a key lookup, telling a gateway that has no key
+ * for the user (None) apart from one that didn't answer (Err)
 */
 
-pub fn handle_ip_retrieval(stream: &mut TcpStream) -> Option<String> {
-    let mut buffer = [0; 2048];
-
-    // Split the message into a status line and a body
-    if let Ok(i) = stream.read(&mut buffer) {
-        let as_string = std::str::from_utf8(&buffer[..i]).unwrap();
-
-        // Split the code of the message
-        if let Some((code, message)) = as_string.split_once(" ") {
-            match code {
-                "IP_RETRIEVAL" => return match message {
-                    "" => None,
-                    _ => Some(message.to_string()),
-                },
-                _ => return None,
-            };
-        };
+pub fn handle_key_lookup(
+    stream: &mut Connection,
+) -> Result<Option<(String, Option<String>)>, String> {
+    match read_message(stream) {
+        Some(Message::Key { key, identity, .. }) => Ok(Some((key, identity))),
+        Some(Message::NotFound { .. }) => Ok(None),
+        Some(other) => Err(format!("unexpected reply {}", other.code())),
+        None => Err("the gateway didn't answer".to_string()),
     }
+}
 
-    None
+/*
+ * Receive the bundle a session with a user is started from
+*/
+
+pub fn handle_bundle(stream: &mut Connection) -> Option<PrekeyBundle> {
+    match read_message(stream)? {
+        Message::Bundle { bundle, .. } => Some(bundle),
+        _ => None,
+    }
 }
 
 /*
- * Receive an ack from a message sent from the main thread
+ * Receive an ack for a message sent from the main thread and write the
+ * message locally once the recipient confirms its id (confirmed delivery)
 */
 
-pub fn handle_ack(stream: &mut TcpStream, recip: &str) {
-    let mut buffer = [0; 2048];
+pub fn handle_ack(stream: &mut Connection, recip: &str, sent: &StoredMessage) {
+    match read_message(stream) {
+        Some(Message::Ack { username, id }) if username == recip && id == sent.id => {
+            // Write the original message to the chat with whoever we sent
+            // it to, never to a log the peer names
+            write_message(contact_file(recip, CHAT_LOG), "You", &sent.body, None);
 
-    // Split the message into a status line and a body
-    if let Ok(i) = stream.read(&mut buffer) {
-        let as_string = std::str::from_utf8(&buffer[..i]).unwrap();
+            let formatted_t = &Utc::now().to_rfc2822()[..25];
+            println!("{} You -> {}", formatted_t, sent.body);
+        }
+        Some(Message::Rejected { reason, .. }) => println!("Message not sent: {}", reason),
+        Some(other) => println!("Invalid ack message: {}", other),
+        None => (),
+    }
+}
 
-        // Split the code of the message
-        if let Some((code, message)) = as_string.split_once(" ") {
-            match code {
-                "ACK" => {
-                    // Pull the original message out
-                    let (username, orig_message) = message.split_once(";").unwrap();
+/*
+ * Receive the delivery tokens the gateway signed for us, still blinded,
+ * or why it wouldn't
+*/
 
-                    // Construct a filename based on directory and username
-                    let file_name: String = MDIR.to_owned() + username + ".txt";
+pub fn handle_issued(stream: &mut Connection) -> Result<Vec<String>, String> {
+    match read_message(stream) {
+        Some(Message::Issued { tokens }) => Ok(tokens),
+        Some(Message::Refused { reason }) | Some(Message::NotFound { reason }) => Err(reason),
+        Some(other) => Err(format!("unexpected reply {}", other.code())),
+        None => Err("no reply".to_string()),
+    }
+}
+
+/*
+ * Receive the gateway's answer to spending a delivery token. None if it
+ * didn't answer, so the token may still be good
+*/
 
-                    // Write the original message to the appropriate file
-                    write_message(file_name, &("You;".to_owned() + orig_message));
+pub fn handle_redeemed(stream: &mut Connection, token: &str) -> Option<Result<(), String>> {
+    match read_message(stream)? {
+        Message::Redeemed { token: spent } if spent == token => Some(Ok(())),
+        Message::Refused { reason } | Message::NotFound { reason } => Some(Err(reason)),
+        other => Some(Err(format!("unexpected reply {}", other.code()))),
+    }
+}
 
-                    // Print to stdout if it matches the current recipt
-                    if username == recip {
-                        let formatted_t = &Utc::now().to_rfc2822()[..25];
-                        println!("{} You -> {}", formatted_t, orig_message);
-                    }
-                }
-                _ => println!("Invalid update message: {}", as_string),
+/*
+ * Receive a buddy's ack for a message we asked it to cache, or the reason
+ * it didn't take it
+*/
+
+pub fn handle_cache_ack(stream: &mut Connection, recipient: &str, id: &str) -> Result<(), String> {
+    match read_message(stream) {
+        Some(Message::Ack {
+            username,
+            id: acked,
+        }) if username == recipient && acked == id => Ok(()),
+        Some(Message::Rejected { reason, .. }) => Err(reason),
+        Some(other) => Err(format!("unexpected reply {}", other.code())),
+        None => Err("no reply".to_string()),
+    }
+}
+
+/*
+ * Pull everything a buddy holds for us a page at a time, once we have
+ * signed its challenge with our identity key. Each message is
+ * written locally and acked before the next page is asked for, so the
+ * buddy only lets go of what we actually have
+*/
+
+pub fn handle_mailbox(stream: &mut Connection, user: &str) {
+    loop {
+        let messages = match read_message(stream) {
+            Some(Message::Update { messages }) => messages,
+            Some(Message::Challenge { nonce }) => {
+                // The buddy wants proof these are ours before handing them out
+                let prove = Message::Prove {
+                    username: user.to_string(),
+                    signature: signing_key().sign(&mailbox_proof(&nonce, user)),
+                };
+                _ = stream.write_frame(&prove.encode());
+                continue;
+            }
+            Some(Message::Refused { reason }) => {
+                println!("A buddy wouldn't hand over our messages: {}", reason);
+                return;
+            }
+            Some(other) => {
+                println!("Invalid update message: {}", other);
+                return;
+            }
+            None => return,
+        };
+
+        // A short page is the last one
+        let cursor = match messages.last() {
+            Some(last) if messages.len() >= PAGE_SIZE as usize => Some(last.id.clone()),
+            _ => None,
+        };
+
+        for message in messages {
+            if !deliver(&message, Some(""), user) {
+                continue;
+            }
+
+            let ack = Message::Ack {
+                username: user.to_string(),
+                id: message.id,
+            };
+            _ = stream.write_frame(&ack.encode());
+        }
+
+        match cursor {
+            Some(cursor) => {
+                let pull = Message::Pull {
+                    username: user.to_string(),
+                    cursor,
+                    limit: PAGE_SIZE,
+                };
+                _ = stream.write_frame(&pull.encode());
             }
+            None => return,
         }
     }
 }
@@ -184,25 +469,40 @@ pub fn handle_ack(stream: &mut TcpStream, recip: &str) {
  * Receive the cache update from the server
 */
 
-pub fn handle_update(stream: &mut TcpStream, message_set: &mut LinkedHashSet<String>) {
-    let mut buffer = [0; 2048];
+pub fn handle_update(stream: &mut Connection, message_set: &mut LinkedHashSet<StoredMessage>) {
+    match read_message(stream) {
+        Some(Message::Update { messages }) => {
+            for message in messages {
+                message_set.insert(message);
+            }
+        }
+        Some(other) => println!("Invalid update message: {}", other),
+        None => (),
+    }
+}
 
-    // Split the message into a status line and a body
-    if let Ok(i) = stream.read(&mut buffer) {
-        let as_string = std::str::from_utf8(&buffer[..i]).unwrap();
+/*
+ * Receive the messages the gateway was holding for us, write each one
+ * locally and ack it by id so the gateway can let go of it
+*/
 
-        // Split the code of the message
-        if let Some((code, message)) = as_string.split_once(" ") {
-            match code {
-                "UPDATE" => {
-                    let mut messages = message.split(DELIMITER);
-                    while let Some(message) = messages.next() {
-                        message_set.insert(message.to_string());
-                    }
+pub fn handle_pending(stream: &mut Connection, recip: &str, user: &str) {
+    match read_message(stream) {
+        Some(Message::Update { messages }) => {
+            for message in messages {
+                if !deliver(&message, Some(recip), user) {
+                    continue;
                 }
-                _ => println!("Invalid update message: {}", as_string),
+
+                let ack = Message::Ack {
+                    username: user.to_string(),
+                    id: message.id,
+                };
+                _ = stream.write_frame(&ack.encode());
             }
         }
+        Some(other) => println!("Invalid update message: {}", other),
+        None => (),
     }
 }
 
@@ -210,85 +510,265 @@ pub fn handle_update(stream: &mut TcpStream, message_set: &mut LinkedHashSet<Str
  * Write the sent message locally, then return an ack
 */
 
-fn handle_send(message: &str, recip: &str, user: &str) -> HandlerResult {
-    // Split sender and message
-    let (sender, orig_message) = message.split_once(";").unwrap();
+fn handle_send(message: StoredMessage, recip: &str, user: &str) -> HandlerResult {
+    if !deliver(&message, Some(recip), user) {
+        return Err(Some(Message::Rejected {
+            username: user.to_string(),
+            id: message.id,
+            reason: "can't check who it is from right now, send it again later".to_string(),
+        }));
+    }
+
+    // Ack by id so the sender knows exactly which message arrived
+    Err(Some(Message::Ack {
+        username: user.to_string(),
+        id: message.id,
+    }))
+}
+
+/*
+ * Decrypt a message sent to user and write it to the chat log with its
+ * sender and whether its signature checked out. It is shown if it comes
+ * from recip, or whoever it comes from if there is no recip. One that
+ * can't be decrypted or carries a bad signature is only warned about, it
+ * is acked all the same since asking for it again won't make it any better.
+ * A body in the clear is only taken from a sender the gateway has no key
+ * for, anyone who has one encrypts. Returns whether the message is done
+ * with and can be acked: one whose sender's identity couldn't be looked up
+ * is held, and is checked again when it comes round another time
+*/
+
+fn deliver(message: &StoredMessage, recip: Option<&str>, user: &str) -> bool {
+    // A sealed message only says who it is from once it is opened
+    let message = match open_envelope(user, message) {
+        Ok(message) => message,
+        Err(e) => {
+            println!("Dropped sealed message {}: {}", message.id, e);
+            return true;
+        }
+    };
+    let message = &message;
+
+    if is_plaintext(message) {
+        if sends_in_the_clear(&message.sender) {
+            show(message, recip, false, "unencrypted");
+        } else {
+            println!(
+                "Dropped message {} from {}: it wasn't encrypted",
+                message.id, message.sender
+            );
+        }
+        return true;
+    }
+
+    let verified = match check_signature(user, message) {
+        Some(Ok(verified)) => verified,
+        Some(Err(e)) => {
+            println!(
+                "Dropped message {} from {}: {}",
+                message.id, message.sender, e
+            );
+            return true;
+        }
+        None => {
+            println!(
+                "Holding message {} from {}: can't look up their identity",
+                message.id, message.sender
+            );
+            return false;
+        }
+    };
+
+    let message = match open_message(user, message) {
+        Ok(message) => message,
+        Err(e) => {
+            println!(
+                "Dropped message {} from {}: {}",
+                message.id, message.sender, e
+            );
+            return true;
+        }
+    };
 
+    show(&message, recip, verified, verified_tag(verified));
+    true
+}
+
+/*
+ * Write a message we received to the chat log, and print it with the tag
+ * if it comes from recip (or there is no recip)
+*/
+
+fn show(message: &StoredMessage, recip: Option<&str>, verified: bool, tag: &str) {
     // Construct a filename based on directory and username
-    let file_name: String = MDIR.to_owned() + sender + ".txt";
+    let file_name = contact_file(&message.sender, CHAT_LOG);
 
     // Write the original message to the appropriate file
-    write_message(file_name, message);
+    write_message(file_name, &message.sender, &message.body, Some(verified));
 
     // Print to stdout if it matches the current recipt
-    if sender == recip {
+    if recip.is_none_or(|recip| message.sender == recip) {
         let formatted_t = &Utc::now().to_rfc2822()[..25];
-        println!("{} {} -> {}", formatted_t, sender, orig_message);
+        println!(
+            "{} {} -> {} [{}]",
+            formatted_t, message.sender, message.body, tag
+        );
     }
-
-    Err("ACK ".to_owned() + user + ";" + orig_message)
 }
 
 /*
  * Return the list of buddies from the stream
 */
 
-pub fn handle_buddies(stream: &mut TcpStream) -> Option<String> {
-    let mut buffer = [0; 2048];
+pub fn handle_buddies(stream: &mut Connection) -> Option<Vec<String>> {
+    match read_message(stream)? {
+        Message::Buddies { buddies, .. } => Some(buddies),
+        _ => None,
+    }
+}
+
+/*
+ * Handles a cache message for a potential buddy, acking it by id so a
+ * buddy handing its cache over knows it can let go. Messages that name
+ * their sender are only taken signed by the sender's identity key, anyone
+ * could have made up the rest. Sealed messages don't say who they are
+ * from, they are only taken with a delivery token the gateway spends on
+ * them, and the recipient checks the signature inside
+*/
 
-    // Split the message into a status line and a body
-    if let Ok(i) = stream.read(&mut buffer) {
-        let as_string = std::str::from_utf8(&buffer[..i]).unwrap();
+fn handle_cache(recip: String, message: StoredMessage, cache: &mut CacheMap) -> HandlerResult {
+    let id = message.id.clone();
 
-        // Split the code of the message
-        if let Some((code, message)) = as_string.split_once(" ") {
-            match code {
-                "BUDDIES" => return Some(message.to_string()),
-                _ => return None,
+    let unverified = if is_sealed(&message) {
+        // Only the gateway can tell a good token, and spending it on this
+        // message keeps it from being spent on any other
+        match redeem_token(&recip, &message) {
+            Some(result) => result.err(),
+            None => {
+                Some("can't check the delivery token right now, send it again later".to_string())
             }
         }
+    } else {
+        // A sender the gateway has no identity for can't sign, its
+        // messages are kept and shown unverified. Only a failed lookup is
+        // worth sending again
+        match check_signature(&recip, &message) {
+            Some(Ok(_)) => None,
+            Some(Err(reason)) => Some(reason),
+            None => Some(format!(
+                "can't look up {}'s identity right now, send it again later",
+                message.sender
+            )),
+        }
+    };
+    if let Some(reason) = unverified {
+        return Err(Some(Message::Rejected {
+            username: recip,
+            id,
+            reason,
+        }));
     }
 
-    None
+    // Add the new message to any existing cached messages, a message
+    // sent to us twice is only kept once. A recipient over quota gets
+    // nothing more and the sender is told why
+    let reply = match cache.lock().unwrap().insert(&recip, message) {
+        Ok(()) => Message::Ack {
+            username: recip,
+            id,
+        },
+        Err(reason) => Message::Rejected {
+            username: recip,
+            id,
+            reason,
+        },
+    };
+
+    Err(Some(reply))
 }
 
 /*
- * Handles a cache message for a potential buddy
+ * Handles an init message from a buddy come to pull its messages. It is
+ * challenged to sign a nonce with the identity key its name is bound to
+ * before it gets any
 */
 
-fn handle_cache(message: &str, cache: &mut CacheMap) -> HandlerResult {
-    // Split the message from the recipient
-    let (recip, cached_message) = message.split_once(";").unwrap();
-
-    let mut cache = cache.lock().unwrap();
+fn handle_init(username: &str, puller: &mut Puller) -> HandlerResult {
+    let nonce = match random_bytes::<NONCE_LEN>() {
+        Ok(bytes) => to_hex(&bytes),
+        Err(e) => {
+            return Err(Some(Message::Refused {
+                reason: format!("can't make a challenge: {}", e),
+            }))
+        }
+    };
 
-    // Get any existing cached messages
-    let existing_cache = match cache.get(recip) {
-        Some(existing_cache) => existing_cache.clone(),
-        None => "".to_string(),
+    *puller = Puller {
+        username: username.to_string(),
+        nonce: nonce.clone(),
+        proven: false,
     };
+    Err(Some(Message::Challenge { nonce }))
+}
+
+/*
+ * Check a buddy's answer to our challenge against the identity key the
+ * gateway has for it, and send the first page of its messages if it signed
+ * the nonce. Nothing is removed until the buddy acks it
+*/
+
+fn handle_prove(
+    username: &str,
+    signature: &str,
+    puller: &mut Puller,
+    cache: &mut CacheMap,
+) -> HandlerResult {
+    // A nonce is only good for one answer
+    let nonce = std::mem::take(&mut puller.nonce);
+    if nonce.is_empty() || puller.username != username {
+        return Err(Some(not_signed_in(username)));
+    }
 
-    // Insert the new message appended to the existing messages
-    cache.insert(
-        recip.to_owned(),
-        existing_cache + DELIMITER + cached_message,
-    );
-    
-    Ok(Ok(String::from("")))
+    let proven = identity_of(username).is_some_and(|identity| {
+        verify(&identity, signature, &mailbox_proof(&nonce, username)).is_ok()
+    });
+    if !proven {
+        return Err(Some(Message::Refused {
+            reason: format!("can't verify that this is {}", username),
+        }));
+    }
+
+    puller.proven = true;
+    handle_pull(username, "", PAGE_SIZE, puller, cache)
 }
 
 /*
- * Handles an init message from a buddy
+ * Send the next page of a buddy's messages, the ones after the cursor in
+ * id order
 */
 
-fn handle_init(message: &str, cache: &mut CacheMap) -> HandlerResult {
-    let cached_messages = cache.lock().unwrap().insert(message.to_owned(), "".to_owned());
+fn handle_pull(
+    username: &str,
+    cursor: &str,
+    limit: u32,
+    puller: &Puller,
+    cache: &mut CacheMap,
+) -> HandlerResult {
+    if !puller.is(username) {
+        return Err(Some(not_signed_in(username)));
+    }
 
-    // If there were cached messages, send them to the buddy
-    if let Some(cached_messages) = cached_messages {
-        Err("UPDATE ".to_owned() + &cached_messages)
-    } else {
-        Err("UPDATE ".to_owned() + DELIMITER)
+    let messages = cache
+        .lock()
+        .unwrap()
+        .page(username, cursor, limit.min(MAX_PAGE_SIZE) as usize);
+
+    Err(Some(Message::Update { messages }))
+}
+
+fn not_signed_in(username: &str) -> Message {
+    Message::Refused {
+        reason: format!("not signed in as {}", username),
     }
 }
 
@@ -298,7 +778,10 @@ fn handle_init(message: &str, cache: &mut CacheMap) -> HandlerResult {
 
 fn handle_error(message: &str) -> HandlerResult {
     // We don't know how to handle this request, so send that to main thread
-    Ok(Err("404 ".to_owned() + message))
+    Ok(Err(Message::NotFound {
+        reason: message.to_string(),
+    }
+    .to_string()))
 }
 
 /*
@@ -307,5 +790,8 @@ fn handle_error(message: &str) -> HandlerResult {
 
 fn handle_not_found(message: &str) -> HandlerResult {
     println!("{}", message);
-    Ok(Err("404 ".to_owned() + message))
+    Ok(Err(Message::NotFound {
+        reason: message.to_string(),
+    }
+    .to_string()))
 }
@@ -0,0 +1,332 @@
+use messaging_protocol::crypto::{
+    from_hex, load_secret, message_proof, open, seal, verify, CryptoError, KeyPair, SigningPair,
+    KEY_LEN,
+};
+use messaging_protocol::envelope::{is_sealed, open_sender};
+use messaging_protocol::message::StoredMessage;
+use messaging_protocol::ratchet::is_session_body;
+use messaging_protocol::tokens::{message_digest, today, token_day};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use super::handlers::{handle_key_lookup, handle_redeemed};
+use super::senders::{init_stream, key_fetch, redeem};
+use super::sessions::{session_open, session_seal};
+use super::utils::{data_dir, gateway};
+
+// The files in the data dir holding our secret keys, the one messages to
+// us are encrypted for and the one our username is bound to
+pub const KEY_FILE: &str = "encryption.key";
+pub const IDENTITY_FILE: &str = "identity.key";
+
+// Our key pairs, loaded from the data dir the first time they are needed
+// and the same for every thread after that
+static ENCRYPTION_KEY: OnceLock<KeyPair> = OnceLock::new();
+static SIGNING_KEY: OnceLock<SigningPair> = OnceLock::new();
+
+// The identity keys the gateway vouched for, by username. Only keys it
+// actually had are kept, a user without one is asked about again
+static IDENTITIES: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
+// The delivery tokens the gateway signed for us that we haven't spent
+static TOKENS: OnceLock<Mutex<Vec<String>>> = OnceLock::new();
+
+// How long we wait on the gateway when looking up an identity key or
+// spending a token
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The public keys of the people we have written to, by username
+pub type Contacts = HashMap<String, String>;
+
+pub fn encryption_key() -> &'static KeyPair {
+    ENCRYPTION_KEY.get_or_init(|| {
+        load_encryption_key(&(data_dir().to_owned() + KEY_FILE))
+            .expect("Couldn't load the encryption key")
+    })
+}
+
+pub fn signing_key() -> &'static SigningPair {
+    SIGNING_KEY.get_or_init(|| {
+        load_signing_key(&(data_dir().to_owned() + IDENTITY_FILE))
+            .expect("Couldn't load the identity key")
+    })
+}
+
+pub fn load_encryption_key(path: &str) -> io::Result<KeyPair> {
+    let secret = load_secret(Path::new(path), || Ok(KeyPair::generate()?.secret_hex()))?;
+    KeyPair::from_secret_hex(&secret).map_err(|e| invalid_key(path, e))
+}
+
+pub fn load_signing_key(path: &str) -> io::Result<SigningPair> {
+    let secret = load_secret(
+        Path::new(path),
+        || Ok(SigningPair::generate()?.secret_hex()),
+    )?;
+    SigningPair::from_secret_hex(&secret).map_err(|e| invalid_key(path, e))
+}
+
+fn invalid_key(path: &str, e: CryptoError) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("{}: {}", path, e))
+}
+
+/*
+ * Keep the key the gateway gave us for a user. A key that changed means
+ * they set up again or someone is pretending to be them, so say so
+*/
+
+pub fn remember_key(contacts: &mut Contacts, username: &str, key: String) {
+    if let Some(old) = contacts.get(username) {
+        if *old != key {
+            println!(
+                "Warning: {}'s encryption key changed, they may have set up a new device",
+                username
+            );
+        }
+    }
+    contacts.insert(username.to_string(), key);
+}
+
+/*
+ * Encrypt a message's body for its recipient. The recipient, sender and id
+ * are bound to the body so it can't be replayed as another message
+*/
+
+pub fn seal_message(
+    recipient: &str,
+    key: &str,
+    message: &StoredMessage,
+) -> Result<StoredMessage, CryptoError> {
+    let context = [recipient, &message.sender, &message.id];
+    Ok(StoredMessage {
+        sender: message.sender.clone(),
+        id: message.id.clone(),
+        body: seal(key, &message.body, &context)?,
+        signature: None,
+    })
+}
+
+/*
+ * Encrypt a message's body with our session with the recipient, bound to
+ * the message the same way. None if we have no session with them yet
+*/
+
+pub fn seal_with_session(
+    recipient: &str,
+    message: &StoredMessage,
+) -> Option<Result<StoredMessage, CryptoError>> {
+    let context = [recipient, &message.sender, &message.id];
+    let body = session_seal(recipient, &message.body, &context)?;
+    Some(body.map(|body| StoredMessage {
+        sender: message.sender.clone(),
+        id: message.id.clone(),
+        body,
+        signature: None,
+    }))
+}
+
+/*
+ * Decrypt a message sent to us, with the session it was written in or
+ * with our own key if it was sealed without one
+*/
+
+pub fn open_message(
+    recipient: &str,
+    message: &StoredMessage,
+) -> Result<StoredMessage, CryptoError> {
+    let context = [recipient, &message.sender, &message.id];
+    let body = if is_session_body(&message.body) {
+        session_open(&message.sender, &message.body, &context)?
+    } else {
+        open(encryption_key(), &message.body, &context)?
+    };
+
+    Ok(StoredMessage {
+        sender: message.sender.clone(),
+        id: message.id.clone(),
+        body,
+        signature: message.signature.clone(),
+    })
+}
+
+/*
+ * Take a sealed message sent to us out of its envelope. Messages that
+ * weren't sealed come back as they are
+*/
+
+pub fn open_envelope(
+    recipient: &str,
+    message: &StoredMessage,
+) -> Result<StoredMessage, CryptoError> {
+    if is_sealed(message) {
+        open_sender(recipient, encryption_key(), message)
+    } else {
+        Ok(message.clone())
+    }
+}
+
+/*
+ * Sign an encrypted message with our identity key. The signature covers
+ * the recipient as well, so it can't be cached for someone else
+*/
+
+pub fn sign_message(recipient: &str, message: &StoredMessage) -> StoredMessage {
+    let proof = message_proof(recipient, &message.sender, &message.id, &message.body);
+    StoredMessage {
+        signature: Some(signing_key().sign(&proof)),
+        ..message.clone()
+    }
+}
+
+/*
+ * Check a message's signature against the identity key the gateway has
+ * for its sender. Ok(true) if it verified, Ok(false) if the gateway says
+ * the sender has no identity to check it against, and Err if it is
+ * missing or doesn't match. None if the identity couldn't be looked up,
+ * the message can't be taken or turned down until it can
+*/
+
+pub fn check_signature(recipient: &str, message: &StoredMessage) -> Option<Result<bool, String>> {
+    let identity = match lookup_identity(&message.sender) {
+        Ok(Some(identity)) => identity,
+        Ok(None) => return Some(Ok(false)),
+        Err(_) => return None,
+    };
+
+    let signature = match &message.signature {
+        Some(signature) => signature,
+        None => return Some(Err(format!("{} didn't sign the message", message.sender))),
+    };
+    let proof = message_proof(recipient, &message.sender, &message.id, &message.body);
+    Some(
+        verify(&identity, signature, &proof)
+            .map(|_| true)
+            .map_err(|_| format!("the signature isn't {}'s", message.sender)),
+    )
+}
+
+/*
+ * Keep delivery tokens for the sealed messages we send later
+*/
+
+pub fn keep_tokens(tokens: Vec<String>) {
+    wallet().lock().unwrap().extend(tokens);
+}
+
+/*
+ * How many of our tokens the gateway would still take. Ones made more
+ * than a day ago are thrown away, it stops taking them at midnight
+*/
+
+pub fn tokens_left() -> usize {
+    let mut tokens = wallet().lock().unwrap();
+    tokens.retain(|token| token_day(token).is_some_and(|day| day + 1 >= today()));
+    tokens.len()
+}
+
+/*
+ * Take a token to spend on a sealed message, the oldest first
+*/
+
+pub fn take_token() -> Option<String> {
+    tokens_left();
+    let mut tokens = wallet().lock().unwrap();
+    if tokens.is_empty() {
+        return None;
+    }
+    Some(tokens.remove(0))
+}
+
+fn wallet() -> &'static Mutex<Vec<String>> {
+    TOKENS.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+/*
+ * Have the gateway spend the delivery token a sealed message carries on
+ * that message, on a connection of our own. Only the gateway can tell a
+ * token it issued, and it is only told a digest of the message, not who
+ * it is for. Spending the same token on the same message again is fine,
+ * on any other it is refused. None if the gateway couldn't be asked
+*/
+
+pub fn redeem_token(recipient: &str, message: &StoredMessage) -> Option<Result<(), String>> {
+    let token = match &message.signature {
+        Some(token) => token,
+        None => return Some(Err("sealed messages need a delivery token".to_string())),
+    };
+
+    let mut stream = init_stream(gateway()).ok()?;
+    _ = stream.get_ref().set_read_timeout(Some(LOOKUP_TIMEOUT));
+    redeem(
+        token,
+        &message_digest(recipient, &message.id, &message.body),
+        &mut stream,
+    );
+    handle_redeemed(&mut stream, token)
+}
+
+/*
+ * Look up the identity key a user's name is bound to, asking the gateway
+ * on a connection of our own the first time
+*/
+
+pub fn identity_of(username: &str) -> Option<String> {
+    lookup_identity(username).ok()?
+}
+
+/*
+ * The identity key a user's name is bound to. None if the gateway says
+ * there is none, Err if it couldn't be asked or didn't answer in time
+*/
+
+fn lookup_identity(username: &str) -> Result<Option<String>, String> {
+    let identities = IDENTITIES.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Some(identity) = identities.lock().unwrap().get(username) {
+        return Ok(Some(identity.clone()));
+    }
+
+    let identity = match lookup_keys(username)? {
+        Some((_, Some(identity))) => identity,
+        _ => return Ok(None),
+    };
+
+    identities
+        .lock()
+        .unwrap()
+        .insert(username.to_string(), identity.clone());
+    Ok(Some(identity))
+}
+
+/*
+ * Whether the gateway says a user has no encryption key. They run a
+ * client from before ENCRYPTED_VERSION, which sends bodies in the clear
+*/
+
+pub fn sends_in_the_clear(username: &str) -> bool {
+    matches!(lookup_keys(username), Ok(None))
+}
+
+/*
+ * Ask the gateway for a user's key and identity on a connection of our
+ * own. None if it has no key for them, Err if it couldn't be asked
+*/
+
+fn lookup_keys(username: &str) -> Result<Option<(String, Option<String>)>, String> {
+    let mut stream =
+        init_stream(gateway()).map_err(|e| format!("can't reach the gateway: {}", e))?;
+    _ = stream.get_ref().set_read_timeout(Some(LOOKUP_TIMEOUT));
+    key_fetch(username, &mut stream);
+    handle_key_lookup(&mut stream)
+}
+
+/*
+ * Whether a message's body went out in the clear: it isn't signed, and is
+ * neither a session message nor sealed for our key
+*/
+
+pub fn is_plaintext(message: &StoredMessage) -> bool {
+    let sealed = from_hex(&message.body).is_some_and(|bytes| bytes.len() > KEY_LEN);
+    message.signature.is_none() && !is_session_body(&message.body) && !sealed
+}
@@ -1,3 +1,7 @@
+pub mod cache;
+pub mod config;
 pub mod handlers;
+pub mod keys;
 pub mod senders;
+pub mod sessions;
 pub mod utils;
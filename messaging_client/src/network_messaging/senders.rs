@@ -1,70 +1,103 @@
-use std::io::Write;
+use chrono::Utc;
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::{
+    Capability, Message, NetworkParams, StoredMessage, PROTOCOL_VERSION,
+};
+use messaging_protocol::tokens::{today, Blind};
+use std::collections::HashMap;
 use std::net::{TcpStream, ToSocketAddrs};
 use std::time::Duration;
-use linked_hash_set::LinkedHashSet;
 
-use super::handlers::{handle_buddies, handle_update, DELIMITER, MDIR};
-use super::utils::write_message;
+use super::handlers::{
+    handle_buddies, handle_bundle, handle_cache_ack, handle_issued, handle_mailbox,
+    handle_main_server_connection, handle_pending, CacheMap, Connection,
+};
+use super::keys::{encryption_key, keep_tokens, signing_key, take_token, tokens_left};
+use super::sessions::{prekeys, PREKEY_FILE};
+use super::utils::{data_dir, token_key};
 
-// Hardcode the gateway address
-const SERVER: &str = "limia.cs.williams.edu:8013";
+// How many delivery tokens we ask for at a time, and how few we let
+// ourselves run down to before asking again
+const TOKEN_BATCH: usize = 20;
+const TOKENS_LOW: usize = 5;
+
+// Features this client announces in its INIT
+const CLIENT_CAPABILITIES: [Capability; 4] = [
+    Capability::ServerRelay,
+    Capability::DirectSend,
+    Capability::BuddyCache,
+    Capability::Encrypted,
+];
 
 /*
- * Creates the tcp connection to the main server and sends an init
- * message based on the entered username
+ * Creates the tcp connection to the gateway and sends an init message
+ * based on the entered username, announcing addr as the place peers reach
+ * us. Returns the connection along
+ * with the features the gateway supports and the network settings
 */
 
-pub fn initialize(username: &str, ip_addr: &str, port: u16) -> Option<TcpStream> {
-    let stream = init_stream(SERVER);
+pub fn initialize(
+    username: &str,
+    addr: &str,
+    gateway: &str,
+) -> Option<(Connection, Vec<Capability>, NetworkParams)> {
+    let stream = init_stream(gateway);
 
     match stream {
         Ok(mut server) => {
-            let message = [
-                "INIT ".as_bytes(),
-                username.as_bytes(),
-                DELIMITER.as_bytes(),
-                ip_addr.as_bytes(),
-                ":".as_bytes(),
-                port.to_string().as_bytes(),
-            ]
-            .concat();
+            let message = Message::Init {
+                username: username.to_string(),
+                addr: addr.to_string(),
+                version: PROTOCOL_VERSION,
+                capabilities: CLIENT_CAPABILITIES.to_vec(),
+                key: Some(encryption_key().public_hex()),
+                identity: Some(signing_key().public_hex()),
+            }
+            .encode();
 
             // Send the init message to the gateway server
-            _ = send_message(&message, &server);
+            _ = send_message(&message, &mut server);
 
             // Try to connect through the given entry points
-            match handle_main_server_connection(&server, &username) {
-                Some(cluster) => {
-                    // Split the cluster into a vector of ip addresses
-                    let mut cluster_tokens = cluster.split(DELIMITER);
+            let mut gateway_capabilities = Capability::legacy();
+            let mut network = NetworkParams::default();
+            match handle_main_server_connection(&mut server, username, addr) {
+                Some((capabilities, params, cluster)) => {
+                    gateway_capabilities = capabilities;
+                    network = params;
+
+                    let mut cluster_tokens = cluster.iter();
                     let mut found_entrance = false;
 
-                    while !found_entrace {
+                    while !found_entrance {
                         if let Some(addr) = cluster_tokens.next() {
-                            if let Ok(mut stream) = init_stream(&addr) {
-                                // Send the init message to a node in the cluster
-                                _ = send_message(
-                                    &message,
-                                    &mut stream,
-                                );
-
-                                found_entrace = true;
+                            if let Ok(mut stream) = init_stream(addr) {
+                                // Send the init message to a node in the cluster and
+                                // collect what it held for us
+                                _ = send_message(&message, &mut stream);
+                                handle_mailbox(&mut stream, username);
+
+                                found_entrance = true;
                             }
                         } else {
                             println!("Unable to enter the network, try again");
                             break;
                         }
                     }
-                },
+                }
                 None => {
                     println!("Starting a new network!");
-                },
+                }
             };
 
+            // A relaying gateway follows up with what it held while we were away
+            if gateway_capabilities.contains(&Capability::ServerRelay) {
+                handle_pending(&mut server, "", username);
+            }
+
             println!("Welcome to Jaelegram");
 
-            // We are now in the network, so the main server connection doesn't matter
-            _ = server.shutdown(std::net::Shutdown::Both);
+            Some((server, gateway_capabilities, network))
         }
         Err(_) => None,
     }
@@ -74,14 +107,18 @@ pub fn initialize(username: &str, ip_addr: &str, port: u16) -> Option<TcpStream>
  * A helper method to make connecting easier
 */
 
-pub fn init_stream(addr: &str) -> Result<TcpStream, std::io::Error> {
-    if addr != "" {
-        TcpStream::connect_timeout(&addr.to_socket_addrs().unwrap().next().unwrap(), Duration::new(3, 0))
+pub fn init_stream(addr: &str) -> Result<Connection, std::io::Error> {
+    if !addr.is_empty() {
+        let stream = TcpStream::connect_timeout(
+            &addr
+                .to_socket_addrs()?
+                .next()
+                .ok_or(std::io::ErrorKind::InvalidInput)?,
+            Duration::new(3, 0),
+        )?;
+        Ok(FramedStream::new(stream))
     } else {
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Invalid Address",
-        ))
+        Err(std::io::Error::other("Invalid Address"))
     }
 }
 
@@ -89,68 +126,338 @@ pub fn init_stream(addr: &str) -> Result<TcpStream, std::io::Error> {
  * Creates ip_fetch method and sends it
 */
 
-pub fn ip_fetch(recipient: &str, server: &TcpStream) -> Option<String> {
-    let message = "IP_FETCH ".to_owned() + recipient;
-    send_message(message.as_bytes(), server)
+pub fn ip_fetch(recipient: &str, server: &mut Connection) -> Option<String> {
+    let message = Message::IpFetch {
+        username: recipient.to_string(),
+    };
+    send_message(&message.encode(), server)
+}
+
+/*
+ * Ask the gateway for the key messages to a user are encrypted with
+*/
+
+pub fn key_fetch(recipient: &str, server: &mut Connection) -> Option<String> {
+    let message = Message::KeyFetch {
+        username: recipient.to_string(),
+    };
+    send_message(&message.encode(), server)
+}
+
+/*
+ * Ask the gateway to spend a delivery token on the message with digest
+*/
+
+pub fn redeem(token: &str, digest: &str, server: &mut Connection) -> Option<String> {
+    let message = Message::Redeem {
+        token: token.to_string(),
+        digest: digest.to_string(),
+    };
+    send_message(&message.encode(), server)
+}
+
+/*
+ * Ask the gateway for another batch of delivery tokens once we are
+ * running low. This goes out on the connection we signed in on, but the
+ * nonces are blinded, so the gateway can't tell later which of the sealed
+ * messages it takes they paid for
+*/
+
+pub fn top_up_tokens(server: &mut Connection) -> Result<(), String> {
+    // A gateway without a token key takes no sealed messages at all
+    let key = match token_key() {
+        Some(key) if tokens_left() < TOKENS_LOW => key,
+        _ => return Ok(()),
+    };
+
+    let blinds = (0..TOKEN_BATCH)
+        .map(|_| Blind::new(today()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    let request = Message::Tokens {
+        blinded: blinds.iter().map(Blind::blinded_hex).collect(),
+    };
+    send_message(&request.encode(), server);
+    let issued = handle_issued(server)?;
+    if issued.len() != blinds.len() {
+        return Err("The gateway didn't sign every token".to_string());
+    }
+
+    let tokens = blinds
+        .iter()
+        .zip(&issued)
+        .map(|(blind, issued)| blind.finish(key, issued))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("The gateway's tokens don't check out: {}", e))?;
+    keep_tokens(tokens);
+    Ok(())
+}
+
+/*
+ * Put one of our delivery tokens on a sealed message, topping up first
+ * if there are none left
+*/
+
+pub fn with_token(
+    message: &StoredMessage,
+    server: &mut Connection,
+) -> Result<StoredMessage, String> {
+    if tokens_left() == 0 {
+        top_up_tokens(server)?;
+    }
+    let token = take_token().ok_or("The gateway doesn't issue delivery tokens")?;
+
+    Ok(StoredMessage {
+        signature: Some(token),
+        ..message.clone()
+    })
+}
+
+/*
+ * Ask the gateway for what it takes to start a session with a user
+*/
+
+pub fn bundle_fetch(recipient: &str, server: &mut Connection) -> Option<String> {
+    let message = Message::BundleFetch {
+        username: recipient.to_string(),
+    };
+    send_message(&message.encode(), server)
+}
+
+/*
+ * Publish a fresh batch of one-time prekeys so contacts can start sessions
+ * with us. They are saved before they go out, so we can always take up a
+ * session started with one of them
+*/
+
+pub fn publish_prekeys(username: &str, server: &mut Connection) -> Result<(), String> {
+    let message = {
+        let mut prekeys = prekeys().lock().unwrap();
+        let message = prekeys
+            .refill(username, signing_key(), Utc::now().timestamp())
+            .map_err(|e| e.to_string())?;
+        prekeys
+            .save(&(data_dir().to_owned() + PREKEY_FILE))
+            .map_err(|e| e.to_string())?;
+        message
+    };
+
+    send_message(&message.encode(), server);
+    match handle_bundle(server) {
+        Some(_) => Ok(()),
+        None => Err("The gateway didn't take our prekeys".to_string()),
+    }
 }
 
 /*
- * Sends a message to a stream
+ * Publish again if the gateway is running low on our one-time prekeys or
+ * the signed prekey is due to be replaced
 */
 
-pub fn send_message(message: &[u8], mut server: &TcpStream) -> Option<String> {
-    _ = server.write(message);
-    _ = server.flush();
+pub fn refresh_prekeys(username: &str, server: &mut Connection) -> Result<(), String> {
+    let due = prekeys().lock().unwrap().due(Utc::now().timestamp());
+    if due {
+        publish_prekeys(username, server)?;
+    }
+    Ok(())
+}
+
+/*
+ * Ask the gateway for anything it is still holding for us
+*/
+
+pub fn fetch(username: &str, server: &mut Connection) -> Option<String> {
+    let message = Message::Fetch {
+        username: username.to_string(),
+    };
+    send_message(&message.encode(), server)
+}
+
+/*
+ * Tell the gateway we are leaving so we stop being handed out as a buddy
+*/
+
+pub fn leave(username: &str, server: &mut Connection) -> Option<String> {
+    let message = Message::Leave {
+        username: username.to_string(),
+    };
+    send_message(&message.encode(), server)
+}
+
+/*
+ * Sends a message to a stream as a single frame
+*/
+
+pub fn send_message(message: &[u8], server: &mut Connection) -> Option<String> {
+    _ = server.write_frame(message);
     Some(String::from("Sent"))
 }
 
 /*
  * Send the message to retrieve buddies. Once the list is received, the
- * buddies are split and the message is sent to all of them
+ * buddies are split and the message is cached on as many of them as the
+ * network's replication factor asks for. Only buddies that ack count, if
+ * none did the error says why
 */
 
 pub fn send_backups(
     recip_copy: &str,
-    username: &str,
-    message: &str,
-    server: &mut TcpStream,
-) -> Option<String> {
+    message: &StoredMessage,
+    server: &mut Connection,
+    replication: u32,
+) -> Result<String, String> {
     // Create the buddies message
-    let buddy_mes = ["BUDDIES ".as_bytes(), recip_copy.as_bytes()].concat();
+    let buddy_mes = Message::Buddies {
+        username: recip_copy.to_string(),
+        buddies: Vec::new(),
+    }
+    .encode();
 
     // Send the buddies message and what to do with the buddies
-    send_to_buddies(&buddy_mes, server, | buddy_list | {
-        let mut buddies = buddy_list.split(DELIMITER);
+    send_to_buddies(&buddy_mes, server, |buddy_list| {
+        let cache_mes = Message::Cache {
+            recipient: recip_copy.to_string(),
+            sender: message.sender.clone(),
+            id: message.id.clone(),
+            body: message.body.clone(),
+            signature: message.signature.clone(),
+        }
+        .encode();
         let mut counter = 0;
+        let mut rejection = None;
+
+        for buddy in buddy_list {
+            if counter == replication {
+                break;
+            }
 
-        while let Some(buddy) = buddies.next() {
             if let Ok(mut stream) = init_stream(&buddy) {
-                _ = send_message(
-                    &["CACHE ".as_bytes(), recip_copy.as_bytes(), ";".as_bytes(), username.as_bytes(), ";".as_bytes(), message.as_bytes()].concat(),
-                    &mut stream,
-                );
-                counter += 1;
+                _ = send_message(&cache_mes, &mut stream);
+                match handle_cache_ack(&mut stream, recip_copy, &message.id) {
+                    Ok(()) => counter += 1,
+                    Err(reason) => rejection = Some(reason),
+                }
             }
         }
 
-        if counter == 0 {
-            "No buddies online".to_string()
-        } else {
-            "Sent".to_string()
+        match (counter, rejection) {
+            (0, Some(reason)) => Err(reason),
+            (0, None) => Err("No buddies online".to_string()),
+            _ => Ok("Sent".to_string()),
         }
     })
+    .unwrap_or(Err("No buddies found".to_string()))
 }
 
 /*
  * Handle all a buddies request given a closure
 */
 
-fn send_to_buddies<F: Fn(String) -> String>(buddies_message: &[u8], server: &mut TcpStream, f: F) -> Option<String> {
-    _ = send_message(buddies_message, &server);
-    match handle_buddies(server) {
-        Some(buddy_list) => {
-            Some(f(buddy_list))
+fn send_to_buddies<T, F: Fn(Vec<String>) -> T>(
+    buddies_message: &[u8],
+    server: &mut Connection,
+    f: F,
+) -> Option<T> {
+    _ = send_message(buddies_message, server);
+    handle_buddies(server).map(f)
+}
+
+/*
+ * Look up the current group of every recipient we hold messages for. A
+ * member that joined since we last looked gets a copy of the messages, and
+ * once we are no longer in the group our copy is dropped, but only after
+ * every member has confirmed it. Groups that were handled are remembered
+ * in known_groups, anything that failed is tried again on the next call
+*/
+
+pub fn hand_off(
+    me: &str,
+    gateway: &str,
+    cache: &CacheMap,
+    known_groups: &mut HashMap<String, Vec<String>>,
+) {
+    let recipients = cache.lock().unwrap().recipients();
+    if recipients.is_empty() {
+        return;
+    }
+
+    let mut server = match init_stream(gateway) {
+        Ok(server) => server,
+        Err(_) => return,
+    };
+
+    for recipient in recipients {
+        let buddy_mes = Message::Buddies {
+            username: recipient.clone(),
+            buddies: Vec::new(),
+        };
+        _ = send_message(&buddy_mes.encode(), &mut server);
+        let group = match handle_buddies(&mut server) {
+            Some(group) => group,
+            None => continue,
+        };
+
+        let member = group.iter().any(|addr| addr == me);
+        let known = known_groups.get(&recipient);
+        if member && (known.is_none() || known == Some(&group)) {
+            // Nothing moved since we last looked. Once we are out of the
+            // group there is no such shortcut, anything cached since the
+            // last hand off still has to go
+            known_groups.insert(recipient, group);
+            continue;
+        }
+
+        let messages = cache.lock().unwrap().pending(&recipient);
+        if messages.is_empty() {
+            continue;
+        }
+
+        // Members we already shared the group with have the messages, but
+        // before our copy is dropped everyone in the group gets one
+        let targets: Vec<&String> = group
+            .iter()
+            .filter(|addr| *addr != me && !(member && known.is_some_and(|k| k.contains(addr))))
+            .collect();
+        let confirmed = targets
+            .iter()
+            .filter(|addr| send_cache(addr, &recipient, &messages))
+            .count();
+
+        if confirmed < targets.len() || (!member && targets.is_empty()) {
+            // Someone didn't confirm (or there is nobody to hand to), keep
+            // our copy and try again later
+            continue;
+        }
+
+        if !member {
+            // Only drop what was handed over, newer messages stay for the
+            // next round
+            let ids: Vec<String> = messages.iter().map(|m| m.id.clone()).collect();
+            cache.lock().unwrap().remove(&recipient, &ids);
         }
-        None => None,
+        known_groups.insert(recipient, group);
     }
 }
+
+/*
+ * Cache a batch of messages on one buddy, true once it acked all of them
+*/
+
+fn send_cache(addr: &str, recipient: &str, messages: &[StoredMessage]) -> bool {
+    let mut stream = match init_stream(addr) {
+        Ok(stream) => stream,
+        Err(_) => return false,
+    };
+
+    messages.iter().all(|message| {
+        let cache_mes = Message::Cache {
+            recipient: recipient.to_string(),
+            sender: message.sender.clone(),
+            id: message.id.clone(),
+            body: message.body.clone(),
+            signature: message.signature.clone(),
+        };
+        _ = send_message(&cache_mes.encode(), &mut stream);
+        handle_cache_ack(&mut stream, recipient, &message.id).is_ok()
+    })
+}
@@ -0,0 +1,389 @@
+use chrono::Utc;
+use messaging_protocol::crypto::{CryptoError, KeyPair, SigningPair};
+use messaging_protocol::message::{Message, FIELD_SEP};
+use messaging_protocol::ratchet::{prekey_header, prekey_proof, PrekeyBundle, Session};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+use super::keys::{encryption_key, signing_key};
+use super::utils::{contact_file, data_dir};
+
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+// The file in the data dir holding the secret halves of our prekeys, and
+// the ending of the files our sessions with each contact are kept in,
+// next to the chat log
+pub const PREKEY_FILE: &str = "prekeys.key";
+pub const SESSION_FILE: &str = ".session";
+
+// How many one-time prekeys are published at a time, how few may be left
+// on the gateway before a new batch goes out, and how many secret halves
+// are kept for bundles that may still be on their way back to us
+pub const PREKEY_BATCH: usize = 20;
+pub const PREKEY_LOW: usize = 5;
+const MAX_ONE_TIME_PREKEYS: usize = 200;
+
+// How long a signed prekey is published for before a new one replaces it,
+// in seconds. The one before is still taken up until the next rotation
+pub const SIGNED_PREKEY_LIFETIME: i64 = 7 * 24 * 60 * 60;
+
+// How many sessions are kept per contact, the newest one is written with
+const MAX_SESSIONS: usize = 5;
+
+// Our prekeys and the sessions of every contact we have talked to since
+// startup, loaded from the data dir the first time they are needed
+static PREKEYS: OnceLock<Mutex<Prekeys>> = OnceLock::new();
+static SESSIONS: OnceLock<Mutex<HashMap<String, Vec<Session>>>> = OnceLock::new();
+
+/*
+ * Our signed prekey with the signature our identity vouches for it with,
+ * when it was made and the one it replaced, and the one-time prekeys
+ * nobody has used yet. The newest `published` of those are the batch the
+ * gateway is handing out. Saved as the signed prekey, its signature, when
+ * it was made, the published count and the one before on the first line,
+ * then one one-time prekey per line
+*/
+
+pub struct Prekeys {
+    pub signed_prekey: KeyPair,
+    pub signature: String,
+    created: i64,
+    previous: Option<KeyPair>,
+    one_time: Vec<KeyPair>,
+    published: usize,
+}
+
+impl Prekeys {
+    fn new(identity: &SigningPair, now: i64) -> Result<Prekeys, CryptoError> {
+        let signed_prekey = KeyPair::generate()?;
+        Ok(Prekeys {
+            signature: identity.sign(&prekey_proof(&signed_prekey.public_hex())),
+            signed_prekey,
+            created: now,
+            previous: None,
+            one_time: Vec::new(),
+            published: 0,
+        })
+    }
+
+    /*
+     * Whether it is time to publish again, because the gateway is running
+     * out of one-time prekeys or the signed prekey is due to be replaced
+     */
+
+    pub fn due(&self, now: i64) -> bool {
+        self.published < PREKEY_LOW || now - self.created >= SIGNED_PREKEY_LIFETIME
+    }
+
+    /*
+     * Make up a batch of new one-time prekeys, and a new signed prekey if
+     * the old one is due, and return the message that publishes them. The
+     * gateway forgets the ones published before, but their secret halves
+     * are kept until enough newer ones pile up
+     */
+
+    pub fn refill(
+        &mut self,
+        username: &str,
+        identity: &SigningPair,
+        now: i64,
+    ) -> Result<Message, CryptoError> {
+        if now - self.created >= SIGNED_PREKEY_LIFETIME {
+            let Prekeys {
+                signed_prekey,
+                signature,
+                created,
+                ..
+            } = Prekeys::new(identity, now)?;
+            self.previous = Some(mem::replace(&mut self.signed_prekey, signed_prekey));
+            self.signature = signature;
+            self.created = created;
+        }
+
+        let mut batch = Vec::new();
+        for _ in 0..PREKEY_BATCH {
+            batch.push(KeyPair::generate()?);
+        }
+
+        let published = batch.iter().map(KeyPair::public_hex).collect();
+        self.one_time.extend(batch);
+        let excess = self.one_time.len().saturating_sub(MAX_ONE_TIME_PREKEYS);
+        self.one_time.drain(..excess);
+        self.published = PREKEY_BATCH;
+
+        Ok(Message::Prekeys {
+            username: username.to_string(),
+            signed_prekey: self.signed_prekey.public_hex(),
+            signature: self.signature.clone(),
+            one_time: published,
+        })
+    }
+
+    pub fn one_time_count(&self) -> usize {
+        self.one_time.len()
+    }
+
+    /*
+     * The signed prekey with the given public half, the current one or the
+     * one it replaced
+     */
+
+    fn signed(&self, public: &str) -> Option<&KeyPair> {
+        std::iter::once(&self.signed_prekey)
+            .chain(&self.previous)
+            .find(|key| key.public_hex() == public)
+    }
+
+    fn one_time(&self, public: &str) -> Option<&KeyPair> {
+        self.one_time.iter().find(|key| key.public_hex() == public)
+    }
+
+    /*
+     * Forget the one-time prekey with the given public half, it is never
+     * used for another session
+     */
+
+    fn take_one_time(&mut self, public: &str) {
+        if let Some(index) = self
+            .one_time
+            .iter()
+            .position(|key| key.public_hex() == public)
+        {
+            if index >= self.one_time.len() - self.published {
+                self.published -= 1;
+            }
+            self.one_time.remove(index);
+        }
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut first = vec![
+            self.signed_prekey.secret_hex(),
+            self.signature.clone(),
+            self.created.to_string(),
+            self.published.to_string(),
+        ];
+        first.extend(self.previous.as_ref().map(KeyPair::secret_hex));
+
+        let mut lines = vec![first.join(FIELD_SEP)];
+        lines.extend(self.one_time.iter().map(KeyPair::secret_hex));
+        write_private(path, &lines.join("\n"))
+    }
+}
+
+pub fn prekeys() -> &'static Mutex<Prekeys> {
+    PREKEYS.get_or_init(|| {
+        let prekeys = load_prekeys(&(data_dir().to_owned() + PREKEY_FILE), signing_key())
+            .expect("Couldn't load the prekeys");
+        Mutex::new(prekeys)
+    })
+}
+
+/*
+ * Read our prekeys from their file, making up a signed prekey signed by
+ * identity the first time. Files from before rotation only have the signed
+ * prekey and its signature, which are then replaced on the next refill
+*/
+
+pub fn load_prekeys(path: &str, identity: &SigningPair) -> io::Result<Prekeys> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let prekeys =
+                Prekeys::new(identity, Utc::now().timestamp()).map_err(io::Error::other)?;
+            prekeys.save(path)?;
+            return Ok(prekeys);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let invalid = || io::Error::new(ErrorKind::InvalidData, format!("{}: not prekeys", path));
+    let mut lines = contents.lines();
+    let first: Vec<&str> = lines.next().unwrap_or_default().split(FIELD_SEP).collect();
+    let (secret, signature, created, published, previous) = match first[..] {
+        [secret, signature] => (secret, signature, "0", "0", None),
+        [secret, signature, created, published] => (secret, signature, created, published, None),
+        [secret, signature, created, published, previous] => {
+            (secret, signature, created, published, Some(previous))
+        }
+        _ => return Err(invalid()),
+    };
+
+    let one_time: Vec<KeyPair> = lines
+        .map(KeyPair::from_secret_hex)
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid())?;
+    Ok(Prekeys {
+        signed_prekey: KeyPair::from_secret_hex(secret).map_err(|_| invalid())?,
+        signature: signature.to_string(),
+        created: created.parse().map_err(|_| invalid())?,
+        previous: previous
+            .map(KeyPair::from_secret_hex)
+            .transpose()
+            .map_err(|_| invalid())?,
+        published: published
+            .parse::<usize>()
+            .map_err(|_| invalid())?
+            .min(one_time.len()),
+        one_time,
+    })
+}
+
+/*
+ * Start a new session with a contact from the bundle the gateway handed
+ * out, it is the one written with from now on
+*/
+
+pub fn start_session(contact: &str, bundle: &PrekeyBundle) -> Result<(), CryptoError> {
+    let session = Session::initiate(encryption_key(), bundle)?;
+    with_sessions(contact, |sessions| {
+        sessions.insert(0, session);
+        Ok(())
+    })
+}
+
+/*
+ * Encrypt a body for a contact with our newest session with them. None if
+ * there is no session to write with yet
+*/
+
+pub fn session_seal(
+    contact: &str,
+    plaintext: &str,
+    context: &[&str],
+) -> Option<Result<String, CryptoError>> {
+    let result = with_sessions(contact, |sessions| match sessions.first_mut() {
+        Some(session) => session.encrypt(plaintext, context),
+        None => Err(CryptoError::NoSession),
+    });
+
+    match result {
+        Err(CryptoError::NoSession) => None,
+        result => Some(result),
+    }
+}
+
+/*
+ * Decrypt a body a contact encrypted with one of our sessions. A body that
+ * starts a session is taken up with the prekeys it names, the one-time
+ * prekey is used up for good
+*/
+
+pub fn session_open(contact: &str, body: &str, context: &[&str]) -> Result<String, CryptoError> {
+    with_sessions(contact, |sessions| {
+        for (i, session) in sessions.iter_mut().enumerate() {
+            if let Ok(plaintext) = session.decrypt(body, context) {
+                // The session they write with is the one we answer with
+                let session = sessions.remove(i);
+                sessions.insert(0, session);
+                return Ok(plaintext);
+            }
+        }
+
+        let header = prekey_header(body).ok_or(CryptoError::NoSession)?;
+        if sessions.iter().any(|s| s.base_key() == header.ephemeral) {
+            // The session was taken up already, this is a replay
+            return Err(CryptoError::Decrypt);
+        }
+
+        let mut prekeys = prekeys().lock().unwrap();
+        let signed_prekey = prekeys
+            .signed(&header.signed_prekey)
+            .ok_or(CryptoError::NoSession)?;
+        let one_time = match &header.one_time {
+            Some(public) => Some(prekeys.one_time(public).ok_or(CryptoError::NoSession)?),
+            None => None,
+        };
+
+        let mut session = Session::respond(encryption_key(), signed_prekey, one_time, &header)?;
+        let plaintext = session.decrypt(body, context)?;
+
+        // Only a body that decrypts uses the one-time prekey up, so a
+        // forged one can't burn it for the contact who really has it
+        if let Some(public) = &header.one_time {
+            prekeys.take_one_time(public);
+        }
+
+        if sessions
+            .iter()
+            .any(|s| s.remote_key() != session.remote_key())
+        {
+            println!(
+                "Warning: {}'s encryption key changed, they may have set up a new device",
+                contact
+            );
+        }
+        if let Err(e) = prekeys.save(&(data_dir().to_owned() + PREKEY_FILE)) {
+            println!("Couldn't save the prekeys: {}", e);
+        }
+        sessions.insert(0, session);
+        Ok(plaintext)
+    })
+}
+
+/*
+ * Run f on a contact's sessions, loading them the first time, and save
+ * them once f is done. The lock is held throughout so two messages from
+ * the same contact can't step on each other
+*/
+
+fn with_sessions<T>(
+    contact: &str,
+    f: impl FnOnce(&mut Vec<Session>) -> Result<T, CryptoError>,
+) -> Result<T, CryptoError> {
+    let mut all = SESSIONS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap();
+    let path = contact_file(contact, SESSION_FILE);
+    let sessions = all
+        .entry(contact.to_string())
+        .or_insert_with(|| load_sessions(&path));
+
+    let result = f(sessions)?;
+    sessions.truncate(MAX_SESSIONS);
+
+    let lines: Vec<String> = sessions.iter().map(Session::to_string).collect();
+    if let Err(e) = write_private(&path, &lines.join("\n")) {
+        println!("Couldn't save the session with {}: {}", contact, e);
+    }
+    Ok(result)
+}
+
+/*
+ * Read a contact's sessions back, newest first. Lines that don't parse are
+ * skipped
+*/
+
+fn load_sessions(path: &str) -> Vec<Session> {
+    fs::read_to_string(path)
+        .map(|contents| contents.lines().filter_map(Session::parse).collect())
+        .unwrap_or_default()
+}
+
+/*
+ * Write a file only our own user may read, replacing the old one in one
+ * step so a crash leaves either the old contents or the new
+*/
+
+fn write_private(path: &str, contents: &str) -> io::Result<()> {
+    if let Some(dir) = Path::new(path).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = format!("{}.tmp", path);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
@@ -1,18 +1,71 @@
 use chrono::prelude::*;
+use messaging_protocol::crypto::to_hex;
+use messaging_protocol::message::{escape, unescape};
 use std::fs::{self, File, OpenOptions};
 use std::io::{prelude::*, BufReader, Write};
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use std::sync::OnceLock;
 
-const MDIR: &str = "./messages/";
-pub const RING_SIZE: u32 = 4096;
-pub const NUM_FINGERS: u32 = 12;
-pub const MAX_GROUP_SIZE: u32 = 20;
+// Where chat logs and the buddy cache are kept. Picked once at startup and
+// the same for every thread after that
+static DATA_DIR: OnceLock<String> = OnceLock::new();
+const DEFAULT_DATA_DIR: &str = "./messages/";
+
+// The gateway we registered with, asked for the identity keys signatures
+// are checked against, and the key it signs delivery tokens with, which
+// the tokens it hands us are checked against
+static GATEWAY: OnceLock<String> = OnceLock::new();
+static TOKEN_KEY: OnceLock<String> = OnceLock::new();
+
+pub fn set_data_dir(dir: &str) {
+    _ = DATA_DIR.set(dir.to_string());
+}
+
+pub fn data_dir() -> &'static str {
+    DATA_DIR.get().map_or(DEFAULT_DATA_DIR, String::as_str)
+}
+
+// The ending of the chat log kept with each contact
+pub const CHAT_LOG: &str = ".txt";
+
+/*
+ * The file in the data dir a contact's chat log or sessions are kept in.
+ * Usernames come off the network, so a name that isn't only letters,
+ * digits, '-' and '_' is written in hex behind a '~', which no such name
+ * starts with, and can't point anywhere outside the data dir
+*/
+
+pub fn contact_file(contact: &str, ending: &str) -> String {
+    let plain = !contact.is_empty()
+        && contact
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    let name = if plain {
+        contact.to_string()
+    } else {
+        format!("~{}", to_hex(contact.as_bytes()))
+    };
+    data_dir().to_owned() + &name + ending
+}
+
+pub fn set_gateway(addr: &str) {
+    _ = GATEWAY.set(addr.to_string());
+}
+
+pub fn gateway() -> &'static str {
+    GATEWAY.get().map_or("", String::as_str)
+}
+
+pub fn set_token_key(key: &str) {
+    _ = TOKEN_KEY.set(key.to_string());
+}
+
+pub fn token_key() -> Option<&'static str> {
+    TOKEN_KEY.get().map(String::as_str)
+}
 
 /*
  * This struct stores necessary data to identify a user
 */
-#[derive(Hash)]
 pub struct User {
     pub ip_addr: String,
     pub total_users: u32,
@@ -28,21 +81,13 @@ pub struct Job {
 }
 
 /*
- * This has function calculates the hash value of a user
-*/
-
-pub fn calculate_hash<User: Hash>(t: &User) -> u64 {
-    let mut s = DefaultHasher::new();
-    t.hash(&mut s);
-    s.finish()
-}
-
-/*
- * Write a message to a file, creates a new file if one doesn't exist
+ * Write a message to a file, creates a new file if one doesn't exist. The
+ * sender and message are escaped so each message stays on one line.
+ * Messages from others say whether their signature was checked
 */
 
 #[allow(dead_code)]
-pub fn write_message(file_name: String, message: &str) {
+pub fn write_message(file_name: String, sender: &str, message: &str, verified: Option<bool>) {
     let mut file = match OpenOptions::new().append(true).open(file_name.clone()) {
         Ok(file) => file,
         Err(_) => File::create(file_name).unwrap(),
@@ -50,8 +95,21 @@ pub fn write_message(file_name: String, message: &str) {
 
     let formatted_t = &Utc::now().to_rfc2822()[..25];
 
+    let mut line = formatted_t.to_owned() + ";" + &escape(sender) + ";" + &escape(message);
+    if let Some(verified) = verified {
+        line = line + ";" + verified_tag(verified);
+    }
+
     // Write the message to the file
-    _ = file.write_all((formatted_t.to_owned() + ";" + message + "\n").as_bytes());
+    _ = file.write_all((line + "\n").as_bytes());
+}
+
+pub fn verified_tag(verified: bool) -> &'static str {
+    if verified {
+        "verified"
+    } else {
+        "unverified"
+    }
 }
 
 /*
@@ -61,20 +119,28 @@ pub fn write_message(file_name: String, message: &str) {
 #[allow(dead_code)]
 pub fn read_file(username: &str) {
     println!("Chat with {}", username);
-    let file_name: String = MDIR.to_owned() + username + ".txt";
+    let file_name = contact_file(username, CHAT_LOG);
     if let Ok(file) = File::open(file_name) {
         let reader = BufReader::new(file);
 
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                let mut line_tokens = line.split(";");
-                println!(
-                    "{} {} -> {}",
-                    line_tokens.next().unwrap(),
-                    line_tokens.next().unwrap(),
-                    line_tokens.collect::<Vec<_>>().join(";")
-                );
-            }
+        for line in reader.lines().map_while(Result::ok) {
+            let mut line_tokens = line.splitn(4, ";");
+            let time = line_tokens.next().unwrap_or("");
+            let sender = line_tokens.next().unwrap_or("");
+            let message = line_tokens.next().unwrap_or("");
+            let tag = line_tokens
+                .next()
+                .map(|tag| format!(" [{}]", tag))
+                .unwrap_or_default();
+
+            // Lines written before escaping was added are shown as they are
+            println!(
+                "{} {} -> {}{}",
+                time,
+                unescape(sender).unwrap_or(sender.to_string()),
+                unescape(message).unwrap_or(message.to_string()),
+                tag
+            );
         }
     }
 }
@@ -85,6 +151,6 @@ pub fn read_file(username: &str) {
 
 #[allow(dead_code)]
 pub fn delete_file(username: &str) -> Result<(), std::io::Error> {
-    let file_name: String = MDIR.to_owned() + username + ".txt";
+    let file_name = contact_file(username, CHAT_LOG);
     fs::remove_file(file_name)
 }
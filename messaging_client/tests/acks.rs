@@ -0,0 +1,50 @@
+use lib::network_messaging::handlers::handle_ack;
+use lib::network_messaging::utils::set_data_dir;
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::{Message, StoredMessage};
+use std::net::{TcpListener, TcpStream};
+use std::{env, fs, process, thread};
+
+/*
+ * A peer that acks whatever it is sent in the name of username
+*/
+
+fn peer(username: &'static str, id: &'static str) -> FramedStream<TcpStream> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = FramedStream::new(stream);
+        let ack = Message::Ack {
+            username: username.to_string(),
+            id: id.to_string(),
+        };
+        stream.write_frame(&ack.encode()).unwrap();
+    });
+
+    FramedStream::new(TcpStream::connect(addr).unwrap())
+}
+
+#[test]
+fn only_the_recipients_ack_writes_the_chat_log() {
+    let dir = env::temp_dir().join(format!("client_acks_{}", process::id()));
+    _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    set_data_dir(&format!("{}/", dir.display()));
+    let sent = StoredMessage {
+        sender: "amy".to_string(),
+        id: "1".to_string(),
+        body: "hi bob".to_string(),
+        signature: None,
+    };
+
+    // Acked in someone else's name, or for another message
+    handle_ack(&mut peer("carl", "1"), "bob", &sent);
+    handle_ack(&mut peer("bob", "2"), "bob", &sent);
+    assert!(!dir.join("carl.txt").exists());
+    assert!(!dir.join("bob.txt").exists());
+
+    handle_ack(&mut peer("bob", "1"), "bob", &sent);
+    let log = fs::read_to_string(dir.join("bob.txt")).unwrap();
+    assert!(log.contains("hi bob"), "{}", log);
+}
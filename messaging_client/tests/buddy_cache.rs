@@ -0,0 +1,169 @@
+use lib::network_messaging::cache::BuddyCache;
+use messaging_protocol::message::{NetworkParams, StoredMessage};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+/*
+ * Network settings with the given ttl and quotas
+*/
+
+fn network(cache_ttl: u32, max_cached_messages: u32, max_cached_bytes: u32) -> NetworkParams {
+    NetworkParams {
+        cache_ttl,
+        max_cached_messages,
+        max_cached_bytes,
+        ..NetworkParams::default()
+    }
+}
+
+const TTL: u32 = 60 * 60;
+
+/*
+ * A fresh cache file for each test
+*/
+
+fn cache_file(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("buddy_cache_{}_{}", process::id(), name));
+    _ = fs::remove_dir_all(&dir);
+    dir.join("cache.txt")
+}
+
+fn message(id: &str, body: &str) -> StoredMessage {
+    StoredMessage {
+        sender: "bob".to_string(),
+        id: id.to_string(),
+        body: body.to_string(),
+        signature: None,
+    }
+}
+
+#[test]
+fn cached_messages_survive_a_restart() {
+    let path = cache_file("restart");
+
+    let mut cache = BuddyCache::open(&path, network(TTL, 10, 1000)).unwrap();
+    cache
+        .insert("amy", message("1", "hi; how & are\nyou %"))
+        .unwrap();
+    cache.insert("amy", message("2", "second")).unwrap();
+    let signed = StoredMessage {
+        signature: Some("signed; by bob".to_string()),
+        ..message("3", "third")
+    };
+    cache.insert("carl", signed.clone()).unwrap();
+    cache.remove("amy", &["2".to_string()]);
+    drop(cache);
+
+    let cache = BuddyCache::open(&path, network(TTL, 10, 1000)).unwrap();
+    assert_eq!(
+        cache.pending("amy"),
+        vec![message("1", "hi; how & are\nyou %")]
+    );
+    assert_eq!(cache.pending("carl"), vec![signed]);
+
+    let mut recipients = cache.recipients();
+    recipients.sort();
+    assert_eq!(recipients, ["amy", "carl"]);
+}
+
+#[test]
+fn old_messages_expire() {
+    let path = cache_file("expire");
+
+    // One message from long ago and one from now
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, "1;amy;bob;old;stale\n").unwrap();
+    let mut cache = BuddyCache::open(&path, network(TTL, 10, 1000)).unwrap();
+    assert!(cache.pending("amy").is_empty());
+
+    cache.insert("amy", message("new", "fresh")).unwrap();
+    drop(cache);
+
+    let cache = BuddyCache::open(&path, network(TTL, 10, 1000)).unwrap();
+    assert_eq!(cache.pending("amy"), vec![message("new", "fresh")]);
+
+    // Nothing outlives a ttl of zero once a second has gone by
+    std::thread::sleep(Duration::from_millis(1100));
+    let cache = BuddyCache::open(&path, network(0, 10, 1000)).unwrap();
+    assert!(cache.recipients().is_empty());
+}
+
+#[test]
+fn broken_lines_are_skipped() {
+    let path = cache_file("broken");
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(
+        &path,
+        format!(
+            "garbage\n{now};amy;bob\n{now};amy;bob;;no id\n{now};amy;bob;%ZZ;bad escape\n{now};amy;bob;1;kept\n"
+        ),
+    )
+    .unwrap();
+
+    let cache = BuddyCache::open(&path, network(TTL, 10, 1000)).unwrap();
+    assert_eq!(cache.pending("amy"), vec![message("1", "kept")]);
+}
+
+#[test]
+fn full_recipients_are_turned_away() {
+    let path = cache_file("quota");
+    let mut cache = BuddyCache::open(&path, network(TTL, 2, 20)).unwrap();
+
+    // Over the byte quota on its own
+    let big = message("big", &"x".repeat(20));
+    assert!(cache.insert("amy", big).is_err());
+
+    cache.insert("amy", message("1", "one")).unwrap();
+    cache.insert("amy", message("2", "two")).unwrap();
+    let reason = cache.insert("amy", message("3", "three")).unwrap_err();
+    assert!(reason.contains("amy"), "{}", reason);
+
+    // A message we already hold and other recipients are still fine
+    cache.insert("amy", message("2", "two")).unwrap();
+    cache.insert("carl", message("3", "three")).unwrap();
+
+    // Acks make room again
+    cache.remove("amy", &["1".to_string()]);
+    cache.insert("amy", message("3", "three")).unwrap();
+    assert_eq!(cache.pending("amy").len(), 2);
+}
+
+#[test]
+fn changes_go_to_the_journal_until_it_is_compacted() {
+    let path = cache_file("journal");
+    let journal = path.with_file_name("cache.txt.journal");
+
+    let mut cache = BuddyCache::open(&path, network(TTL, 10, 1000)).unwrap();
+    cache.insert("amy", message("1", "one")).unwrap();
+    cache.insert("amy", message("2", "two")).unwrap();
+    cache.remove("amy", &["1".to_string(), "missing".to_string()]);
+    drop(cache);
+
+    // The cache file isn't rewritten for every change
+    assert_eq!(fs::read_to_string(&path).unwrap(), "");
+    let lines: Vec<String> = fs::read_to_string(&journal)
+        .unwrap()
+        .lines()
+        .map(|line| line.split(' ').next().unwrap().to_string())
+        .collect();
+    assert_eq!(lines, ["CACHE", "CACHE", "ACK"]);
+
+    // The buddy died halfway through writing a line
+    let mut torn = fs::OpenOptions::new().append(true).open(&journal).unwrap();
+    std::io::Write::write_all(&mut torn, b"CACHE 1;amy;bob;3;ha").unwrap();
+
+    let cache = BuddyCache::open(&path, network(TTL, 10, 1000)).unwrap();
+    assert_eq!(cache.pending("amy"), vec![message("2", "two")]);
+
+    // Opening starts over from a fresh copy
+    assert_eq!(fs::read_to_string(&journal).unwrap(), "");
+    assert!(fs::read_to_string(&path).unwrap().contains(";2;two"));
+}
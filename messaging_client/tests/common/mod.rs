@@ -0,0 +1,124 @@
+// Not every test file uses every helper
+#![allow(dead_code)]
+
+use messaging_protocol::crypto::KeyPair;
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::Message;
+use messaging_protocol::tokens::{today, Blind, TokenKey};
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+// Buddy groups by recipient, which a test can move around while the fake
+// gateway is handing them out
+pub type Groups = Arc<Mutex<HashMap<String, Vec<String>>>>;
+
+// The digests the fake gateway spent delivery tokens on, by token
+type Spent = Arc<Mutex<HashMap<String, String>>>;
+
+/*
+ * The key every fake gateway signs delivery tokens with
+*/
+
+pub fn token_key() -> &'static TokenKey {
+    static KEY: OnceLock<TokenKey> = OnceLock::new();
+    KEY.get_or_init(|| TokenKey::generate().unwrap())
+}
+
+/*
+ * A delivery token signed with key, made the way a client makes them
+*/
+
+pub fn delivery_token(key: &TokenKey) -> String {
+    let blind = Blind::new(today()).unwrap();
+    let issued = key.issue(&blind.blinded_hex()).unwrap();
+    blind.finish(&key.public_hex(), &issued).unwrap()
+}
+
+/*
+ * A gateway that only answers key lookups, with the identity keys it was
+ * given by username, and spends delivery tokens signed with token_key.
+ * Returns the address it listens on
+*/
+
+pub fn fake_gateway(identities: &[(&str, String)]) -> String {
+    fake_gateway_with_groups(identities, Groups::default())
+}
+
+/*
+ * Like fake_gateway, but it also answers buddy lookups from groups
+*/
+
+pub fn fake_gateway_with_groups(identities: &[(&str, String)], groups: Groups) -> String {
+    let identities: Arc<HashMap<String, String>> = Arc::new(
+        identities
+            .iter()
+            .map(|(username, identity)| (username.to_string(), identity.clone()))
+            .collect(),
+    );
+    let spent = Spent::default();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    // Every connection gets a thread, a client may look up keys while it
+    // holds another connection open
+    thread::spawn(move || {
+        for stream in listener.incoming().map_while(Result::ok) {
+            let (identities, groups, spent) = (identities.clone(), groups.clone(), spent.clone());
+            thread::spawn(move || {
+                let mut stream = FramedStream::new(stream);
+                while let Ok(frame) = stream.read_frame() {
+                    let reply = answer(&frame, &identities, &groups, &spent);
+                    _ = stream.write_frame(&reply.encode());
+                }
+            });
+        }
+    });
+
+    addr
+}
+
+fn answer(
+    frame: &[u8],
+    identities: &HashMap<String, String>,
+    groups: &Groups,
+    spent: &Spent,
+) -> Message {
+    match Message::decode(frame) {
+        Ok(Message::KeyFetch { username }) => match identities.get(&username) {
+            Some(identity) => Message::Key {
+                username,
+                key: KeyPair::generate().unwrap().public_hex(),
+                identity: Some(identity.clone()),
+            },
+            None => Message::NotFound {
+                reason: format!("{} not found", username),
+            },
+        },
+        Ok(Message::Buddies { username, .. }) => Message::Buddies {
+            buddies: groups
+                .lock()
+                .unwrap()
+                .get(&username)
+                .cloned()
+                .unwrap_or_default(),
+            username,
+        },
+        Ok(Message::Redeem { token, digest }) => {
+            // A token is only ever spent on one message
+            let mut spent = spent.lock().unwrap();
+            let on = spent.entry(token.clone()).or_insert_with(|| digest.clone());
+            if token_key().check(&token).is_err() || *on != digest {
+                Message::Refused {
+                    reason: "not a token for this message".to_string(),
+                }
+            } else {
+                Message::Redeemed { token }
+            }
+        }
+        _ => Message::NotFound {
+            reason: "unexpected request".to_string(),
+        },
+    }
+}
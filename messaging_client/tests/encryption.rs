@@ -0,0 +1,137 @@
+mod common;
+
+use common::fake_gateway;
+use lib::network_messaging::cache::BuddyCache;
+use lib::network_messaging::handlers::{handle_connection, CacheMap};
+use lib::network_messaging::keys::{
+    encryption_key, load_encryption_key, load_signing_key, seal_message, IDENTITY_FILE, KEY_FILE,
+};
+use lib::network_messaging::utils::{set_data_dir, set_gateway};
+use messaging_protocol::crypto::{message_proof, SigningPair};
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::{Message, NetworkParams, StoredMessage};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::{env, fs, process, thread};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("client_keys_{}_{}", process::id(), name));
+    _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+/*
+ * Carl answering one peer connection on a background thread
+*/
+
+fn carl() -> FramedStream<TcpStream> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut cache: CacheMap = Arc::new(Mutex::new(BuddyCache::new(NetworkParams::default())));
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = FramedStream::new(stream);
+        handle_connection(&mut stream, "", "carl", &mut cache);
+    });
+
+    FramedStream::new(TcpStream::connect(addr).unwrap())
+}
+
+fn send(stream: &mut FramedStream<TcpStream>, message: &StoredMessage) {
+    let send = Message::Send {
+        recipient: "carl".to_string(),
+        sender: message.sender.clone(),
+        id: message.id.clone(),
+        body: message.body.clone(),
+        signature: message.signature.clone(),
+    };
+    stream.write_frame(&send.encode()).unwrap();
+    match Message::decode(&stream.read_frame().unwrap()).unwrap() {
+        Message::Ack { id, .. } => assert_eq!(id, message.id),
+        other => panic!("expected an ack, got {}", other),
+    }
+}
+
+#[test]
+fn encryption_keys_are_kept_private_and_reused() {
+    let path = temp_dir("encryption").join(KEY_FILE);
+    let path = path.to_str().unwrap();
+
+    let keys = load_encryption_key(path).unwrap();
+    assert_eq!(
+        load_encryption_key(path).unwrap().public_hex(),
+        keys.public_hex()
+    );
+    assert!(!fs::read_to_string(path)
+        .unwrap()
+        .contains(&keys.public_hex()));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    fs::write(path, "not a key").unwrap();
+    assert!(load_encryption_key(path).is_err());
+}
+
+#[test]
+fn signing_keys_are_reused() {
+    let path = temp_dir("signing").join(IDENTITY_FILE);
+    let path = path.to_str().unwrap();
+
+    let keys = load_signing_key(path).unwrap();
+    assert_eq!(
+        load_signing_key(path).unwrap().public_hex(),
+        keys.public_hex()
+    );
+    fs::write(path, "not a key").unwrap();
+    assert!(load_signing_key(path).is_err());
+}
+
+#[test]
+fn only_sealed_messages_reach_the_chat_log() {
+    let dir = temp_dir("log");
+    set_data_dir(&format!("{}/", dir.display()));
+    let key = encryption_key().public_hex();
+    let amy = SigningPair::generate().unwrap();
+    set_gateway(&fake_gateway(&[("amy", amy.public_hex())]));
+
+    let message = |id: &str, body: &str| StoredMessage {
+        sender: "amy".to_string(),
+        id: id.to_string(),
+        body: body.to_string(),
+        signature: None,
+    };
+    let signed = |message: StoredMessage| StoredMessage {
+        signature: Some(amy.sign(&message_proof(
+            "carl",
+            &message.sender,
+            &message.id,
+            &message.body,
+        ))),
+        ..message
+    };
+    let mut stream = carl();
+
+    // Sealed for carl, the log holds the text
+    send(
+        &mut stream,
+        &signed(seal_message("carl", &key, &message("1", "see you at noon")).unwrap()),
+    );
+
+    // Plain text and a body sealed for someone else are acked but dropped
+    send(&mut stream, &message("2", "plain text"));
+    send(
+        &mut stream,
+        &signed(seal_message("bob", &key, &message("3", "not for carl")).unwrap()),
+    );
+
+    let log = fs::read_to_string(dir.join("amy.txt")).unwrap();
+    assert!(log.contains("see you at noon"), "{}", log);
+    assert_eq!(log.lines().count(), 1, "{}", log);
+    assert!(dir.join(KEY_FILE).exists());
+}
@@ -0,0 +1,122 @@
+mod common;
+
+use common::{fake_gateway_with_groups, Groups};
+use lib::network_messaging::cache::BuddyCache;
+use lib::network_messaging::handlers::{handle_connection, CacheMap};
+use lib::network_messaging::senders::hand_off;
+use lib::network_messaging::utils::set_gateway;
+use messaging_protocol::crypto::{message_proof, SigningPair};
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::{NetworkParams, StoredMessage};
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Where we are as far as the buddy groups go, nobody ever connects to it
+const ME: &str = "127.0.0.1:1";
+
+fn empty_cache() -> CacheMap {
+    Arc::new(Mutex::new(BuddyCache::new(NetworkParams::default())))
+}
+
+/*
+ * A buddy answering every connection made to listener, returning the
+ * cache it fills
+*/
+
+fn buddy(listener: TcpListener) -> CacheMap {
+    let cache = empty_cache();
+    let mut buddy_cache = cache.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().map_while(Result::ok) {
+            let mut stream = FramedStream::new(stream);
+            handle_connection(&mut stream, "", "carl", &mut buddy_cache);
+        }
+    });
+    cache
+}
+
+/*
+ * An address nothing listens on yet, for a buddy that hasn't come up
+*/
+
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+fn ids(cache: &CacheMap) -> Vec<String> {
+    let pending = cache.lock().unwrap().pending("amy");
+    pending.into_iter().map(|m| m.id).collect()
+}
+
+#[test]
+fn messages_move_to_the_new_buddy_once_it_confirms() {
+    // Bob's messages are signed, so the buddies take them
+    let bob = SigningPair::generate().unwrap();
+    let groups = Groups::default();
+    let gateway = fake_gateway_with_groups(&[("bob", bob.public_hex())], groups.clone());
+    set_gateway(&gateway);
+
+    let ours = empty_cache();
+    for id in ["1", "2"] {
+        let body = format!("message {}", id);
+        let signature = bob.sign(&message_proof("amy", "bob", id, &body));
+        let message = StoredMessage {
+            sender: "bob".to_string(),
+            id: id.to_string(),
+            body,
+            signature: Some(signature),
+        };
+        ours.lock().unwrap().insert("amy", message).unwrap();
+    }
+
+    let old_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let old_addr = old_listener.local_addr().unwrap().to_string();
+    let old = buddy(old_listener);
+    let new_addr = free_addr();
+    let mut known_groups = HashMap::new();
+
+    // We are in amy's group, so there is nothing to hand over
+    groups
+        .lock()
+        .unwrap()
+        .insert("amy".to_string(), vec![ME.to_string(), old_addr.clone()]);
+    hand_off(ME, &gateway, &ours, &mut known_groups);
+    assert!(ids(&old).is_empty());
+    assert_eq!(ids(&ours), ["1", "2"]);
+
+    // We are moved out for a buddy that isn't up yet, so our copy stays
+    let moved = vec![old_addr.clone(), new_addr.clone()];
+    groups
+        .lock()
+        .unwrap()
+        .insert("amy".to_string(), moved.clone());
+    hand_off(ME, &gateway, &ours, &mut known_groups);
+    assert_eq!(ids(&ours), ["1", "2"]);
+    assert_ne!(known_groups.get("amy"), Some(&moved));
+
+    // Once every member confirms, ours is dropped
+    let new = buddy(TcpListener::bind(&new_addr).unwrap());
+    hand_off(ME, &gateway, &ours, &mut known_groups);
+    assert_eq!(ids(&new), ["1", "2"]);
+    assert_eq!(ids(&old), ["1", "2"]);
+    assert!(ids(&ours).is_empty());
+    assert_eq!(known_groups.get("amy"), Some(&moved));
+
+    // A message that reaches us after we left is passed on too, even
+    // though the group hasn't moved since
+    let body = "message 3".to_string();
+    let signature = bob.sign(&message_proof("amy", "bob", "3", &body));
+    let late = StoredMessage {
+        sender: "bob".to_string(),
+        id: "3".to_string(),
+        body,
+        signature: Some(signature),
+    };
+    ours.lock().unwrap().insert("amy", late).unwrap();
+    hand_off(ME, &gateway, &ours, &mut known_groups);
+    assert_eq!(ids(&new), ["1", "2", "3"]);
+    assert!(ids(&ours).is_empty());
+}
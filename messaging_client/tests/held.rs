@@ -0,0 +1,84 @@
+use lib::network_messaging::cache::BuddyCache;
+use lib::network_messaging::handlers::{handle_connection, CacheMap};
+use lib::network_messaging::keys::{encryption_key, seal_message};
+use lib::network_messaging::utils::{set_data_dir, set_gateway};
+use messaging_protocol::crypto::{message_proof, SigningPair};
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::{Message, NetworkParams, StoredMessage};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::{env, fs, process, thread};
+
+/*
+ * Carl answering one peer connection on a background thread
+*/
+
+fn carl(cache: &CacheMap) -> FramedStream<TcpStream> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut cache = cache.clone();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = FramedStream::new(stream);
+        handle_connection(&mut stream, "", "carl", &mut cache);
+    });
+
+    FramedStream::new(TcpStream::connect(addr).unwrap())
+}
+
+fn ask(stream: &mut FramedStream<TcpStream>, message: Message) -> Message {
+    stream.write_frame(&message.encode()).unwrap();
+    Message::decode(&stream.read_frame().unwrap()).unwrap()
+}
+
+#[test]
+fn messages_wait_until_the_sender_can_be_checked() {
+    let dir = env::temp_dir().join(format!("client_held_{}", process::id()));
+    _ = fs::remove_dir_all(&dir);
+    set_data_dir(&format!("{}/", dir.display()));
+
+    // A gateway that is gone, so nobody's identity can be looked up
+    let gone = TcpListener::bind("127.0.0.1:0").unwrap();
+    set_gateway(&gone.local_addr().unwrap().to_string());
+    drop(gone);
+
+    let bob = SigningPair::generate().unwrap();
+    let key = encryption_key().public_hex();
+    let message = StoredMessage {
+        sender: "bob".to_string(),
+        id: "1".to_string(),
+        body: "hi carl".to_string(),
+        signature: None,
+    };
+    let sealed = seal_message("carl", &key, &message).unwrap();
+    let proof = message_proof("carl", "bob", "1", &sealed.body);
+    let signature = Some(bob.sign(&proof));
+
+    let held: CacheMap = Arc::new(Mutex::new(BuddyCache::new(NetworkParams::default())));
+    let mut stream = carl(&held);
+
+    // Sent to carl, it isn't acked, so it comes round again later
+    let send = Message::Send {
+        recipient: "carl".to_string(),
+        sender: "bob".to_string(),
+        id: "1".to_string(),
+        body: sealed.body.clone(),
+        signature: signature.clone(),
+    };
+    match ask(&mut stream, send) {
+        Message::Rejected { id, .. } => assert_eq!(id, "1"),
+        other => panic!("expected a rejection, got {}", other),
+    }
+    assert!(!dir.join("bob.txt").exists());
+
+    // As carl's buddy, it isn't cached on bob's word alone
+    let cache = Message::Cache {
+        recipient: "carl".to_string(),
+        sender: "bob".to_string(),
+        id: "1".to_string(),
+        body: sealed.body,
+        signature,
+    };
+    assert!(matches!(ask(&mut stream, cache), Message::Rejected { .. }));
+    assert!(held.lock().unwrap().pending("carl").is_empty());
+}
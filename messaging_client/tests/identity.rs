@@ -0,0 +1,55 @@
+use lib::network_messaging::handlers::handle_main_server_connection;
+use lib::network_messaging::keys::{encryption_key, signing_key, IDENTITY_FILE};
+use lib::network_messaging::utils::set_data_dir;
+use messaging_protocol::crypto::{init_proof, verify};
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::Message;
+use std::net::{TcpListener, TcpStream};
+use std::{env, fs, process, thread};
+
+#[test]
+fn the_gateways_challenge_is_signed() {
+    let dir = env::temp_dir().join(format!("client_identity_{}", process::id()));
+    _ = fs::remove_dir_all(&dir);
+    set_data_dir(&format!("{}/", dir.display()));
+
+    // A gateway that only lets amy in once she signs its nonce
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let gateway = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = FramedStream::new(stream);
+        let nonce = "ab".repeat(32);
+        let challenge = Message::Challenge {
+            nonce: nonce.clone(),
+        };
+        stream.write_frame(&challenge.encode()).unwrap();
+
+        let signature = match Message::decode(&stream.read_frame().unwrap()).unwrap() {
+            Message::Prove {
+                username,
+                signature,
+            } if username == "amy" => signature,
+            other => panic!("expected a proof, got {}", other),
+        };
+        let key = encryption_key().public_hex();
+        let identity = signing_key().public_hex();
+        let proof = init_proof(&nonce, "amy", "10.0.0.1:8013", &key, &identity);
+        verify(&identity, &signature, &proof).unwrap();
+
+        let buddies = Message::Buddies {
+            username: "amy".to_string(),
+            buddies: vec!["10.0.0.2:8013".to_string()],
+        };
+        stream.write_frame(&buddies.encode()).unwrap();
+    });
+
+    let mut stream = FramedStream::new(TcpStream::connect(addr).unwrap());
+    let (_, _, buddies) =
+        handle_main_server_connection(&mut stream, "amy", "10.0.0.1:8013").unwrap();
+    assert_eq!(buddies, ["10.0.0.2:8013"]);
+    gateway.join().unwrap();
+
+    // The identity is kept for the next run
+    assert!(dir.join(IDENTITY_FILE).exists());
+}
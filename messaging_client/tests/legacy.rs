@@ -0,0 +1,93 @@
+mod common;
+
+use common::fake_gateway;
+use lib::network_messaging::cache::BuddyCache;
+use lib::network_messaging::handlers::{handle_connection, CacheMap};
+use lib::network_messaging::utils::{contact_file, set_data_dir, set_gateway, CHAT_LOG};
+use messaging_protocol::crypto::{to_hex, SigningPair};
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::{Message, NetworkParams};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::{env, fs, process, thread};
+
+/*
+ * A fresh data dir and a gateway with keys for bob, shared by every test
+ * here. Dave's client is older than keys
+*/
+
+fn setup() -> PathBuf {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let dir = env::temp_dir().join(format!("client_legacy_{}", process::id()));
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        set_data_dir(&format!("{}/", dir.display()));
+
+        let bob = SigningPair::generate().unwrap();
+        set_gateway(&fake_gateway(&[("bob", bob.public_hex())]));
+        dir
+    })
+    .clone()
+}
+
+/*
+ * Carl answering one peer connection on a background thread
+*/
+
+fn carl() -> FramedStream<TcpStream> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut cache: CacheMap = Arc::new(Mutex::new(BuddyCache::new(NetworkParams::default())));
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = FramedStream::new(stream);
+        handle_connection(&mut stream, "", "carl", &mut cache);
+    });
+
+    FramedStream::new(TcpStream::connect(addr).unwrap())
+}
+
+/*
+ * Send carl a body in the clear, the way clients from before encryption
+ * do, and wait for the ack
+*/
+
+fn send_plain(stream: &mut FramedStream<TcpStream>, sender: &str, body: &str) {
+    let send = Message::Send {
+        recipient: "carl".to_string(),
+        sender: sender.to_string(),
+        id: "1".to_string(),
+        body: body.to_string(),
+        signature: None,
+    };
+    stream.write_frame(&send.encode()).unwrap();
+    let reply = Message::decode(&stream.read_frame().unwrap()).unwrap();
+    assert!(matches!(reply, Message::Ack { .. }), "{}", reply);
+}
+
+#[test]
+fn plain_text_is_only_taken_from_clients_without_keys() {
+    let dir = setup();
+    let mut stream = carl();
+    send_plain(&mut stream, "dave", "hello from an old client");
+    send_plain(&mut stream, "bob", "bob always encrypts");
+
+    let log = fs::read_to_string(dir.join("dave.txt")).unwrap();
+    assert!(log.contains("hello from an old client"), "{}", log);
+    assert!(!dir.join("bob.txt").exists());
+}
+
+#[test]
+fn chat_logs_stay_in_the_data_dir() {
+    let dir = setup();
+    let mut stream = carl();
+    send_plain(&mut stream, "../escaped", "out of the data dir");
+
+    let name = format!("~{}.txt", to_hex(b"../escaped"));
+    assert!(contact_file("../escaped", CHAT_LOG).ends_with(&name));
+    let log = fs::read_to_string(dir.join(name)).unwrap();
+    assert!(log.contains("out of the data dir"), "{}", log);
+    assert!(!dir.parent().unwrap().join("escaped.txt").exists());
+}
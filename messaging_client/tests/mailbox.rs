@@ -0,0 +1,314 @@
+mod common;
+
+use common::fake_gateway;
+use lib::network_messaging::cache::BuddyCache;
+use lib::network_messaging::handlers::{handle_connection, CacheMap, PAGE_SIZE};
+use lib::network_messaging::utils::set_gateway;
+use messaging_protocol::crypto::{mailbox_proof, message_proof, SigningPair};
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::{
+    new_message_id, Message, NetworkParams, StoredMessage, PROTOCOL_VERSION,
+};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+// Amy's and bob's identity keys, known to the one fake gateway every test
+// in here shares
+static IDENTITIES: OnceLock<(SigningPair, SigningPair)> = OnceLock::new();
+
+fn identities() -> &'static (SigningPair, SigningPair) {
+    IDENTITIES.get_or_init(|| {
+        let (amy, bob) = (
+            SigningPair::generate().unwrap(),
+            SigningPair::generate().unwrap(),
+        );
+        set_gateway(&fake_gateway(&[
+            ("amy", amy.public_hex()),
+            ("bob", bob.public_hex()),
+        ]));
+        (amy, bob)
+    })
+}
+
+/*
+ * A buddy holding the given number of messages for amy, answering one
+ * connection on a background thread
+*/
+
+fn buddy(count: usize) -> (CacheMap, FramedStream<TcpStream>) {
+    identities();
+    let mut pending = BuddyCache::new(NetworkParams::default());
+    for i in 0..count {
+        let message = StoredMessage {
+            sender: "bob".to_string(),
+            id: format!("{:04}", i),
+            body: format!("message {}", i),
+            signature: None,
+        };
+        pending.insert("amy", message).unwrap();
+    }
+    let cache: CacheMap = Arc::new(Mutex::new(pending));
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut buddy_cache = cache.clone();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = FramedStream::new(stream);
+        handle_connection(&mut stream, "", "carl", &mut buddy_cache);
+    });
+
+    (cache, FramedStream::new(TcpStream::connect(addr).unwrap()))
+}
+
+fn send(stream: &mut FramedStream<TcpStream>, message: Message) {
+    stream.write_frame(&message.encode()).unwrap();
+}
+
+fn receive(stream: &mut FramedStream<TcpStream>) -> Message {
+    Message::decode(&stream.read_frame().unwrap()).unwrap()
+}
+
+/*
+ * Ask for username's messages and answer the challenge with identity,
+ * returning the buddy's reply
+*/
+
+fn sign_in(
+    stream: &mut FramedStream<TcpStream>,
+    username: &str,
+    identity: &SigningPair,
+) -> Message {
+    send(
+        stream,
+        Message::Init {
+            username: username.to_string(),
+            addr: "127.0.0.1:1".to_string(),
+            version: PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+            key: None,
+            identity: Some(identity.public_hex()),
+        },
+    );
+    let nonce = match receive(stream) {
+        Message::Challenge { nonce } => nonce,
+        other => panic!("expected a challenge, got {}", other),
+    };
+    send(
+        stream,
+        Message::Prove {
+            username: username.to_string(),
+            signature: identity.sign(&mailbox_proof(&nonce, username)),
+        },
+    );
+    receive(stream)
+}
+
+fn page(stream: &mut FramedStream<TcpStream>) -> Vec<StoredMessage> {
+    as_page(receive(stream))
+}
+
+fn as_page(reply: Message) -> Vec<StoredMessage> {
+    match reply {
+        Message::Update { messages } => messages,
+        other => panic!("expected a page, got {}", other),
+    }
+}
+
+fn ack(stream: &mut FramedStream<TcpStream>, messages: &[StoredMessage]) {
+    for message in messages {
+        send(
+            stream,
+            Message::Ack {
+                username: "amy".to_string(),
+                id: message.id.clone(),
+            },
+        );
+    }
+}
+
+fn held(cache: &CacheMap) -> usize {
+    cache.lock().unwrap().pending("amy").len()
+}
+
+#[test]
+fn messages_are_paged_and_dropped_only_once_acked() {
+    let (cache, mut stream) = buddy(120);
+    let mut reply = sign_in(&mut stream, "amy", &identities().0);
+
+    let mut received = Vec::new();
+    loop {
+        let messages = as_page(reply);
+        assert!(messages.len() <= PAGE_SIZE as usize);
+
+        // Nothing is gone before we ack it
+        assert_eq!(held(&cache), 120 - received.len());
+
+        ack(&mut stream, &messages);
+        received.extend(messages.iter().map(|m| m.id.clone()));
+        if messages.len() < PAGE_SIZE as usize {
+            break;
+        }
+
+        send(
+            &mut stream,
+            Message::Pull {
+                username: "amy".to_string(),
+                cursor: messages.last().unwrap().id.clone(),
+                limit: PAGE_SIZE,
+            },
+        );
+        reply = receive(&mut stream);
+    }
+
+    let expected: Vec<String> = (0..120).map(|i| format!("{:04}", i)).collect();
+    assert_eq!(received, expected);
+
+    // One more pull makes sure the last acks were handled
+    send(
+        &mut stream,
+        Message::Pull {
+            username: "amy".to_string(),
+            cursor: String::new(),
+            limit: PAGE_SIZE,
+        },
+    );
+    assert!(page(&mut stream).is_empty());
+    assert_eq!(held(&cache), 0);
+}
+
+#[test]
+fn unacked_messages_stay_for_the_next_pull() {
+    let (cache, mut stream) = buddy(10);
+    sign_in(&mut stream, "amy", &identities().0);
+    let pull = Message::Pull {
+        username: "amy".to_string(),
+        cursor: String::new(),
+        limit: 4,
+    };
+
+    send(&mut stream, pull.clone());
+    let first = page(&mut stream);
+    assert_eq!(first.len(), 4);
+
+    // Only half the page makes it back
+    ack(&mut stream, &first[..2]);
+
+    // Starting over hands out the rest again
+    send(&mut stream, pull);
+    let ids: Vec<String> = page(&mut stream).into_iter().map(|m| m.id).collect();
+    assert_eq!(ids, ["0002", "0003", "0004", "0005"]);
+    assert_eq!(held(&cache), 8);
+}
+
+#[test]
+fn acks_only_drop_the_message_with_their_id() {
+    let (cache, mut stream) = buddy(0);
+    sign_in(&mut stream, "amy", &identities().0);
+
+    // Two messages with the same text, told apart by their ids alone
+    let same = |id: String| StoredMessage {
+        sender: "bob".to_string(),
+        id,
+        body: "ok".to_string(),
+        signature: None,
+    };
+    let (first, second) = (
+        same(new_message_id().unwrap()),
+        same(new_message_id().unwrap()),
+    );
+    assert_ne!(first.id, second.id);
+    for message in [&first, &second] {
+        cache
+            .lock()
+            .unwrap()
+            .insert("amy", message.clone())
+            .unwrap();
+    }
+
+    ack(&mut stream, std::slice::from_ref(&first));
+    send(
+        &mut stream,
+        Message::Pull {
+            username: "amy".to_string(),
+            cursor: String::new(),
+            limit: PAGE_SIZE,
+        },
+    );
+    assert_eq!(page(&mut stream), vec![second]);
+}
+
+#[test]
+fn full_buddy_rejects_cache_requests() {
+    // Bob's message is signed, so only the quota stands in the way
+    let bob = &identities().1;
+    let limit = NetworkParams::default().max_cached_messages as usize;
+    let (cache, mut stream) = buddy(limit);
+
+    let proof = message_proof("amy", "bob", "extra", "one too many");
+    send(
+        &mut stream,
+        Message::Cache {
+            recipient: "amy".to_string(),
+            sender: "bob".to_string(),
+            id: "extra".to_string(),
+            body: "one too many".to_string(),
+            signature: Some(bob.sign(&proof)),
+        },
+    );
+    match Message::decode(&stream.read_frame().unwrap()).unwrap() {
+        Message::Rejected {
+            username,
+            id,
+            reason,
+        } => {
+            assert_eq!((username.as_str(), id.as_str()), ("amy", "extra"));
+            assert!(!reason.contains("signature"), "{}", reason);
+        }
+        other => panic!("expected a rejection, got {}", other),
+    }
+    assert_eq!(held(&cache), limit);
+}
+
+#[test]
+fn only_the_proven_recipient_pulls_and_acks() {
+    let (cache, mut stream) = buddy(3);
+    let (amy, bob) = identities();
+
+    // Bob can't sign for amy, and signing as bob only gets bob's messages,
+    // of which there are none
+    let reply = sign_in(&mut stream, "amy", bob);
+    assert!(matches!(reply, Message::Refused { .. }), "{}", reply);
+    assert!(as_page(sign_in(&mut stream, "bob", bob)).is_empty());
+
+    // Acks and pulls for amy are turned away without touching amy's messages
+    let pending = cache.lock().unwrap().pending("amy");
+    ack(&mut stream, &pending);
+    for _ in &pending {
+        let reply = receive(&mut stream);
+        assert!(matches!(reply, Message::Refused { .. }), "{}", reply);
+    }
+    send(
+        &mut stream,
+        Message::Pull {
+            username: "amy".to_string(),
+            cursor: String::new(),
+            limit: PAGE_SIZE,
+        },
+    );
+    let reply = receive(&mut stream);
+    assert!(matches!(reply, Message::Refused { .. }), "{}", reply);
+    assert_eq!(held(&cache), 3);
+
+    // Neither is an ack with nobody signed in at all
+    let (cache, mut stream) = buddy(3);
+    let pending = cache.lock().unwrap().pending("amy");
+    ack(&mut stream, &pending[..1]);
+    let reply = receive(&mut stream);
+    assert!(matches!(reply, Message::Refused { .. }), "{}", reply);
+    assert_eq!(held(&cache), 3);
+
+    // Amy gets them all
+    assert_eq!(as_page(sign_in(&mut stream, "amy", amy)), pending);
+}
@@ -0,0 +1,123 @@
+use lib::network_messaging::config::{announced_addr, Profile};
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+
+/*
+ * Write a config file in a fresh directory of its own
+*/
+
+fn config_file(name: &str, text: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("client_profile_{}_{}", process::id(), name));
+    _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("client.toml");
+    fs::write(&path, text).unwrap();
+    path
+}
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+const SHARED: &str = "gateway = \"127.0.0.1:9000\"
+data_dir = \"/tmp/chats\"
+
+[profiles.amy]
+username = \"amy\"
+port = 9001
+bind = \"127.0.0.1\"
+
+[profiles.bob]
+username = \"bob\"
+port = 9002
+data_dir = \"/tmp/bob\"
+";
+
+#[test]
+fn profiles_change_the_shared_settings() {
+    let path = config_file("shared", SHARED);
+    let path = path.to_str().unwrap();
+
+    // Without a profile only the top of the file counts
+    let base = Profile::from_args(args(&["--config", path])).unwrap();
+    assert_eq!(base.gateway, "127.0.0.1:9000");
+    assert_eq!(base.port, 8013);
+    assert_eq!(base.data_dir, "/tmp/chats/");
+    assert_eq!(base.username, None);
+
+    // A profile keeps its files under the shared directory unless it names
+    // its own
+    let amy = Profile::from_args(args(&["--config", path, "--profile", "amy"])).unwrap();
+    assert_eq!(amy.username.as_deref(), Some("amy"));
+    assert_eq!(amy.data_dir, "/tmp/chats/amy/");
+    assert_eq!(
+        amy.listen_addr(),
+        "127.0.0.1:9001".parse::<SocketAddr>().unwrap()
+    );
+
+    let bob = Profile::from_args(args(&["--config", path, "--profile", "bob"])).unwrap();
+    assert_eq!(bob.data_dir, "/tmp/bob/");
+    assert_eq!(bob.gateway, "127.0.0.1:9000");
+}
+
+#[test]
+fn flags_win_over_the_profile() {
+    let path = config_file("flags", SHARED);
+
+    let amy = Profile::from_args(args(&[
+        "--profile",
+        "amy",
+        "--config",
+        path.to_str().unwrap(),
+        "--port",
+        "0",
+        "--gateway",
+        "localhost:1234",
+        "--data-dir",
+        "/tmp/elsewhere",
+    ]))
+    .unwrap();
+    assert_eq!(amy.port, 0);
+    assert_eq!(amy.gateway, "localhost:1234");
+    assert_eq!(amy.data_dir, "/tmp/elsewhere/");
+    assert_eq!(amy.username.as_deref(), Some("amy"));
+}
+
+#[test]
+fn bad_profiles_are_rejected() {
+    let path = config_file("bad", SHARED);
+    let path = path.to_str().unwrap();
+
+    for bad in [
+        args(&["--config", path, "--profile", "carl"]),
+        args(&["--config", path, "--port", "http"]),
+        args(&["--config", path, "--port", "70000"]),
+        args(&["--config", path, "--bind", "localhost"]),
+        args(&["--config", path, "--username", "a;b"]),
+        args(&["--config", path, "--colour", "red"]),
+        args(&["--config", path, "--port"]),
+        args(&["--config", path, "amy"]),
+        args(&["--config", "/nonexistent/client.toml"]),
+    ] {
+        assert!(Profile::from_args(bad.clone()).is_err(), "{:?}", bad);
+    }
+
+    let path = config_file("typo", "gatway = \"127.0.0.1:9000\"\n");
+    assert!(Profile::from_args(args(&["--config", path.to_str().unwrap()])).is_err());
+}
+
+#[test]
+fn listening_everywhere_announces_a_real_address() {
+    let addr = announced_addr("0.0.0.0:4000".parse().unwrap());
+    let addr: SocketAddr = addr.parse().unwrap();
+    assert!(!addr.ip().is_unspecified());
+    assert_eq!(addr.port(), 4000);
+
+    assert_eq!(
+        announced_addr("127.0.0.1:4000".parse().unwrap()),
+        "127.0.0.1:4000"
+    );
+}
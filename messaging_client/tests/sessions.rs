@@ -0,0 +1,236 @@
+mod common;
+
+use chrono::Utc;
+use common::fake_gateway;
+use lib::network_messaging::cache::BuddyCache;
+use lib::network_messaging::handlers::{handle_connection, CacheMap};
+use lib::network_messaging::keys::{encryption_key, seal_with_session, signing_key};
+use lib::network_messaging::sessions::{
+    load_prekeys, prekeys, start_session, PREKEY_BATCH, PREKEY_FILE, SESSION_FILE,
+    SIGNED_PREKEY_LIFETIME,
+};
+use lib::network_messaging::utils::{set_data_dir, set_gateway};
+use messaging_protocol::crypto::{message_proof, verify, KeyPair, SigningPair};
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::{Message, NetworkParams, StoredMessage};
+use messaging_protocol::ratchet::{prekey_header, prekey_proof, PrekeyBundle, Session};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::{env, fs, process, thread};
+
+/*
+ * Amy answering one peer connection on a background thread
+*/
+
+fn amy() -> FramedStream<TcpStream> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut cache: CacheMap = Arc::new(Mutex::new(BuddyCache::new(NetworkParams::default())));
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = FramedStream::new(stream);
+        handle_connection(&mut stream, "", "amy", &mut cache);
+    });
+
+    FramedStream::new(TcpStream::connect(addr).unwrap())
+}
+
+/*
+ * Send amy a body signed by the sender's identity
+*/
+
+fn send(stream: &mut FramedStream<TcpStream>, sender: &SigningPair, id: &str, body: String) {
+    let send = Message::Send {
+        recipient: "amy".to_string(),
+        sender: "bob".to_string(),
+        id: id.to_string(),
+        signature: Some(sender.sign(&message_proof("amy", "bob", id, &body))),
+        body,
+    };
+    stream.write_frame(&send.encode()).unwrap();
+    assert!(matches!(
+        Message::decode(&stream.read_frame().unwrap()).unwrap(),
+        Message::Ack { .. }
+    ));
+}
+
+fn reply(id: &str, body: &str) -> StoredMessage {
+    StoredMessage {
+        sender: "amy".to_string(),
+        id: id.to_string(),
+        body: body.to_string(),
+        signature: None,
+    }
+}
+
+#[test]
+fn prekeys_are_kept_private_and_signed() {
+    let dir = env::temp_dir().join(format!("client_prekeys_{}", process::id()));
+    _ = fs::remove_dir_all(&dir);
+    let path = dir.join(PREKEY_FILE);
+    let path = path.to_str().unwrap();
+    let identity = SigningPair::generate().unwrap();
+
+    let now = Utc::now().timestamp();
+    let mut prekeys = load_prekeys(path, &identity).unwrap();
+    assert!(prekeys.due(now));
+    let published = match prekeys.refill("amy", &identity, now).unwrap() {
+        Message::Prekeys {
+            signed_prekey,
+            signature,
+            one_time,
+            ..
+        } => {
+            verify(
+                &identity.public_hex(),
+                &signature,
+                &prekey_proof(&signed_prekey),
+            )
+            .unwrap();
+            one_time
+        }
+        other => panic!("expected prekeys, got {}", other),
+    };
+    assert_eq!(published.len(), PREKEY_BATCH);
+    prekeys.save(path).unwrap();
+
+    // The secret halves come back, the public ones never hit the disk
+    let loaded = load_prekeys(path, &identity).unwrap();
+    assert_eq!(loaded.one_time_count(), PREKEY_BATCH);
+    assert_eq!(
+        loaded.signed_prekey.public_hex(),
+        prekeys.signed_prekey.public_hex()
+    );
+    assert!(!fs::read_to_string(path).unwrap().contains(&published[0]));
+    assert!(!loaded.due(now));
+
+    // A week on the signed prekey is replaced along with the batch
+    let later = now + SIGNED_PREKEY_LIFETIME;
+    assert!(loaded.due(later));
+    let mut loaded = loaded;
+    loaded.refill("amy", &identity, later).unwrap();
+    assert_ne!(
+        loaded.signed_prekey.public_hex(),
+        prekeys.signed_prekey.public_hex()
+    );
+    assert!(!loaded.due(later));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    fs::write(path, "not prekeys").unwrap();
+    assert!(load_prekeys(path, &identity).is_err());
+}
+
+#[test]
+fn sessions_are_taken_up_and_kept() {
+    let dir = env::temp_dir().join(format!("client_sessions_{}", process::id()));
+    _ = fs::remove_dir_all(&dir);
+    set_data_dir(&format!("{}/", dir.display()));
+
+    // What the gateway would hand out for amy
+    let now = Utc::now().timestamp();
+    let bundle = match prekeys()
+        .lock()
+        .unwrap()
+        .refill("amy", signing_key(), now)
+        .unwrap()
+    {
+        Message::Prekeys {
+            signed_prekey,
+            signature,
+            one_time,
+            ..
+        } => PrekeyBundle {
+            identity: signing_key().public_hex(),
+            key: encryption_key().public_hex(),
+            signed_prekey,
+            signature,
+            one_time: one_time.first().cloned(),
+        },
+        other => panic!("expected prekeys, got {}", other),
+    };
+
+    // Amy's signed prekey is replaced before bob gets round to using it
+    prekeys()
+        .lock()
+        .unwrap()
+        .refill("amy", signing_key(), now + SIGNED_PREKEY_LIFETIME)
+        .unwrap();
+
+    // Bob starts a session with amy, the first message takes it up. A
+    // forged copy that doesn't decrypt leaves the one-time prekey be
+    let bob_identity = SigningPair::generate().unwrap();
+    set_gateway(&fake_gateway(&[("bob", bob_identity.public_hex())]));
+    let bob = KeyPair::generate().unwrap();
+    let mut bob_session = Session::initiate(&bob, &bundle).unwrap();
+    let first = bob_session.encrypt("hi amy", &["amy", "bob", "1"]).unwrap();
+    let mut forged = first.clone();
+    let last = if forged.pop() == Some('0') { '1' } else { '0' };
+    forged.push(last);
+    let mut stream = amy();
+    send(&mut stream, &bob_identity, "1", forged);
+    assert_eq!(prekeys().lock().unwrap().one_time_count(), 2 * PREKEY_BATCH);
+    send(&mut stream, &bob_identity, "1", first.clone());
+    let second = bob_session
+        .encrypt("still there?", &["amy", "bob", "2"])
+        .unwrap();
+    send(&mut stream, &bob_identity, "2", second);
+
+    // Replaying the first message doesn't start the session over
+    send(&mut stream, &bob_identity, "1", first);
+    let log = fs::read_to_string(dir.join("bob.txt")).unwrap();
+    assert!(
+        log.contains("hi amy") && log.contains("still there?"),
+        "{}",
+        log
+    );
+    assert_eq!(log.lines().count(), 2, "{}", log);
+    assert_eq!(
+        prekeys().lock().unwrap().one_time_count(),
+        2 * PREKEY_BATCH - 1
+    );
+
+    // The session is saved next to the chat log, and amy answers with it
+    let saved = fs::read_to_string(dir.join(format!("bob{}", SESSION_FILE))).unwrap();
+    assert_eq!(saved.lines().count(), 1);
+    let answer = seal_with_session("bob", &reply("3", "yes"))
+        .unwrap()
+        .unwrap();
+    assert!(prekey_header(&answer.body).is_none());
+    assert_eq!(
+        bob_session
+            .decrypt(&answer.body, &["bob", "amy", "3"])
+            .unwrap(),
+        "yes"
+    );
+
+    // Amy starting a session of her own sends her prekeys along
+    let carl_identity = SigningPair::generate().unwrap();
+    let carl = KeyPair::generate().unwrap();
+    let carl_prekey = KeyPair::generate().unwrap();
+    let carl_bundle = PrekeyBundle {
+        identity: carl_identity.public_hex(),
+        key: carl.public_hex(),
+        signed_prekey: carl_prekey.public_hex(),
+        signature: carl_identity.sign(&prekey_proof(&carl_prekey.public_hex())),
+        one_time: None,
+    };
+    assert!(seal_with_session("carl", &reply("4", "hi")).is_none());
+    start_session("carl", &carl_bundle).unwrap();
+    let hello = seal_with_session("carl", &reply("4", "hi carl"))
+        .unwrap()
+        .unwrap();
+    let header = prekey_header(&hello.body).unwrap();
+    let mut carl_session = Session::respond(&carl, &carl_prekey, None, &header).unwrap();
+    assert_eq!(
+        carl_session
+            .decrypt(&hello.body, &["carl", "amy", "4"])
+            .unwrap(),
+        "hi carl"
+    );
+}
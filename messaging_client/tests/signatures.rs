@@ -0,0 +1,211 @@
+mod common;
+
+use common::{delivery_token, fake_gateway, token_key};
+use lib::network_messaging::cache::BuddyCache;
+use lib::network_messaging::handlers::{handle_connection, CacheMap};
+use lib::network_messaging::keys::{encryption_key, seal_message};
+use lib::network_messaging::utils::{set_data_dir, set_gateway};
+use messaging_protocol::crypto::{message_proof, KeyPair, SigningPair};
+use messaging_protocol::envelope::seal_sender;
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::{Message, NetworkParams, StoredMessage};
+use messaging_protocol::tokens::TokenKey;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::{env, fs, process, thread};
+
+/*
+ * Bob's identity key, which the gateway every test here talks to has on
+ * file for him. Nobody else has one
+*/
+
+fn bob() -> &'static SigningPair {
+    static BOB: OnceLock<SigningPair> = OnceLock::new();
+    BOB.get_or_init(|| {
+        let bob = SigningPair::generate().unwrap();
+        set_gateway(&fake_gateway(&[("bob", bob.public_hex())]));
+        bob
+    })
+}
+
+/*
+ * Carl answering one peer connection on a background thread
+*/
+
+fn carl(cache: &CacheMap) -> FramedStream<TcpStream> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut cache = cache.clone();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = FramedStream::new(stream);
+        handle_connection(&mut stream, "", "carl", &mut cache);
+    });
+
+    FramedStream::new(TcpStream::connect(addr).unwrap())
+}
+
+fn message(sender: &str, id: &str, body: &str) -> StoredMessage {
+    StoredMessage {
+        sender: sender.to_string(),
+        id: id.to_string(),
+        body: body.to_string(),
+        signature: None,
+    }
+}
+
+fn signed(recipient: &str, message: StoredMessage, key: &SigningPair) -> StoredMessage {
+    let proof = message_proof(recipient, &message.sender, &message.id, &message.body);
+    StoredMessage {
+        signature: Some(key.sign(&proof)),
+        ..message
+    }
+}
+
+fn cache(stream: &mut FramedStream<TcpStream>, message: &StoredMessage) -> Message {
+    let cache = Message::Cache {
+        recipient: "amy".to_string(),
+        sender: message.sender.clone(),
+        id: message.id.clone(),
+        body: message.body.clone(),
+        signature: message.signature.clone(),
+    };
+    stream.write_frame(&cache.encode()).unwrap();
+    Message::decode(&stream.read_frame().unwrap()).unwrap()
+}
+
+fn send(stream: &mut FramedStream<TcpStream>, message: &StoredMessage) {
+    let send = Message::Send {
+        recipient: "carl".to_string(),
+        sender: message.sender.clone(),
+        id: message.id.clone(),
+        body: message.body.clone(),
+        signature: message.signature.clone(),
+    };
+    stream.write_frame(&send.encode()).unwrap();
+    assert!(matches!(
+        Message::decode(&stream.read_frame().unwrap()).unwrap(),
+        Message::Ack { .. }
+    ));
+}
+
+#[test]
+fn buddies_only_cache_signed_messages() {
+    let bob = bob();
+    let mallory = SigningPair::generate().unwrap();
+    let held: CacheMap = Arc::new(Mutex::new(BuddyCache::new(NetworkParams::default())));
+    let mut stream = carl(&held);
+
+    // Unsigned, signed by someone else, or signed for someone else
+    let rejected = [
+        message("bob", "1", "unsigned"),
+        signed("amy", message("bob", "2", "forged"), &mallory),
+        signed("carl", message("bob", "3", "for carl"), bob),
+    ];
+    for message in &rejected {
+        match cache(&mut stream, message) {
+            Message::Rejected { id, .. } => assert_eq!(id, message.id),
+            other => panic!("expected a rejection, got {}", other),
+        }
+    }
+    assert!(held.lock().unwrap().pending("amy").is_empty());
+
+    // Bob's own signature is taken, and stays with the message. Dan has
+    // no identity to sign with, so that one is taken unverified
+    let unverified = message("dan", "4", "nobody knows dan");
+    let genuine = signed("amy", message("bob", "5", "hi amy"), bob);
+    for message in [&unverified, &genuine] {
+        assert!(matches!(cache(&mut stream, message), Message::Ack { .. }));
+    }
+    assert_eq!(
+        held.lock().unwrap().pending("amy"),
+        vec![unverified, genuine]
+    );
+}
+
+#[test]
+fn buddies_only_cache_sealed_messages_the_gateway_spent_a_token_on() {
+    bob();
+    let held: CacheMap = Arc::new(Mutex::new(BuddyCache::new(NetworkParams::default())));
+    let mut stream = carl(&held);
+
+    // Carl can't tell who sealed it, only whether the gateway takes the
+    // token it carries
+    let amy = KeyPair::generate().unwrap();
+    let envelope =
+        |id: &str| seal_sender("amy", &amy.public_hex(), &message("mallory", id, "?")).unwrap();
+    let with = |sealed: StoredMessage, token: &str| StoredMessage {
+        signature: Some(token.to_string()),
+        ..sealed
+    };
+
+    // No token, one mallory made up, or one already spent on another
+    // message
+    let (token, spent) = (delivery_token(token_key()), delivery_token(token_key()));
+    let forged = delivery_token(&TokenKey::generate().unwrap());
+    assert!(matches!(
+        cache(&mut stream, &with(envelope("7"), &spent)),
+        Message::Ack { .. }
+    ));
+    for unpaid in [
+        envelope("6"),
+        with(envelope("6"), &forged),
+        with(envelope("6"), &spent),
+    ] {
+        match cache(&mut stream, &unpaid) {
+            Message::Rejected { id, .. } => assert_eq!(id, "6"),
+            other => panic!("expected a rejection, got {}", other),
+        }
+    }
+
+    // A good token is taken, and stays with the message for amy. Handed
+    // over again, the same message is still taken
+    let paid = with(envelope("6"), &token);
+    for _ in 0..2 {
+        assert!(matches!(cache(&mut stream, &paid), Message::Ack { .. }));
+    }
+    assert_eq!(held.lock().unwrap().pending("amy").len(), 2);
+    assert!(held.lock().unwrap().pending("amy").contains(&paid));
+}
+
+#[test]
+fn messages_with_bad_signatures_are_dropped() {
+    let bob = bob();
+    let dir = env::temp_dir().join(format!("client_signatures_{}", process::id()));
+    _ = fs::remove_dir_all(&dir);
+    set_data_dir(&format!("{}/", dir.display()));
+    let key = encryption_key().public_hex();
+    let held: CacheMap = Arc::new(Mutex::new(BuddyCache::new(NetworkParams::default())));
+    let mut stream = carl(&held);
+
+    let sealed = seal_message("carl", &key, &message("bob", "1", "signed by bob")).unwrap();
+    send(&mut stream, &signed("carl", sealed, bob));
+
+    // A signature that doesn't check out gets the message dropped
+    let sealed = seal_message("carl", &key, &message("bob", "2", "not bob")).unwrap();
+    send(&mut stream, &sealed);
+
+    // Dan has no identity to check against, so that one shows up unverified
+    let sealed = seal_message("carl", &key, &message("dan", "3", "from dan")).unwrap();
+    send(&mut stream, &sealed);
+
+    // Sealed, the sender only shows up once carl opens it
+    let sealed = seal_message("carl", &key, &message("bob", "4", "sealed by bob")).unwrap();
+    let envelope = seal_sender("carl", &key, &signed("carl", sealed, bob)).unwrap();
+    send(&mut stream, &envelope);
+
+    // An envelope hiding a forged signature, or sealed for someone else
+    let sealed = seal_message("carl", &key, &message("bob", "5", "forged")).unwrap();
+    let forged = signed("carl", sealed, &SigningPair::generate().unwrap());
+    send(&mut stream, &seal_sender("carl", &key, &forged).unwrap());
+    let sealed = seal_message("carl", &key, &message("bob", "6", "for amy")).unwrap();
+    send(&mut stream, &seal_sender("amy", &key, &sealed).unwrap());
+
+    let log = fs::read_to_string(dir.join("bob.txt")).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", log);
+    assert!(lines[0].ends_with("signed by bob;verified"), "{}", log);
+    assert!(lines[1].ends_with("sealed by bob;verified"), "{}", log);
+    let log = fs::read_to_string(dir.join("dan.txt")).unwrap();
+    assert!(log.trim_end().ends_with("from dan;unverified"), "{}", log);
+}
@@ -0,0 +1,388 @@
+use crate::message::{
+    escape, format_capabilities, parse_capabilities, require, split_field, unescape, Capability,
+    ParseError, DELIMITER, FIELD_SEP,
+};
+use std::fmt;
+
+/*
+ * Requests an operator sends to the gateway's admin listener and the
+ * gateway's replies. They are framed and escaped like every Message, but
+ * never travel over the public port. A connection has to open with AUTH
+ * and the gateway's admin token before anything else is answered:
+ *
+ *   AUTH token
+ *   SHUTDOWN
+ *   DRAIN
+ *   USERS
+ *   CACHES
+ *   INSPECT recipient
+ *   PURGE recipient[;id]
+ *   KICK username
+ *
+ *   OK detail
+ *   DENIED reason
+ *   USERS [username;ip:port;presence;capability,capability&&...]
+ *   CACHES [recipient;messages;bytes&&...]
+ *   MESSAGES recipient[&&sender;id;bytes;cached_at...]
+*/
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AdminRequest {
+    Auth {
+        token: String,
+    },
+    // Exit right away
+    Shutdown,
+    // Stop taking new connections and exit once the last client is gone
+    Drain,
+    Users,
+    Caches,
+    Inspect {
+        recipient: String,
+    },
+    // Drop one cached message, or all of them without an id
+    Purge {
+        recipient: String,
+        id: Option<String>,
+    },
+    Kick {
+        username: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AdminReply {
+    Ok {
+        detail: String,
+    },
+    Denied {
+        reason: String,
+    },
+    Users {
+        users: Vec<UserInfo>,
+    },
+    Caches {
+        caches: Vec<CacheInfo>,
+    },
+    Messages {
+        recipient: String,
+        messages: Vec<CachedInfo>,
+    },
+}
+
+/*
+ * Where a user stands with the gateway. An away user still holds its
+ * place in the buddy ring, a gone one has left it
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Presence {
+    Online,
+    Away,
+    Gone,
+}
+
+impl Presence {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Presence::Online => "online",
+            Presence::Away => "away",
+            Presence::Gone => "gone",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Presence> {
+        match name {
+            "online" => Some(Presence::Online),
+            "away" => Some(Presence::Away),
+            "gone" => Some(Presence::Gone),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserInfo {
+    pub username: String,
+    pub addr: String,
+    pub presence: Presence,
+    pub capabilities: Vec<Capability>,
+}
+
+// How much the gateway holds for one recipient
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheInfo {
+    pub recipient: String,
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+/*
+ * One message the gateway holds, without its body. The operator can see
+ * that a message is there and how big it is, but not what it says
+*/
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CachedInfo {
+    pub sender: String,
+    pub id: String,
+    pub bytes: u64,
+    pub cached_at: u64,
+}
+
+impl AdminRequest {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AdminRequest::Auth { .. } => "AUTH",
+            AdminRequest::Shutdown => "SHUTDOWN",
+            AdminRequest::Drain => "DRAIN",
+            AdminRequest::Users => "USERS",
+            AdminRequest::Caches => "CACHES",
+            AdminRequest::Inspect { .. } => "INSPECT",
+            AdminRequest::Purge { .. } => "PURGE",
+            AdminRequest::Kick { .. } => "KICK",
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<AdminRequest, ParseError> {
+        let text = std::str::from_utf8(bytes).map_err(|_| ParseError::InvalidUtf8)?;
+        AdminRequest::parse(text)
+    }
+
+    pub fn parse(text: &str) -> Result<AdminRequest, ParseError> {
+        let (code, body) = text.split_once(' ').unwrap_or((text, ""));
+
+        let request = match code {
+            "AUTH" => AdminRequest::Auth {
+                token: require(body, "AUTH", "token")?,
+            },
+            "SHUTDOWN" => AdminRequest::Shutdown,
+            "DRAIN" => AdminRequest::Drain,
+            "USERS" => AdminRequest::Users,
+            "CACHES" => AdminRequest::Caches,
+            "INSPECT" => AdminRequest::Inspect {
+                recipient: require(body, "INSPECT", "recipient")?,
+            },
+            "PURGE" => {
+                let (recipient, id) = match body.split_once(FIELD_SEP) {
+                    Some((recipient, id)) => (recipient, Some(require(id, "PURGE", "id")?)),
+                    None => (body, None),
+                };
+                AdminRequest::Purge {
+                    recipient: require(recipient, "PURGE", "recipient")?,
+                    id,
+                }
+            }
+            "KICK" => AdminRequest::Kick {
+                username: require(body, "KICK", "username")?,
+            },
+            _ => return Err(ParseError::UnknownCode(code.to_string())),
+        };
+
+        Ok(request)
+    }
+}
+
+impl fmt::Display for AdminRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.code())?;
+
+        match self {
+            AdminRequest::Auth { token } => write!(f, " {}", escape(token)),
+            AdminRequest::Inspect { recipient } => write!(f, " {}", escape(recipient)),
+            AdminRequest::Kick { username } => write!(f, " {}", escape(username)),
+            AdminRequest::Purge { recipient, id } => {
+                write!(f, " {}", escape(recipient))?;
+                match id {
+                    Some(id) => write!(f, "{}{}", FIELD_SEP, escape(id)),
+                    None => Ok(()),
+                }
+            }
+            AdminRequest::Shutdown
+            | AdminRequest::Drain
+            | AdminRequest::Users
+            | AdminRequest::Caches => Ok(()),
+        }
+    }
+}
+
+impl AdminReply {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AdminReply::Ok { .. } => "OK",
+            AdminReply::Denied { .. } => "DENIED",
+            AdminReply::Users { .. } => "USERS",
+            AdminReply::Caches { .. } => "CACHES",
+            AdminReply::Messages { .. } => "MESSAGES",
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<AdminReply, ParseError> {
+        let text = std::str::from_utf8(bytes).map_err(|_| ParseError::InvalidUtf8)?;
+        AdminReply::parse(text)
+    }
+
+    pub fn parse(text: &str) -> Result<AdminReply, ParseError> {
+        let (code, body) = text.split_once(' ').unwrap_or((text, ""));
+
+        let reply = match code {
+            "OK" => AdminReply::Ok {
+                detail: unescape(body)?,
+            },
+            "DENIED" => AdminReply::Denied {
+                reason: unescape(body)?,
+            },
+            "USERS" => {
+                let mut users = Vec::new();
+                for entry in body.split(DELIMITER).filter(|e| !e.is_empty()) {
+                    users.push(parse_user(entry)?);
+                }
+                AdminReply::Users { users }
+            }
+            "CACHES" => {
+                let mut caches = Vec::new();
+                for entry in body.split(DELIMITER).filter(|e| !e.is_empty()) {
+                    let (recipient, rest) = split_field(entry, FIELD_SEP, "CACHES", "messages")?;
+                    let (messages, bytes) = split_field(rest, FIELD_SEP, "CACHES", "bytes")?;
+                    caches.push(CacheInfo {
+                        recipient: unescape(recipient)?,
+                        messages: parse_number(messages, "CACHES", "messages")?,
+                        bytes: parse_number(bytes, "CACHES", "bytes")?,
+                    });
+                }
+                AdminReply::Caches { caches }
+            }
+            "MESSAGES" => {
+                let mut entries = body.split(DELIMITER);
+                let recipient = require(entries.next().unwrap_or(""), "MESSAGES", "recipient")?;
+                let mut messages = Vec::new();
+                for entry in entries.filter(|e| !e.is_empty()) {
+                    messages.push(parse_cached(entry)?);
+                }
+                AdminReply::Messages {
+                    recipient,
+                    messages,
+                }
+            }
+            _ => return Err(ParseError::UnknownCode(code.to_string())),
+        };
+
+        Ok(reply)
+    }
+}
+
+impl fmt::Display for AdminReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.code())?;
+
+        match self {
+            AdminReply::Ok { detail: text } | AdminReply::Denied { reason: text } => {
+                write!(f, " {}", escape(text))
+            }
+            AdminReply::Users { users } => {
+                let entries: Vec<String> = users
+                    .iter()
+                    .map(|u| {
+                        format!(
+                            "{}{}{}{}{}{}{}",
+                            escape(&u.username),
+                            FIELD_SEP,
+                            escape(&u.addr),
+                            FIELD_SEP,
+                            u.presence.name(),
+                            FIELD_SEP,
+                            format_capabilities(&u.capabilities)
+                        )
+                    })
+                    .collect();
+                write!(f, " {}", entries.join(DELIMITER))
+            }
+            AdminReply::Caches { caches } => {
+                let entries: Vec<String> = caches
+                    .iter()
+                    .map(|c| {
+                        format!(
+                            "{}{}{}{}{}",
+                            escape(&c.recipient),
+                            FIELD_SEP,
+                            c.messages,
+                            FIELD_SEP,
+                            c.bytes
+                        )
+                    })
+                    .collect();
+                write!(f, " {}", entries.join(DELIMITER))
+            }
+            AdminReply::Messages {
+                recipient,
+                messages,
+            } => {
+                write!(f, " {}", escape(recipient))?;
+                for m in messages {
+                    write!(
+                        f,
+                        "{}{}{}{}{}{}{}{}",
+                        DELIMITER,
+                        escape(&m.sender),
+                        FIELD_SEP,
+                        escape(&m.id),
+                        FIELD_SEP,
+                        m.bytes,
+                        FIELD_SEP,
+                        m.cached_at
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/*
+ * Parse the username;ip:port;presence;capabilities of one USERS entry
+*/
+
+fn parse_user(entry: &str) -> Result<UserInfo, ParseError> {
+    let (username, rest) = split_field(entry, FIELD_SEP, "USERS", "address")?;
+    let (addr, rest) = split_field(rest, FIELD_SEP, "USERS", "presence")?;
+    let (presence, capabilities) = split_field(rest, FIELD_SEP, "USERS", "capabilities")?;
+    Ok(UserInfo {
+        username: unescape(username)?,
+        addr: unescape(addr)?,
+        presence: Presence::from_name(presence).ok_or(ParseError::InvalidField {
+            code: "USERS",
+            field: "presence",
+        })?,
+        capabilities: parse_capabilities(capabilities),
+    })
+}
+
+/*
+ * Parse the sender;id;bytes;cached_at of one MESSAGES entry
+*/
+
+fn parse_cached(entry: &str) -> Result<CachedInfo, ParseError> {
+    let (sender, rest) = split_field(entry, FIELD_SEP, "MESSAGES", "id")?;
+    let (id, rest) = split_field(rest, FIELD_SEP, "MESSAGES", "bytes")?;
+    let (bytes, cached_at) = split_field(rest, FIELD_SEP, "MESSAGES", "cached_at")?;
+    Ok(CachedInfo {
+        sender: unescape(sender)?,
+        id: require(id, "MESSAGES", "id")?,
+        bytes: parse_number(bytes, "MESSAGES", "bytes")?,
+        cached_at: parse_number(cached_at, "MESSAGES", "cached_at")?,
+    })
+}
+
+fn parse_number(field: &str, code: &'static str, name: &'static str) -> Result<u64, ParseError> {
+    field
+        .parse()
+        .map_err(|_| ParseError::InvalidField { code, field: name })
+}
@@ -0,0 +1,389 @@
+use crate::message::{escape, FIELD_SEP};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+/*
+ * Message bodies are encrypted for their recipient before they leave the
+ * sender, so the gateway and buddies only ever hold ciphertext. Every user
+ * has an X25519 key pair and registers the public half with the gateway.
+ * A sealed body is a fresh ephemeral public key followed by the
+ * ChaCha20-Poly1305 ciphertext, all hex encoded:
+ *
+ *   ephemeral_public ciphertext
+ *
+ * The cipher key and nonce come from HKDF-SHA256 over the shared secret of
+ * the ephemeral key and the recipient's key. The caller's context fields
+ * (recipient, sender and id for a message) are bound in as associated
+ * data, so a relay can't pass a body off as part of another message.
+ *
+ * Every user also has an Ed25519 signing key pair, the identity their
+ * username is bound to. Signatures cover a list of fields escaped and
+ * joined like they are on the wire, starting with what they are for
+*/
+
+pub const KEY_LEN: usize = 32;
+
+// Tells keys derived here apart from keys derived for anything else
+const SEAL_INFO: &[u8] = b"jaelegram seal v1";
+
+const NONCE_LEN: usize = 12;
+
+// Starts the fields a client signs to prove it holds the identity in its
+// INIT, so the signature can't be passed off as anything else
+const INIT_PROOF: &str = "jaelegram init v1";
+
+// Starts the fields a sender signs for every message
+const MESSAGE_PROOF: &str = "jaelegram signed message v1";
+
+// Starts the fields a client signs to prove to a buddy whose messages it
+// is pulling. Kept apart from INIT_PROOF so a buddy can't pass a gateway's
+// challenge off as its own and sign in with the answer
+const MAILBOX_PROOF: &str = "jaelegram mailbox v1";
+
+/*
+ * Reasons a key or a sealed body could not be used
+*/
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CryptoError {
+    InvalidKey,
+    InvalidEnvelope,
+    Decrypt,
+    BadSignature,
+    NoSession,
+    BadToken,
+    Random,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::InvalidKey => write!(f, "the key is not a valid public key"),
+            CryptoError::InvalidEnvelope => write!(f, "the body is not a sealed message"),
+            CryptoError::Decrypt => write!(f, "the body could not be decrypted"),
+            CryptoError::BadSignature => write!(f, "the signature does not verify"),
+            CryptoError::NoSession => write!(f, "there is no session to use"),
+            CryptoError::BadToken => write!(f, "the token is not one the gateway issued"),
+            CryptoError::Random => write!(f, "the system has no randomness to give"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+/*
+ * A user's X25519 key pair. Keys travel and are stored as hex
+*/
+
+#[derive(Clone)]
+pub struct KeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl KeyPair {
+    pub fn generate() -> Result<KeyPair, CryptoError> {
+        Ok(KeyPair::from_bytes(random_bytes()?))
+    }
+
+    pub fn from_bytes(bytes: [u8; KEY_LEN]) -> KeyPair {
+        let secret = StaticSecret::from(bytes);
+        let public = PublicKey::from(&secret);
+        KeyPair { secret, public }
+    }
+
+    pub fn from_secret_hex(hex: &str) -> Result<KeyPair, CryptoError> {
+        Ok(KeyPair::from_bytes(key_from_hex(hex)?))
+    }
+
+    pub fn secret_hex(&self) -> String {
+        to_hex(self.secret.as_bytes())
+    }
+
+    pub fn public_hex(&self) -> String {
+        to_hex(self.public.as_bytes())
+    }
+
+    /*
+     * The shared secret with someone else's public key
+     */
+
+    pub fn agree(&self, public: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+        self.secret
+            .diffie_hellman(&PublicKey::from(*public))
+            .to_bytes()
+    }
+}
+
+/*
+ * A user's Ed25519 identity key pair
+*/
+
+#[derive(Clone)]
+pub struct SigningPair {
+    key: SigningKey,
+}
+
+impl SigningPair {
+    pub fn generate() -> Result<SigningPair, CryptoError> {
+        Ok(SigningPair::from_bytes(random_bytes()?))
+    }
+
+    pub fn from_bytes(bytes: [u8; KEY_LEN]) -> SigningPair {
+        SigningPair {
+            key: SigningKey::from_bytes(&bytes),
+        }
+    }
+
+    pub fn from_secret_hex(hex: &str) -> Result<SigningPair, CryptoError> {
+        Ok(SigningPair::from_bytes(key_from_hex(hex)?))
+    }
+
+    pub fn secret_hex(&self) -> String {
+        to_hex(self.key.as_bytes())
+    }
+
+    pub fn public_hex(&self) -> String {
+        to_hex(self.key.verifying_key().as_bytes())
+    }
+
+    pub fn sign(&self, fields: &[&str]) -> String {
+        to_hex(&self.key.sign(associated_data(fields).as_bytes()).to_bytes())
+    }
+}
+
+/*
+ * Check a signature over fields against the hex encoded public key of the
+ * one who is supposed to have made it
+*/
+
+pub fn verify(public_key: &str, signature: &str, fields: &[&str]) -> Result<(), CryptoError> {
+    let key = VerifyingKey::from_bytes(&key_from_hex(public_key)?)
+        .map_err(|_| CryptoError::InvalidKey)?;
+    let signature: [u8; 64] = from_hex(signature)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(CryptoError::BadSignature)?;
+
+    key.verify_strict(
+        associated_data(fields).as_bytes(),
+        &Signature::from_bytes(&signature),
+    )
+    .map_err(|_| CryptoError::BadSignature)
+}
+
+/*
+ * The fields a client signs to answer the gateway's challenge. They tie
+ * the nonce to everything the INIT registers, so a proof for one INIT
+ * can't vouch for another
+*/
+
+pub fn init_proof<'a>(
+    nonce: &'a str,
+    username: &'a str,
+    addr: &'a str,
+    key: &'a str,
+    identity: &'a str,
+) -> [&'a str; 6] {
+    [INIT_PROOF, nonce, username, addr, key, identity]
+}
+
+/*
+ * The fields a client signs to answer a buddy's challenge before it is
+ * handed the messages held for username
+*/
+
+pub fn mailbox_proof<'a>(nonce: &'a str, username: &'a str) -> [&'a str; 3] {
+    [MAILBOX_PROOF, nonce, username]
+}
+
+/*
+ * The fields a sender signs for a message. The body is signed as it goes
+ * out, encrypted, so caches can check the signature without reading it
+*/
+
+pub fn message_proof<'a>(
+    recipient: &'a str,
+    sender: &'a str,
+    id: &'a str,
+    body: &'a str,
+) -> [&'a str; 5] {
+    [MESSAGE_PROOF, recipient, sender, id, body]
+}
+
+/*
+ * Encrypt text so only the holder of the secret half of recipient_key can
+ * read it
+*/
+
+pub fn seal(recipient_key: &str, plaintext: &str, context: &[&str]) -> Result<String, CryptoError> {
+    let recipient = key_from_hex(recipient_key)?;
+    let ephemeral = KeyPair::generate()?;
+
+    let ciphertext = encrypt(&ephemeral.agree(&recipient), SEAL_INFO, plaintext, context);
+    Ok(format!("{}{}", ephemeral.public_hex(), to_hex(&ciphertext)))
+}
+
+/*
+ * Undo seal with the recipient's key pair. Fails if the body was sealed
+ * for someone else, changed on the way or given a different context
+*/
+
+pub fn open(keys: &KeyPair, sealed: &str, context: &[&str]) -> Result<String, CryptoError> {
+    let bytes = from_hex(sealed).ok_or(CryptoError::InvalidEnvelope)?;
+    if bytes.len() < KEY_LEN {
+        return Err(CryptoError::InvalidEnvelope);
+    }
+    let (ephemeral, ciphertext) = bytes.split_at(KEY_LEN);
+    let ephemeral: [u8; KEY_LEN] = ephemeral.try_into().unwrap();
+
+    decrypt(&keys.agree(&ephemeral), SEAL_INFO, ciphertext, context)
+}
+
+/*
+ * Encrypt with a cipher key and nonce derived from secret. Every secret
+ * passed in is used for one message only, so the nonce never repeats
+*/
+
+pub(crate) fn encrypt(
+    secret: &[u8; KEY_LEN],
+    info: &[u8],
+    plaintext: &str,
+    context: &[&str],
+) -> Vec<u8> {
+    let (cipher, nonce) = derive_cipher(secret, info);
+    cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext.as_bytes(),
+                aad: associated_data(context).as_bytes(),
+            },
+        )
+        .expect("a message fits in one ChaCha20-Poly1305 payload")
+}
+
+pub(crate) fn decrypt(
+    secret: &[u8; KEY_LEN],
+    info: &[u8],
+    ciphertext: &[u8],
+    context: &[&str],
+) -> Result<String, CryptoError> {
+    let (cipher, nonce) = derive_cipher(secret, info);
+    let plaintext = cipher
+        .decrypt(
+            &nonce,
+            Payload {
+                msg: ciphertext,
+                aad: associated_data(context).as_bytes(),
+            },
+        )
+        .map_err(|_| CryptoError::Decrypt)?;
+
+    String::from_utf8(plaintext).map_err(|_| CryptoError::Decrypt)
+}
+
+fn derive_cipher(secret: &[u8; KEY_LEN], info: &[u8]) -> (ChaCha20Poly1305, Nonce) {
+    let mut okm = [0u8; KEY_LEN + NONCE_LEN];
+    Hkdf::<Sha256>::new(None, secret)
+        .expand(info, &mut okm)
+        .expect("okm is a valid length for HKDF-SHA256");
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&okm[..KEY_LEN]));
+    (cipher, *Nonce::from_slice(&okm[KEY_LEN..]))
+}
+
+pub(crate) fn associated_data(context: &[&str]) -> String {
+    let fields: Vec<String> = context.iter().map(|field| escape(field)).collect();
+    fields.join(FIELD_SEP)
+}
+
+/*
+ * Bytes from the operating system's random source
+*/
+
+pub fn random_bytes<const N: usize>() -> Result<[u8; N], CryptoError> {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes).map_err(|_| CryptoError::Random)?;
+    Ok(bytes)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/*
+ * Read a secret from its file, making one up with generate the first
+ * time. A new file is only created if there is none yet, only its owner
+ * may read it, and the secret is on disk before it is handed out
+*/
+
+pub fn load_secret(
+    path: &Path,
+    generate: impl FnOnce() -> Result<String, CryptoError>,
+) -> io::Result<String> {
+    match read_secret(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        result => return result,
+    }
+
+    let secret = generate().map_err(io::Error::other)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(path)?;
+    file.write_all(secret.as_bytes())?;
+    file.sync_all()?;
+    Ok(secret)
+}
+
+/*
+ * Read a secret load_secret wrote, an empty file has none
+*/
+
+pub fn read_secret(path: &Path) -> io::Result<String> {
+    let secret = fs::read_to_string(path)?.trim().to_string();
+    if secret.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is empty", path.display()),
+        ));
+    }
+    Ok(secret)
+}
+
+/*
+ * Parse a hex encoded key, checking its length
+*/
+
+pub fn key_from_hex(hex: &str) -> Result<[u8; KEY_LEN], CryptoError> {
+    from_hex(hex)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(CryptoError::InvalidKey)
+}
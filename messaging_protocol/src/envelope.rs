@@ -0,0 +1,86 @@
+use crate::crypto::{open, seal, CryptoError, KeyPair};
+use crate::message::{format_stored, parse_stored, StoredMessage};
+
+/*
+ * Sealed-sender envelopes, so the gateway and buddies holding a message
+ * only learn who it is for. The sender's name, the encrypted body and the
+ * sender's signature are sealed together for the recipient's key, and
+ * the message travels with an empty sender:
+ *
+ *   recipient;;id;s1.sealed;token
+ *
+ * where the sealed part is laid out like the sender;id;body;signature
+ * fields of a SEND. The recipient and id stay outside since relays need
+ * them to deliver and ack the message, and both are bound to the seal so
+ * an envelope can't be passed off as another message. The token is a
+ * delivery token (see tokens) the sender spends on the envelope, it is
+ * added once the envelope is sealed
+*/
+
+const ENVELOPE_TAG: &str = "s1.";
+
+// Starts the context an envelope is sealed with, so it can't be opened
+// as a plain sealed body
+const ENVELOPE_CONTEXT: &str = "jaelegram sealed sender v1";
+
+/*
+ * Whether a message is an envelope that still has to be opened
+*/
+
+pub fn is_sealed(message: &StoredMessage) -> bool {
+    message.sender.is_empty() && message.body.starts_with(ENVELOPE_TAG)
+}
+
+/*
+ * Put a message in an envelope only the holder of recipient_key can open
+*/
+
+pub fn seal_sender(
+    recipient: &str,
+    recipient_key: &str,
+    message: &StoredMessage,
+) -> Result<StoredMessage, CryptoError> {
+    let inner = format_stored(
+        &message.sender,
+        &message.id,
+        &message.body,
+        message.signature.as_deref(),
+    );
+    let sealed = seal(
+        recipient_key,
+        &inner,
+        &[ENVELOPE_CONTEXT, recipient, &message.id],
+    )?;
+
+    Ok(StoredMessage {
+        sender: String::new(),
+        id: message.id.clone(),
+        body: format!("{}{}", ENVELOPE_TAG, sealed),
+        signature: None,
+    })
+}
+
+/*
+ * Take the message back out of an envelope sent to recipient. Fails if it
+ * was sealed for someone else, changed on the way, or the message inside
+ * has no sender or claims another id
+*/
+
+pub fn open_sender(
+    recipient: &str,
+    keys: &KeyPair,
+    envelope: &StoredMessage,
+) -> Result<StoredMessage, CryptoError> {
+    let sealed = envelope
+        .body
+        .strip_prefix(ENVELOPE_TAG)
+        .filter(|_| envelope.sender.is_empty())
+        .ok_or(CryptoError::InvalidEnvelope)?;
+    let inner = open(keys, sealed, &[ENVELOPE_CONTEXT, recipient, &envelope.id])?;
+
+    let message = parse_stored(&inner, "SEALED").map_err(|_| CryptoError::InvalidEnvelope)?;
+    if message.sender.is_empty() || message.id != envelope.id {
+        return Err(CryptoError::InvalidEnvelope);
+    }
+    Ok(message)
+}
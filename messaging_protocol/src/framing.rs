@@ -0,0 +1,192 @@
+use std::io::{self, Read, Write};
+
+// Every frame starts with the length of its payload as a big endian u32
+pub const HEADER_LEN: usize = 4;
+
+// Largest payload we are willing to buffer for a single frame, unless the
+// stream is given its own limit
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+// How many bytes we try to pull off the socket per read call
+const READ_CHUNK: usize = 4096;
+
+/*
+ * Prefix a payload with its length so the other side knows exactly
+ * where the message stops
+*/
+
+pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/*
+ * A stream wrapped with its own read and write buffers. Bytes read off
+ * the stream are held until a whole frame has arrived, and bytes that
+ * could not be written yet (non-blocking sockets) wait in the write
+ * buffer until the socket is writable again.
+*/
+
+pub struct FramedStream<S> {
+    stream: S,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    max_frame_len: usize,
+}
+
+impl<S> FramedStream<S> {
+    pub fn new(stream: S) -> FramedStream<S> {
+        FramedStream {
+            stream,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            max_frame_len: MAX_FRAME_LEN,
+        }
+    }
+
+    /*
+     * Change the largest frame this stream will accept
+     */
+
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize) {
+        self.max_frame_len = max_frame_len;
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /*
+     * Pull the next complete frame out of the read buffer, if there is one.
+     * Anything after it stays buffered for the next call.
+     */
+
+    pub fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.read_buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+
+        let mut header = [0; HEADER_LEN];
+        header.copy_from_slice(&self.read_buf[..HEADER_LEN]);
+        let len = u32::from_be_bytes(header) as usize;
+
+        // Refuse to buffer frames that could exhaust our memory
+        if len > self.max_frame_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame of {} bytes exceeds the limit", len),
+            ));
+        }
+
+        if self.read_buf.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let frame = self.read_buf[HEADER_LEN..HEADER_LEN + len].to_vec();
+        self.read_buf.drain(..HEADER_LEN + len);
+        Ok(Some(frame))
+    }
+
+    /*
+     * Add a frame to the write buffer without touching the stream
+     */
+
+    pub fn queue_frame(&mut self, payload: &[u8]) {
+        self.write_buf.extend_from_slice(&encode_frame(payload));
+    }
+
+    pub fn has_pending_writes(&self) -> bool {
+        !self.write_buf.is_empty()
+    }
+}
+
+impl<S: Read> FramedStream<S> {
+    /*
+     * Do a single read into the read buffer. Returns the number of bytes
+     * read, so 0 means the other side closed the connection. A
+     * non-blocking stream with nothing to read returns WouldBlock.
+     */
+
+    pub fn fill(&mut self) -> io::Result<usize> {
+        let mut chunk = [0; READ_CHUNK];
+
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(i) => {
+                    self.read_buf.extend_from_slice(&chunk[..i]);
+                    return Ok(i);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /*
+     * Block until a whole frame has arrived and return its payload
+     */
+
+    pub fn read_frame(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(frame) = self.next_frame()? {
+                return Ok(frame);
+            }
+
+            if self.fill()? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before a whole frame arrived",
+                ));
+            }
+        }
+    }
+}
+
+impl<S: Write> FramedStream<S> {
+    /*
+     * Write as much of the write buffer as the stream will take. Returns
+     * true once everything is written, false if the stream would block.
+     */
+
+    pub fn flush_pending(&mut self) -> io::Result<bool> {
+        while !self.write_buf.is_empty() {
+            match self.stream.write(&self.write_buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "stream stopped accepting bytes",
+                    ));
+                }
+                Ok(i) => {
+                    self.write_buf.drain(..i);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.stream.flush()?;
+        Ok(true)
+    }
+
+    /*
+     * Frame a payload and write it out. Meant for blocking streams, on a
+     * non-blocking stream the remainder stays queued.
+     */
+
+    pub fn write_frame(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.queue_frame(payload);
+        self.flush_pending().map(|_| ())
+    }
+}
@@ -0,0 +1,101 @@
+/*
+ * The hash every node uses to place users in buddy groups. It has to give
+ * the same answer on every build and every machine, so instead of the
+ * standard library hashers (which may change between Rust releases) this
+ * is 64 bit FNV-1a over the UTF-8 bytes of the username:
+ *
+ *   hash = 0xcbf29ce484222325
+ *   for each byte: hash = (hash ^ byte) * 0x100000001b3 (wrapping)
+*/
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/*
+ * Hash a username. Only the name goes in, so a user keeps the same value
+ * no matter how or from where they connect
+*/
+
+pub fn calculate_hash(username: &str) -> u64 {
+    username.bytes().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/*
+ * Score a member for a user with rendezvous (highest random weight)
+ * hashing. The name and the member are hashed together, split by a byte
+ * that can't appear in UTF-8, and the result is run through the MurmurHash3
+ * finalizer since FNV alone mixes the last few bytes poorly
+*/
+
+pub fn rendezvous_score(username: &str, member: &str) -> u64 {
+    score(calculate_hash(username), member)
+}
+
+fn score(user_hash: u64, member: &str) -> u64 {
+    let mut hash = user_hash;
+    for byte in [0xff].into_iter().chain(member.bytes()) {
+        hash = (hash ^ byte as u64).wrapping_mul(FNV_PRIME);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+/*
+ * Pick a user's buddy group: the members with the highest scores for that
+ * user. A member joining or leaving only changes the groups it ranks in,
+ * everyone else keeps the buddies (and the cached messages) they had
+*/
+
+pub fn select_group(username: &str, members: &[String], size: usize) -> Vec<String> {
+    // The best members so far, highest score first. Ties are broken by
+    // address so every node agrees, and a member listed twice is kept once
+    let mut best: Vec<(u64, &String)> = Vec::with_capacity(size + 1);
+
+    let user_hash = calculate_hash(username);
+    for member in members {
+        let entry = (score(user_hash, member), member);
+        if best.contains(&entry) {
+            continue;
+        }
+
+        let rank = best
+            .iter()
+            .position(|other| entry > *other)
+            .unwrap_or(best.len());
+        if rank < size {
+            best.insert(rank, entry);
+            best.truncate(size);
+        }
+    }
+
+    best.into_iter().map(|(_, member)| member.clone()).collect()
+}
+
+/*
+ * Whether member, one of members, makes it into username's group without
+ * picking the whole group: it does when fewer than size other members
+ * outscore it. Most members are outscored early, so this rarely walks the
+ * whole ring
+*/
+
+pub fn in_group(username: &str, member: &str, members: &[String], size: usize) -> bool {
+    let user_hash = calculate_hash(username);
+    let entry = (score(user_hash, member), member);
+
+    let mut ahead: Vec<&str> = Vec::with_capacity(size);
+    for other in members {
+        if ahead.len() == size {
+            return false;
+        }
+        if (score(user_hash, other), other.as_str()) > entry && !ahead.contains(&other.as_str()) {
+            ahead.push(other);
+        }
+    }
+    ahead.len() < size
+}
@@ -0,0 +1,133 @@
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*
+ * State kept on disk as a snapshot and a journal of the changes made
+ * since, one change per line. Changes are appended to the journal, and
+ * once it has grown long enough the whole state is written to a new
+ * snapshot and the journal starts over. What the lines say is up to the
+ * owner, this only keeps them safe across a crash
+*/
+
+pub struct Journal {
+    snapshot: PathBuf,
+    path: PathBuf,
+    file: File,
+    entries: u32,
+}
+
+impl Journal {
+    /*
+     * Open the journal at path that goes with the snapshot, creating the
+     * directories and the journal if needed
+     */
+
+    pub fn open(snapshot: impl Into<PathBuf>, path: impl Into<PathBuf>) -> io::Result<Journal> {
+        let (snapshot, path) = (snapshot.into(), path.into());
+        for dir in [&snapshot, &path].into_iter().filter_map(|p| p.parent()) {
+            fs::create_dir_all(dir)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Journal {
+            snapshot,
+            path,
+            file,
+            entries: 0,
+        })
+    }
+
+    /*
+     * The lines of the snapshot, then the lines of the journal. Replaying
+     * both in order rebuilds the state as it was last written
+     */
+
+    pub fn replay(&self) -> io::Result<(Vec<String>, Vec<String>)> {
+        Ok((whole_lines(&self.snapshot)?, whole_lines(&self.path)?))
+    }
+
+    /*
+     * Append whole lines, each ending in a newline, as one change. With
+     * sync set this waits for them to reach the disk
+     */
+
+    pub fn append(&mut self, lines: &str, sync: bool) -> io::Result<()> {
+        self.file.write_all(lines.as_bytes())?;
+        if sync {
+            self.file.sync_data()?;
+        }
+        self.entries += 1;
+        Ok(())
+    }
+
+    /*
+     * How many changes were appended since the last snapshot
+     */
+
+    pub fn entries(&self) -> u32 {
+        self.entries
+    }
+
+    /*
+     * Write the whole state to a new file and move it over the old
+     * snapshot, then empty the journal. A crash before the rename keeps
+     * the old snapshot and the full journal, a crash after it replays the
+     * journal on top of the new snapshot, so changes have to be safe to
+     * apply twice. This also drops a line a crash cut off
+     */
+
+    pub fn snapshot(&mut self, lines: impl IntoIterator<Item = String>) -> io::Result<()> {
+        let mut tmp = OsString::from(self.snapshot.as_os_str());
+        tmp.push(".tmp");
+
+        let mut file = File::create(&tmp)?;
+        for line in lines {
+            file.write_all(format!("{}\n", line).as_bytes())?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &self.snapshot)?;
+
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.entries = 0;
+        Ok(())
+    }
+}
+
+/*
+ * Every whole line of a file. A line cut off by a crash has no newline
+ * and is left out, a missing file has no lines
+*/
+
+fn whole_lines(path: &Path) -> io::Result<Vec<String>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let whole = match bytes.iter().rposition(|b| *b == b'\n') {
+        Some(end) => &bytes[..end],
+        None => return Ok(Vec::new()),
+    };
+
+    Ok(whole
+        .split(|b| *b == b'\n')
+        .filter_map(|line| std::str::from_utf8(line).ok())
+        .map(str::to_string)
+        .collect())
+}
+
+/*
+ * Seconds since the epoch, what journaled entries are stamped with
+*/
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,14 @@
+/*
+ * Code shared by the gateway server and the clients. Everything that
+ * travels over a TCP connection between two nodes goes through here.
+*/
+
+pub mod admin;
+pub mod crypto;
+pub mod envelope;
+pub mod framing;
+pub mod hash;
+pub mod journal;
+pub mod message;
+pub mod ratchet;
+pub mod tokens;
@@ -0,0 +1,1083 @@
+use crate::crypto::{random_bytes, CryptoError};
+use crate::ratchet::PrekeyBundle;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*
+ * Every message is a status code followed by a space and a body. Fields
+ * inside the body are split by FIELD_SEP, lists are split by DELIMITER.
+ * Each field is escaped (see escape) so it can hold any text, including
+ * the separators themselves:
+ *
+ *   INIT username&&ip:port&&version&&capability,capability[&&key[&&identity]]
+ *   VERSION version&&capability,capability&&name=value,name=value[&&token_key]
+ *   REFUSED reason
+ *   CHALLENGE nonce
+ *   PROVE username;signature
+ *   SEND recipient;sender;id;body[;signature]
+ *   ACK username;id
+ *   REJECTED username;id;reason
+ *   CACHE recipient;sender;id;body[;signature]
+ *   TOKENS blinded[&&blinded...]
+ *   ISSUED issued[&&issued...]
+ *   REDEEM token;digest
+ *   REDEEMED token
+ *   BUDDIES username[&&ip:port...]
+ *   IP_FETCH username
+ *   KEY_FETCH username
+ *   KEY username;key[;identity]
+ *   PREKEYS username;signed_prekey;signature[&&one_time_prekey...]
+ *   BUNDLE_FETCH username
+ *   BUNDLE username;identity;key;signed_prekey;signature[;one_time_prekey]
+ *   FETCH username
+ *   PULL username;cursor;limit
+ *   LEAVE username
+ *   IP_RETRIEVAL ip:port
+ *   UPDATE [sender;id;body[;signature]&&sender;id;body[;signature]...]
+ *   404 reason
+ *   UPDATE_FINGERS [ip:port&&ip:port...]
+ *   NEW_FINGER ip:port
+ *   UPDATE_GROUP [ip:port&&ip:port...]
+ *
+ * A SEND, CACHE or UPDATE message with an empty sender is a sealed-sender
+ * envelope (see envelope), the sender is inside the body and the
+ * signature is a delivery token (see tokens)
+*/
+
+pub const DELIMITER: &str = "&&";
+pub const FIELD_SEP: &str = ";";
+
+// The protocol version this build speaks, and the oldest one it still
+// serves. An INIT without a version comes from a version 1 client
+pub const PROTOCOL_VERSION: u32 = 3;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+pub const LEGACY_VERSION: u32 = 1;
+
+// The first version with encrypted, signed and sealed messages, identity
+// keys and prekeys. Older clients send and expect bodies in the clear
+pub const ENCRYPTED_VERSION: u32 = 3;
+
+// Splits the capabilities in INIT and VERSION, and the network settings
+// in VERSION
+const CAPABILITY_SEP: char = ',';
+
+// Starts an escape sequence of two hex digits
+const ESCAPE: char = '%';
+
+// Characters that never appear raw inside an escaped field
+const RESERVED: [char; 5] = [ESCAPE, ';', '&', '\n', '\r'];
+
+/*
+ * A message someone is holding on to for a recipient. The id is picked by
+ * the sender and stays the same on every hop, so caches and acks can tell
+ * two messages with the same text apart. The signature is the sender's,
+ * made with their identity key over the recipient, sender, id and body
+*/
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct StoredMessage {
+    pub sender: String,
+    pub id: String,
+    pub body: String,
+    pub signature: Option<String>,
+}
+
+impl StoredMessage {
+    /*
+     * How many bytes the message takes up in a cache
+     */
+
+    pub fn size(&self) -> usize {
+        let signature = self.signature.as_ref().map_or(0, String::len);
+        self.sender.len() + self.id.len() + self.body.len() + signature
+    }
+}
+
+/*
+ * Features a node can offer, sent in INIT and VERSION so each side knows
+ * what the other will do
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    // The gateway holds and forwards messages (client_v_1, client_v_2)
+    ServerRelay,
+    // Messages go straight to the recipient when it is online
+    DirectSend,
+    // Buddies cache messages for offline recipients (client_v_3)
+    BuddyCache,
+    // Bodies are encrypted end to end, the gateway hands out the keys and
+    // bundles to do it with (ENCRYPTED_VERSION and up)
+    Encrypted,
+}
+
+impl Capability {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::ServerRelay => "server_relay",
+            Capability::DirectSend => "direct_send",
+            Capability::BuddyCache => "buddy_cache",
+            Capability::Encrypted => "encrypted",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Capability> {
+        match name {
+            "server_relay" => Some(Capability::ServerRelay),
+            "direct_send" => Some(Capability::DirectSend),
+            "buddy_cache" => Some(Capability::BuddyCache),
+            "encrypted" => Some(Capability::Encrypted),
+            _ => None,
+        }
+    }
+
+    /*
+     * Version 1 clients never said what they support, they could do it all
+     */
+
+    pub fn legacy() -> Vec<Capability> {
+        vec![
+            Capability::ServerRelay,
+            Capability::DirectSend,
+            Capability::BuddyCache,
+        ]
+    }
+}
+
+/*
+ * Settings every node in a network has to agree on. The gateway reads them
+ * from its config and announces them in VERSION, a gateway that doesn't
+ * announce them runs the defaults
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NetworkParams {
+    // How many buddies make up a user's group
+    pub group_size: u32,
+    // How many of those buddies each message is cached on
+    pub replication: u32,
+    // How many seconds a buddy holds on to a cached message
+    pub cache_ttl: u32,
+    // How many messages and bytes one cache holds for a single recipient
+    pub max_cached_messages: u32,
+    pub max_cached_bytes: u32,
+}
+
+impl Default for NetworkParams {
+    fn default() -> NetworkParams {
+        NetworkParams {
+            group_size: 2,
+            replication: 2,
+            cache_ttl: 7 * 24 * 60 * 60,
+            max_cached_messages: 1000,
+            max_cached_bytes: 1024 * 1024,
+        }
+    }
+}
+
+impl NetworkParams {
+    /*
+     * Read name=value pairs, settings we don't know are skipped and
+     * settings that are left out keep their default
+     */
+
+    fn parse(field: &str) -> Result<NetworkParams, ParseError> {
+        let mut params = NetworkParams::default();
+
+        for pair in field.split(CAPABILITY_SEP).filter(|p| !p.is_empty()) {
+            let (name, value) = pair.split_once('=').ok_or(ParseError::InvalidField {
+                code: "VERSION",
+                field: "network settings",
+            })?;
+            let value: u32 = value.parse().map_err(|_| ParseError::InvalidField {
+                code: "VERSION",
+                field: "network settings",
+            })?;
+
+            match name {
+                "group_size" => params.group_size = value,
+                "replication" => params.replication = value,
+                "cache_ttl" => params.cache_ttl = value,
+                "max_cached_messages" => params.max_cached_messages = value,
+                "max_cached_bytes" => params.max_cached_bytes = value,
+                _ => (),
+            }
+        }
+
+        Ok(params)
+    }
+
+    /*
+     * Check whether a cache already holding the given number of messages
+     * and bytes for a recipient has room for one more. The error is the
+     * reason to send back to whoever asked us to keep it
+     */
+
+    pub fn check_quota(
+        &self,
+        recipient: &str,
+        held: usize,
+        held_bytes: usize,
+        incoming: &StoredMessage,
+    ) -> Result<(), String> {
+        if held >= self.max_cached_messages as usize {
+            return Err(format!(
+                "the cache for {} is full ({} messages)",
+                recipient, self.max_cached_messages
+            ));
+        }
+        if held_bytes + incoming.size() > self.max_cached_bytes as usize {
+            return Err(format!(
+                "the cache for {} is full ({} bytes)",
+                recipient, self.max_cached_bytes
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for NetworkParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "group_size={},replication={},cache_ttl={},max_cached_messages={},max_cached_bytes={}",
+            self.group_size,
+            self.replication,
+            self.cache_ttl,
+            self.max_cached_messages,
+            self.max_cached_bytes
+        )
+    }
+}
+
+/*
+ * Pick the version to talk to a node that speaks the given version, None
+ * if it is too old for us to serve
+*/
+
+pub fn negotiate_version(theirs: u32) -> Option<u32> {
+    if theirs < MIN_PROTOCOL_VERSION {
+        None
+    } else {
+        Some(theirs.min(PROTOCOL_VERSION))
+    }
+}
+
+/*
+ * Every message that can travel between two nodes
+*/
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    // The key is the public half of the user's encryption key pair and
+    // the identity the public half of their signing key pair, both hex
+    // encoded. Version 1 clients and clients that don't encrypt or sign
+    // leave them out
+    Init {
+        username: String,
+        addr: String,
+        version: u32,
+        capabilities: Vec<Capability>,
+        key: Option<String>,
+        identity: Option<String>,
+    },
+    // The token key is the public half of the key the gateway issues
+    // delivery tokens with. Gateways from before sealed messages had to
+    // carry one leave it out
+    Version {
+        version: u32,
+        capabilities: Vec<Capability>,
+        params: NetworkParams,
+        token_key: Option<String>,
+    },
+    Refused {
+        reason: String,
+    },
+    // The gateway asks an INIT with an identity to sign the nonce, the
+    // client answers with PROVE before it is registered
+    Challenge {
+        nonce: String,
+    },
+    Prove {
+        username: String,
+        signature: String,
+    },
+    Send {
+        recipient: String,
+        sender: String,
+        id: String,
+        body: String,
+        signature: Option<String>,
+    },
+    Ack {
+        username: String,
+        id: String,
+    },
+    // A cache turned a message away, the sender still holds it
+    Rejected {
+        username: String,
+        id: String,
+        reason: String,
+    },
+    Cache {
+        recipient: String,
+        sender: String,
+        id: String,
+        body: String,
+        signature: Option<String>,
+    },
+    // A signed-in client asks for delivery tokens ahead of time with
+    // blinded nonces, answered with ISSUED and one signature for each
+    Tokens {
+        blinded: Vec<String>,
+    },
+    Issued {
+        tokens: Vec<String>,
+    },
+    // Spend a delivery token on the message with the digest, answered
+    // with REDEEMED if it wasn't spent on another one, REFUSED otherwise
+    Redeem {
+        token: String,
+        digest: String,
+    },
+    Redeemed {
+        token: String,
+    },
+    Buddies {
+        username: String,
+        buddies: Vec<String>,
+    },
+    IpFetch {
+        username: String,
+    },
+    // Ask the gateway for a user's encryption key, answered with KEY
+    KeyFetch {
+        username: String,
+    },
+    Key {
+        username: String,
+        key: String,
+        identity: Option<String>,
+    },
+    // A client publishes its prekeys for sessions, replacing the ones it
+    // published before. BUNDLE_FETCH is answered with BUNDLE, handing out
+    // one of the one-time prekeys
+    Prekeys {
+        username: String,
+        signed_prekey: String,
+        signature: String,
+        one_time: Vec<String>,
+    },
+    BundleFetch {
+        username: String,
+    },
+    Bundle {
+        username: String,
+        bundle: PrekeyBundle,
+    },
+    Fetch {
+        username: String,
+    },
+    Leave {
+        username: String,
+    },
+    // Ask a buddy for up to limit cached messages with ids after cursor,
+    // an empty cursor starts from the oldest one
+    Pull {
+        username: String,
+        cursor: String,
+        limit: u32,
+    },
+    IpRetrieval {
+        addr: String,
+    },
+    Update {
+        messages: Vec<StoredMessage>,
+    },
+    NotFound {
+        reason: String,
+    },
+    UpdateFingers {
+        fingers: Vec<String>,
+    },
+    NewFinger {
+        addr: String,
+    },
+    UpdateGroup {
+        members: Vec<String>,
+    },
+}
+
+/*
+ * Reasons a packet could not be turned into a message
+*/
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    InvalidUtf8,
+    InvalidEscape,
+    UnknownCode(String),
+    MissingField {
+        code: &'static str,
+        field: &'static str,
+    },
+    InvalidField {
+        code: &'static str,
+        field: &'static str,
+    },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::InvalidUtf8 => write!(f, "message is not valid utf-8"),
+            ParseError::InvalidEscape => write!(f, "message has a broken escape sequence"),
+            ParseError::UnknownCode(code) => write!(f, "unknown status code {}", code),
+            ParseError::MissingField { code, field } => {
+                write!(f, "{} message is missing its {}", code, field)
+            }
+            ParseError::InvalidField { code, field } => {
+                write!(f, "{} message has an invalid {}", code, field)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl Message {
+    /*
+     * The status code that starts this message on the wire
+     */
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Message::Init { .. } => "INIT",
+            Message::Version { .. } => "VERSION",
+            Message::Refused { .. } => "REFUSED",
+            Message::Send { .. } => "SEND",
+            Message::Ack { .. } => "ACK",
+            Message::Rejected { .. } => "REJECTED",
+            Message::Cache { .. } => "CACHE",
+            Message::Tokens { .. } => "TOKENS",
+            Message::Issued { .. } => "ISSUED",
+            Message::Redeem { .. } => "REDEEM",
+            Message::Redeemed { .. } => "REDEEMED",
+            Message::Buddies { .. } => "BUDDIES",
+            Message::IpFetch { .. } => "IP_FETCH",
+            Message::Challenge { .. } => "CHALLENGE",
+            Message::Prove { .. } => "PROVE",
+            Message::KeyFetch { .. } => "KEY_FETCH",
+            Message::Key { .. } => "KEY",
+            Message::Prekeys { .. } => "PREKEYS",
+            Message::BundleFetch { .. } => "BUNDLE_FETCH",
+            Message::Bundle { .. } => "BUNDLE",
+            Message::Fetch { .. } => "FETCH",
+            Message::Leave { .. } => "LEAVE",
+            Message::Pull { .. } => "PULL",
+            Message::IpRetrieval { .. } => "IP_RETRIEVAL",
+            Message::Update { .. } => "UPDATE",
+            Message::NotFound { .. } => "404",
+            Message::UpdateFingers { .. } => "UPDATE_FINGERS",
+            Message::NewFinger { .. } => "NEW_FINGER",
+            Message::UpdateGroup { .. } => "UPDATE_GROUP",
+        }
+    }
+
+    /*
+     * Turn the message into the bytes that go inside a frame
+     */
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+
+    /*
+     * Parse the bytes of a frame back into a message
+     */
+
+    pub fn decode(bytes: &[u8]) -> Result<Message, ParseError> {
+        let text = std::str::from_utf8(bytes).map_err(|_| ParseError::InvalidUtf8)?;
+        Message::parse(text)
+    }
+
+    pub fn parse(text: &str) -> Result<Message, ParseError> {
+        // A message without a body may leave off the space
+        let (code, body) = text.split_once(' ').unwrap_or((text, ""));
+
+        let message = match code {
+            "INIT" => {
+                let (username, rest) = split_field(body, DELIMITER, "INIT", "address")?;
+                let mut fields = rest.splitn(5, DELIMITER);
+                let addr = fields.next().unwrap_or("");
+
+                // Version 1 clients end the message after the address
+                let (version, capabilities) = match fields.next() {
+                    Some(version) => (
+                        parse_version(version, "INIT")?,
+                        parse_capabilities(fields.next().unwrap_or("")),
+                    ),
+                    None => (LEGACY_VERSION, Capability::legacy()),
+                };
+                let key = optional_field(fields.next())?;
+                let identity = optional_field(fields.next())?;
+
+                // An empty name is taken by sealed messages, nobody registers it
+                Message::Init {
+                    username: require(username, "INIT", "username")?,
+                    addr: unescape(addr)?,
+                    version,
+                    capabilities,
+                    key,
+                    identity,
+                }
+            }
+            "VERSION" => {
+                let mut fields = body.splitn(4, DELIMITER);
+                Message::Version {
+                    version: parse_version(fields.next().unwrap_or(""), "VERSION")?,
+                    capabilities: parse_capabilities(fields.next().unwrap_or("")),
+                    params: NetworkParams::parse(fields.next().unwrap_or(""))?,
+                    token_key: optional_field(fields.next())?,
+                }
+            }
+            "REFUSED" => Message::Refused {
+                reason: unescape(body)?,
+            },
+            "SEND" => {
+                let (recipient, rest) = split_field(body, FIELD_SEP, "SEND", "sender")?;
+                let stored = parse_stored(rest, "SEND")?;
+                Message::Send {
+                    recipient: unescape(recipient)?,
+                    sender: stored.sender,
+                    id: stored.id,
+                    body: stored.body,
+                    signature: stored.signature,
+                }
+            }
+            "ACK" => {
+                let (username, id) = split_field(body, FIELD_SEP, "ACK", "id")?;
+                Message::Ack {
+                    username: unescape(username)?,
+                    id: require(id, "ACK", "id")?,
+                }
+            }
+            "REJECTED" => {
+                let (username, rest) = split_field(body, FIELD_SEP, "REJECTED", "id")?;
+                let (id, reason) = split_field(rest, FIELD_SEP, "REJECTED", "reason")?;
+                Message::Rejected {
+                    username: unescape(username)?,
+                    id: require(id, "REJECTED", "id")?,
+                    reason: unescape(reason)?,
+                }
+            }
+            "PULL" => {
+                let (username, rest) = split_field(body, FIELD_SEP, "PULL", "cursor")?;
+                let (cursor, limit) = split_field(rest, FIELD_SEP, "PULL", "limit")?;
+                Message::Pull {
+                    username: require(username, "PULL", "username")?,
+                    cursor: unescape(cursor)?,
+                    limit: limit.parse().map_err(|_| ParseError::InvalidField {
+                        code: "PULL",
+                        field: "limit",
+                    })?,
+                }
+            }
+            "CACHE" => {
+                let (recipient, rest) = split_field(body, FIELD_SEP, "CACHE", "sender")?;
+                let stored = parse_stored(rest, "CACHE")?;
+                Message::Cache {
+                    recipient: unescape(recipient)?,
+                    sender: stored.sender,
+                    id: stored.id,
+                    body: stored.body,
+                    signature: stored.signature,
+                }
+            }
+            "TOKENS" => Message::Tokens {
+                blinded: split_list(body)?,
+            },
+            "ISSUED" => Message::Issued {
+                tokens: split_list(body)?,
+            },
+            "REDEEM" => {
+                let (token, digest) = split_field(body, FIELD_SEP, "REDEEM", "digest")?;
+                Message::Redeem {
+                    token: require(token, "REDEEM", "token")?,
+                    digest: require(digest, "REDEEM", "digest")?,
+                }
+            }
+            "REDEEMED" => Message::Redeemed {
+                token: require(body, "REDEEMED", "token")?,
+            },
+            "BUDDIES" => {
+                let (username, buddies) = body.split_once(DELIMITER).unwrap_or((body, ""));
+                Message::Buddies {
+                    username: unescape(username)?,
+                    buddies: split_list(buddies)?,
+                }
+            }
+            "IP_FETCH" => Message::IpFetch {
+                username: require(body, "IP_FETCH", "username")?,
+            },
+            "CHALLENGE" => Message::Challenge {
+                nonce: require(body, "CHALLENGE", "nonce")?,
+            },
+            "PROVE" => {
+                let (username, signature) = split_field(body, FIELD_SEP, "PROVE", "signature")?;
+                Message::Prove {
+                    username: require(username, "PROVE", "username")?,
+                    signature: require(signature, "PROVE", "signature")?,
+                }
+            }
+            "KEY_FETCH" => Message::KeyFetch {
+                username: require(body, "KEY_FETCH", "username")?,
+            },
+            "KEY" => {
+                let (username, rest) = split_field(body, FIELD_SEP, "KEY", "key")?;
+                let mut fields = rest.splitn(2, FIELD_SEP);
+                Message::Key {
+                    username: require(username, "KEY", "username")?,
+                    key: require(fields.next().unwrap_or(""), "KEY", "key")?,
+                    identity: optional_field(fields.next())?,
+                }
+            }
+            "PREKEYS" => {
+                let (fields, one_time) = body.split_once(DELIMITER).unwrap_or((body, ""));
+                let (username, rest) = split_field(fields, FIELD_SEP, "PREKEYS", "signed prekey")?;
+                let (signed_prekey, signature) =
+                    split_field(rest, FIELD_SEP, "PREKEYS", "signature")?;
+                Message::Prekeys {
+                    username: require(username, "PREKEYS", "username")?,
+                    signed_prekey: require(signed_prekey, "PREKEYS", "signed prekey")?,
+                    signature: require(signature, "PREKEYS", "signature")?,
+                    one_time: split_list(one_time)?,
+                }
+            }
+            "BUNDLE_FETCH" => Message::BundleFetch {
+                username: require(body, "BUNDLE_FETCH", "username")?,
+            },
+            "BUNDLE" => {
+                let mut fields = body.splitn(6, FIELD_SEP);
+                let mut next = |field| require(fields.next().unwrap_or(""), "BUNDLE", field);
+                let username = next("username")?;
+                let bundle = PrekeyBundle {
+                    identity: next("identity")?,
+                    key: next("key")?,
+                    signed_prekey: next("signed prekey")?,
+                    signature: next("signature")?,
+                    one_time: optional_field(fields.next())?,
+                };
+                Message::Bundle { username, bundle }
+            }
+            "FETCH" => Message::Fetch {
+                username: require(body, "FETCH", "username")?,
+            },
+            "LEAVE" => Message::Leave {
+                username: require(body, "LEAVE", "username")?,
+            },
+            "IP_RETRIEVAL" => Message::IpRetrieval {
+                addr: require(body, "IP_RETRIEVAL", "address")?,
+            },
+            "UPDATE" => {
+                let mut messages = Vec::new();
+                for entry in body.split(DELIMITER).filter(|e| !e.is_empty()) {
+                    messages.push(parse_stored(entry, "UPDATE")?);
+                }
+                Message::Update { messages }
+            }
+            "404" => Message::NotFound {
+                reason: unescape(body)?,
+            },
+            "UPDATE_FINGERS" => Message::UpdateFingers {
+                fingers: split_list(body)?,
+            },
+            "NEW_FINGER" => Message::NewFinger {
+                addr: require(body, "NEW_FINGER", "address")?,
+            },
+            "UPDATE_GROUP" => Message::UpdateGroup {
+                members: split_list(body)?,
+            },
+            _ => return Err(ParseError::UnknownCode(code.to_string())),
+        };
+
+        Ok(message)
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.code())?;
+
+        match self {
+            Message::Init {
+                username,
+                addr,
+                version,
+                capabilities,
+                key,
+                identity,
+            } => {
+                write!(
+                    f,
+                    " {}{}{}{}{}{}{}",
+                    escape(username),
+                    DELIMITER,
+                    escape(addr),
+                    DELIMITER,
+                    version,
+                    DELIMITER,
+                    format_capabilities(capabilities)
+                )?;
+                // A missing key is left empty when an identity follows it
+                if key.is_some() || identity.is_some() {
+                    let key = key.as_deref().unwrap_or_default();
+                    write!(f, "{}{}", DELIMITER, escape(key))?;
+                }
+                match identity {
+                    Some(identity) => write!(f, "{}{}", DELIMITER, escape(identity)),
+                    None => Ok(()),
+                }
+            }
+            Message::Version {
+                version,
+                capabilities,
+                params,
+                token_key,
+            } => {
+                write!(
+                    f,
+                    " {}{}{}{}{}",
+                    version,
+                    DELIMITER,
+                    format_capabilities(capabilities),
+                    DELIMITER,
+                    params
+                )?;
+                match token_key {
+                    Some(key) => write!(f, "{}{}", DELIMITER, escape(key)),
+                    None => Ok(()),
+                }
+            }
+            Message::Refused { reason } => write!(f, " {}", escape(reason)),
+            Message::Send {
+                recipient,
+                sender,
+                id,
+                body,
+                signature,
+            }
+            | Message::Cache {
+                recipient,
+                sender,
+                id,
+                body,
+                signature,
+            } => write!(
+                f,
+                " {}{}{}",
+                escape(recipient),
+                FIELD_SEP,
+                format_stored(sender, id, body, signature.as_deref())
+            ),
+            Message::Ack { username, id } => {
+                write!(f, " {}{}{}", escape(username), FIELD_SEP, escape(id))
+            }
+            Message::Key {
+                username,
+                key,
+                identity,
+            } => {
+                write!(f, " {}{}{}", escape(username), FIELD_SEP, escape(key))?;
+                match identity {
+                    Some(identity) => write!(f, "{}{}", FIELD_SEP, escape(identity)),
+                    None => Ok(()),
+                }
+            }
+            Message::Prove {
+                username,
+                signature: key,
+            } => {
+                write!(f, " {}{}{}", escape(username), FIELD_SEP, escape(key))
+            }
+            Message::Prekeys {
+                username,
+                signed_prekey,
+                signature,
+                one_time,
+            } => {
+                write!(
+                    f,
+                    " {}{}{}{}{}",
+                    escape(username),
+                    FIELD_SEP,
+                    escape(signed_prekey),
+                    FIELD_SEP,
+                    escape(signature)
+                )?;
+                for key in one_time {
+                    write!(f, "{}{}", DELIMITER, escape(key))?;
+                }
+                Ok(())
+            }
+            Message::Bundle { username, bundle } => {
+                let fields = [
+                    username,
+                    &bundle.identity,
+                    &bundle.key,
+                    &bundle.signed_prekey,
+                    &bundle.signature,
+                ];
+                let fields: Vec<String> = fields.iter().map(|field| escape(field)).collect();
+                write!(f, " {}", fields.join(FIELD_SEP))?;
+                match &bundle.one_time {
+                    Some(key) => write!(f, "{}{}", FIELD_SEP, escape(key)),
+                    None => Ok(()),
+                }
+            }
+            Message::Redeem { token, digest } => {
+                write!(f, " {}{}{}", escape(token), FIELD_SEP, escape(digest))
+            }
+            Message::Redeemed { token } => write!(f, " {}", escape(token)),
+            Message::Rejected {
+                username,
+                id,
+                reason,
+            } => write!(
+                f,
+                " {}{}{}{}{}",
+                escape(username),
+                FIELD_SEP,
+                escape(id),
+                FIELD_SEP,
+                escape(reason)
+            ),
+            Message::Pull {
+                username,
+                cursor,
+                limit,
+            } => write!(
+                f,
+                " {}{}{}{}{}",
+                escape(username),
+                FIELD_SEP,
+                escape(cursor),
+                FIELD_SEP,
+                limit
+            ),
+            Message::Buddies { username, buddies } => {
+                write!(f, " {}", escape(username))?;
+                for buddy in buddies {
+                    write!(f, "{}{}", DELIMITER, escape(buddy))?;
+                }
+                Ok(())
+            }
+            Message::IpFetch { username }
+            | Message::KeyFetch { username }
+            | Message::BundleFetch { username }
+            | Message::Fetch { username }
+            | Message::Leave { username } => {
+                write!(f, " {}", escape(username))
+            }
+            Message::IpRetrieval { addr } | Message::NewFinger { addr } => {
+                write!(f, " {}", escape(addr))
+            }
+            Message::Update { messages } => {
+                let entries: Vec<String> = messages
+                    .iter()
+                    .map(|m| format_stored(&m.sender, &m.id, &m.body, m.signature.as_deref()))
+                    .collect();
+                write!(f, " {}", entries.join(DELIMITER))
+            }
+            Message::NotFound { reason } => write!(f, " {}", escape(reason)),
+            Message::Challenge { nonce } => write!(f, " {}", escape(nonce)),
+            Message::UpdateFingers { fingers: list }
+            | Message::UpdateGroup { members: list }
+            | Message::Tokens { blinded: list }
+            | Message::Issued { tokens: list } => {
+                let entries: Vec<String> = list.iter().map(|e| escape(e)).collect();
+                write!(f, " {}", entries.join(DELIMITER))
+            }
+        }
+    }
+}
+
+/*
+ * Split a body in two at the first separator, naming the missing field if
+ * the separator isn't there
+*/
+
+pub(crate) fn split_field<'a>(
+    body: &'a str,
+    sep: &str,
+    code: &'static str,
+    field: &'static str,
+) -> Result<(&'a str, &'a str), ParseError> {
+    body.split_once(sep)
+        .ok_or(ParseError::MissingField { code, field })
+}
+
+/*
+ * Parse the sender;id;body[;signature] fields shared by SEND, CACHE and
+ * UPDATE. Messages from before signatures end after the body
+*/
+
+pub(crate) fn parse_stored(fields: &str, code: &'static str) -> Result<StoredMessage, ParseError> {
+    let (sender, rest) = split_field(fields, FIELD_SEP, code, "id")?;
+    let (id, rest) = split_field(rest, FIELD_SEP, code, "body")?;
+    let (body, signature) = match rest.split_once(FIELD_SEP) {
+        Some((body, signature)) => (body, Some(signature)),
+        None => (rest, None),
+    };
+    Ok(StoredMessage {
+        sender: unescape(sender)?,
+        id: require(id, code, "id")?,
+        body: unescape(body)?,
+        signature: optional_field(signature)?,
+    })
+}
+
+pub(crate) fn format_stored(sender: &str, id: &str, body: &str, signature: Option<&str>) -> String {
+    let mut fields = format!(
+        "{}{}{}{}{}",
+        escape(sender),
+        FIELD_SEP,
+        escape(id),
+        FIELD_SEP,
+        escape(body)
+    );
+    if let Some(signature) = signature {
+        fields.push_str(FIELD_SEP);
+        fields.push_str(&escape(signature));
+    }
+    fields
+}
+
+/*
+ * Parse a protocol version number
+*/
+
+fn parse_version(field: &str, code: &'static str) -> Result<u32, ParseError> {
+    field.parse().map_err(|_| ParseError::InvalidField {
+        code,
+        field: "version",
+    })
+}
+
+/*
+ * Parse a capability list, skipping names from newer versions we don't know
+*/
+
+pub(crate) fn parse_capabilities(field: &str) -> Vec<Capability> {
+    field
+        .split(CAPABILITY_SEP)
+        .filter_map(Capability::from_name)
+        .collect()
+}
+
+pub(crate) fn format_capabilities(capabilities: &[Capability]) -> String {
+    let names: Vec<&str> = capabilities.iter().map(|c| c.name()).collect();
+    names.join(&CAPABILITY_SEP.to_string())
+}
+
+/*
+ * Parse a field that may be left out or left empty
+*/
+
+fn optional_field(field: Option<&str>) -> Result<Option<String>, ParseError> {
+    match field {
+        Some(field) if !field.is_empty() => Ok(Some(unescape(field)?)),
+        _ => Ok(None),
+    }
+}
+
+/*
+ * Make sure a single field body is not empty
+*/
+
+pub(crate) fn require(
+    body: &str,
+    code: &'static str,
+    field: &'static str,
+) -> Result<String, ParseError> {
+    if body.is_empty() {
+        Err(ParseError::MissingField { code, field })
+    } else {
+        unescape(body)
+    }
+}
+
+/*
+ * Split a DELIMITER separated list, dropping empty entries
+*/
+
+pub(crate) fn split_list(body: &str) -> Result<Vec<String>, ParseError> {
+    body.split(DELIMITER)
+        .filter(|t| !t.is_empty())
+        .map(unescape)
+        .collect()
+}
+
+/*
+ * Replace every reserved character with ESCAPE and its two digit hex
+ * code, so a field never contains a separator
+*/
+
+pub fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        if RESERVED.contains(&c) {
+            escaped.push_str(&format!("{}{:02X}", ESCAPE, c as u32));
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/*
+ * Undo escape. Fails if an ESCAPE isn't followed by two hex digits
+*/
+
+pub fn unescape(field: &str) -> Result<String, ParseError> {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+
+    while let Some(c) = chars.next() {
+        if c != ESCAPE {
+            unescaped.push(c);
+            continue;
+        }
+
+        let hex: String = chars.by_ref().take(2).collect();
+        let code = match hex.len() {
+            2 => u8::from_str_radix(&hex, 16).map_err(|_| ParseError::InvalidEscape)?,
+            _ => return Err(ParseError::InvalidEscape),
+        };
+
+        // Only reserved characters are ever escaped
+        match RESERVED.iter().find(|r| **r as u32 == code as u32) {
+            Some(reserved) => unescaped.push(*reserved),
+            None => return Err(ParseError::InvalidEscape),
+        }
+    }
+
+    Ok(unescaped)
+}
+
+/*
+ * Create an id for a new message. The time comes first so ids sort in
+ * roughly the order they were sent, the random half comes from the
+ * system's random number generator and keeps two messages sent in the
+ * same instant apart, whichever process sent them. Fails if the system
+ * has no randomness to give
+*/
+
+pub fn new_message_id() -> Result<String, CryptoError> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let random = random_bytes::<8>()?;
+    Ok(format!("{:016x}{:016x}", nanos, u64::from_be_bytes(random)))
+}
@@ -0,0 +1,581 @@
+use crate::crypto::{
+    decrypt, encrypt, from_hex, key_from_hex, to_hex, verify, CryptoError, KeyPair, KEY_LEN,
+};
+use crate::message::FIELD_SEP;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::fmt;
+
+/*
+ * Pairwise sessions between contacts, so a key stolen later can't read
+ * what was sent before. A session is started X3DH-style: every user
+ * publishes a signed prekey and a batch of one-time prekeys through the
+ * gateway, and whoever writes first combines them with their own keys and
+ * a fresh ephemeral key into the shared secret. From there both sides run
+ * a double ratchet, every message is encrypted with a key of its own that
+ * is thrown away once used, and every reply brings fresh Diffie-Hellman
+ * keys into the chain.
+ *
+ * A body encrypted with a session is a dot-separated list of hex and
+ * numbers, so it needs no escaping:
+ *
+ *   r1.ratchet_key.previous_count.number.ciphertext
+ *
+ * Until the other side has answered, the initiator adds what they need to
+ * start their half of the session:
+ *
+ *   ...ciphertext.key.ephemeral_key.signed_prekey.one_time_prekey
+ *
+ * The one-time prekey is left empty if the gateway had none left
+*/
+
+// How far ahead of the last message one chain may skip, and how many keys
+// of skipped messages a session keeps for when they turn up
+pub const MAX_SKIP: u32 = 1000;
+const MAX_SKIPPED_KEYS: usize = 2000;
+
+// Tell the keys derived at each step apart
+const X3DH_INFO: &[u8] = b"jaelegram x3dh v1";
+const ROOT_INFO: &[u8] = b"jaelegram ratchet v1";
+const CHAIN_INFO: &[u8] = b"jaelegram chain v1";
+const MESSAGE_INFO: &[u8] = b"jaelegram message v1";
+
+// Starts the fields a signed prekey signature covers
+const PREKEY_PROOF: &str = "jaelegram prekey v1";
+
+const BODY_TAG: &str = "r1";
+const PART_SEP: char = '.';
+const LIST_SEP: char = ',';
+
+type Secret = [u8; KEY_LEN];
+
+/*
+ * The fields a user signs with their identity key to vouch for their
+ * signed prekey
+*/
+
+pub fn prekey_proof(signed_prekey: &str) -> [&str; 2] {
+    [PREKEY_PROOF, signed_prekey]
+}
+
+/*
+ * What the gateway hands out so someone can start a session with a user
+*/
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrekeyBundle {
+    pub identity: String,
+    pub key: String,
+    pub signed_prekey: String,
+    pub signature: String,
+    pub one_time: Option<String>,
+}
+
+/*
+ * The initiator's half of the X3DH exchange, sent with every message until
+ * the other side answers
+*/
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrekeyHeader {
+    pub key: String,
+    pub ephemeral: String,
+    pub signed_prekey: String,
+    pub one_time: Option<String>,
+}
+
+impl PrekeyHeader {
+    fn parts(&self) -> [&str; 4] {
+        [
+            &self.key,
+            &self.ephemeral,
+            &self.signed_prekey,
+            self.one_time.as_deref().unwrap_or_default(),
+        ]
+    }
+
+    fn from_parts(parts: &[&str]) -> Option<PrekeyHeader> {
+        match parts {
+            [key, ephemeral, signed_prekey, one_time] => Some(PrekeyHeader {
+                key: key.to_string(),
+                ephemeral: ephemeral.to_string(),
+                signed_prekey: signed_prekey.to_string(),
+                one_time: Some(one_time.to_string()).filter(|key| !key.is_empty()),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/*
+ * A body taken apart
+*/
+
+struct Envelope {
+    header: String,
+    ratchet: Secret,
+    previous: u32,
+    number: u32,
+    ciphertext: Vec<u8>,
+    prekey: Option<PrekeyHeader>,
+}
+
+fn parse_envelope(body: &str) -> Option<Envelope> {
+    let parts: Vec<&str> = body.split(PART_SEP).collect();
+    let (head, prekey) = match parts.len() {
+        5 => (&parts[..], None),
+        9 => (&parts[..5], Some(PrekeyHeader::from_parts(&parts[5..])?)),
+        _ => return None,
+    };
+    if head[0] != BODY_TAG {
+        return None;
+    }
+
+    Some(Envelope {
+        header: head[..4].join(&PART_SEP.to_string()),
+        ratchet: key_from_hex(head[1]).ok()?,
+        previous: head[2].parse().ok()?,
+        number: head[3].parse().ok()?,
+        ciphertext: from_hex(head[4])?,
+        prekey,
+    })
+}
+
+pub fn is_session_body(body: &str) -> bool {
+    parse_envelope(body).is_some()
+}
+
+/*
+ * The prekeys a body starts a session with, if it is one of the first
+ * messages of a session
+*/
+
+pub fn prekey_header(body: &str) -> Option<PrekeyHeader> {
+    parse_envelope(body)?.prekey
+}
+
+/*
+ * A message key kept for a message that hasn't turned up yet
+*/
+
+#[derive(Clone)]
+struct Skipped {
+    ratchet: Secret,
+    number: u32,
+    key: Secret,
+}
+
+/*
+ * One side of a session. The remote key is the other side's long-term
+ * encryption key and the base key the ephemeral key the session was
+ * started with, which tells sessions with the same contact apart
+*/
+
+#[derive(Clone)]
+pub struct Session {
+    remote_key: String,
+    base_key: String,
+    associated: String,
+    root: Secret,
+    ours: KeyPair,
+    theirs: Option<Secret>,
+    send_chain: Option<Secret>,
+    receive_chain: Option<Secret>,
+    sent: u32,
+    received: u32,
+    previous: u32,
+    skipped: Vec<Skipped>,
+    prekey: Option<PrekeyHeader>,
+}
+
+impl Session {
+    /*
+     * Start a session with whoever published the bundle, after checking
+     * their identity vouches for the signed prekey
+     */
+
+    pub fn initiate(me: &KeyPair, bundle: &PrekeyBundle) -> Result<Session, CryptoError> {
+        verify(
+            &bundle.identity,
+            &bundle.signature,
+            &prekey_proof(&bundle.signed_prekey),
+        )?;
+        let their_key = key_from_hex(&bundle.key)?;
+        let signed_prekey = key_from_hex(&bundle.signed_prekey)?;
+        let one_time = bundle.one_time.as_deref().map(key_from_hex).transpose()?;
+
+        let ephemeral = KeyPair::generate()?;
+        let mut secrets = vec![
+            me.agree(&signed_prekey),
+            ephemeral.agree(&their_key),
+            ephemeral.agree(&signed_prekey),
+        ];
+        if let Some(one_time) = one_time {
+            secrets.push(ephemeral.agree(&one_time));
+        }
+
+        let ours = KeyPair::generate()?;
+        let (root, send_chain) = kdf_root(&x3dh(&secrets), &ours.agree(&signed_prekey));
+
+        Ok(Session {
+            remote_key: bundle.key.clone(),
+            base_key: ephemeral.public_hex(),
+            associated: format!("{}{}", me.public_hex(), bundle.key),
+            root,
+            ours,
+            theirs: Some(signed_prekey),
+            send_chain: Some(send_chain),
+            receive_chain: None,
+            sent: 0,
+            received: 0,
+            previous: 0,
+            skipped: Vec::new(),
+            prekey: Some(PrekeyHeader {
+                key: me.public_hex(),
+                ephemeral: ephemeral.public_hex(),
+                signed_prekey: bundle.signed_prekey.clone(),
+                one_time: bundle.one_time.clone(),
+            }),
+        })
+    }
+
+    /*
+     * Take up a session someone started with our prekeys. It can only
+     * send once it has decrypted their first message
+     */
+
+    pub fn respond(
+        me: &KeyPair,
+        signed_prekey: &KeyPair,
+        one_time: Option<&KeyPair>,
+        header: &PrekeyHeader,
+    ) -> Result<Session, CryptoError> {
+        let their_key = key_from_hex(&header.key)?;
+        let ephemeral = key_from_hex(&header.ephemeral)?;
+
+        let mut secrets = vec![
+            signed_prekey.agree(&their_key),
+            me.agree(&ephemeral),
+            signed_prekey.agree(&ephemeral),
+        ];
+        if let Some(one_time) = one_time {
+            secrets.push(one_time.agree(&ephemeral));
+        }
+
+        Ok(Session {
+            remote_key: header.key.clone(),
+            base_key: header.ephemeral.clone(),
+            associated: format!("{}{}", header.key, me.public_hex()),
+            root: x3dh(&secrets),
+            ours: signed_prekey.clone(),
+            theirs: None,
+            send_chain: None,
+            receive_chain: None,
+            sent: 0,
+            received: 0,
+            previous: 0,
+            skipped: Vec::new(),
+            prekey: None,
+        })
+    }
+
+    pub fn remote_key(&self) -> &str {
+        &self.remote_key
+    }
+
+    pub fn base_key(&self) -> &str {
+        &self.base_key
+    }
+
+    /*
+     * Encrypt the next message with a key of its own. The context fields
+     * are bound in like they are for seal
+     */
+
+    pub fn encrypt(&mut self, plaintext: &str, context: &[&str]) -> Result<String, CryptoError> {
+        let chain = self.send_chain.ok_or(CryptoError::NoSession)?;
+        let (next, key) = kdf_chain(&chain);
+
+        let header = format!(
+            "{}{sep}{}{sep}{}{sep}{}",
+            BODY_TAG,
+            self.ours.public_hex(),
+            self.previous,
+            self.sent,
+            sep = PART_SEP
+        );
+        let ciphertext = encrypt(
+            &key,
+            MESSAGE_INFO,
+            plaintext,
+            &self.associated_data(&header, context),
+        );
+        self.send_chain = Some(next);
+        self.sent += 1;
+
+        let mut body = format!("{}{}{}", header, PART_SEP, to_hex(&ciphertext));
+        if let Some(prekey) = &self.prekey {
+            for part in prekey.parts() {
+                body.push(PART_SEP);
+                body.push_str(part);
+            }
+        }
+        Ok(body)
+    }
+
+    /*
+     * Decrypt a message of this session. The session only changes if it
+     * works, so a forged or replayed body can't throw it off
+     */
+
+    pub fn decrypt(&mut self, body: &str, context: &[&str]) -> Result<String, CryptoError> {
+        let envelope = parse_envelope(body).ok_or(CryptoError::InvalidEnvelope)?;
+
+        let mut next = self.clone();
+        let plaintext = next.receive(&envelope, context)?;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    fn receive(&mut self, envelope: &Envelope, context: &[&str]) -> Result<String, CryptoError> {
+        let key = match self.take_skipped(&envelope.ratchet, envelope.number) {
+            Some(key) => key,
+            None => {
+                // A new ratchet key from them means they got our last
+                // messages, finish the old chain and step forward
+                if self.theirs != Some(envelope.ratchet) {
+                    self.skip_to(envelope.previous)?;
+                    self.step(&envelope.ratchet)?;
+                }
+                self.skip_to(envelope.number)?;
+
+                let chain = self.receive_chain.ok_or(CryptoError::Decrypt)?;
+                let (next, key) = kdf_chain(&chain);
+                self.receive_chain = Some(next);
+                self.received += 1;
+                key
+            }
+        };
+
+        let plaintext = decrypt(
+            &key,
+            MESSAGE_INFO,
+            &envelope.ciphertext,
+            &self.associated_data(&envelope.header, context),
+        )?;
+
+        // They have their half of the session now
+        self.prekey = None;
+        Ok(plaintext)
+    }
+
+    fn take_skipped(&mut self, ratchet: &Secret, number: u32) -> Option<Secret> {
+        let index = self
+            .skipped
+            .iter()
+            .position(|s| s.ratchet == *ratchet && s.number == number)?;
+        Some(self.skipped.remove(index).key)
+    }
+
+    /*
+     * Keep the keys of the messages before until on the current receiving
+     * chain, they may still turn up
+     */
+
+    fn skip_to(&mut self, until: u32) -> Result<(), CryptoError> {
+        let (mut chain, ratchet) = match (self.receive_chain, self.theirs) {
+            (Some(chain), Some(ratchet)) => (chain, ratchet),
+            _ => return Ok(()),
+        };
+        if until > self.received.saturating_add(MAX_SKIP) {
+            return Err(CryptoError::InvalidEnvelope);
+        }
+
+        while self.received < until {
+            let (next, key) = kdf_chain(&chain);
+            self.skipped.push(Skipped {
+                ratchet,
+                number: self.received,
+                key,
+            });
+            chain = next;
+            self.received += 1;
+        }
+        self.receive_chain = Some(chain);
+
+        let excess = self.skipped.len().saturating_sub(MAX_SKIPPED_KEYS);
+        self.skipped.drain(..excess);
+        Ok(())
+    }
+
+    /*
+     * The Diffie-Hellman ratchet step: new chains from their new key, and
+     * a new key of ours for the replies
+     */
+
+    fn step(&mut self, ratchet: &Secret) -> Result<(), CryptoError> {
+        self.previous = self.sent;
+        self.sent = 0;
+        self.received = 0;
+        self.theirs = Some(*ratchet);
+
+        let (root, receive_chain) = kdf_root(&self.root, &self.ours.agree(ratchet));
+        self.ours = KeyPair::generate()?;
+        let (root, send_chain) = kdf_root(&root, &self.ours.agree(ratchet));
+
+        self.root = root;
+        self.receive_chain = Some(receive_chain);
+        self.send_chain = Some(send_chain);
+        Ok(())
+    }
+
+    fn associated_data<'a>(&'a self, header: &'a str, context: &[&'a str]) -> Vec<&'a str> {
+        let mut fields = vec![self.associated.as_str(), header];
+        fields.extend_from_slice(context);
+        fields
+    }
+
+    /*
+     * Read a session back from the line it was saved as
+     */
+
+    pub fn parse(line: &str) -> Option<Session> {
+        let fields: Vec<&str> = line.split(FIELD_SEP).collect();
+        let [remote_key, base_key, associated, root, ours, theirs, send_chain, receive_chain, sent, received, previous, prekey, skipped] =
+            fields.as_slice()
+        else {
+            return None;
+        };
+
+        let prekey = match *prekey {
+            "" => None,
+            prekey => {
+                let parts: Vec<&str> = prekey.split(PART_SEP).collect();
+                Some(PrekeyHeader::from_parts(&parts)?)
+            }
+        };
+        let mut skipped_keys = Vec::new();
+        for entry in skipped.split(LIST_SEP).filter(|e| !e.is_empty()) {
+            let parts: Vec<&str> = entry.split(PART_SEP).collect();
+            let [ratchet, number, key] = parts.as_slice() else {
+                return None;
+            };
+            skipped_keys.push(Skipped {
+                ratchet: key_from_hex(ratchet).ok()?,
+                number: number.parse().ok()?,
+                key: key_from_hex(key).ok()?,
+            });
+        }
+
+        Some(Session {
+            remote_key: remote_key.to_string(),
+            base_key: base_key.to_string(),
+            associated: associated.to_string(),
+            root: key_from_hex(root).ok()?,
+            ours: KeyPair::from_secret_hex(ours).ok()?,
+            theirs: optional_key(theirs)?,
+            send_chain: optional_key(send_chain)?,
+            receive_chain: optional_key(receive_chain)?,
+            sent: sent.parse().ok()?,
+            received: received.parse().ok()?,
+            previous: previous.parse().ok()?,
+            skipped: skipped_keys,
+            prekey,
+        })
+    }
+}
+
+/*
+ * A session is saved as one line of ;-separated fields, secrets included,
+ * so whatever it is written to must be kept private
+*/
+
+impl fmt::Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = |key: &Option<Secret>| key.map(|key| to_hex(&key)).unwrap_or_default();
+        let prekey = self
+            .prekey
+            .as_ref()
+            .map(|prekey| prekey.parts().join(&PART_SEP.to_string()))
+            .unwrap_or_default();
+        let skipped: Vec<String> = self
+            .skipped
+            .iter()
+            .map(|s| {
+                format!(
+                    "{}{sep}{}{sep}{}",
+                    to_hex(&s.ratchet),
+                    s.number,
+                    to_hex(&s.key),
+                    sep = PART_SEP
+                )
+            })
+            .collect();
+
+        let fields = [
+            self.remote_key.clone(),
+            self.base_key.clone(),
+            self.associated.clone(),
+            to_hex(&self.root),
+            self.ours.secret_hex(),
+            hex(&self.theirs),
+            hex(&self.send_chain),
+            hex(&self.receive_chain),
+            self.sent.to_string(),
+            self.received.to_string(),
+            self.previous.to_string(),
+            prekey,
+            skipped.join(&LIST_SEP.to_string()),
+        ];
+        write!(f, "{}", fields.join(FIELD_SEP))
+    }
+}
+
+fn optional_key(field: &str) -> Option<Option<Secret>> {
+    match field {
+        "" => Some(None),
+        field => key_from_hex(field).ok().map(Some),
+    }
+}
+
+/*
+ * The shared secret of an X3DH exchange from its Diffie-Hellman outputs
+*/
+
+fn x3dh(secrets: &[Secret]) -> Secret {
+    let mut input = vec![0xff; KEY_LEN];
+    for secret in secrets {
+        input.extend_from_slice(secret);
+    }
+
+    let mut okm = [0u8; KEY_LEN];
+    Hkdf::<Sha256>::new(Some(&[0u8; KEY_LEN]), &input)
+        .expand(X3DH_INFO, &mut okm)
+        .expect("okm is a valid length for HKDF-SHA256");
+    okm
+}
+
+/*
+ * The next root key and a new chain key from a ratchet step
+*/
+
+fn kdf_root(root: &Secret, shared: &Secret) -> (Secret, Secret) {
+    split_okm(Hkdf::<Sha256>::new(Some(root), shared), ROOT_INFO)
+}
+
+/*
+ * The next chain key and the key for one message
+*/
+
+fn kdf_chain(chain: &Secret) -> (Secret, Secret) {
+    split_okm(Hkdf::<Sha256>::new(None, chain), CHAIN_INFO)
+}
+
+fn split_okm(hkdf: Hkdf<Sha256>, info: &[u8]) -> (Secret, Secret) {
+    let mut okm = [0u8; 2 * KEY_LEN];
+    hkdf.expand(info, &mut okm)
+        .expect("okm is a valid length for HKDF-SHA256");
+
+    let (first, second) = okm.split_at(KEY_LEN);
+    (first.try_into().unwrap(), second.try_into().unwrap())
+}
@@ -0,0 +1,266 @@
+use crate::crypto::{associated_data, from_hex, key_from_hex, random_bytes, to_hex, CryptoError};
+use crate::journal::now;
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use sha2::{Digest, Sha256, Sha512};
+
+/*
+ * Delivery tokens, which let the gateway and buddies take a sealed message
+ * from a registered user without learning which one. A signed-in client
+ * asks for tokens ahead of time, before it knows what it will send. Each
+ * is a random nonce the gateway signs blind: the client sends r·H(nonce),
+ * the gateway multiplies it by its secret key k and proves it used the
+ * key it announced in VERSION, and the client takes r back out. What the
+ * client ends up with,
+ *
+ *   day.nonce.k·H(day, nonce)
+ *
+ * can be checked by the gateway, but it never saw the nonce or the
+ * result, so it can't tell whose request it came out of. The day the
+ * client made it on is part of it, so spent tokens only have to be
+ * remembered for a few days.
+ *
+ * A token is spent on one message, which the gateway remembers it by
+ * (see message_digest). It can be handed to as many buddies as the
+ * message is, but never with another message
+*/
+
+// Starts what nonces are hashed with, so a token can't be made from
+// anything else hashed to the curve
+const TOKEN_NONCE: &str = "jaelegram token v1";
+
+// Starts what the gateway's proof is hashed with
+const TOKEN_PROOF: &str = "jaelegram token proof v1";
+
+// Starts the fields a message digest covers
+const MESSAGE_DIGEST: &str = "jaelegram delivery v2";
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/*
+ * The gateway's token key. Its public half goes out in VERSION
+*/
+
+#[derive(Clone)]
+pub struct TokenKey {
+    secret: Scalar,
+    public: RistrettoPoint,
+}
+
+impl TokenKey {
+    pub fn generate() -> Result<TokenKey, CryptoError> {
+        Ok(TokenKey::from_scalar(random_scalar()?))
+    }
+
+    pub fn from_secret_hex(hex: &str) -> Result<TokenKey, CryptoError> {
+        let secret = Option::from(Scalar::from_canonical_bytes(key_from_hex(hex)?))
+            .ok_or(CryptoError::InvalidKey)?;
+        Ok(TokenKey::from_scalar(secret))
+    }
+
+    fn from_scalar(secret: Scalar) -> TokenKey {
+        TokenKey {
+            secret,
+            public: secret * RISTRETTO_BASEPOINT_POINT,
+        }
+    }
+
+    pub fn secret_hex(&self) -> String {
+        to_hex(self.secret.as_bytes())
+    }
+
+    pub fn public_hex(&self) -> String {
+        to_hex(self.public.compress().as_bytes())
+    }
+
+    /*
+     * Sign a blinded nonce, with a proof that it was signed with this key
+     * and not one kept for the client asking
+     */
+
+    pub fn issue(&self, blinded: &str) -> Result<String, CryptoError> {
+        let blinded = point_from_hex(blinded)?;
+        let signed = self.secret * blinded;
+
+        // A Chaum-Pedersen proof that signed and public share a logarithm
+        let r = random_scalar()?;
+        let c = challenge(
+            &self.public,
+            &blinded,
+            &signed,
+            &(r * RISTRETTO_BASEPOINT_POINT),
+            &(r * blinded),
+        );
+        let s = r - c * self.secret;
+
+        let mut issued = signed.compress().as_bytes().to_vec();
+        issued.extend_from_slice(c.as_bytes());
+        issued.extend_from_slice(s.as_bytes());
+        Ok(to_hex(&issued))
+    }
+
+    /*
+     * Check that a token was made from one we issued, returning the day
+     * it was made on
+     */
+
+    pub fn check(&self, token: &str) -> Result<u64, CryptoError> {
+        let (day, nonce, signed) = parse_token(token).ok_or(CryptoError::BadToken)?;
+        let signed = point_from_hex(signed).map_err(|_| CryptoError::BadToken)?;
+        if self.secret * hash_nonce(day, nonce) != signed {
+            return Err(CryptoError::BadToken);
+        }
+        Ok(day)
+    }
+}
+
+/*
+ * A nonce a client is having signed, and the factor it was blinded with
+*/
+
+pub struct Blind {
+    day: u64,
+    nonce: String,
+    factor: Scalar,
+    blinded: RistrettoPoint,
+}
+
+impl Blind {
+    /*
+     * Make up a nonce for a token made on day and blind it
+     */
+
+    pub fn new(day: u64) -> Result<Blind, CryptoError> {
+        let nonce = to_hex(&random_bytes::<32>()?);
+        let factor = random_scalar()?;
+        let blinded = factor * hash_nonce(day, &nonce);
+        Ok(Blind {
+            day,
+            nonce,
+            factor,
+            blinded,
+        })
+    }
+
+    /*
+     * What the gateway is asked to sign
+     */
+
+    pub fn blinded_hex(&self) -> String {
+        to_hex(self.blinded.compress().as_bytes())
+    }
+
+    /*
+     * Check the gateway's answer against the key it announced and take
+     * the blinding back out, leaving the token
+     */
+
+    pub fn finish(&self, key: &str, issued: &str) -> Result<String, CryptoError> {
+        let key = point_from_hex(key)?;
+        let bytes = from_hex(issued)
+            .filter(|bytes| bytes.len() == 96)
+            .ok_or(CryptoError::BadToken)?;
+        let signed = point_from_bytes(&bytes[..32]).map_err(|_| CryptoError::BadToken)?;
+        let c = scalar_from_bytes(&bytes[32..64])?;
+        let s = scalar_from_bytes(&bytes[64..])?;
+
+        let commitments = (
+            s * RISTRETTO_BASEPOINT_POINT + c * key,
+            s * self.blinded + c * signed,
+        );
+        if challenge(&key, &self.blinded, &signed, &commitments.0, &commitments.1) != c {
+            return Err(CryptoError::BadToken);
+        }
+
+        let unblinded = self.factor.invert() * signed;
+        Ok(format!(
+            "{}.{}.{}",
+            self.day,
+            self.nonce,
+            to_hex(unblinded.compress().as_bytes())
+        ))
+    }
+}
+
+/*
+ * The day a token was made on, if it is one at all
+*/
+
+pub fn token_day(token: &str) -> Option<u64> {
+    parse_token(token).map(|(day, _, _)| day)
+}
+
+/*
+ * The day it is now, counted like token days are
+*/
+
+pub fn today() -> u64 {
+    now() / SECONDS_PER_DAY
+}
+
+/*
+ * What a token is spent on: the recipient, id and sealed body of one
+ * message, hashed so whoever checks the token with the gateway doesn't
+ * have to hand it the message
+*/
+
+pub fn message_digest(recipient: &str, id: &str, body: &str) -> String {
+    let fields = associated_data(&[MESSAGE_DIGEST, recipient, id, body]);
+    to_hex(&Sha256::digest(fields.as_bytes()))
+}
+
+fn parse_token(token: &str) -> Option<(u64, &str, &str)> {
+    let mut parts = token.splitn(3, '.');
+    let day = parts.next()?.parse().ok()?;
+    Some((day, parts.next()?, parts.next()?))
+}
+
+fn hash_nonce(day: u64, nonce: &str) -> RistrettoPoint {
+    let fields = associated_data(&[TOKEN_NONCE, &day.to_string(), nonce]);
+    RistrettoPoint::from_uniform_bytes(&Sha512::digest(fields.as_bytes()).into())
+}
+
+fn challenge(
+    key: &RistrettoPoint,
+    blinded: &RistrettoPoint,
+    signed: &RistrettoPoint,
+    first: &RistrettoPoint,
+    second: &RistrettoPoint,
+) -> Scalar {
+    let mut hash = Sha512::new();
+    hash.update(TOKEN_PROOF.as_bytes());
+    for point in [
+        RISTRETTO_BASEPOINT_POINT,
+        *key,
+        *blinded,
+        *signed,
+        *first,
+        *second,
+    ] {
+        hash.update(point.compress().as_bytes());
+    }
+    Scalar::from_bytes_mod_order_wide(&hash.finalize().into())
+}
+
+fn random_scalar() -> Result<Scalar, CryptoError> {
+    Ok(Scalar::from_bytes_mod_order_wide(&random_bytes::<64>()?))
+}
+
+fn scalar_from_bytes(bytes: &[u8]) -> Result<Scalar, CryptoError> {
+    let bytes: [u8; 32] = bytes.try_into().map_err(|_| CryptoError::BadToken)?;
+    Option::from(Scalar::from_canonical_bytes(bytes)).ok_or(CryptoError::BadToken)
+}
+
+fn point_from_hex(hex: &str) -> Result<RistrettoPoint, CryptoError> {
+    point_from_bytes(&key_from_hex(hex)?)
+}
+
+// The identity point is refused, it would sign to itself under any key
+fn point_from_bytes(bytes: &[u8]) -> Result<RistrettoPoint, CryptoError> {
+    CompressedRistretto::from_slice(bytes)
+        .ok()
+        .and_then(|point| point.decompress())
+        .filter(|point| *point != RistrettoPoint::default())
+        .ok_or(CryptoError::InvalidKey)
+}
@@ -0,0 +1,88 @@
+use messaging_protocol::admin::{
+    AdminReply, AdminRequest, CacheInfo, CachedInfo, Presence, UserInfo,
+};
+use messaging_protocol::message::Capability;
+
+#[test]
+fn requests_survive_the_wire() {
+    for request in [
+        AdminRequest::Auth {
+            token: "s3cr;t&&".to_string(),
+        },
+        AdminRequest::Shutdown,
+        AdminRequest::Drain,
+        AdminRequest::Users,
+        AdminRequest::Caches,
+        AdminRequest::Inspect {
+            recipient: "bob".to_string(),
+        },
+        AdminRequest::Purge {
+            recipient: "b;ob".to_string(),
+            id: None,
+        },
+        AdminRequest::Purge {
+            recipient: "bob".to_string(),
+            id: Some("1".to_string()),
+        },
+        AdminRequest::Kick {
+            username: "amy".to_string(),
+        },
+    ] {
+        assert_eq!(AdminRequest::decode(&request.encode()), Ok(request));
+    }
+
+    for garbage in ["AUTH", "INSPECT", "PURGE ;1", "PURGE bob;", "KICK", "HELLO"] {
+        assert!(AdminRequest::parse(garbage).is_err(), "{}", garbage);
+    }
+}
+
+#[test]
+fn replies_survive_the_wire() {
+    for reply in [
+        AdminReply::Ok {
+            detail: "draining".to_string(),
+        },
+        AdminReply::Denied {
+            reason: "bad token".to_string(),
+        },
+        AdminReply::Users { users: Vec::new() },
+        AdminReply::Users {
+            users: vec![
+                UserInfo {
+                    username: "amy&&".to_string(),
+                    addr: "127.0.0.1:1".to_string(),
+                    presence: Presence::Online,
+                    capabilities: vec![Capability::DirectSend, Capability::BuddyCache],
+                },
+                UserInfo {
+                    username: "bob".to_string(),
+                    addr: "127.0.0.1:2".to_string(),
+                    presence: Presence::Gone,
+                    capabilities: Vec::new(),
+                },
+            ],
+        },
+        AdminReply::Caches {
+            caches: vec![CacheInfo {
+                recipient: "bob".to_string(),
+                messages: 2,
+                bytes: 12,
+            }],
+        },
+        AdminReply::Messages {
+            recipient: "bob".to_string(),
+            messages: Vec::new(),
+        },
+        AdminReply::Messages {
+            recipient: "bob".to_string(),
+            messages: vec![CachedInfo {
+                sender: "amy".to_string(),
+                id: "1".to_string(),
+                bytes: 6,
+                cached_at: 1700000000,
+            }],
+        },
+    ] {
+        assert_eq!(AdminReply::decode(&reply.encode()), Ok(reply));
+    }
+}
@@ -0,0 +1,262 @@
+use messaging_protocol::crypto::{
+    init_proof, load_secret, message_proof, open, read_secret, seal, verify, CryptoError, KeyPair,
+    SigningPair,
+};
+use messaging_protocol::message::{Capability, Message, StoredMessage, PROTOCOL_VERSION};
+
+#[test]
+fn only_the_recipient_can_open_a_sealed_body() {
+    let bob = KeyPair::generate().unwrap();
+    let carl = KeyPair::generate().unwrap();
+    let context = ["bob", "amy", "1"];
+
+    let sealed = seal(
+        &bob.public_hex(),
+        "meet at noon; bring % & friends",
+        &context,
+    )
+    .unwrap();
+    assert!(!sealed.contains("noon"));
+    assert_eq!(
+        open(&bob, &sealed, &context).unwrap(),
+        "meet at noon; bring % & friends"
+    );
+    assert_eq!(open(&carl, &sealed, &context), Err(CryptoError::Decrypt));
+
+    // Sealing the same text twice never gives the same body
+    assert_ne!(
+        sealed,
+        seal(
+            &bob.public_hex(),
+            "meet at noon; bring % & friends",
+            &context
+        )
+        .unwrap()
+    );
+}
+
+#[test]
+fn sealed_bodies_are_bound_to_their_message() {
+    let bob = KeyPair::generate().unwrap();
+    let sealed = seal(&bob.public_hex(), "hi", &["bob", "amy", "1"]).unwrap();
+
+    // Passed off as coming from someone else, or as another message
+    assert_eq!(
+        open(&bob, &sealed, &["bob", "carl", "1"]),
+        Err(CryptoError::Decrypt)
+    );
+    assert_eq!(
+        open(&bob, &sealed, &["bob", "amy", "2"]),
+        Err(CryptoError::Decrypt)
+    );
+
+    // A flipped bit anywhere is caught
+    let mut tampered = sealed.into_bytes();
+    let last = tampered.len() - 1;
+    tampered[last] = if tampered[last] == b'0' { b'1' } else { b'0' };
+    let tampered = String::from_utf8(tampered).unwrap();
+    assert_eq!(
+        open(&bob, &tampered, &["bob", "amy", "1"]),
+        Err(CryptoError::Decrypt)
+    );
+
+    for garbage in ["", "hi", "zz", &"00".repeat(20)] {
+        assert_eq!(
+            open(&bob, garbage, &["bob", "amy", "1"]),
+            Err(CryptoError::InvalidEnvelope)
+        );
+    }
+    assert_eq!(seal("abc", "hi", &[]), Err(CryptoError::InvalidKey));
+}
+
+#[test]
+fn key_pairs_come_back_from_their_secret() {
+    let keys = KeyPair::generate().unwrap();
+    let restored = KeyPair::from_secret_hex(&keys.secret_hex()).unwrap();
+    assert_eq!(restored.public_hex(), keys.public_hex());
+    assert_eq!(keys.public_hex().len(), 64);
+}
+
+#[test]
+fn signatures_only_verify_for_what_was_signed() {
+    let amy = SigningPair::generate().unwrap();
+    let carl = SigningPair::generate().unwrap();
+    let identity = amy.public_hex();
+    let proof = init_proof("nonce", "amy", "127.0.0.1:1", "", &identity);
+    let signature = amy.sign(&proof);
+
+    assert_eq!(verify(&amy.public_hex(), &signature, &proof), Ok(()));
+    assert_eq!(
+        verify(&carl.public_hex(), &signature, &proof),
+        Err(CryptoError::BadSignature)
+    );
+
+    // Another nonce or address needs a signature of its own
+    for proof in [
+        init_proof("other", "amy", "127.0.0.1:1", "", &identity),
+        init_proof("nonce", "amy", "127.0.0.1:2", "", &identity),
+    ] {
+        assert_eq!(
+            verify(&amy.public_hex(), &signature, &proof),
+            Err(CryptoError::BadSignature)
+        );
+    }
+    assert_eq!(
+        verify(&amy.public_hex(), "abcd", &proof),
+        Err(CryptoError::BadSignature)
+    );
+    assert_eq!(
+        verify("abcd", &signature, &proof),
+        Err(CryptoError::InvalidKey)
+    );
+
+    let restored = SigningPair::from_secret_hex(&amy.secret_hex()).unwrap();
+    assert_eq!(restored.public_hex(), amy.public_hex());
+}
+
+#[test]
+fn init_carries_the_keys() {
+    let key = KeyPair::generate().unwrap().public_hex();
+    let identity = SigningPair::generate().unwrap().public_hex();
+    for (key, identity) in [
+        (None, None),
+        (Some(key.clone()), None),
+        (None, Some(identity.clone())),
+        (Some(key), Some(identity)),
+    ] {
+        let init = Message::Init {
+            username: "amy".to_string(),
+            addr: "127.0.0.1:1".to_string(),
+            version: PROTOCOL_VERSION,
+            capabilities: vec![Capability::DirectSend],
+            key,
+            identity,
+        };
+        assert_eq!(Message::decode(&init.encode()), Ok(init));
+    }
+
+    for message in [
+        Message::Challenge {
+            nonce: "ab".repeat(16),
+        },
+        Message::Prove {
+            username: "amy".to_string(),
+            signature: "cd".repeat(64),
+        },
+    ] {
+        assert_eq!(Message::decode(&message.encode()), Ok(message));
+    }
+
+    // Clients from before keys still parse
+    let old = Message::parse("INIT amy&&127.0.0.1:1&&2&&direct_send").unwrap();
+    assert!(matches!(
+        old,
+        Message::Init {
+            key: None,
+            identity: None,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn messages_carry_the_senders_signature() {
+    let amy = SigningPair::generate().unwrap();
+    let body = seal(&KeyPair::generate().unwrap().public_hex(), "hi", &[]).unwrap();
+    let signature = amy.sign(&message_proof("bob", "amy", "1", &body));
+    assert_eq!(
+        verify(
+            &amy.public_hex(),
+            &signature,
+            &message_proof("bob", "amy", "1", &body)
+        ),
+        Ok(())
+    );
+
+    // The signature is no good for another recipient, sender or id
+    for proof in [
+        message_proof("carl", "amy", "1", &body),
+        message_proof("bob", "carl", "1", &body),
+        message_proof("bob", "amy", "2", &body),
+    ] {
+        assert_eq!(
+            verify(&amy.public_hex(), &signature, &proof),
+            Err(CryptoError::BadSignature)
+        );
+    }
+
+    let stored = StoredMessage {
+        sender: "amy".to_string(),
+        id: "1".to_string(),
+        body: body.clone(),
+        signature: Some(signature.clone()),
+    };
+    for message in [
+        Message::Send {
+            recipient: "bob".to_string(),
+            sender: "amy".to_string(),
+            id: "1".to_string(),
+            body: body.clone(),
+            signature: Some(signature.clone()),
+        },
+        Message::Cache {
+            recipient: "bob".to_string(),
+            sender: "amy".to_string(),
+            id: "1".to_string(),
+            body: "a; b".to_string(),
+            signature: None,
+        },
+        Message::Update {
+            messages: vec![
+                stored.clone(),
+                StoredMessage {
+                    signature: None,
+                    ..stored
+                },
+            ],
+        },
+        Message::Key {
+            username: "amy".to_string(),
+            key: "ab".repeat(32),
+            identity: Some(amy.public_hex()),
+        },
+    ] {
+        assert_eq!(Message::decode(&message.encode()), Ok(message));
+    }
+
+    // Messages and keys from before signatures still parse
+    assert!(matches!(
+        Message::parse("CACHE bob;amy;1;text").unwrap(),
+        Message::Cache {
+            signature: None,
+            ..
+        }
+    ));
+    assert!(matches!(
+        Message::parse("KEY amy;abcd").unwrap(),
+        Message::Key { identity: None, .. }
+    ));
+}
+
+#[test]
+fn secrets_are_made_up_once_and_kept_private() {
+    let dir = std::env::temp_dir().join(format!("messaging_secret_{}", std::process::id()));
+    _ = std::fs::remove_dir_all(&dir);
+    let path = dir.join("keys").join("identity.key");
+
+    let secret = load_secret(&path, || Ok(SigningPair::generate()?.secret_hex())).unwrap();
+    assert_eq!(read_secret(&path).unwrap(), secret);
+    let again = load_secret(&path, || panic!("the secret was made up again")).unwrap();
+    assert_eq!(again, secret);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    // An empty file holds no secret, and isn't overwritten with one
+    std::fs::write(&path, "").unwrap();
+    assert!(load_secret(&path, || Ok("ab".repeat(32))).is_err());
+}
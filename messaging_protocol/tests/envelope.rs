@@ -0,0 +1,79 @@
+use messaging_protocol::crypto::{CryptoError, KeyPair};
+use messaging_protocol::envelope::{is_sealed, open_sender, seal_sender};
+use messaging_protocol::message::{Message, StoredMessage};
+
+fn message() -> StoredMessage {
+    StoredMessage {
+        sender: "amy".to_string(),
+        id: "1".to_string(),
+        body: "r1.sealed; & body".to_string(),
+        signature: Some("amys signature".to_string()),
+    }
+}
+
+#[test]
+fn only_the_recipient_learns_the_sender() {
+    let bob = KeyPair::generate().unwrap();
+    let carl = KeyPair::generate().unwrap();
+
+    let envelope = seal_sender("bob", &bob.public_hex(), &message()).unwrap();
+    assert!(is_sealed(&envelope) && !is_sealed(&message()));
+    assert_eq!((envelope.sender.as_str(), envelope.id.as_str()), ("", "1"));
+    assert_eq!(envelope.signature, None);
+    assert!(!envelope.body.contains("amy"));
+
+    // Relays pass it on with nothing but the recipient and id in the clear
+    let send = Message::Send {
+        recipient: "bob".to_string(),
+        sender: envelope.sender.clone(),
+        id: envelope.id.clone(),
+        body: envelope.body.clone(),
+        signature: None,
+    };
+    let relayed = match Message::decode(&send.encode()).unwrap() {
+        Message::Send {
+            sender, id, body, ..
+        } => StoredMessage {
+            sender,
+            id,
+            body,
+            signature: None,
+        },
+        other => panic!("expected a send, got {}", other),
+    };
+    assert_eq!(relayed, envelope);
+
+    assert_eq!(open_sender("bob", &bob, &relayed).unwrap(), message());
+    assert_eq!(
+        open_sender("bob", &carl, &relayed),
+        Err(CryptoError::Decrypt)
+    );
+}
+
+#[test]
+fn envelopes_are_bound_to_their_message() {
+    let bob = KeyPair::generate().unwrap();
+    let envelope = seal_sender("bob", &bob.public_hex(), &message()).unwrap();
+
+    // Handed to someone else or given another id
+    assert_eq!(
+        open_sender("carl", &bob, &envelope),
+        Err(CryptoError::Decrypt)
+    );
+    let moved = StoredMessage {
+        id: "2".to_string(),
+        ..envelope.clone()
+    };
+    assert_eq!(open_sender("bob", &bob, &moved), Err(CryptoError::Decrypt));
+
+    // Only bodies with no sender outside are envelopes
+    let named = StoredMessage {
+        sender: "amy".to_string(),
+        ..envelope
+    };
+    assert!(!is_sealed(&named));
+    assert_eq!(
+        open_sender("bob", &bob, &named),
+        Err(CryptoError::InvalidEnvelope)
+    );
+}
@@ -0,0 +1,96 @@
+use messaging_protocol::framing::{encode_frame, FramedStream, HEADER_LEN};
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read};
+
+/*
+ * A stream that hands out its bytes in the given pieces, one per read, the
+ * way a socket hands out whatever has arrived so far
+*/
+
+struct Pieces(VecDeque<Vec<u8>>);
+
+impl Pieces {
+    fn new(pieces: Vec<Vec<u8>>) -> Pieces {
+        Pieces(pieces.into())
+    }
+}
+
+impl Read for Pieces {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut piece = match self.0.pop_front() {
+            Some(piece) => piece,
+            None => return Ok(0),
+        };
+        let len = piece.len().min(buf.len());
+        buf[..len].copy_from_slice(&piece[..len]);
+        if len < piece.len() {
+            self.0.push_front(piece.split_off(len));
+        }
+        Ok(len)
+    }
+}
+
+#[test]
+fn frames_split_across_reads_are_put_back_together() {
+    let frame = encode_frame(b"SEND bob;amy;1;hello");
+
+    // One byte at a time, the header split down the middle included
+    let pieces = frame.iter().map(|b| vec![*b]).collect();
+    let mut stream = FramedStream::new(Pieces::new(pieces));
+    assert_eq!(stream.read_frame().unwrap(), b"SEND bob;amy;1;hello");
+
+    let (head, tail) = frame.split_at(HEADER_LEN + 3);
+    let mut stream = FramedStream::new(Pieces::new(vec![head.to_vec(), tail.to_vec()]));
+    assert_eq!(stream.read_frame().unwrap(), b"SEND bob;amy;1;hello");
+}
+
+#[test]
+fn several_frames_in_one_read_come_out_one_at_a_time() {
+    let mut bytes = encode_frame(b"first");
+    bytes.extend(encode_frame(b"second"));
+    bytes.extend(encode_frame(b"thi"));
+    let mut stream = FramedStream::new(Pieces::new(vec![bytes]));
+
+    assert_eq!(stream.read_frame().unwrap(), b"first");
+    assert_eq!(stream.read_frame().unwrap(), b"second");
+    assert_eq!(stream.read_frame().unwrap(), b"thi");
+
+    // Nothing left, the other side hung up
+    let err = stream.read_frame().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn empty_frames_are_frames() {
+    let mut bytes = encode_frame(b"");
+    bytes.extend(encode_frame(b"after"));
+    let mut stream = FramedStream::new(Pieces::new(vec![bytes]));
+
+    assert_eq!(stream.read_frame().unwrap(), b"");
+    assert_eq!(stream.read_frame().unwrap(), b"after");
+}
+
+#[test]
+fn oversized_frames_are_refused_before_they_are_read() {
+    // Only the header of the big frame ever arrives
+    let header = 1025u32.to_be_bytes().to_vec();
+    let mut stream = FramedStream::new(Pieces::new(vec![header]));
+    stream.set_max_frame_len(1024);
+
+    let err = stream.read_frame().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    // Right at the limit is fine
+    let mut stream = FramedStream::new(Pieces::new(vec![encode_frame(&[b'x'; 1024])]));
+    stream.set_max_frame_len(1024);
+    assert_eq!(stream.read_frame().unwrap().len(), 1024);
+}
+
+#[test]
+fn a_frame_cut_off_by_a_hang_up_is_an_error() {
+    let frame = encode_frame(b"half a message");
+    let mut stream = FramedStream::new(Pieces::new(vec![frame[..8].to_vec()]));
+
+    let err = stream.read_frame().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+}
@@ -0,0 +1,16 @@
+use messaging_protocol::hash::calculate_hash;
+
+// Published FNV-1a 64 test vectors, if these change every existing buddy
+// group moves
+#[test]
+fn hash_matches_fnv1a() {
+    assert_eq!(calculate_hash(""), 0xcbf29ce484222325);
+    assert_eq!(calculate_hash("a"), 0xaf63dc4c8601ec8c);
+    assert_eq!(calculate_hash("foobar"), 0x85944171f73967e8);
+}
+
+#[test]
+fn hash_depends_on_the_whole_name() {
+    assert_ne!(calculate_hash("amy"), calculate_hash("amy2"));
+    assert_ne!(calculate_hash("amy"), calculate_hash("may"));
+}
@@ -0,0 +1,65 @@
+use messaging_protocol::journal::Journal;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::{env, process};
+
+fn state_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("messaging_journal_{}_{}", process::id(), name));
+    _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn changes_replay_after_the_snapshot() {
+    let dir = state_dir("replay");
+    let (snapshot, path) = (dir.join("state.log"), dir.join("state.log.journal"));
+
+    let mut journal = Journal::open(&snapshot, &path).unwrap();
+    assert_eq!(journal.replay().unwrap(), (Vec::new(), Vec::new()));
+    journal.append("one\n", true).unwrap();
+    journal.append("two\nthree\n", false).unwrap();
+    assert_eq!(journal.entries(), 2);
+
+    let journal = Journal::open(&snapshot, &path).unwrap();
+    let (before, changes) = journal.replay().unwrap();
+    assert!(before.is_empty());
+    assert_eq!(changes, ["one", "two", "three"]);
+
+    // A snapshot takes the place of everything journaled so far
+    let mut journal = journal;
+    journal
+        .snapshot(["state".to_string(), "more state".to_string()])
+        .unwrap();
+    assert_eq!(journal.entries(), 0);
+    journal.append("four\n", true).unwrap();
+    let (before, changes) = journal.replay().unwrap();
+    assert_eq!(before, ["state", "more state"]);
+    assert_eq!(changes, ["four"]);
+}
+
+#[test]
+fn torn_lines_are_left_out_and_dropped() {
+    let dir = state_dir("torn");
+    let (snapshot, path) = (dir.join("state.log"), dir.join("state.log.journal"));
+
+    let mut journal = Journal::open(&snapshot, &path).unwrap();
+    journal.append("whole\n", true).unwrap();
+
+    // A crash halfway through a line and through a snapshot
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(b"half a li").unwrap();
+    fs::write(dir.join("state.log.tmp"), "half a snap").unwrap();
+
+    let mut journal = Journal::open(&snapshot, &path).unwrap();
+    let (before, changes) = journal.replay().unwrap();
+    assert!(before.is_empty());
+    assert_eq!(changes, ["whole"]);
+
+    // Writing the state out again leaves nothing of the torn line behind
+    journal.snapshot(changes).unwrap();
+    journal.append("after\n", true).unwrap();
+    let (before, changes) = journal.replay().unwrap();
+    assert_eq!(before, ["whole"]);
+    assert_eq!(changes, ["after"]);
+}
@@ -0,0 +1,295 @@
+use messaging_protocol::message::{
+    escape, negotiate_version, new_message_id, unescape, Capability, Message, NetworkParams,
+    ParseError, StoredMessage, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use messaging_protocol::ratchet::PrekeyBundle;
+
+fn stored(sender: &str, id: &str, signature: Option<&str>) -> StoredMessage {
+    StoredMessage {
+        sender: sender.to_string(),
+        id: id.to_string(),
+        body: "hi; how & are\nyou %".to_string(),
+        signature: signature.map(str::to_string),
+    }
+}
+
+/*
+ * One of every message, with the separators inside fields wherever a
+ * field can hold them
+*/
+
+fn every_message() -> Vec<Message> {
+    let bundle = PrekeyBundle {
+        identity: "1d".to_string(),
+        key: "4b".to_string(),
+        signed_prekey: "5b".to_string(),
+        signature: "51".to_string(),
+        one_time: Some("07".to_string()),
+    };
+
+    vec![
+        Message::Init {
+            username: "a;m&&y".to_string(),
+            addr: "127.0.0.1:5000".to_string(),
+            version: 3,
+            capabilities: vec![
+                Capability::DirectSend,
+                Capability::BuddyCache,
+                Capability::Encrypted,
+            ],
+            key: None,
+            identity: Some("1d".to_string()),
+        },
+        Message::Version {
+            version: 2,
+            capabilities: vec![Capability::ServerRelay],
+            params: NetworkParams::default(),
+            token_key: None,
+        },
+        Message::Version {
+            version: 3,
+            capabilities: vec![Capability::Encrypted],
+            params: NetworkParams::default(),
+            token_key: Some("1d".to_string()),
+        },
+        Message::Tokens {
+            blinded: vec!["b1".to_string(), "b2".to_string()],
+        },
+        Message::Issued {
+            tokens: vec!["51".to_string()],
+        },
+        Message::Redeem {
+            token: "20000.aa.bb".to_string(),
+            digest: "d1".to_string(),
+        },
+        Message::Redeemed {
+            token: "20000.aa.bb".to_string(),
+        },
+        Message::Refused {
+            reason: "too old; sorry".to_string(),
+        },
+        Message::Challenge {
+            nonce: "00ff".to_string(),
+        },
+        Message::Prove {
+            username: "amy".to_string(),
+            signature: "51".to_string(),
+        },
+        Message::Send {
+            recipient: "b;ob".to_string(),
+            sender: "amy".to_string(),
+            id: "1".to_string(),
+            body: "%&&;".to_string(),
+            signature: Some("51".to_string()),
+        },
+        Message::Ack {
+            username: "bob".to_string(),
+            id: "1".to_string(),
+        },
+        Message::Rejected {
+            username: "bob".to_string(),
+            id: "1".to_string(),
+            reason: "the cache for bob is full; try later".to_string(),
+        },
+        Message::Cache {
+            recipient: "bob".to_string(),
+            sender: "a&&my".to_string(),
+            id: "1".to_string(),
+            body: "hello".to_string(),
+            signature: None,
+        },
+        Message::Buddies {
+            username: "bob".to_string(),
+            buddies: vec!["127.0.0.1:1".to_string(), "[::1]:2".to_string()],
+        },
+        Message::IpFetch {
+            username: "bob".to_string(),
+        },
+        Message::KeyFetch {
+            username: "bob".to_string(),
+        },
+        Message::Key {
+            username: "bob".to_string(),
+            key: "4b".to_string(),
+            identity: Some("1d".to_string()),
+        },
+        Message::Prekeys {
+            username: "bob".to_string(),
+            signed_prekey: "5b".to_string(),
+            signature: "51".to_string(),
+            one_time: vec!["07".to_string(), "08".to_string()],
+        },
+        Message::BundleFetch {
+            username: "bob".to_string(),
+        },
+        Message::Bundle {
+            username: "bob".to_string(),
+            bundle,
+        },
+        Message::Fetch {
+            username: "b%ob".to_string(),
+        },
+        Message::Leave {
+            username: "bob".to_string(),
+        },
+        Message::Pull {
+            username: "bob".to_string(),
+            cursor: "0042".to_string(),
+            limit: 50,
+        },
+        Message::IpRetrieval {
+            addr: "127.0.0.1:5000".to_string(),
+        },
+        Message::Update {
+            messages: vec![stored("amy", "1", Some("51")), stored("carl", "2", None)],
+        },
+        Message::Update {
+            messages: Vec::new(),
+        },
+        Message::NotFound {
+            reason: "bob not found".to_string(),
+        },
+        Message::UpdateFingers {
+            fingers: vec!["127.0.0.1:1".to_string()],
+        },
+        Message::NewFinger {
+            addr: "127.0.0.1:1".to_string(),
+        },
+        Message::UpdateGroup {
+            members: Vec::new(),
+        },
+    ]
+}
+
+#[test]
+fn every_message_survives_the_wire() {
+    for message in every_message() {
+        let encoded = message.encode();
+        assert_eq!(
+            Message::decode(&encoded),
+            Ok(message.clone()),
+            "{}",
+            message
+        );
+        assert!(String::from_utf8(encoded)
+            .unwrap()
+            .starts_with(message.code()));
+    }
+}
+
+#[test]
+fn broken_messages_say_what_is_wrong() {
+    assert_eq!(Message::decode(&[0xff, 0xfe]), Err(ParseError::InvalidUtf8));
+    assert_eq!(
+        Message::parse("HELLO bob"),
+        Err(ParseError::UnknownCode("HELLO".to_string()))
+    );
+    assert_eq!(
+        Message::parse("ACK bob"),
+        Err(ParseError::MissingField {
+            code: "ACK",
+            field: "id"
+        })
+    );
+    assert_eq!(
+        Message::parse("FETCH"),
+        Err(ParseError::MissingField {
+            code: "FETCH",
+            field: "username"
+        })
+    );
+    assert_eq!(
+        Message::parse("PULL bob;;lots"),
+        Err(ParseError::InvalidField {
+            code: "PULL",
+            field: "limit"
+        })
+    );
+    assert_eq!(
+        Message::parse("INIT bob&&127.0.0.1:1&&two"),
+        Err(ParseError::InvalidField {
+            code: "INIT",
+            field: "version"
+        })
+    );
+    assert_eq!(
+        Message::parse("SEND bob;amy;1;broken %zz"),
+        Err(ParseError::InvalidEscape)
+    );
+}
+
+#[test]
+fn version_1_inits_still_parse() {
+    assert_eq!(
+        Message::parse("INIT bob&&127.0.0.1:5000"),
+        Ok(Message::Init {
+            username: "bob".to_string(),
+            addr: "127.0.0.1:5000".to_string(),
+            version: 1,
+            capabilities: Capability::legacy(),
+            key: None,
+            identity: None,
+        })
+    );
+}
+
+#[test]
+fn separators_are_escaped_out_of_fields() {
+    assert_eq!(escape("a;b"), "a%3Bb");
+    assert_eq!(escape("a&&b"), "a%26%26b");
+    assert_eq!(escape("100%"), "100%25");
+    assert_eq!(escape("two\r\nlines"), "two%0D%0Alines");
+    assert_eq!(escape("plain text, ünïcode"), "plain text, ünïcode");
+
+    for field in ["a;b", "a&&b", "100%", "%3B", "%%;;&&\n", ""] {
+        let escaped = escape(field);
+        assert!(!escaped.contains(';') && !escaped.contains('&'));
+        assert_eq!(unescape(&escaped).unwrap(), field);
+    }
+}
+
+#[test]
+fn broken_escapes_are_refused() {
+    // Cut short, not hex, or a character that is never escaped
+    for field in ["%", "%3", "abc%", "%zz", "%4A", "%41"] {
+        assert_eq!(unescape(field), Err(ParseError::InvalidEscape), "{}", field);
+    }
+}
+
+#[test]
+fn nobody_registers_the_empty_name() {
+    assert_eq!(
+        Message::parse("INIT &&127.0.0.1:5000&&2&&direct_send"),
+        Err(ParseError::MissingField {
+            code: "INIT",
+            field: "username"
+        })
+    );
+}
+
+#[test]
+fn message_ids_never_repeat() {
+    let ids: Vec<String> = (0..10_000).map(|_| new_message_id().unwrap()).collect();
+    let distinct: std::collections::HashSet<&String> = ids.iter().collect();
+    assert_eq!(distinct.len(), ids.len());
+
+    // Every id is 32 hex digits, and later ids never sort first
+    assert!(ids
+        .iter()
+        .all(|id| id.len() == 32 && id.chars().all(|c| c.is_ascii_hexdigit())));
+    assert!(ids.first().unwrap()[..16] <= ids.last().unwrap()[..16]);
+}
+
+#[test]
+fn versions_are_negotiated_down_never_up() {
+    assert_eq!(
+        negotiate_version(PROTOCOL_VERSION + 5),
+        Some(PROTOCOL_VERSION)
+    );
+    assert_eq!(negotiate_version(PROTOCOL_VERSION), Some(PROTOCOL_VERSION));
+    assert_eq!(
+        negotiate_version(MIN_PROTOCOL_VERSION),
+        Some(MIN_PROTOCOL_VERSION)
+    );
+    assert_eq!(negotiate_version(MIN_PROTOCOL_VERSION - 1), None);
+}
@@ -0,0 +1,218 @@
+use messaging_protocol::crypto::{CryptoError, KeyPair, SigningPair};
+use messaging_protocol::message::Message;
+use messaging_protocol::ratchet::{
+    is_session_body, prekey_header, prekey_proof, PrekeyBundle, Session, MAX_SKIP,
+};
+
+/*
+ * Bob's long-term keys and the prekeys he published
+*/
+
+struct Bob {
+    keys: KeyPair,
+    signed_prekey: KeyPair,
+    one_time: KeyPair,
+    bundle: PrekeyBundle,
+}
+
+fn bob() -> Bob {
+    let identity = SigningPair::generate().unwrap();
+    let keys = KeyPair::generate().unwrap();
+    let signed_prekey = KeyPair::generate().unwrap();
+    let one_time = KeyPair::generate().unwrap();
+    let bundle = PrekeyBundle {
+        identity: identity.public_hex(),
+        key: keys.public_hex(),
+        signed_prekey: signed_prekey.public_hex(),
+        signature: identity.sign(&prekey_proof(&signed_prekey.public_hex())),
+        one_time: Some(one_time.public_hex()),
+    };
+    Bob {
+        keys,
+        signed_prekey,
+        one_time,
+        bundle,
+    }
+}
+
+/*
+ * Amy starts a session with bob, and bob takes it up from her first message
+*/
+
+fn start(amy: &KeyPair, bob: &Bob) -> (Session, Session, String) {
+    let mut amy_session = Session::initiate(amy, &bob.bundle).unwrap();
+    let first = amy_session.encrypt("hi bob", &["bob", "amy", "1"]).unwrap();
+
+    let header = prekey_header(&first).unwrap();
+    assert_eq!(header.key, amy.public_hex());
+    let mut bob_session =
+        Session::respond(&bob.keys, &bob.signed_prekey, Some(&bob.one_time), &header).unwrap();
+    assert_eq!(
+        bob_session.decrypt(&first, &["bob", "amy", "1"]).unwrap(),
+        "hi bob"
+    );
+    assert_eq!(bob_session.remote_key(), amy.public_hex());
+    assert_eq!(bob_session.base_key(), amy_session.base_key());
+    (amy_session, bob_session, first)
+}
+
+#[test]
+fn sessions_carry_a_conversation_both_ways() {
+    let amy = KeyPair::generate().unwrap();
+    let bob = bob();
+    let (mut amy_session, mut bob_session, _) = start(&amy, &bob);
+
+    // Bob's session can't send before it has heard from amy, but now can
+    let reply = bob_session.encrypt("hi amy", &["amy", "bob", "2"]).unwrap();
+    assert!(is_session_body(&reply));
+    assert!(prekey_header(&reply).is_none());
+    assert_eq!(
+        amy_session.decrypt(&reply, &["amy", "bob", "2"]).unwrap(),
+        "hi amy"
+    );
+
+    // Once bob answered, amy stops sending her prekeys
+    let next = amy_session.encrypt("lunch?", &["bob", "amy", "3"]).unwrap();
+    assert!(prekey_header(&next).is_none());
+    assert_eq!(
+        bob_session.decrypt(&next, &["bob", "amy", "3"]).unwrap(),
+        "lunch?"
+    );
+
+    // Bodies are bound to their message like sealed ones
+    let body = amy_session
+        .encrypt("at noon", &["bob", "amy", "4"])
+        .unwrap();
+    assert_eq!(
+        bob_session.decrypt(&body, &["bob", "amy", "5"]),
+        Err(CryptoError::Decrypt)
+    );
+    assert_eq!(
+        bob_session.decrypt(&body, &["bob", "amy", "4"]).unwrap(),
+        "at noon"
+    );
+}
+
+#[test]
+fn messages_may_arrive_out_of_order_but_only_once() {
+    let amy = KeyPair::generate().unwrap();
+    let bob = bob();
+    let (mut amy_session, mut bob_session, first) = start(&amy, &bob);
+
+    let bodies: Vec<String> = (0..3)
+        .map(|i| amy_session.encrypt(&format!("message {}", i), &[]).unwrap())
+        .collect();
+    assert_eq!(bob_session.decrypt(&bodies[2], &[]).unwrap(), "message 2");
+    assert_eq!(bob_session.decrypt(&bodies[0], &[]).unwrap(), "message 0");
+    assert_eq!(bob_session.decrypt(&bodies[1], &[]).unwrap(), "message 1");
+
+    // Every message key is gone once used
+    assert!(bob_session.decrypt(&bodies[1], &[]).is_err());
+    assert!(bob_session.decrypt(&first, &["bob", "amy", "1"]).is_err());
+
+    // A failed decrypt leaves the session as it was
+    let body = amy_session.encrypt("still here", &[]).unwrap();
+    assert!(bob_session.decrypt("r1.00.0.0.00", &[]).is_err());
+    assert_eq!(bob_session.decrypt(&body, &[]).unwrap(), "still here");
+}
+
+#[test]
+fn chains_cannot_skip_without_limit() {
+    let amy = KeyPair::generate().unwrap();
+    let bob = bob();
+    let (mut amy_session, mut bob_session, _) = start(&amy, &bob);
+
+    for _ in 0..=MAX_SKIP {
+        amy_session.encrypt("lost", &[]).unwrap();
+    }
+    let body = amy_session.encrypt("too far", &[]).unwrap();
+    assert_eq!(
+        bob_session.decrypt(&body, &[]),
+        Err(CryptoError::InvalidEnvelope)
+    );
+}
+
+#[test]
+fn stolen_long_term_keys_do_not_open_earlier_messages() {
+    let amy = KeyPair::generate().unwrap();
+    let bob = bob();
+    let (_, _, first) = start(&amy, &bob);
+
+    // Whoever later steals bob's key and signed prekey is missing the
+    // one-time prekey he threw away
+    let header = prekey_header(&first).unwrap();
+    let mut thief = Session::respond(&bob.keys, &bob.signed_prekey, None, &header).unwrap();
+    assert!(thief.decrypt(&first, &["bob", "amy", "1"]).is_err());
+}
+
+#[test]
+fn bundles_need_a_valid_prekey_signature() {
+    let amy = KeyPair::generate().unwrap();
+    let mut bundle = bob().bundle;
+    bundle.signed_prekey = KeyPair::generate().unwrap().public_hex();
+    assert!(matches!(
+        Session::initiate(&amy, &bundle),
+        Err(CryptoError::BadSignature)
+    ));
+}
+
+#[test]
+fn sessions_survive_being_saved() {
+    let amy = KeyPair::generate().unwrap();
+    let bob = bob();
+    let (mut amy_session, mut bob_session, _) = start(&amy, &bob);
+
+    let skipped = amy_session.encrypt("skipped", &[]).unwrap();
+    let body = amy_session.encrypt("saved", &[]).unwrap();
+    assert_eq!(bob_session.decrypt(&body, &[]).unwrap(), "saved");
+
+    // The line holds no ;-escaping surprises and parses back
+    let line = bob_session.to_string();
+    assert!(!line.contains('\n'));
+    let mut bob_session = Session::parse(&line).unwrap();
+    assert_eq!(bob_session.to_string(), line);
+    assert_eq!(bob_session.decrypt(&skipped, &[]).unwrap(), "skipped");
+
+    let mut amy_session = Session::parse(&amy_session.to_string()).unwrap();
+    let reply = bob_session.encrypt("got both", &[]).unwrap();
+    assert_eq!(amy_session.decrypt(&reply, &[]).unwrap(), "got both");
+
+    assert!(Session::parse("not a session").is_none());
+}
+
+#[test]
+fn prekeys_and_bundles_go_over_the_wire() {
+    let bundle = bob().bundle;
+    let without_one_time = PrekeyBundle {
+        one_time: None,
+        ..bundle.clone()
+    };
+    for message in [
+        Message::Prekeys {
+            username: "bob".to_string(),
+            signed_prekey: bundle.signed_prekey.clone(),
+            signature: bundle.signature.clone(),
+            one_time: vec!["ab".repeat(32), "cd".repeat(32)],
+        },
+        Message::Prekeys {
+            username: "bob".to_string(),
+            signed_prekey: bundle.signed_prekey.clone(),
+            signature: bundle.signature.clone(),
+            one_time: Vec::new(),
+        },
+        Message::BundleFetch {
+            username: "bob".to_string(),
+        },
+        Message::Bundle {
+            username: "bob".to_string(),
+            bundle,
+        },
+        Message::Bundle {
+            username: "bob".to_string(),
+            bundle: without_one_time,
+        },
+    ] {
+        assert_eq!(Message::decode(&message.encode()), Ok(message));
+    }
+    assert!(Message::parse("BUNDLE bob;aa;bb").is_err());
+}
@@ -0,0 +1,174 @@
+use messaging_protocol::hash::{in_group, select_group};
+use std::collections::HashMap;
+
+const GROUP_SIZE: usize = 2;
+
+fn members(count: usize) -> Vec<String> {
+    (0..count)
+        .map(|i| format!("10.{}.{}.{}:8013", i / 65536, (i / 256) % 256, i % 256))
+        .collect()
+}
+
+fn users(count: usize) -> Vec<String> {
+    (0..count).map(|i| format!("user{}", i)).collect()
+}
+
+fn groups(users: &[String], members: &[String]) -> Vec<Vec<String>> {
+    users
+        .iter()
+        .map(|user| select_group(user, members, GROUP_SIZE))
+        .collect()
+}
+
+// How many users ended up with a different group
+fn churn(before: &[Vec<String>], after: &[Vec<String>]) -> usize {
+    before.iter().zip(after).filter(|(b, a)| b != a).count()
+}
+
+#[test]
+fn groups_are_full_distinct_and_stable() {
+    let members = members(50);
+
+    for user in users(1000) {
+        let group = select_group(&user, &members, GROUP_SIZE);
+        assert_eq!(group.len(), GROUP_SIZE);
+        assert_ne!(group[0], group[1]);
+        assert_eq!(group, select_group(&user, &members, GROUP_SIZE));
+    }
+
+    // The order members were registered in doesn't matter
+    let mut reversed = members.clone();
+    reversed.reverse();
+    for user in users(1000) {
+        assert_eq!(
+            select_group(&user, &members, GROUP_SIZE),
+            select_group(&user, &reversed, GROUP_SIZE)
+        );
+    }
+}
+
+#[test]
+fn small_rings_hand_out_what_they_have() {
+    assert!(select_group("amy", &[], GROUP_SIZE).is_empty());
+
+    let one = members(1);
+    assert_eq!(select_group("amy", &one, GROUP_SIZE), one);
+
+    let doubled = vec![one[0].clone(), one[0].clone()];
+    assert_eq!(select_group("amy", &doubled, GROUP_SIZE), one);
+}
+
+#[test]
+fn membership_agrees_with_the_picked_group() {
+    let mut members = members(200);
+    members.push(members[7].clone());
+
+    for user in users(300) {
+        let group = select_group(&user, &members, GROUP_SIZE);
+        for member in &members {
+            assert_eq!(
+                in_group(&user, member, &members, GROUP_SIZE),
+                group.contains(member)
+            );
+        }
+    }
+}
+
+#[test]
+fn joining_member_only_moves_the_groups_it_wins() {
+    let users = users(4000);
+    let mut ring = members(500);
+    let before = groups(&users, &ring);
+
+    let newcomer = "192.168.0.1:8013".to_string();
+    ring.push(newcomer.clone());
+    let after = groups(&users, &ring);
+
+    // Every group that moved now holds the newcomer and kept the rest
+    for (b, a) in before.iter().zip(&after) {
+        if b != a {
+            assert!(a.contains(&newcomer));
+            assert_eq!(a.iter().filter(|m| b.contains(m)).count(), GROUP_SIZE - 1);
+        }
+    }
+
+    // About GROUP_SIZE / ring size of the users should move, allow for noise
+    let moved = churn(&before, &after);
+    let expected = users.len() * GROUP_SIZE / ring.len();
+    assert!(
+        moved <= expected * 4 + 5,
+        "{} of {} groups moved, expected about {}",
+        moved,
+        users.len(),
+        expected
+    );
+}
+
+#[test]
+fn leaving_member_only_moves_the_groups_it_was_in() {
+    let users = users(4000);
+    let mut ring = members(500);
+    let before = groups(&users, &ring);
+
+    let gone = ring.remove(123);
+    let after = groups(&users, &ring);
+
+    for (b, a) in before.iter().zip(&after) {
+        assert_eq!(b != a, b.contains(&gone));
+        assert!(!a.contains(&gone));
+    }
+
+    let held = before.iter().filter(|group| group.contains(&gone)).count();
+    assert_eq!(churn(&before, &after), held);
+}
+
+#[test]
+fn steady_growth_churns_little() {
+    let users = users(1000);
+    let mut ring = members(100);
+    let mut moved = 0;
+    let mut steps = 0;
+
+    // Grow the ring one member at a time and add up how many groups move
+    for newcomer in members(130).into_iter().skip(100) {
+        let before = groups(&users, &ring);
+        ring.push(newcomer);
+        moved += churn(&before, &groups(&users, &ring));
+        steps += 1;
+    }
+
+    // Each join should move roughly GROUP_SIZE / ring size of the groups,
+    // the old offset arithmetic moved nearly all of them
+    let average = moved as f64 / (steps * users.len()) as f64;
+    assert!(average < 0.02, "{:.4} of groups moved per join", average);
+}
+
+#[test]
+fn load_is_balanced() {
+    let users = users(10000);
+    let ring = members(500);
+    let mut load: HashMap<&String, usize> = ring.iter().map(|m| (m, 0)).collect();
+
+    for user in &users {
+        for member in select_group(user, &ring, GROUP_SIZE) {
+            *load.get_mut(&member).unwrap() += 1;
+        }
+    }
+
+    // Every member should carry close to the mean number of groups
+    let mean = users.len() * GROUP_SIZE / ring.len();
+    let max = *load.values().max().unwrap();
+    let min = *load.values().min().unwrap();
+    assert!(
+        max < mean * 2,
+        "busiest member holds {} groups, mean {}",
+        max,
+        mean
+    );
+    assert!(
+        min * 3 > mean,
+        "idlest member holds {} groups, mean {}",
+        min,
+        mean
+    );
+}
@@ -0,0 +1,70 @@
+use messaging_protocol::crypto::CryptoError;
+use messaging_protocol::tokens::{message_digest, today, token_day, Blind, TokenKey};
+
+#[test]
+fn tokens_check_out_without_the_gateway_seeing_them_made() {
+    let gateway = TokenKey::generate().unwrap();
+    let blind = Blind::new(today()).unwrap();
+
+    // The gateway only ever sees the blinded nonce, which looks nothing
+    // like the token that comes out of it
+    let issued = gateway.issue(&blind.blinded_hex()).unwrap();
+    let token = blind.finish(&gateway.public_hex(), &issued).unwrap();
+    assert!(!token.contains(&blind.blinded_hex()));
+    assert!(!issued.contains(token.rsplit('.').next().unwrap()));
+
+    assert_eq!(gateway.check(&token), Ok(today()));
+    assert_eq!(token_day(&token), Some(today()));
+
+    // The same key read back from its secret checks it too
+    let reloaded = TokenKey::from_secret_hex(&gateway.secret_hex()).unwrap();
+    assert_eq!(reloaded.check(&token), Ok(today()));
+}
+
+#[test]
+fn forged_tokens_are_refused() {
+    let gateway = TokenKey::generate().unwrap();
+    let other = TokenKey::generate().unwrap();
+    let blind = Blind::new(today()).unwrap();
+    let token = blind
+        .finish(
+            &other.public_hex(),
+            &other.issue(&blind.blinded_hex()).unwrap(),
+        )
+        .unwrap();
+
+    // Signed by another key, or moved to another day
+    assert_eq!(gateway.check(&token), Err(CryptoError::BadToken));
+    let (_, rest) = token.split_once('.').unwrap();
+    let moved = format!("{}.{}", today() + 7, rest);
+    assert_eq!(other.check(&moved), Err(CryptoError::BadToken));
+    assert_eq!(gateway.check("not a token"), Err(CryptoError::BadToken));
+}
+
+#[test]
+fn clients_refuse_tokens_from_a_key_the_gateway_didnt_announce() {
+    let gateway = TokenKey::generate().unwrap();
+    let tagged = TokenKey::generate().unwrap();
+    let blind = Blind::new(today()).unwrap();
+
+    // A gateway signing with a key kept for one client could tell its
+    // tokens apart later, the proof gives it away
+    let issued = tagged.issue(&blind.blinded_hex()).unwrap();
+    assert_eq!(
+        blind.finish(&gateway.public_hex(), &issued).err(),
+        Some(CryptoError::BadToken)
+    );
+}
+
+#[test]
+fn digests_cover_the_whole_message() {
+    let digest = message_digest("bob", "1", "s1.sealed");
+    assert_eq!(digest, message_digest("bob", "1", "s1.sealed"));
+    for other in [
+        message_digest("carl", "1", "s1.sealed"),
+        message_digest("bob", "2", "s1.sealed"),
+        message_digest("bob", "1", "s1.other"),
+    ] {
+        assert_ne!(digest, other);
+    }
+}
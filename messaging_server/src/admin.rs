@@ -0,0 +1,330 @@
+use crate::store::{Record, Store};
+use crate::utils::User;
+use crate::{CacheMap, ConnMap, SessionMap, SockMap, UserList};
+use messaging_protocol::admin::{
+    AdminReply, AdminRequest, CacheInfo, CachedInfo, Presence, UserInfo,
+};
+use messaging_protocol::crypto::{load_secret, random_bytes, to_hex};
+use mio::Token;
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+
+// The file in the state directory holding the token admin connections
+// have to present
+pub const TOKEN_FILE: &str = "admin_token";
+
+/*
+ * The connections to the admin listener and which of them have sent the
+ * right token. Nothing is answered for the others besides AUTH
+*/
+
+pub struct AdminChannel {
+    pub sessions: SockMap,
+    pub trusted: HashSet<Token>,
+    pub token: String,
+}
+
+/*
+ * What the main loop has to do after an admin request
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Control {
+    Continue,
+    Drain,
+    Shutdown,
+}
+
+impl AdminChannel {
+    pub fn new(token: String) -> AdminChannel {
+        AdminChannel {
+            sessions: SockMap::new(),
+            trusted: HashSet::new(),
+            token,
+        }
+    }
+
+    pub fn close(&mut self, token: &Token) {
+        self.sessions.remove(token);
+        self.trusted.remove(token);
+    }
+
+    fn reply(&mut self, token: &Token, reply: AdminReply) {
+        if let Some(stream) = self.sessions.get_mut(token) {
+            stream.queue_frame(&reply.encode());
+
+            if let Err(e) = stream.flush_pending() {
+                error!("Dropping admin connection, err={:?}", e);
+                self.close(token);
+            }
+        }
+    }
+}
+
+/*
+ * Read the admin token from the state directory, making up a new one the
+ * first time. Only the gateway's own user may read the file
+*/
+
+pub fn load_token(state_dir: &str) -> io::Result<String> {
+    load_secret(&Path::new(state_dir).join(TOKEN_FILE), || {
+        Ok(to_hex(&random_bytes::<32>()?))
+    })
+}
+
+/*
+ * Compare tokens without stopping at the first difference, so how long
+ * the check takes says nothing about how much of a guess was right
+*/
+
+fn token_matches(expected: &str, given: &str) -> bool {
+    let (expected, given) = (expected.as_bytes(), given.as_bytes());
+    if expected.len() != given.len() {
+        return false;
+    }
+    expected
+        .iter()
+        .zip(given)
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/*
+ * Answer one frame from an admin connection. A connection that fails to
+ * authenticate is told why and closed
+*/
+
+#[allow(clippy::too_many_arguments)]
+pub fn handle_admin(
+    token: &Token,
+    admin: &mut AdminChannel,
+    frame: &[u8],
+    sockets: &mut SockMap,
+    connections: &mut ConnMap,
+    sessions: &mut SessionMap,
+    cache: &mut CacheMap,
+    user_list: &mut UserList,
+    store: &mut Store,
+) -> Control {
+    let trusted = admin.trusted.contains(token);
+    let request = match AdminRequest::decode(frame) {
+        Ok(request) => request,
+        Err(e) => {
+            admin.reply(
+                token,
+                AdminReply::Denied {
+                    reason: e.to_string(),
+                },
+            );
+            if !trusted {
+                admin.close(token);
+            }
+            return Control::Continue;
+        }
+    };
+
+    if !trusted {
+        match request {
+            AdminRequest::Auth { token: given } if token_matches(&admin.token, &given) => {
+                info!("Admin connection authenticated");
+                admin.trusted.insert(*token);
+                admin.reply(
+                    token,
+                    AdminReply::Ok {
+                        detail: "authenticated".to_string(),
+                    },
+                );
+            }
+            _ => {
+                error!("Admin connection refused");
+                let reason = "send AUTH with the admin token first".to_string();
+                admin.reply(token, AdminReply::Denied { reason });
+                admin.close(token);
+            }
+        }
+        return Control::Continue;
+    }
+
+    info!("Admin request: {}", request.code());
+    let (reply, control) = match request {
+        AdminRequest::Auth { .. } => (ok("already authenticated"), Control::Continue),
+        AdminRequest::Shutdown => (ok("shutting down"), Control::Shutdown),
+        AdminRequest::Drain => (
+            ok(&format!(
+                "draining, {} connections still open",
+                sockets.len()
+            )),
+            Control::Drain,
+        ),
+        AdminRequest::Users => (list_users(connections, user_list), Control::Continue),
+        AdminRequest::Caches => (list_caches(cache), Control::Continue),
+        AdminRequest::Inspect { recipient } => {
+            (inspect_cache(&recipient, cache), Control::Continue)
+        }
+        AdminRequest::Purge { recipient, id } => (
+            purge_cache(&recipient, id.as_deref(), cache, store),
+            Control::Continue,
+        ),
+        AdminRequest::Kick { username } => (
+            kick_user(&username, sockets, connections, sessions, user_list, store),
+            Control::Continue,
+        ),
+    };
+
+    admin.reply(token, reply);
+    control
+}
+
+fn ok(detail: &str) -> AdminReply {
+    AdminReply::Ok {
+        detail: detail.to_string(),
+    }
+}
+
+/*
+ * Every user the gateway knows, by name
+*/
+
+fn list_users(connections: &ConnMap, user_list: &UserList) -> AdminReply {
+    let mut users: Vec<UserInfo> = connections
+        .iter()
+        .map(|(username, user)| UserInfo {
+            username: username.clone(),
+            addr: user.ip_addr.clone(),
+            presence: presence(user, user_list),
+            capabilities: user.capabilities.clone(),
+        })
+        .collect();
+    users.sort_by(|a, b| a.username.cmp(&b.username));
+
+    AdminReply::Users { users }
+}
+
+fn presence(user: &User, user_list: &UserList) -> Presence {
+    if user.offline_since.is_none() {
+        Presence::Online
+    } else if user_list.contains(&user.ip_addr) {
+        Presence::Away
+    } else {
+        Presence::Gone
+    }
+}
+
+/*
+ * How many messages and bytes are held for each recipient
+*/
+
+fn list_caches(cache: &CacheMap) -> AdminReply {
+    let mut caches: Vec<CacheInfo> = cache
+        .iter()
+        .map(|(recipient, pending)| CacheInfo {
+            recipient: recipient.clone(),
+            messages: pending.len() as u64,
+            bytes: pending
+                .values()
+                .map(|cached| cached.message.size() as u64)
+                .sum(),
+        })
+        .collect();
+    caches.sort_by(|a, b| a.recipient.cmp(&b.recipient));
+
+    AdminReply::Caches { caches }
+}
+
+fn inspect_cache(recipient: &str, cache: &CacheMap) -> AdminReply {
+    let messages = cache
+        .get(recipient)
+        .map(|pending| {
+            pending
+                .values()
+                .map(|cached| CachedInfo {
+                    sender: cached.message.sender.clone(),
+                    id: cached.message.id.clone(),
+                    bytes: cached.message.size() as u64,
+                    cached_at: cached.cached_at,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    AdminReply::Messages {
+        recipient: recipient.to_string(),
+        messages,
+    }
+}
+
+/*
+ * Drop cached messages like the recipient had acked them
+*/
+
+fn purge_cache(
+    recipient: &str,
+    id: Option<&str>,
+    cache: &mut CacheMap,
+    store: &mut Store,
+) -> AdminReply {
+    let pending = match cache.get_mut(recipient) {
+        Some(pending) => pending,
+        None => return ok(&format!("purged 0 messages for {}", recipient)),
+    };
+
+    let ids: Vec<String> = match id {
+        Some(id) if pending.contains_key(id) => vec![id.to_string()],
+        Some(id) => {
+            return AdminReply::Denied {
+                reason: format!("no message {} is cached for {}", id, recipient),
+            }
+        }
+        None => pending.keys().cloned().collect(),
+    };
+
+    for id in &ids {
+        pending.remove(id);
+        _ = store.log(Record::Ack {
+            recipient: recipient.to_string(),
+            id: id.clone(),
+        });
+    }
+    if pending.is_empty() {
+        cache.remove(recipient);
+    }
+
+    ok(&format!("purged {} messages for {}", ids.len(), recipient))
+}
+
+/*
+ * Close a user's session and take them out of the buddy ring, like they
+ * had left
+*/
+
+fn kick_user(
+    username: &str,
+    sockets: &mut SockMap,
+    connections: &mut ConnMap,
+    sessions: &mut SessionMap,
+    user_list: &mut UserList,
+    store: &mut Store,
+) -> AdminReply {
+    let user = match connections.get_mut(username) {
+        Some(user) => user,
+        None => {
+            return AdminReply::Denied {
+                reason: format!("there is no user named {}", username),
+            }
+        }
+    };
+
+    sockets.remove(&user.token);
+    sessions.remove(&user.token);
+    user.offline_since.get_or_insert_with(Instant::now);
+    if user_list.contains(&user.ip_addr) {
+        user_list.retain(|addr| *addr != user.ip_addr);
+        _ = store.log(Record::Part {
+            addr: user.ip_addr.clone(),
+        });
+    }
+
+    ok(&format!("kicked {}", username))
+}
@@ -0,0 +1,178 @@
+use handlers::admin::TOKEN_FILE;
+use handlers::config::Config;
+use messaging_protocol::admin::{AdminReply, AdminRequest};
+use messaging_protocol::crypto::read_secret;
+use messaging_protocol::framing::FramedStream;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, process};
+
+const USAGE: &str =
+    "Usage: messaging_admin [--token-file FILE] [--config FILE] [--SETTING VALUE]... COMMAND
+
+Commands:
+  users                  every user the gateway knows and whether they are around
+  caches                 how much the gateway holds for each recipient
+  inspect RECIPIENT      the messages held for one recipient, without their text
+  purge RECIPIENT [ID]   drop one or all of the messages held for a recipient
+  kick USERNAME          close a user's session and take them out of the ring
+  drain                  turn new clients away and exit once the rest are gone
+  shutdown               exit right away
+
+The admin address and the token come from the gateway's own config, so run
+it where the gateway runs or give the same --config and flags, for example
+--admin-port 9014 or --state-dir /var/gateway";
+
+// How long to wait on a gateway that doesn't answer
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/*
+ * Turn the command words into a request
+*/
+
+fn parse_command(words: &[String]) -> Result<AdminRequest, String> {
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    let request = match words.as_slice() {
+        ["users"] => AdminRequest::Users,
+        ["caches"] => AdminRequest::Caches,
+        ["inspect", recipient] => AdminRequest::Inspect {
+            recipient: recipient.to_string(),
+        },
+        ["purge", recipient] => AdminRequest::Purge {
+            recipient: recipient.to_string(),
+            id: None,
+        },
+        ["purge", recipient, id] => AdminRequest::Purge {
+            recipient: recipient.to_string(),
+            id: Some(id.to_string()),
+        },
+        ["kick", username] => AdminRequest::Kick {
+            username: username.to_string(),
+        },
+        ["drain"] => AdminRequest::Drain,
+        ["shutdown"] => AdminRequest::Shutdown,
+        [] => return Err("no command given".to_string()),
+        _ => return Err(format!("{} is not a command", words.join(" "))),
+    };
+    Ok(request)
+}
+
+/*
+ * Send one request and wait for its reply
+*/
+
+fn ask(stream: &mut FramedStream<TcpStream>, request: &AdminRequest) -> Result<AdminReply, String> {
+    stream
+        .write_frame(&request.encode())
+        .map_err(|e| e.to_string())?;
+    let frame = stream.read_frame().map_err(|e| e.to_string())?;
+    AdminReply::decode(&frame).map_err(|e| e.to_string())
+}
+
+/*
+ * Connect to the admin listener, authenticate and run the request
+*/
+
+fn run(addr: SocketAddr, token: String, request: AdminRequest) -> Result<AdminReply, String> {
+    let stream = TcpStream::connect_timeout(&addr, TIMEOUT)
+        .map_err(|e| format!("couldn't reach the gateway at {}: {}", addr, e))?;
+    _ = stream.set_read_timeout(Some(TIMEOUT));
+    let mut stream = FramedStream::new(stream);
+
+    match ask(&mut stream, &AdminRequest::Auth { token })? {
+        AdminReply::Ok { .. } => (),
+        AdminReply::Denied { reason } => return Err(reason),
+        other => return Err(format!("unexpected reply {}", other)),
+    }
+    ask(&mut stream, &request)
+}
+
+/*
+ * Print a reply the way an operator wants to read it
+*/
+
+fn print_reply(reply: AdminReply) -> Result<(), String> {
+    match reply {
+        AdminReply::Ok { detail } => println!("{}", detail),
+        AdminReply::Denied { reason } => return Err(reason),
+        AdminReply::Users { users } => {
+            for user in users {
+                let capabilities: Vec<&str> = user.capabilities.iter().map(|c| c.name()).collect();
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    user.username,
+                    user.addr,
+                    user.presence.name(),
+                    capabilities.join(",")
+                );
+            }
+        }
+        AdminReply::Caches { caches } => {
+            for cache in caches {
+                println!(
+                    "{}\t{} messages\t{} bytes",
+                    cache.recipient, cache.messages, cache.bytes
+                );
+            }
+        }
+        AdminReply::Messages { messages, .. } => {
+            for message in messages {
+                println!(
+                    "{}\tfrom {}\t{} bytes\tcached at {}",
+                    message.id, message.sender, message.bytes, message.cached_at
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn fail(error: &str) -> ! {
+    eprintln!("{}", error);
+    process::exit(1);
+}
+
+fn main() {
+    if env::args().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    // Flags come before the command, all but --token-file are the
+    // gateway's own settings
+    let mut args = env::args().skip(1).peekable();
+    let mut settings = Vec::new();
+    let mut token_file = None;
+    while let Some(flag) = args.next_if(|arg| arg.starts_with("--")) {
+        let value = match args.next() {
+            Some(value) => value,
+            None => fail(&format!("{} needs a value\n\n{}", flag, USAGE)),
+        };
+        if flag == "--token-file" {
+            token_file = Some(PathBuf::from(value));
+        } else {
+            settings.push(flag);
+            settings.push(value);
+        }
+    }
+    let command: Vec<String> = args.collect();
+
+    let config =
+        Config::from_args(settings).unwrap_or_else(|e| fail(&format!("{}\n\n{}", e, USAGE)));
+    let request = parse_command(&command).unwrap_or_else(|e| fail(&format!("{}\n\n{}", e, USAGE)));
+
+    let token_file = token_file.unwrap_or(Path::new(&config.state_dir).join(TOKEN_FILE));
+    let token = read_secret(&token_file)
+        .unwrap_or_else(|e| fail(&format!("couldn't read {}: {}", token_file.display(), e)));
+
+    // A gateway listening everywhere is reached through loopback
+    let mut addr = config.admin_addr();
+    if addr.ip().is_unspecified() {
+        addr.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+
+    if let Err(e) = run(addr, token, request).and_then(print_reply) {
+        fail(&e);
+    }
+}
@@ -0,0 +1,268 @@
+use crate::logging::LogLevel;
+use local_ip_address::local_ip;
+use messaging_protocol::framing::MAX_FRAME_LEN;
+use messaging_protocol::message::NetworkParams;
+use serde::Deserialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use toml::{Table, Value};
+
+// Where the gateway looks for its settings
+pub const CONFIG_FILE: &str = "gateway.toml";
+
+pub const USAGE: &str = "Usage: messaging_server [--config FILE] [--SETTING VALUE]...
+
+Every setting in the config file can be given on the command line, with
+dashes in place of underscores. Flags win over the file, and --bind can be
+given more than once:
+
+  messaging_server --bind 0.0.0.0 --bind :: --port 9000 --log-level debug";
+
+/*
+ * Settings an operator can change without rebuilding. Anything left out of
+ * the file keeps the default, the network settings are announced to every
+ * client that INITs
+*/
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub group_size: u32,
+    pub replication: u32,
+    pub cache_ttl: u32,
+    pub max_cached_messages: u32,
+    pub max_cached_bytes: u32,
+    // Where the journal and snapshots are kept
+    pub state_dir: String,
+    // How many journal entries to collect before taking a snapshot
+    pub snapshot_every: u32,
+    // Addresses to listen on, the machine's own address if none are given.
+    // A port of 0 lets the system pick a free one
+    pub bind: Vec<String>,
+    pub port: u16,
+    // Largest frame a connection may send, and how many socket events one
+    // poll hands back
+    pub max_frame_len: usize,
+    pub events_capacity: usize,
+    pub log_level: LogLevel,
+    // Where operators reach the admin channel, loopback unless changed. The
+    // token it asks for is kept in state_dir
+    pub admin_bind: String,
+    pub admin_port: u16,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        let params = NetworkParams::default();
+        Config {
+            group_size: params.group_size,
+            replication: params.replication,
+            cache_ttl: params.cache_ttl,
+            max_cached_messages: params.max_cached_messages,
+            max_cached_bytes: params.max_cached_bytes,
+            state_dir: "gateway_state".to_string(),
+            snapshot_every: 1000,
+            bind: Vec::new(),
+            port: 8013,
+            max_frame_len: MAX_FRAME_LEN,
+            events_capacity: 1024,
+            log_level: LogLevel::Info,
+            admin_bind: "127.0.0.1".to_string(),
+            admin_port: 8014,
+        }
+    }
+}
+
+impl Config {
+    /*
+     * Read the config file, a missing file means every setting is default
+     */
+
+    pub fn load(path: &str) -> Result<Config, String> {
+        let table = read_table(path, false)?;
+        Config::from_table(table).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /*
+     * Parse the contents of a config file and check the settings
+     */
+
+    pub fn from_toml(text: &str) -> Result<Config, String> {
+        let table: Table = toml::from_str(text).map_err(|e| e.to_string())?;
+        Config::from_table(table)
+    }
+
+    /*
+     * Build the config from command line arguments (without the program
+     * name). The file named by --config, or gateway.toml, is read first and
+     * every other --name value pair replaces the setting of that name
+     */
+
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Config, String> {
+        let mut path = None;
+        let mut overrides = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            let name = match flag.strip_prefix("--") {
+                Some(name) => name.replace('-', "_"),
+                None => return Err(format!("unexpected argument {}", flag)),
+            };
+            let value = args.next().ok_or(format!("{} needs a value", flag))?;
+
+            if name == "config" {
+                path = Some(value);
+            } else {
+                overrides.push((name, value));
+            }
+        }
+
+        // A file that was asked for by name has to be there
+        let mut table = match &path {
+            Some(path) => read_table(path, true)?,
+            None => read_table(CONFIG_FILE, false)?,
+        };
+
+        // The first --bind replaces the addresses in the file, the rest add
+        // to it
+        let mut binds = Vec::new();
+        for (name, value) in overrides {
+            if name == "bind" {
+                binds.push(Value::String(value));
+            } else {
+                table.insert(name, flag_value(&value));
+            }
+        }
+        if !binds.is_empty() {
+            table.insert("bind".to_string(), Value::Array(binds));
+        }
+
+        Config::from_table(table)
+    }
+
+    fn from_table(table: Table) -> Result<Config, String> {
+        let config: Config = table
+            .try_into()
+            .map_err(|e: toml::de::Error| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /*
+     * Catch settings that can't work before any client sees them
+     */
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.group_size == 0 {
+            return Err("group_size must be at least 1".to_string());
+        }
+        if self.replication == 0 || self.replication > self.group_size {
+            return Err("replication must be between 1 and group_size".to_string());
+        }
+        if self.max_cached_messages == 0 || self.max_cached_bytes == 0 {
+            return Err("the cache quotas must be at least 1".to_string());
+        }
+        if self.snapshot_every == 0 {
+            return Err("snapshot_every must be at least 1".to_string());
+        }
+        if self.max_frame_len == 0 || self.events_capacity == 0 {
+            return Err("max_frame_len and events_capacity must be at least 1".to_string());
+        }
+        if let Some(addr) = self.bind.iter().find(|a| a.parse::<IpAddr>().is_err()) {
+            return Err(format!("{} is not an IP address", addr));
+        }
+        if let Some((a, b)) = overlapping(&self.bind) {
+            return Err(format!("bind addresses {} and {} overlap", a, b));
+        }
+        if self.admin_bind.parse::<IpAddr>().is_err() {
+            return Err(format!("{} is not an IP address", self.admin_bind));
+        }
+        Ok(())
+    }
+
+    /*
+     * The socket addresses to listen on
+     */
+
+    pub fn listen_addrs(&self) -> Vec<SocketAddr> {
+        if self.bind.is_empty() {
+            let ip = local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+            return vec![SocketAddr::new(ip, self.port)];
+        }
+
+        self.bind
+            .iter()
+            .filter_map(|addr| addr.parse().ok())
+            .map(|ip| SocketAddr::new(ip, self.port))
+            .collect()
+    }
+
+    pub fn admin_addr(&self) -> SocketAddr {
+        let ip = self
+            .admin_bind
+            .parse()
+            .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        SocketAddr::new(ip, self.admin_port)
+    }
+
+    pub fn params(&self) -> NetworkParams {
+        NetworkParams {
+            group_size: self.group_size,
+            replication: self.replication,
+            cache_ttl: self.cache_ttl,
+            max_cached_messages: self.max_cached_messages,
+            max_cached_bytes: self.max_cached_bytes,
+        }
+    }
+}
+
+/*
+ * Read a config file into a table. A missing file is an empty table
+ * unless it was asked for by name
+*/
+
+fn read_table(path: &str, required: bool) -> Result<Table, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound && !required => return Ok(Table::new()),
+        Err(e) => return Err(format!("couldn't read {}: {}", path, e)),
+    };
+
+    toml::from_str(&text).map_err(|e| format!("{}: {}", path, e))
+}
+
+/*
+ * A flag's value is read as a toml value when it is one (numbers, true and
+ * false, quoted strings) and as a plain string otherwise, so addresses and
+ * paths don't need quotes
+*/
+
+fn flag_value(raw: &str) -> Value {
+    match toml::from_str::<Table>(&format!("value = {}", raw)) {
+        Ok(mut table) => table
+            .remove("value")
+            .unwrap_or(Value::String(raw.to_string())),
+        Err(_) => Value::String(raw.to_string()),
+    }
+}
+
+/*
+ * Two addresses of one family that can't both be listened on with the same
+ * port: the same address twice, or one next to the address that takes all
+ * of them. IPv6 listeners only take IPv6, so "0.0.0.0" and "::" don't
+ * overlap
+*/
+
+fn overlapping(bind: &[String]) -> Option<(IpAddr, IpAddr)> {
+    let ips: Vec<IpAddr> = bind.iter().filter_map(|addr| addr.parse().ok()).collect();
+
+    for (i, a) in ips.iter().enumerate() {
+        for b in &ips[i + 1..] {
+            if a.is_ipv4() == b.is_ipv4() && (a == b || a.is_unspecified() || b.is_unspecified()) {
+                return Some((*a, *b));
+            }
+        }
+    }
+    None
+}
@@ -1,64 +1,434 @@
+use messaging_protocol::crypto::{
+    init_proof, load_secret, message_proof, random_bytes, to_hex, verify,
+};
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::hash::{in_group, select_group};
+use messaging_protocol::journal::now;
+use messaging_protocol::message::{
+    negotiate_version, Capability, Message, NetworkParams, StoredMessage, LEGACY_VERSION,
+    MIN_PROTOCOL_VERSION,
+};
+use messaging_protocol::ratchet::{prekey_proof, PrekeyBundle};
+use messaging_protocol::tokens::{message_digest, today, TokenKey};
 use mio::net::TcpStream;
 use mio::Token;
-use std::collections::HashMap;
-use std::convert::From;
-use std::io::Write;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::time::{Duration, Instant};
 
+#[macro_use]
+pub mod logging;
+pub mod admin;
+pub mod config;
+pub mod store;
 mod utils;
-use utils::{calculate_hash, User};
+use store::{Record, Store};
+use utils::{CachedMessage, Prekeys, SpentToken, User};
+pub use utils::{PendingInit, Tokens};
 
-// Define types of our storage structures
-pub type CacheMap = HashMap<String, Vec<String>>;
+// Define types of our storage structures, cached messages are kept per
+// recipient and keyed by message id
+pub type CacheMap = HashMap<String, BTreeMap<String, CachedMessage>>;
 pub type ConnMap = HashMap<String, User>;
-pub type SockMap = HashMap<Token, TcpStream>;
+pub type Connection = FramedStream<TcpStream>;
+pub type SockMap = HashMap<Token, Connection>;
 pub type UserList = Vec<String>;
+pub type ChallengeMap = HashMap<Token, PendingInit>;
+pub type SpentMap = HashMap<String, SpentToken>;
 
-// Hyperparameter defining group size
-const GROUP_SIZE: u32 = 2;
-pub const DELIMITER: &str = "&&";
+// Which user each signed in socket belongs to, so per frame lookups don't
+// walk every user
+pub type SessionMap = HashMap<Token, String>;
+
+// A user we haven't heard from in this long is treated as gone
+const IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+// How long a gone user keeps its place in the buddy ring, so a short
+// disconnect doesn't reshuffle everyone's groups
+const GRACE_PERIOD: Duration = Duration::from_secs(2 * 60);
+
+// How many random bytes a client signs to prove its identity
+const NONCE_LEN: usize = 32;
+
+// The most one-time prekeys kept for one user, extra ones are dropped
+const MAX_ONE_TIME_PREKEYS: usize = 100;
+
+// How many delivery tokens we issue per user in a window, so nobody can
+// flood other users' buddies through us
+const MAX_TOKENS: usize = 120;
+const TOKEN_WINDOW: Duration = Duration::from_secs(60);
+
+// The file in the state directory holding the key delivery tokens are
+// issued with
+pub const TOKEN_KEY_FILE: &str = "token.key";
+
+// Features this gateway offers to clients
+const SERVER_CAPABILITIES: [Capability; 3] = [
+    Capability::ServerRelay,
+    Capability::BuddyCache,
+    Capability::Encrypted,
+];
+
+/*
+ * Read the key delivery tokens are issued with from the state directory,
+ * making up a new one the first time
+*/
+
+pub fn load_token_key(state_dir: &str) -> io::Result<TokenKey> {
+    let path = Path::new(state_dir).join(TOKEN_KEY_FILE);
+    let secret = load_secret(&path, || Ok(TokenKey::generate()?.secret_hex()))?;
+    TokenKey::from_secret_hex(&secret)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("{}: {}", TOKEN_KEY_FILE, e)))
+}
+
+/*
+ * Check the protocol version a client opened with. Clients newer than
+ * version 1 are told the version and capabilities we have in common and
+ * how the network is set up before anything else, along with the key
+ * our delivery tokens are issued with. Clients we can't
+ * serve are refused and disconnected. Returns whether to go on with INIT
+*/
+
+pub fn handle_version(
+    token: &Token,
+    sockets: &mut SockMap,
+    version: u32,
+    capabilities: &[Capability],
+    params: &NetworkParams,
+    token_key: &TokenKey,
+) -> bool {
+    let shared: Vec<Capability> = SERVER_CAPABILITIES
+        .iter()
+        .filter(|c| capabilities.contains(c))
+        .copied()
+        .collect();
+
+    let agreed = match negotiate_version(version) {
+        Some(agreed) if !shared.is_empty() => agreed,
+        Some(_) => {
+            refuse(
+                token,
+                sockets,
+                "no capabilities in common with this gateway",
+            );
+            return false;
+        }
+        None => {
+            let reason = format!(
+                "protocol version {} is not supported, use version {} or newer",
+                version, MIN_PROTOCOL_VERSION
+            );
+            refuse(token, sockets, &reason);
+            return false;
+        }
+    };
+
+    // Version 1 clients don't know the VERSION message
+    if version > LEGACY_VERSION {
+        write_m(
+            sockets,
+            token,
+            Message::Version {
+                version: agreed,
+                capabilities: shared,
+                params: *params,
+                token_key: Some(token_key.public_hex()),
+            },
+        );
+    }
+
+    true
+}
+
+/*
+ * A username bound to an identity is only handed to a client that can sign
+ * for it. An INIT with an identity is sent a fresh nonce to sign and held
+ * until PROVE comes back, one without is registered right away as long as
+ * the name isn't bound. Returns the INIT to register now, if any
+*/
+
+pub fn handle_identity(
+    token: &Token,
+    sockets: &mut SockMap,
+    mut init: PendingInit,
+    connections: &ConnMap,
+    challenges: &mut ChallengeMap,
+) -> Option<PendingInit> {
+    if let Some(reason) = identity_conflict(&init, connections) {
+        refuse(token, sockets, &reason);
+        return None;
+    }
+    if init.identity.is_none() {
+        return Some(init);
+    }
+
+    init.nonce = match random_bytes::<NONCE_LEN>() {
+        Ok(bytes) => to_hex(&bytes),
+        Err(e) => {
+            error!("Couldn't make a challenge: {}", e);
+            refuse(
+                token,
+                sockets,
+                "the gateway can't check identities right now",
+            );
+            return None;
+        }
+    };
+    write_m(
+        sockets,
+        token,
+        Message::Challenge {
+            nonce: init.nonce.clone(),
+        },
+    );
+    challenges.insert(*token, init);
+    None
+}
+
+/*
+ * Check the signature over the challenge this connection was sent. Returns
+ * the INIT to register if it verifies, a client that fails is refused
+*/
+
+pub fn handle_prove(
+    token: &Token,
+    sockets: &mut SockMap,
+    username: &str,
+    signature: &str,
+    connections: &ConnMap,
+    challenges: &mut ChallengeMap,
+) -> Option<PendingInit> {
+    let init = match challenges.remove(token) {
+        Some(init) if init.username == username => init,
+        _ => {
+            handle_error(token, sockets, &format!("no challenge for {}", username));
+            return None;
+        }
+    };
+
+    let identity = init.identity.as_deref().unwrap_or_default();
+    let proof = init_proof(
+        &init.nonce,
+        &init.username,
+        &init.addr,
+        init.key.as_deref().unwrap_or_default(),
+        identity,
+    );
+    if let Err(e) = verify(identity, signature, &proof) {
+        info!("{} failed to prove its identity: {}", username, e);
+        refuse(
+            token,
+            sockets,
+            &format!("couldn't prove the identity: {}", e),
+        );
+        return None;
+    }
+
+    // Someone else may have claimed the name while we waited
+    if let Some(reason) = identity_conflict(&init, connections) {
+        refuse(token, sockets, &reason);
+        return None;
+    }
+    Some(init)
+}
+
+fn identity_conflict(init: &PendingInit, connections: &ConnMap) -> Option<String> {
+    let bound = connections.get(&init.username)?.identity.as_ref()?;
+    if init.identity.as_ref() == Some(bound) {
+        None
+    } else {
+        Some(format!("{} belongs to another identity", init.username))
+    }
+}
 
 /*
- * Handle init messages from a new connection. User is either old, so we
- * find the old information and update or we create an entirely new user
+ * Tell a client why it isn't let in and hang up on it
 */
 
+fn refuse(token: &Token, sockets: &mut SockMap, reason: &str) {
+    write_m(
+        sockets,
+        token,
+        Message::Refused {
+            reason: reason.to_string(),
+        },
+    );
+    sockets.remove(token);
+}
+
+/*
+ * Handle init messages from a new connection, once any identity it sent is
+ * proven. User is either old, so we find the old information and update
+ * or we create an entirely new user
+*/
+
+#[allow(clippy::too_many_arguments)]
 pub fn handle_init(
     token: &Token,
     sockets: &mut SockMap,
-    message: &str,
+    init: PendingInit,
     connections: &mut ConnMap,
+    sessions: &mut SessionMap,
     user_list: &mut UserList,
-) -> Option<usize> {
-    // Split the message into tokens
-    let (username, ip) = message.split_once(DELIMITER).unwrap();
+    params: &NetworkParams,
+    store: &mut Store,
+) {
+    let PendingInit {
+        username,
+        addr,
+        capabilities,
+        key,
+        identity,
+        ..
+    } = init;
+    let (username, ip) = (username.as_str(), addr.as_str());
 
-    let mut message = String::from("BUDDIES ");
+    let mut message = Message::Buddies {
+        username: username.to_string(),
+        buddies: Vec::new(),
+    };
 
-    // See if this user exists
-    let tval = match connections.get_mut(username) {
+    // See if this user exists. A move in the ring remembers where it was
+    let mut moved = false;
+    let mut moved_from = None;
+    match connections.get_mut(username) {
         Some(user) => {
+            // A user that changed networks is moved to its new address
+            // everywhere, keeping its place in the buddy ring
+            if user.ip_addr != ip {
+                if let Some(slot) = user_list.iter_mut().find(|addr| **addr == user.ip_addr) {
+                    *slot = ip.to_string();
+                    _ = store.log(Record::Move {
+                        from: user.ip_addr.clone(),
+                        to: ip.to_string(),
+                    });
+                    moved_from = Some(user.ip_addr.clone());
+                }
+                user.ip_addr = ip.to_string();
+                moved = true;
+            }
+
+            // A user that was gone long enough to leave the ring gets back in
+            if capabilities.contains(&Capability::BuddyCache) && !user_list.contains(&user.ip_addr)
+            {
+                user_list.push(user.ip_addr.clone());
+                _ = store.log(Record::Join {
+                    addr: user.ip_addr.clone(),
+                });
+            }
+
             // Get buddies before updating vals
-            message = get_buddies(user, user_list);
+            message = get_buddies(username, user_list, params);
+
+            // The new socket takes over the session, the old one is closed
+            if user.token != *token {
+                sockets.remove(&user.token);
+                sessions.remove(&user.token);
+                user.token = *token;
+            }
 
-            // If they do, update total and reregister with existing token #
-            user.total_users = user_list.len() as u32;
-            Some(usize::from(user.token))
+            // A client that sent no key keeps the one it registered before,
+            // and a name stays bound to its identity
+            let key = key.or_else(|| user.key.clone());
+            let identity = identity.or_else(|| user.identity.clone());
+            if moved
+                || user.capabilities != capabilities
+                || user.key != key
+                || user.identity != identity
+            {
+                _ = store.log(Record::User {
+                    username: username.to_string(),
+                    addr: user.ip_addr.clone(),
+                    capabilities: capabilities.clone(),
+                    key: key.clone(),
+                    identity: identity.clone(),
+                });
+            }
+
+            user.capabilities = capabilities;
+            user.key = key;
+            user.identity = identity;
+            user.last_seen = Instant::now();
+            user.offline_since = None;
         }
         None => {
-            // If they do not, register them in connections arr
+            // If they do not, register them in connections arr. Only clients
+            // that can cache for others are handed out as buddies
+            _ = store.log(Record::User {
+                username: username.to_string(),
+                addr: ip.to_string(),
+                capabilities: capabilities.clone(),
+                key: key.clone(),
+                identity: identity.clone(),
+            });
+            if capabilities.contains(&Capability::BuddyCache) {
+                user_list.push(ip.to_string());
+                _ = store.log(Record::Join {
+                    addr: ip.to_string(),
+                });
+            }
+
             let new_user = User {
                 token: *token,
                 ip_addr: ip.to_string(),
-                total_users: user_list.len() as u32,
+                capabilities,
+                key,
+                identity,
+                prekeys: None,
+                last_seen: Instant::now(),
+                offline_since: None,
+                issued: VecDeque::new(),
             };
             connections.insert(username.to_string(), new_user);
-            user_list.push(ip.to_string());
-            None
         }
     };
+    sessions.insert(*token, username.to_string());
+
+    write_m(sockets, token, message);
+
+    if let Some(from) = moved_from {
+        notify_groups(username, &from, ip, sockets, connections, user_list, params);
+    }
+}
+
+/*
+ * Tell every other online user whose buddy group held the old address or
+ * holds the new one what their group looks like now. Only their groups
+ * changed, so only they get theirs picked again
+*/
 
-    write_m(sockets.get_mut(&token).unwrap(), message);
-    tval
+fn notify_groups(
+    username: &str,
+    from: &str,
+    to: &str,
+    sockets: &mut SockMap,
+    connections: &ConnMap,
+    user_list: &UserList,
+    params: &NetworkParams,
+) {
+    let size = params.group_size as usize;
+    let before: Vec<String> = user_list
+        .iter()
+        .map(|addr| if addr == to { from } else { addr })
+        .map(str::to_string)
+        .collect();
+
+    for (name, user) in connections {
+        if name == username || user.offline_since.is_some() {
+            continue;
+        }
+
+        if in_group(name, to, user_list, size) || in_group(name, from, &before, size) {
+            write_m(
+                sockets,
+                &user.token,
+                Message::UpdateGroup {
+                    members: select_group(name, user_list, size),
+                },
+            );
+        }
+    }
 }
 
 /*
@@ -71,115 +441,336 @@ pub fn handle_buddies(
     username: &str,
     connections: &ConnMap,
     user_list: &UserList,
-) -> Option<usize> {
+    params: &NetworkParams,
+) {
     // Try to get the user from the connections table
-    if let Some(user) = connections.get(username) {
-        let buddies = get_buddies(user, user_list);
-        write_m(sockets.get_mut(&token).unwrap(), buddies);
+    if connections.contains_key(username) {
+        let buddies = get_buddies(username, user_list, params);
+        write_m(sockets, token, buddies);
     } else {
         // Send back not found if we don't find the user
         write_m(
-            sockets.get_mut(&token).unwrap(),
-            "404 User Not Found".to_string(),
+            sockets,
+            token,
+            Message::NotFound {
+                reason: "User Not Found".to_string(),
+            },
         );
     }
-
-    None
 }
 
 /*
- * Helper function to get the list of buddies
+ * Helper function to get the list of buddies. Groups are picked with
+ * rendezvous hashing, so a user joining or leaving the ring only moves the
+ * groups it ranks in
 */
 
-fn get_buddies(user: &User, user_list: &UserList) -> String {
-    // Function to get evenly distributed, but also changing buddies
-    let t = user.total_users;
-    let seed: u32 = calculate_hash(&user) as u32;
-
-    let groups = t / GROUP_SIZE;
-    let mut offset = 1;
-    if groups > 0 {
-        offset = seed % groups;
+fn get_buddies(username: &str, user_list: &UserList, params: &NetworkParams) -> Message {
+    Message::Buddies {
+        username: username.to_string(),
+        buddies: select_group(username, user_list, params.group_size as usize),
     }
-
-    let mut returner = String::from("BUDDIES ");
-
-    for n in 0..GROUP_SIZE {
-        returner += DELIMITER;
-        returner += &user_list[(offset + (n * groups)) as usize];
-    }
-
-    returner
 }
 
 /*
  * If the server is sent a message, ack this message to take
- * responsibility, then forward it and add to the cache
+ * responsibility, then forward it and add to the cache. A receiver whose
+ * cache is over its quota gets nothing and the sender is told why
 */
 
+#[allow(clippy::too_many_arguments)]
 pub fn handle_send(
     token: &Token,
     sockets: &mut SockMap,
-    message: &str,
+    receiver: &str,
+    orig_message: StoredMessage,
     connections: &mut ConnMap,
     cache: &mut CacheMap,
-) -> Option<usize> {
-    // Pull the sender and receiver out
-    let (receiver, orig_message) = message.split_once(";").unwrap();
-    let (_, encrypted_message) = orig_message.split_once(";").unwrap();
-    let mut message = String::new();
-
-    // Try to find the receiver's struct in connections
-    if let Some(user) = connections.get(receiver) {
-        // Try to get the stream associated with the user's token
-        if let Some(stream) = sockets.get_mut(&user.token) {
-            // Send the message
-            write_m(stream, "SEND ".to_owned() + orig_message);
-            println!("Sent {}", message);
-        }
-
-        // Send an ack to the sender as we now take responsibility for delivery
-        message = format!("ACK {};{}", receiver, encrypted_message);
-        let orig_message = orig_message.to_string();
-
-        // Create a cache or add the message to the receiver's cache in case it is not delivered
-        if let Some(v) = cache.get_mut(receiver) {
-            v.push(orig_message);
+    params: &NetworkParams,
+    tokens: &mut Tokens,
+    store: &mut Store,
+) {
+    let message;
+
+    // A forged or full message is turned away, otherwise try to find the
+    // receiver's struct in connections
+    let refusal = forged(receiver, &orig_message, connections, tokens, store)
+        .or_else(|| over_quota(receiver, &orig_message, cache, params));
+    if let Some(reason) = refusal {
+        message = Message::Rejected {
+            username: receiver.to_string(),
+            id: orig_message.id,
+            reason,
+        };
+    } else if let Some(user) = connections.get(receiver) {
+        // Add the message to the receiver's cache in case it is not delivered,
+        // a resent message with the same id only gets stored once. It is on
+        // disk before the sender gets the ack, if it can't get there the
+        // sender keeps it
+        let cached_at = now();
+        let logged = store.log(Record::Cache {
+            recipient: receiver.to_string(),
+            message: orig_message.clone(),
+            cached_at,
+        });
+
+        if let Err(e) = logged {
+            message = Message::Rejected {
+                username: receiver.to_string(),
+                id: orig_message.id,
+                reason: format!("the gateway couldn't store it: {}", e),
+            };
         } else {
-            cache.insert(receiver.to_string(), vec![orig_message]);
+            // Try to get the stream associated with the user's token
+            write_m(
+                sockets,
+                &user.token,
+                Message::Send {
+                    recipient: receiver.to_string(),
+                    sender: orig_message.sender.clone(),
+                    id: orig_message.id.clone(),
+                    body: orig_message.body.clone(),
+                    signature: orig_message.signature.clone(),
+                },
+            );
+
+            // Send an ack to the sender as we now take responsibility for delivery
+            message = Message::Ack {
+                username: receiver.to_string(),
+                id: orig_message.id.clone(),
+            };
+            cache.entry(receiver.to_string()).or_default().insert(
+                orig_message.id.clone(),
+                CachedMessage {
+                    message: orig_message,
+                    cached_at,
+                },
+            );
         }
     } else {
         // If we can't find the receiver, indicate that to the sender
-        message = format!("404 {} not found", receiver);
-        println!("Sent {}", message);
+        message = Message::NotFound {
+            reason: format!("{} not found", receiver),
+        };
     }
 
     // Write the message to the receiver
-    write_m(sockets.get_mut(&token).unwrap(), message);
-    None
+    write_m(sockets, token, message);
 }
 
 /*
- * Confirm message was received, so remove it from the cache
+ * Why the receiver's cache can't take the message, None if it can. A
+ * message that is already held is never turned away
 */
 
-pub fn handle_ack(message: &str, cache: &mut CacheMap) -> Option<usize> {
-    // Remove the message from the cache one there is a receipt
-    let (username, orig_message) = message.split_once(";").unwrap();
+fn over_quota(
+    receiver: &str,
+    message: &StoredMessage,
+    cache: &CacheMap,
+    params: &NetworkParams,
+) -> Option<String> {
+    let (held, bytes) = match cache.get(receiver) {
+        Some(pending) if pending.contains_key(&message.id) => return None,
+        Some(pending) => (
+            pending.len(),
+            pending.values().map(|c| c.message.size()).sum(),
+        ),
+        None => (0, 0),
+    };
 
-    // Get the users cache if it exists (it should always)
-    let mut default = Vec::<String>::new();
-    let user_cache: &mut Vec<String> = match cache.get_mut(username) {
-        Some(v) => v,
-        None => &mut default,
+    params.check_quota(receiver, held, bytes, message).err()
+}
+
+/*
+ * Whether username is signed in, and signed in on the connection behind
+ * token
+*/
+
+fn signed_in_as(token: &Token, username: &str, connections: &ConnMap) -> bool {
+    matches!(
+        connections.get(username),
+        Some(user) if user.token == *token && user.offline_since.is_none()
+    )
+}
+
+/*
+ * Send a user everything we are still holding for them. Messages stay in
+ * the cache until the user acks them by id. Only the connection the user
+ * is signed in on may fetch them
+*/
+
+pub fn handle_fetch(
+    token: &Token,
+    sockets: &mut SockMap,
+    username: &str,
+    connections: &ConnMap,
+    cache: &CacheMap,
+) {
+    if !signed_in_as(token, username, connections) {
+        return handle_error(token, sockets, &format!("not registered as {}", username));
+    }
+
+    let messages = match cache.get(username) {
+        Some(pending) => pending.values().map(|c| c.message.clone()).collect(),
+        None => Vec::new(),
     };
 
-    // Try and remove the message by idx from the cache
-    if let Some(index) = user_cache.iter().position(|x| *x == orig_message) {
-        user_cache.remove(index);
+    write_m(sockets, token, Message::Update { messages });
+}
+
+/*
+ * Confirm message was received, so remove it from the cache. Only the
+ * recipient, on the connection they are signed in on, may ack it
+*/
+
+pub fn handle_ack(
+    token: &Token,
+    sockets: &mut SockMap,
+    username: &str,
+    id: &str,
+    connections: &ConnMap,
+    cache: &mut CacheMap,
+    store: &mut Store,
+) {
+    if !signed_in_as(token, username, connections) {
+        return handle_error(token, sockets, &format!("not registered as {}", username));
     }
 
-    None
+    // Remove the message by id from the users cache if it exists (it should always)
+    if let Some(user_cache) = cache.get_mut(username) {
+        if user_cache.remove(id).is_some() {
+            _ = store.log(Record::Ack {
+                recipient: username.to_string(),
+                id: id.to_string(),
+            });
+        }
+    }
+}
+
+/*
+ * Drop every cached message older than the ttl, like it was acked
+*/
+
+pub fn expire_cache(cache: &mut CacheMap, ttl: u64, store: &mut Store) {
+    let cutoff = now().saturating_sub(ttl);
+
+    for (recipient, pending) in cache.iter_mut() {
+        pending.retain(|id, cached| {
+            if cached.cached_at >= cutoff {
+                return true;
+            }
+            _ = store.log(Record::Ack {
+                recipient: recipient.clone(),
+                id: id.clone(),
+            });
+            false
+        });
+    }
+    cache.retain(|_, pending| !pending.is_empty());
+}
+
+/*
+ * A user says goodbye, so they leave the buddy ring right away and their
+ * session is closed
+*/
+
+pub fn handle_leave(
+    token: &Token,
+    sockets: &mut SockMap,
+    username: &str,
+    connections: &mut ConnMap,
+    sessions: &mut SessionMap,
+    user_list: &mut UserList,
+    store: &mut Store,
+) {
+    // Only the user's own session may say they are leaving
+    match connections.get_mut(username) {
+        Some(user) if user.token == *token => {
+            user.offline_since = Some(Instant::now());
+            user_list.retain(|addr| *addr != user.ip_addr);
+            _ = store.log(Record::Part {
+                addr: user.ip_addr.clone(),
+            });
+            sockets.remove(token);
+            sessions.remove(token);
+        }
+        _ => handle_error(
+            token,
+            sockets,
+            &format!("{} is not logged in here", username),
+        ),
+    }
+}
+
+/*
+ * The user signed in on this token, if the session is still theirs
+*/
+
+fn session_user<'a>(
+    token: &Token,
+    connections: &'a mut ConnMap,
+    sessions: &SessionMap,
+) -> Option<&'a mut User> {
+    let user = connections.get_mut(sessions.get(token)?)?;
+    if user.token != *token {
+        return None;
+    }
+    Some(user)
+}
+
+/*
+ * The socket with this token closed, so whoever it belonged to is offline
+*/
+
+pub fn handle_disconnect(token: &Token, connections: &mut ConnMap, sessions: &mut SessionMap) {
+    if let Some(user) = session_user(token, connections, sessions) {
+        user.offline_since.get_or_insert_with(Instant::now);
+    }
+    sessions.remove(token);
+}
+
+/*
+ * Note that we just heard from the user behind this token
+*/
+
+pub fn handle_activity(token: &Token, connections: &mut ConnMap, sessions: &SessionMap) {
+    if let Some(user) = session_user(token, connections, sessions) {
+        user.last_seen = Instant::now();
+        user.offline_since = None;
+    }
+}
+
+/*
+ * Mark users that went quiet as offline and take users that have been
+ * offline for longer than the grace period out of the buddy ring
+*/
+
+pub fn sweep_presence(
+    sockets: &SockMap,
+    connections: &mut ConnMap,
+    user_list: &mut UserList,
+    store: &mut Store,
+) {
+    let now = Instant::now();
+
+    for user in connections.values_mut() {
+        if user.offline_since.is_none() {
+            if !sockets.contains_key(&user.token) {
+                user.offline_since = Some(now);
+            } else if now.duration_since(user.last_seen) > IDLE_TIMEOUT {
+                user.offline_since = Some(user.last_seen + IDLE_TIMEOUT);
+            }
+        }
+
+        if let Some(since) = user.offline_since {
+            if now.duration_since(since) > GRACE_PERIOD && user_list.contains(&user.ip_addr) {
+                user_list.retain(|addr| *addr != user.ip_addr);
+                _ = store.log(Record::Part {
+                    addr: user.ip_addr.clone(),
+                });
+            }
+        }
+    }
 }
 
 /*
@@ -191,36 +782,335 @@ pub fn handle_ip_retrieval(
     sockets: &mut SockMap,
     username: &str,
     connections: &ConnMap,
-) -> Option<usize> {
+) {
+    let message = match connections.get(username) {
+        Some(User { ip_addr, .. }) => Message::IpRetrieval {
+            addr: ip_addr.clone(),
+        },
+        None => Message::NotFound {
+            reason: "not found".to_string(),
+        },
+    };
+
+    write_m(sockets, token, message);
+}
+
+/*
+ * Look up the public key messages to a user are encrypted with, along with
+ * the identity their messages are signed with
+*/
+
+pub fn handle_key_fetch(
+    token: &Token,
+    sockets: &mut SockMap,
+    username: &str,
+    connections: &ConnMap,
+) {
     let message = match connections.get(username) {
         Some(User {
-            token: _,
-            ip_addr,
-            total_users: _,
-        }) => String::from("IP_RETRIEVAL ") + ip_addr,
-        None => String::from("404 not found"),
+            key: Some(key),
+            identity,
+            ..
+        }) => Message::Key {
+            username: username.to_string(),
+            key: key.clone(),
+            identity: identity.clone(),
+        },
+        _ => Message::NotFound {
+            reason: format!("no key for {}", username),
+        },
     };
 
-    write_m(sockets.get_mut(&token).unwrap(), message);
+    write_m(sockets, token, message);
+}
 
-    None
+/*
+ * Keep the prekeys a user published, replacing the ones from before. Only
+ * the connection the user is registered on may publish them, and the
+ * signed prekey has to be signed by the identity their name is bound to.
+ * The user is answered with the bundle others will be handed, without a
+ * one-time prekey
+*/
+
+#[allow(clippy::too_many_arguments)]
+pub fn handle_prekeys(
+    token: &Token,
+    sockets: &mut SockMap,
+    username: &str,
+    signed_prekey: String,
+    signature: String,
+    mut one_time: Vec<String>,
+    connections: &mut ConnMap,
+    store: &mut Store,
+) {
+    let user = match connections.get_mut(username) {
+        Some(user) if user.token == *token && user.offline_since.is_none() => user,
+        _ => return handle_error(token, sockets, &format!("not registered as {}", username)),
+    };
+    let identity = match (&user.identity, &user.key) {
+        (Some(identity), Some(_)) => identity,
+        _ => {
+            return handle_error(
+                token,
+                sockets,
+                &format!("{} needs a key and an identity for prekeys", username),
+            )
+        }
+    };
+    if let Err(e) = verify(identity, &signature, &prekey_proof(&signed_prekey)) {
+        return handle_error(token, sockets, &format!("bad signed prekey: {}", e));
+    }
+
+    one_time.truncate(MAX_ONE_TIME_PREKEYS);
+    let prekeys = Prekeys {
+        signed_prekey,
+        signature,
+        one_time: one_time.into(),
+    };
+    _ = store.log(Record::Prekeys {
+        username: username.to_string(),
+        prekeys: prekeys.clone(),
+    });
+    user.prekeys = Some(prekeys);
+
+    let message = bundle(username, user, None);
+    write_m(sockets, token, message);
 }
 
 /*
- * Handle errors, should not ever be reached
+ * Hand out what it takes to start a session with a user, along with the
+ * oldest of their one-time prekeys, which nobody else will get
 */
 
-pub fn handle_error(message: &str) -> Option<usize> {
-    println!("received error {}", message);
-    None
+pub fn handle_bundle_fetch(
+    token: &Token,
+    sockets: &mut SockMap,
+    username: &str,
+    connections: &mut ConnMap,
+    store: &mut Store,
+) {
+    let message = match connections.get_mut(username) {
+        Some(user) if user.prekeys.is_some() => {
+            let one_time = user
+                .prekeys
+                .as_mut()
+                .and_then(|prekeys| prekeys.one_time.pop_front());
+            if let Some(one_time) = &one_time {
+                _ = store.log(Record::Claim {
+                    username: username.to_string(),
+                    one_time: one_time.clone(),
+                });
+            }
+            bundle(username, user, one_time)
+        }
+        _ => Message::NotFound {
+            reason: format!("no prekeys for {}", username),
+        },
+    };
+
+    write_m(sockets, token, message);
+}
+
+/*
+ * A user's bundle, for a user who published prekeys
+*/
+
+fn bundle(username: &str, user: &User, one_time: Option<String>) -> Message {
+    let prekeys = user.prekeys.as_ref().expect("the user published prekeys");
+    Message::Bundle {
+        username: username.to_string(),
+        bundle: PrekeyBundle {
+            identity: user.identity.clone().unwrap_or_default(),
+            key: user.key.clone().unwrap_or_default(),
+            signed_prekey: prekeys.signed_prekey.clone(),
+            signature: prekeys.signature.clone(),
+            one_time,
+        },
+    }
+}
+
+/*
+ * A sender bound to an identity has to sign every message with it.
+ * Returns why the message can't be from them, if it can't. A sealed
+ * message doesn't say who it is from and may come in on any connection,
+ * it has to carry a delivery token that is spent on it
+*/
+
+fn forged(
+    receiver: &str,
+    message: &StoredMessage,
+    connections: &ConnMap,
+    tokens: &mut Tokens,
+    store: &mut Store,
+) -> Option<String> {
+    if message.sender.is_empty() {
+        let token = match &message.signature {
+            Some(token) => token,
+            None => return Some("sealed messages need a delivery token".to_string()),
+        };
+        let digest = message_digest(receiver, &message.id, &message.body);
+        return spend(token, &digest, tokens, store).err();
+    }
+
+    let identity = connections.get(&message.sender)?.identity.as_ref()?;
+    let signature = match &message.signature {
+        Some(signature) => signature,
+        None => return Some(format!("{} signs their messages", message.sender)),
+    };
+
+    let proof = message_proof(receiver, &message.sender, &message.id, &message.body);
+    match verify(identity, signature, &proof) {
+        Ok(()) => None,
+        Err(e) => Some(format!("not signed by {}: {}", message.sender, e)),
+    }
+}
+
+/*
+ * Spend a delivery token on the message with the digest. A token can be
+ * spent on the same message again, it goes to every buddy the message
+ * does, but never on another one. Tokens are taken from the day before
+ * to the day after ours, so clocks that are a little off don't matter
+*/
+
+fn spend(token: &str, digest: &str, tokens: &mut Tokens, store: &mut Store) -> Result<(), String> {
+    let day = tokens
+        .key
+        .check(token)
+        .map_err(|e| format!("not issued by this gateway: {}", e))?;
+    if day + 1 < today() || day > today() + 1 {
+        return Err("the delivery token is out of date".to_string());
+    }
+
+    match tokens.spent.get(token) {
+        Some(spent) if spent.digest == digest => return Ok(()),
+        Some(_) => return Err("the delivery token was spent on another message".to_string()),
+        None => (),
+    }
+
+    // Written down before anyone is told, so a restart can't spend it twice
+    let spent = SpentToken {
+        day,
+        digest: digest.to_string(),
+    };
+    store
+        .log(Record::Spend {
+            token: token.to_string(),
+            spent: spent.clone(),
+        })
+        .map_err(|e| format!("the gateway couldn't store it: {}", e))?;
+    tokens.spent.insert(token.to_string(), spent);
+    Ok(())
 }
 
 /*
- * Helper method to write messages to a stream
+ * Forget tokens too old to be taken anymore
 */
 
-fn write_m(stream: &mut TcpStream, message: String) {
-    println!("Writing back: {}", message);
-    stream.write_all(message.as_bytes()).unwrap();
-    stream.flush().unwrap();
+pub fn expire_tokens(tokens: &mut Tokens) {
+    tokens.spent.retain(|_, spent| spent.day + 1 >= today());
+}
+
+/*
+ * Sign the blinded nonces a registered user sends us, so they can seal
+ * messages later without us learning which were theirs. Each user only
+ * gets so many tokens a minute, a request for more than that is refused
+ * until the window moves on
+*/
+
+pub fn handle_tokens(
+    token: &Token,
+    sockets: &mut SockMap,
+    blinded: &[String],
+    connections: &mut ConnMap,
+    sessions: &SessionMap,
+    key: &TokenKey,
+) {
+    let user = match session_user(token, connections, sessions) {
+        Some(user) if user.offline_since.is_none() => user,
+        _ => return handle_error(token, sockets, "only registered users get tokens"),
+    };
+
+    let now = Instant::now();
+    while user
+        .issued
+        .front()
+        .is_some_and(|at| now.duration_since(*at) >= TOKEN_WINDOW)
+    {
+        user.issued.pop_front();
+    }
+
+    let message = if user.issued.len() + blinded.len() > MAX_TOKENS {
+        Message::Refused {
+            reason: "too many delivery tokens, try again in a minute".to_string(),
+        }
+    } else {
+        match blinded.iter().map(|b| key.issue(b)).collect() {
+            Ok(tokens) => {
+                user.issued.extend(blinded.iter().map(|_| now));
+                Message::Issued { tokens }
+            }
+            Err(e) => Message::Refused {
+                reason: format!("can't sign that: {}", e),
+            },
+        }
+    };
+
+    write_m(sockets, token, message);
+}
+
+/*
+ * Spend a delivery token for whoever asks, a buddy checking a sealed
+ * message it was handed or its sender making sure nobody else spends it
+ * first. Only the digest of the message is sent, not who it is for
+*/
+
+pub fn handle_redeem(
+    token: &Token,
+    sockets: &mut SockMap,
+    delivery_token: &str,
+    digest: &str,
+    tokens: &mut Tokens,
+    store: &mut Store,
+) {
+    let message = match spend(delivery_token, digest, tokens, store) {
+        Ok(()) => Message::Redeemed {
+            token: delivery_token.to_string(),
+        },
+        Err(reason) => Message::Refused { reason },
+    };
+
+    write_m(sockets, token, message);
+}
+
+/*
+ * Handle requests we can't parse or don't serve by telling the sender why
+*/
+
+pub fn handle_error(token: &Token, sockets: &mut SockMap, message: &str) {
+    info!("received error {}", message);
+    write_m(
+        sockets,
+        token,
+        Message::NotFound {
+            reason: message.to_string(),
+        },
+    );
+}
+
+/*
+ * Helper method to write messages to a stream. Whatever the socket can't
+ * take right now stays queued until it is writable again, a socket that
+ * is gone or broken is dropped
+*/
+
+fn write_m(sockets: &mut SockMap, token: &Token, message: Message) {
+    if let Some(stream) = sockets.get_mut(token) {
+        debug!("Writing back: {}", message);
+        stream.queue_frame(&message.encode());
+
+        if let Err(e) = stream.flush_pending() {
+            error!("Dropping connection, err={:?}", e);
+            sockets.remove(token);
+        }
+    }
 }
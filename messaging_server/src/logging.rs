@@ -0,0 +1,53 @@
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/*
+ * How much the gateway prints. Each level includes the ones before it, so
+ * info also shows errors and debug shows every message that comes and goes
+*/
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Info,
+    Debug,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Error) {
+            println!($($arg)*);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Info) {
+            println!($($arg)*);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Debug) {
+            println!($($arg)*);
+        }
+    };
+}
@@ -1,16 +1,49 @@
+use handlers::admin::{handle_admin, load_token, AdminChannel, Control};
+use handlers::config::{Config, USAGE};
+use handlers::logging::set_level;
+use handlers::store::Store;
+use handlers::{debug, error, info};
 use handlers::{
-    handle_ack, handle_buddies, handle_error, handle_init, handle_ip_retrieval, handle_send,
-    CacheMap, ConnMap, SockMap, UserList,
+    expire_cache, expire_tokens, handle_ack, handle_activity, handle_buddies, handle_bundle_fetch,
+    handle_disconnect, handle_error, handle_fetch, handle_identity, handle_init,
+    handle_ip_retrieval, handle_key_fetch, handle_leave, handle_prekeys, handle_prove,
+    handle_redeem, handle_send, handle_tokens, handle_version, load_token_key, sweep_presence,
+    CacheMap, ChallengeMap, ConnMap, PendingInit, SessionMap, SockMap, SpentMap, Tokens, UserList,
 };
-use local_ip_address::local_ip;
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::{Capability, Message, NetworkParams, StoredMessage};
 use mio::net::TcpListener;
 use mio::{Events, Interest, Poll, Token};
+use socket2::{Domain, Socket, Type};
 use std::collections::HashMap;
-use std::io::{self, Read};
-use std::process;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use std::{env, process};
 
-const PORT: u16 = 8013;
-const LISTENER: Token = Token(0);
+// How often we check who has gone quiet or away and drop expired messages
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+// How many connections may wait to be accepted, the same as mio's default
+const BACKLOG: i32 = 1024;
+
+/*
+ * Listen on addr the way TcpListener::bind does, except that an IPv6
+ * listener only takes IPv6. Otherwise "::" also claims the port on every
+ * IPv4 address, and a "0.0.0.0" listener next to it can't be opened
+*/
+
+fn listen(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(TcpListener::from_std(socket.into()))
+}
 
 /*
  * Handles new connection requests by setting aside a new port in hardward
@@ -22,6 +55,7 @@ fn listener_poll(
     poll: &Poll,
     sockets: &mut SockMap,
     socket_index: &mut usize,
+    max_frame_len: usize,
 ) {
     loop {
         match listener.accept() {
@@ -30,145 +64,600 @@ fn listener_poll(
                 let token = Token(*socket_index);
                 *socket_index += 1;
 
-                // Register the new socket w/ poll
-                poll.registry()
-                    .register(&mut socket, token, Interest::READABLE | Interest::WRITABLE)
-                    .unwrap();
+                // Register the new socket w/ poll, a socket we can't watch is dropped
+                if let Err(e) = poll.registry().register(
+                    &mut socket,
+                    token,
+                    Interest::READABLE | Interest::WRITABLE,
+                ) {
+                    error!("err={:?}", e);
+                    continue;
+                }
 
-                // Store the socket
-                sockets.insert(token, socket);
+                // Store the socket along with its frame buffers
+                let mut stream = FramedStream::new(socket);
+                stream.set_max_frame_len(max_frame_len);
+                sockets.insert(token, stream);
             }
             // Socket is not ready anymore, stop accepting
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                 break;
             }
-            // Unexpected error
-            e => panic!("err={:?}", e),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            // Unexpected error, try again on the next event
+            Err(e) => {
+                error!("err={:?}", e);
+                break;
+            }
         }
     }
 }
 
 /*
- * If a connection has new bytes, handle them apropriately
+ * If a connection has new bytes, buffer them and handle every complete
+ * frame that has arrived so far
 */
 
+#[allow(clippy::too_many_arguments)]
 fn token_poll(
-    poll: &Poll,
     token: &Token,
     sockets: &mut SockMap,
-    buf: &mut [u8],
     connections: &mut ConnMap,
+    sessions: &mut SessionMap,
+    challenges: &mut ChallengeMap,
     cache: &mut CacheMap,
     user_list: &mut UserList,
+    params: &NetworkParams,
+    tokens: &mut Tokens,
+    store: &mut Store,
 ) {
-    loop {
-        if let Some(stream) = sockets.get_mut(&token) {
-            match stream.read(buf) {
-                Ok(0) => {
-                    // Socket is closed, remove it from the map
-                    sockets.remove(&token);
-                    break;
-                }
-                // Data is not actually sent in this example
-                Ok(i) => {
-                    let (code, message) = std::str::from_utf8(&buf[..i])
-                        .unwrap()
-                        .split_once(" ")
-                        .unwrap();
-                    println!("This is the message: {}:{}", code, message);
-                    // Handle based on the status code
-                    let t_val = match code {
-                        "ACK" => handle_ack(message, cache),
-                        "SEND" => handle_send(token, sockets, message, connections, cache),
-                        "INIT" => handle_init(token, sockets, message, connections, user_list),
-                        "IP_FETCH" => handle_ip_retrieval(token, sockets, message, connections),
-                        "BUDDIES" => {
-                            handle_buddies(token, sockets, message, connections, user_list)
-                        }
-                        "SHUTDOWN" => process::exit(0),
-                        _ => handle_error(message),
-                    };
-
-                    // Handle individuals who already have an "id"
-                    if let Some(t) = t_val {
-                        let mut socket = sockets.remove(token).unwrap();
-                        poll.registry()
-                            .reregister(
-                                &mut socket,
-                                Token(t),
-                                Interest::READABLE | Interest::WRITABLE,
-                            )
-                            .unwrap();
+    let token = *token;
+
+    // Push out anything an earlier write left queued
+    if let Some(stream) = sockets.get_mut(&token) {
+        if let Err(e) = stream.flush_pending() {
+            error!("err={:?}", e);
+            sockets.remove(&token);
+            handle_disconnect(&token, connections, sessions);
+            return;
+        }
+    }
+
+    while let Some(stream) = sockets.get_mut(&token) {
+        match stream.fill() {
+            Ok(0) => {
+                // Socket is closed, remove it from the map
+                sockets.remove(&token);
+                handle_disconnect(&token, connections, sessions);
+                break;
+            }
+            Ok(_) => loop {
+                // Pull the next whole frame, bytes of a partial one stay buffered
+                let frame = match sockets.get_mut(&token).map(|s| s.next_frame()) {
+                    Some(Ok(Some(frame))) => frame,
+                    Some(Err(e)) => {
+                        error!("err={:?}", e);
                         sockets.remove(&token);
-                        sockets.insert(Token(t), socket);
+                        handle_disconnect(&token, connections, sessions);
+                        return;
                     }
+                    _ => break,
+                };
+
+                handle_activity(&token, connections, sessions);
+                handle_frame(
+                    &token,
+                    sockets,
+                    &frame,
+                    connections,
+                    sessions,
+                    challenges,
+                    cache,
+                    user_list,
+                    params,
+                    tokens,
+                    store,
+                );
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                // Socket is not ready anymore, stop reading
+                break;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                // Unexpected error, the connection is no good anymore
+                error!("err={:?}", e);
+                sockets.remove(&token);
+                handle_disconnect(&token, connections, sessions);
+                break;
+            }
+        }
+    }
+}
+
+/*
+ * Parse a single frame and dispatch it based on its status code
+*/
+
+#[allow(clippy::too_many_arguments)]
+fn handle_frame(
+    token: &Token,
+    sockets: &mut SockMap,
+    frame: &[u8],
+    connections: &mut ConnMap,
+    sessions: &mut SessionMap,
+    challenges: &mut ChallengeMap,
+    cache: &mut CacheMap,
+    user_list: &mut UserList,
+    params: &NetworkParams,
+    tokens: &mut Tokens,
+    store: &mut Store,
+) {
+    let message = match Message::decode(frame) {
+        Ok(message) => message,
+        Err(e) => return handle_error(token, sockets, &e.to_string()),
+    };
+    debug!("This is the message: {}", message);
+
+    // Handle based on the status code
+    match message {
+        Message::Ack { username, id } => {
+            handle_ack(token, sockets, &username, &id, connections, cache, store)
+        }
+        Message::Send {
+            recipient,
+            sender,
+            id,
+            body,
+            signature,
+        } => handle_send(
+            token,
+            sockets,
+            &recipient,
+            StoredMessage {
+                sender,
+                id,
+                body,
+                signature,
+            },
+            connections,
+            cache,
+            params,
+            tokens,
+            store,
+        ),
+        Message::Tokens { blinded } => {
+            handle_tokens(token, sockets, &blinded, connections, sessions, &tokens.key)
+        }
+        Message::Redeem {
+            token: spent,
+            digest,
+        } => handle_redeem(token, sockets, &spent, &digest, tokens, store),
+        Message::Init {
+            username,
+            addr,
+            version,
+            capabilities,
+            key,
+            identity,
+        } => {
+            // Only register clients that speak a version we can serve
+            if !handle_version(token, sockets, version, &capabilities, params, &tokens.key) {
+                return;
+            }
+
+            let init = PendingInit {
+                username,
+                addr,
+                capabilities,
+                key,
+                identity,
+                nonce: String::new(),
+            };
+            if let Some(init) = handle_identity(token, sockets, init, connections, challenges) {
+                register(
+                    token,
+                    sockets,
+                    init,
+                    connections,
+                    sessions,
+                    cache,
+                    user_list,
+                    params,
+                    store,
+                );
+            }
+        }
+        Message::Prove {
+            username,
+            signature,
+        } => {
+            let init = handle_prove(
+                token,
+                sockets,
+                &username,
+                &signature,
+                connections,
+                challenges,
+            );
+            if let Some(init) = init {
+                register(
+                    token,
+                    sockets,
+                    init,
+                    connections,
+                    sessions,
+                    cache,
+                    user_list,
+                    params,
+                    store,
+                );
+            }
+        }
+        Message::IpFetch { username } => {
+            handle_ip_retrieval(token, sockets, &username, connections)
+        }
+        Message::KeyFetch { username } => handle_key_fetch(token, sockets, &username, connections),
+        Message::Prekeys {
+            username,
+            signed_prekey,
+            signature,
+            one_time,
+        } => handle_prekeys(
+            token,
+            sockets,
+            &username,
+            signed_prekey,
+            signature,
+            one_time,
+            connections,
+            store,
+        ),
+        Message::BundleFetch { username } => {
+            handle_bundle_fetch(token, sockets, &username, connections, store)
+        }
+        Message::Fetch { username } => handle_fetch(token, sockets, &username, connections, cache),
+        Message::Leave { username } => handle_leave(
+            token,
+            sockets,
+            &username,
+            connections,
+            sessions,
+            user_list,
+            store,
+        ),
+        Message::Buddies { username, .. } => {
+            handle_buddies(token, sockets, &username, connections, user_list, params)
+        }
+        other => handle_error(
+            token,
+            sockets,
+            &format!("{} is not handled by the gateway", other.code()),
+        ),
+    }
+}
+
+/*
+ * Register a client whose INIT went through, and hand a relay client
+ * whatever was sent while it was away
+*/
+
+#[allow(clippy::too_many_arguments)]
+fn register(
+    token: &Token,
+    sockets: &mut SockMap,
+    init: PendingInit,
+    connections: &mut ConnMap,
+    sessions: &mut SessionMap,
+    cache: &mut CacheMap,
+    user_list: &mut UserList,
+    params: &NetworkParams,
+    store: &mut Store,
+) {
+    let username = init.username.clone();
+    let relayed = init.capabilities.contains(&Capability::ServerRelay);
+    handle_init(
+        token,
+        sockets,
+        init,
+        connections,
+        sessions,
+        user_list,
+        params,
+        store,
+    );
+
+    if relayed {
+        handle_fetch(token, sockets, &username, connections, cache);
+    }
+}
+
+/*
+ * Handle every complete frame an admin connection has sent, the same way
+ * token_poll does for clients. Returns what the main loop should do next
+*/
+
+#[allow(clippy::too_many_arguments)]
+fn admin_poll(
+    token: &Token,
+    admin: &mut AdminChannel,
+    sockets: &mut SockMap,
+    connections: &mut ConnMap,
+    sessions: &mut SessionMap,
+    cache: &mut CacheMap,
+    user_list: &mut UserList,
+    store: &mut Store,
+) -> Control {
+    while let Some(stream) = admin.sessions.get_mut(token) {
+        match stream.fill() {
+            Ok(0) => {
+                admin.close(token);
+                break;
+            }
+            Ok(_) => loop {
+                let frame = match admin.sessions.get_mut(token).map(|s| s.next_frame()) {
+                    Some(Ok(Some(frame))) => frame,
+                    Some(Err(e)) => {
+                        error!("err={:?}", e);
+                        admin.close(token);
+                        return Control::Continue;
+                    }
+                    _ => break,
+                };
+
+                let control = handle_admin(
+                    token,
+                    admin,
+                    &frame,
+                    sockets,
+                    connections,
+                    sessions,
+                    cache,
+                    user_list,
+                    store,
+                );
+                if control != Control::Continue {
+                    return control;
                 }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    // Socket is not ready anymore, stop reading
-                    break;
-                }
-                e => println!("err={:?}", e), // Unexpected error
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                error!("err={:?}", e);
+                admin.close(token);
+                break;
             }
-        } else {
-            break;
         }
     }
+
+    Control::Continue
 }
 
 /*
  * Loop through the poll and handle bytes when they come through a stream
 */
 
-fn run_server(mut conn: ConnMap, mut cache: CacheMap, mut user_list: UserList) {
+fn run_server(
+    config: Config,
+    mut tokens: Tokens,
+    mut admin: AdminChannel,
+    mut store: Store,
+    mut conn: ConnMap,
+    mut cache: CacheMap,
+    mut user_list: UserList,
+) {
+    let params = config.params();
+    let cache_ttl = config.cache_ttl.into();
+
     // Create poll and appropriate objects
     let mut poll = Poll::new().unwrap();
     let mut sockets: SockMap = HashMap::new();
-    let mut events = Events::with_capacity(1024);
-    let mut socket_index = 1;
-
-    // Create listener and buffer
-    let mut listener = TcpListener::bind(
-        format!("{:?}:{}", local_ip().unwrap(), PORT)
-            .parse()
-            .unwrap(),
-    )
-    .unwrap();
+    let mut challenges: ChallengeMap = HashMap::new();
+    let mut sessions: SessionMap = HashMap::new();
+    let mut events = Events::with_capacity(config.events_capacity);
+    let mut last_sweep = Instant::now();
+
+    // Create a listener for every address, they take the first tokens and
+    // sockets get the ones after them
+    let mut listeners = Vec::new();
+    for addr in config.listen_addrs() {
+        let mut listener = match listen(addr) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("couldn't listen on {}: {}", addr, e);
+                process::exit(1);
+            }
+        };
+        poll.registry()
+            .register(&mut listener, Token(listeners.len()), Interest::READABLE)
+            .unwrap();
+        if let Ok(addr) = listener.local_addr() {
+            info!("Listening on {}", addr);
+        }
+        listeners.push(listener);
+    }
+
+    // The admin listener takes the token after them
+    let admin_token = Token(listeners.len());
+    let mut admin_listener = match listen(config.admin_addr()) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("couldn't listen on {}: {}", config.admin_addr(), e);
+            process::exit(1);
+        }
+    };
     poll.registry()
-        .register(&mut listener, LISTENER, Interest::READABLE)
+        .register(&mut admin_listener, admin_token, Interest::READABLE)
         .unwrap();
+    if let Ok(addr) = admin_listener.local_addr() {
+        info!("Admin listening on {}", addr);
+    }
+    let mut socket_index = admin_token.0 + 1;
+    let mut draining = false;
 
     loop {
-        // Wait for events
-        poll.poll(&mut events, None).unwrap();
+        // Wait for events, a signal interrupting the wait is not a problem
+        if let Err(e) = poll.poll(&mut events, Some(SWEEP_INTERVAL)) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            panic!("err={:?}", e);
+        }
 
         // Iterate through events
         for event in &events {
-            let mut buf = [0; 1024];
+            let mut control = Control::Continue;
             match event.token() {
-                LISTENER => {
-                    listener_poll(&mut listener, &poll, &mut sockets, &mut socket_index);
+                Token(i) if i < listeners.len() => {
+                    listener_poll(
+                        &mut listeners[i],
+                        &poll,
+                        &mut sockets,
+                        &mut socket_index,
+                        config.max_frame_len,
+                    );
+                }
+                token if token == admin_token => {
+                    listener_poll(
+                        &mut admin_listener,
+                        &poll,
+                        &mut admin.sessions,
+                        &mut socket_index,
+                        config.max_frame_len,
+                    );
+                }
+                token if admin.sessions.contains_key(&token) => {
+                    control = admin_poll(
+                        &token,
+                        &mut admin,
+                        &mut sockets,
+                        &mut conn,
+                        &mut sessions,
+                        &mut cache,
+                        &mut user_list,
+                        &mut store,
+                    );
                 }
                 token => {
                     token_poll(
-                        &poll,
                         &token,
                         &mut sockets,
-                        &mut buf,
                         &mut conn,
+                        &mut sessions,
+                        &mut challenges,
                         &mut cache,
                         &mut user_list,
+                        &params,
+                        &mut tokens,
+                        &mut store,
                     );
                 }
             }
+
+            match control {
+                Control::Continue => (),
+                // Everything is in the journal already, nothing is lost
+                Control::Shutdown => {
+                    info!("Shutting down");
+                    process::exit(0);
+                }
+                // Closing the public listeners turns new clients away,
+                // the ones already here are served until they go
+                Control::Drain => {
+                    info!("Draining");
+                    for listener in listeners.iter_mut() {
+                        _ = poll.registry().deregister(listener);
+                    }
+                    listeners.clear();
+                    draining = true;
+                }
+            }
+        }
+
+        if draining && sockets.is_empty() {
+            info!("Drained, shutting down");
+            process::exit(0);
+        }
+
+        // Check who went away every so often, even when nothing happens
+        if last_sweep.elapsed() >= SWEEP_INTERVAL {
+            sweep_presence(&sockets, &mut conn, &mut user_list, &mut store);
+            expire_cache(&mut cache, cache_ttl, &mut store);
+            expire_tokens(&mut tokens);
+            challenges.retain(|token, _| sockets.contains_key(token));
+            last_sweep = Instant::now();
         }
+
+        // Fold the journal into a snapshot once it has grown long
+        store.compact(&conn, &cache, &user_list, &tokens.spent);
     }
 }
 
 fn main() {
-    let active_connections: ConnMap = HashMap::new();
-    let cached_messages: CacheMap = HashMap::new();
-    let user_list: UserList = Vec::new();
-    run_server(active_connections, cached_messages, user_list);
+    if env::args().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    // Settings come from the config file and the command line, a broken
+    // one stops us early
+    let config = match Config::from_args(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}\n\n{}", e, USAGE);
+            process::exit(1);
+        }
+    };
+    set_level(config.log_level);
+
+    // Pick up where the last run left off
+    let mut active_connections: ConnMap = HashMap::new();
+    let mut cached_messages: CacheMap = HashMap::new();
+    let mut user_list: UserList = Vec::new();
+    let mut spent: SpentMap = HashMap::new();
+    let store = match Store::open(
+        &config.state_dir,
+        config.snapshot_every,
+        &mut active_connections,
+        &mut cached_messages,
+        &mut user_list,
+        &mut spent,
+    ) {
+        Ok(store) => store,
+        Err(e) => {
+            println!("couldn't open the state in {}: {}", config.state_dir, e);
+            process::exit(1);
+        }
+    };
+
+    // Operators have to present this token on the admin listener
+    let admin = match load_token(&config.state_dir) {
+        Ok(token) => AdminChannel::new(token),
+        Err(e) => {
+            println!(
+                "couldn't read the admin token in {}: {}",
+                config.state_dir, e
+            );
+            process::exit(1);
+        }
+    };
+
+    // Delivery tokens for sealed messages are issued with this
+    let tokens = match load_token_key(&config.state_dir) {
+        Ok(key) => Tokens { key, spent },
+        Err(e) => {
+            println!("couldn't read the token key in {}: {}", config.state_dir, e);
+            process::exit(1);
+        }
+    };
 
-    println!("Hello, world!");
+    run_server(
+        config,
+        tokens,
+        admin,
+        store,
+        active_connections,
+        cached_messages,
+        user_list,
+    );
 }
@@ -0,0 +1,463 @@
+use crate::utils::{CachedMessage, Prekeys, SpentToken, User};
+use crate::{CacheMap, ConnMap, SpentMap, UserList};
+use messaging_protocol::journal::Journal;
+use messaging_protocol::message::{escape, unescape, Capability, StoredMessage, FIELD_SEP};
+use mio::Token;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::Instant;
+
+// Files kept in the state directory
+const JOURNAL_FILE: &str = "journal.log";
+const SNAPSHOT_FILE: &str = "snapshot.log";
+
+// Restored users have no socket until they INIT again, token 0 always
+// belongs to a listener
+const NO_SOCKET: Token = Token(0);
+
+/*
+ * One change to the gateway's tables. Records are written one per line as
+ * a code followed by ;-separated fields escaped like they are on the wire:
+ *
+ *   USER username;addr;capability,capability;key;identity
+ *   PREKEYS username;signed_prekey;signature;one_time,one_time
+ *   CLAIM username;one_time
+ *   JOIN addr
+ *   PART addr
+ *   MOVE from;to
+ *   CACHE cached_at;recipient;sender;id;body;signature
+ *   ACK recipient;id
+ *   SPEND day;token;digest
+ *
+ * Applying a record twice leaves the tables as applying it once, so a
+ * journal that overlaps the snapshot can be replayed on top of it
+*/
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Record {
+    User {
+        username: String,
+        addr: String,
+        capabilities: Vec<Capability>,
+        key: Option<String>,
+        identity: Option<String>,
+    },
+    Prekeys {
+        username: String,
+        prekeys: Prekeys,
+    },
+    Claim {
+        username: String,
+        one_time: String,
+    },
+    Join {
+        addr: String,
+    },
+    Part {
+        addr: String,
+    },
+    Move {
+        from: String,
+        to: String,
+    },
+    Cache {
+        recipient: String,
+        message: StoredMessage,
+        cached_at: u64,
+    },
+    Ack {
+        recipient: String,
+        id: String,
+    },
+    Spend {
+        token: String,
+        spent: SpentToken,
+    },
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (code, fields) = match self {
+            Record::User {
+                username,
+                addr,
+                capabilities,
+                key,
+                identity,
+            } => {
+                let names: Vec<&str> = capabilities.iter().map(|c| c.name()).collect();
+                (
+                    "USER",
+                    vec![
+                        escape(username),
+                        escape(addr),
+                        names.join(","),
+                        escape(key.as_deref().unwrap_or_default()),
+                        escape(identity.as_deref().unwrap_or_default()),
+                    ],
+                )
+            }
+            Record::Prekeys { username, prekeys } => {
+                let one_time: Vec<&str> = prekeys.one_time.iter().map(String::as_str).collect();
+                (
+                    "PREKEYS",
+                    vec![
+                        escape(username),
+                        escape(&prekeys.signed_prekey),
+                        escape(&prekeys.signature),
+                        escape(&one_time.join(",")),
+                    ],
+                )
+            }
+            Record::Claim { username, one_time } => {
+                ("CLAIM", vec![escape(username), escape(one_time)])
+            }
+            Record::Join { addr } => ("JOIN", vec![escape(addr)]),
+            Record::Part { addr } => ("PART", vec![escape(addr)]),
+            Record::Move { from, to } => ("MOVE", vec![escape(from), escape(to)]),
+            Record::Cache {
+                recipient,
+                message,
+                cached_at,
+            } => (
+                "CACHE",
+                vec![
+                    cached_at.to_string(),
+                    escape(recipient),
+                    escape(&message.sender),
+                    escape(&message.id),
+                    escape(&message.body),
+                    escape(message.signature.as_deref().unwrap_or_default()),
+                ],
+            ),
+            Record::Ack { recipient, id } => ("ACK", vec![escape(recipient), escape(id)]),
+            Record::Spend { token, spent } => (
+                "SPEND",
+                vec![spent.day.to_string(), escape(token), escape(&spent.digest)],
+            ),
+        };
+        write!(f, "{} {}", code, fields.join(FIELD_SEP))
+    }
+}
+
+impl Record {
+    /*
+     * Read a record back from its line, None if the line is not one
+     */
+
+    pub fn parse(line: &str) -> Option<Record> {
+        let (code, rest) = line.split_once(' ')?;
+        let fields = rest
+            .split(FIELD_SEP)
+            .map(unescape)
+            .collect::<Result<Vec<String>, _>>()
+            .ok()?;
+
+        let record = match (code, fields.as_slice()) {
+            // Lines from before keys were kept leave them out
+            ("USER", [username, addr, capabilities, keys @ ..]) if keys.len() <= 2 => {
+                Record::User {
+                    username: username.clone(),
+                    addr: addr.clone(),
+                    capabilities: capabilities
+                        .split(',')
+                        .filter_map(Capability::from_name)
+                        .collect(),
+                    key: keys.first().filter(|key| !key.is_empty()).cloned(),
+                    identity: keys.get(1).filter(|key| !key.is_empty()).cloned(),
+                }
+            }
+            ("PREKEYS", [username, signed_prekey, signature, one_time]) => Record::Prekeys {
+                username: username.clone(),
+                prekeys: Prekeys {
+                    signed_prekey: signed_prekey.clone(),
+                    signature: signature.clone(),
+                    one_time: one_time
+                        .split(',')
+                        .filter(|key| !key.is_empty())
+                        .map(str::to_string)
+                        .collect(),
+                },
+            },
+            ("CLAIM", [username, one_time]) => Record::Claim {
+                username: username.clone(),
+                one_time: one_time.clone(),
+            },
+            ("JOIN", [addr]) => Record::Join { addr: addr.clone() },
+            ("PART", [addr]) => Record::Part { addr: addr.clone() },
+            ("MOVE", [from, to]) => Record::Move {
+                from: from.clone(),
+                to: to.clone(),
+            },
+            // Lines from before signatures end after the body
+            ("CACHE", [cached_at, recipient, sender, id, body, signature @ ..])
+                if !id.is_empty() && signature.len() <= 1 =>
+            {
+                Record::Cache {
+                    recipient: recipient.clone(),
+                    message: StoredMessage {
+                        sender: sender.clone(),
+                        id: id.clone(),
+                        body: body.clone(),
+                        signature: signature.first().filter(|s| !s.is_empty()).cloned(),
+                    },
+                    cached_at: cached_at.parse().ok()?,
+                }
+            }
+            ("ACK", [recipient, id]) => Record::Ack {
+                recipient: recipient.clone(),
+                id: id.clone(),
+            },
+            ("SPEND", [day, token, digest]) => Record::Spend {
+                token: token.clone(),
+                spent: SpentToken {
+                    day: day.parse().ok()?,
+                    digest: digest.clone(),
+                },
+            },
+            _ => return None,
+        };
+        Some(record)
+    }
+}
+
+/*
+ * Keeps the gateway's tables on disk. Every change is appended to the
+ * journal before the gateway answers for it, and once the journal grows
+ * long enough the whole state is written to a new snapshot and the journal
+ * starts over. On startup the snapshot and then the journal are replayed
+*/
+
+pub struct Store {
+    journal: Journal,
+    snapshot_every: u32,
+}
+
+impl Store {
+    /*
+     * Open the state kept in dir and rebuild the tables from it. Users
+     * come back offline, they keep their place in the ring for the grace
+     * period like after any disconnect
+     */
+
+    pub fn open(
+        dir: impl Into<PathBuf>,
+        snapshot_every: u32,
+        connections: &mut ConnMap,
+        cache: &mut CacheMap,
+        user_list: &mut UserList,
+        spent: &mut SpentMap,
+    ) -> io::Result<Store> {
+        let dir = dir.into();
+        let journal = Journal::open(dir.join(SNAPSHOT_FILE), dir.join(JOURNAL_FILE))?;
+
+        // Lines that don't parse are skipped
+        let (snapshot, changes) = journal.replay()?;
+        for record in snapshot
+            .iter()
+            .chain(&changes)
+            .filter_map(|l| Record::parse(l))
+        {
+            apply(record, connections, cache, user_list, spent);
+        }
+
+        let mut store = Store {
+            journal,
+            snapshot_every,
+        };
+
+        // Start from a fresh snapshot, which also drops a torn last line
+        store.snapshot(connections, cache, user_list, spent)?;
+        Ok(store)
+    }
+
+    /*
+     * Append a change to the journal and wait for it to reach the disk. A
+     * failed write is logged here, callers that promised the change to a
+     * client have to take the promise back
+     */
+
+    pub fn log(&mut self, record: Record) -> io::Result<()> {
+        let result = self.journal.append(&format!("{}\n", record), true);
+        if let Err(e) = &result {
+            error!("Couldn't write the journal: {}", e);
+        }
+        result
+    }
+
+    /*
+     * Take a snapshot if enough changes have piled up since the last one
+     */
+
+    pub fn compact(
+        &mut self,
+        connections: &ConnMap,
+        cache: &CacheMap,
+        user_list: &UserList,
+        spent: &SpentMap,
+    ) {
+        if self.journal.entries() < self.snapshot_every {
+            return;
+        }
+
+        if let Err(e) = self.snapshot(connections, cache, user_list, spent) {
+            error!("Couldn't write a snapshot: {}", e);
+        }
+    }
+
+    /*
+     * Write the whole state to a new snapshot and empty the journal
+     */
+
+    fn snapshot(
+        &mut self,
+        connections: &ConnMap,
+        cache: &CacheMap,
+        user_list: &UserList,
+        spent: &SpentMap,
+    ) -> io::Result<()> {
+        let records = records(connections, cache, user_list, spent);
+        self.journal.snapshot(records.iter().map(Record::to_string))
+    }
+}
+
+/*
+ * The records that rebuild the current state from nothing
+*/
+
+fn records(
+    connections: &ConnMap,
+    cache: &CacheMap,
+    user_list: &UserList,
+    spent: &SpentMap,
+) -> Vec<Record> {
+    let mut records = Vec::new();
+
+    for (username, user) in connections {
+        records.push(Record::User {
+            username: username.clone(),
+            addr: user.ip_addr.clone(),
+            capabilities: user.capabilities.clone(),
+            key: user.key.clone(),
+            identity: user.identity.clone(),
+        });
+        if let Some(prekeys) = &user.prekeys {
+            records.push(Record::Prekeys {
+                username: username.clone(),
+                prekeys: prekeys.clone(),
+            });
+        }
+    }
+    for addr in user_list {
+        records.push(Record::Join { addr: addr.clone() });
+    }
+    for (recipient, pending) in cache {
+        for cached in pending.values() {
+            records.push(Record::Cache {
+                recipient: recipient.clone(),
+                message: cached.message.clone(),
+                cached_at: cached.cached_at,
+            });
+        }
+    }
+    for (token, spent) in spent {
+        records.push(Record::Spend {
+            token: token.clone(),
+            spent: spent.clone(),
+        });
+    }
+
+    records
+}
+
+/*
+ * Make a change to the tables as it was made when the record was written
+*/
+
+fn apply(
+    record: Record,
+    connections: &mut ConnMap,
+    cache: &mut CacheMap,
+    user_list: &mut UserList,
+    spent: &mut SpentMap,
+) {
+    match record {
+        Record::User {
+            username,
+            addr,
+            capabilities,
+            key,
+            identity,
+        } => match connections.get_mut(&username) {
+            Some(user) => {
+                user.ip_addr = addr;
+                user.capabilities = capabilities;
+                user.key = key;
+                user.identity = identity;
+            }
+            None => {
+                let now = Instant::now();
+                let user = User {
+                    token: NO_SOCKET,
+                    ip_addr: addr,
+                    capabilities,
+                    key,
+                    identity,
+                    prekeys: None,
+                    last_seen: now,
+                    offline_since: Some(now),
+                    issued: VecDeque::new(),
+                };
+                connections.insert(username, user);
+            }
+        },
+        Record::Prekeys { username, prekeys } => {
+            if let Some(user) = connections.get_mut(&username) {
+                user.prekeys = Some(prekeys);
+            }
+        }
+        Record::Claim { username, one_time } => {
+            if let Some(prekeys) = connections
+                .get_mut(&username)
+                .and_then(|user| user.prekeys.as_mut())
+            {
+                prekeys.one_time.retain(|key| *key != one_time);
+            }
+        }
+        Record::Join { addr } => {
+            if !user_list.contains(&addr) {
+                user_list.push(addr);
+            }
+        }
+        Record::Part { addr } => user_list.retain(|member| *member != addr),
+        Record::Move { from, to } => {
+            if user_list.contains(&to) {
+                user_list.retain(|member| *member != from);
+            } else if let Some(slot) = user_list.iter_mut().find(|member| **member == from) {
+                *slot = to;
+            }
+        }
+        Record::Cache {
+            recipient,
+            message,
+            cached_at,
+        } => {
+            let id = message.id.clone();
+            let cached = CachedMessage { message, cached_at };
+            cache.entry(recipient).or_default().insert(id, cached);
+        }
+        Record::Ack { recipient, id } => {
+            if let Some(pending) = cache.get_mut(&recipient) {
+                pending.remove(&id);
+            }
+        }
+        Record::Spend {
+            token,
+            spent: entry,
+        } => {
+            spent.insert(token, entry);
+        }
+    }
+}
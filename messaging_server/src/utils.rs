@@ -1,23 +1,75 @@
+use messaging_protocol::message::{Capability, StoredMessage};
+use messaging_protocol::tokens::TokenKey;
 use mio::Token;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
 
 /*
- * This struct stores necessary data to identify a user
+ * This struct stores necessary data to identify a user, the public key
+ * messages to them are encrypted with, the identity their name is bound
+ * to and the prekeys others start sessions with, along with when we last
+ * heard from them, since when they have been gone and when we issued
+ * them delivery tokens lately
 */
-#[derive(Hash)]
 pub struct User {
     pub token: Token,
     pub ip_addr: String,
-    pub total_users: u32,
+    pub capabilities: Vec<Capability>,
+    pub key: Option<String>,
+    pub identity: Option<String>,
+    pub prekeys: Option<Prekeys>,
+    pub last_seen: Instant,
+    pub offline_since: Option<Instant>,
+    pub issued: VecDeque<Instant>,
 }
 
 /*
- * This has function calculates the hash value of a user
+ * A message the gateway holds for a recipient, with when it arrived in
+ * seconds since the epoch so it can expire across restarts
 */
+pub struct CachedMessage {
+    pub message: StoredMessage,
+    pub cached_at: u64,
+}
+
+/*
+ * The prekeys a user published. Every bundle handed out takes the oldest
+ * one-time prekey with it, once they run out bundles go without
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Prekeys {
+    pub signed_prekey: String,
+    pub signature: String,
+    pub one_time: VecDeque<String>,
+}
 
-pub fn calculate_hash<User: Hash>(t: &User) -> u64 {
-    let mut s = DefaultHasher::new();
-    t.hash(&mut s);
-    s.finish()
+/*
+ * The key delivery tokens are issued with, and the tokens spent lately
+*/
+pub struct Tokens {
+    pub key: TokenKey,
+    pub spent: HashMap<String, SpentToken>,
+}
+
+/*
+ * A delivery token that was spent, with the day it was made on and the
+ * digest of the message it paid for
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpentToken {
+    pub day: u64,
+    pub digest: String,
+}
+
+/*
+ * An INIT from a client with an identity, held until the client signs the
+ * nonce it was sent
+*/
+pub struct PendingInit {
+    pub username: String,
+    pub addr: String,
+    pub capabilities: Vec<Capability>,
+    pub key: Option<String>,
+    pub identity: Option<String>,
+    pub nonce: String,
 }
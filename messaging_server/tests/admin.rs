@@ -0,0 +1,279 @@
+mod common;
+
+use common::{connect, receive, send, send_raw, sign_in, start_gateway, Gateway, Init};
+use handlers::admin::TOKEN_FILE;
+use messaging_protocol::admin::{AdminReply, AdminRequest, Presence};
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::Message;
+use std::fs;
+use std::net::TcpStream;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+/*
+ * Open an admin connection, authenticated with the gateway's token unless
+ * another one is given
+*/
+
+fn admin_with(gateway: &Gateway, token: Option<&str>) -> FramedStream<TcpStream> {
+    let stream = TcpStream::connect(&gateway.admin_addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut stream = FramedStream::new(stream);
+
+    if let Some(token) = token {
+        let reply = ask(
+            &mut stream,
+            AdminRequest::Auth {
+                token: token.to_string(),
+            },
+        );
+        assert!(matches!(reply, AdminReply::Ok { .. }), "{}", reply);
+    }
+    stream
+}
+
+fn admin(gateway: &Gateway) -> FramedStream<TcpStream> {
+    let token = fs::read_to_string(gateway.dir.join("gateway_state").join(TOKEN_FILE)).unwrap();
+    admin_with(gateway, Some(&token))
+}
+
+fn ask(stream: &mut FramedStream<TcpStream>, request: AdminRequest) -> AdminReply {
+    stream.write_frame(&request.encode()).unwrap();
+    AdminReply::decode(&stream.read_frame().unwrap()).unwrap()
+}
+
+fn presence(admin: &mut FramedStream<TcpStream>) -> Vec<(String, Presence)> {
+    match ask(admin, AdminRequest::Users) {
+        AdminReply::Users { users } => users
+            .into_iter()
+            .map(|user| (user.username, user.presence))
+            .collect(),
+        other => panic!("expected users, got {}", other),
+    }
+}
+
+fn cached_ids(admin: &mut FramedStream<TcpStream>, recipient: &str) -> Vec<String> {
+    let request = AdminRequest::Inspect {
+        recipient: recipient.to_string(),
+    };
+    match ask(admin, request) {
+        AdminReply::Messages { messages, .. } => messages.into_iter().map(|m| m.id).collect(),
+        other => panic!("expected messages, got {}", other),
+    }
+}
+
+#[test]
+fn admin_channel_needs_the_token() {
+    let gateway = start_gateway();
+
+    // The public port doesn't know SHUTDOWN anymore
+    let reply = send_raw(&gateway, b"SHUTDOWN").unwrap();
+    assert!(reply.starts_with("404 "), "{}", reply);
+
+    // Requests before AUTH and a wrong token are refused and hung up on
+    for token in [None, Some("guess")] {
+        let mut stream = admin_with(&gateway, None);
+        if let Some(token) = token {
+            let request = AdminRequest::Auth {
+                token: token.to_string(),
+            };
+            assert!(matches!(
+                ask(&mut stream, request),
+                AdminReply::Denied { .. }
+            ));
+        } else {
+            let reply = ask(&mut stream, AdminRequest::Shutdown);
+            assert!(matches!(reply, AdminReply::Denied { .. }));
+        }
+        assert!(stream.read_frame().is_err());
+    }
+
+    // The gateway is still there for whoever has the token
+    assert!(presence(&mut admin(&gateway)).is_empty());
+}
+
+#[test]
+fn users_are_listed_and_kicked() {
+    let gateway = start_gateway();
+    let mut amy = connect(&gateway);
+    sign_in(&mut amy, "amy", Init::default());
+    let mut bob = connect(&gateway);
+    sign_in(&mut bob, "bob", Init::default());
+    let mut admin = admin(&gateway);
+
+    assert_eq!(
+        presence(&mut admin),
+        [
+            ("amy".to_string(), Presence::Online),
+            ("bob".to_string(), Presence::Online)
+        ]
+    );
+
+    // Bob's session is closed and he is out of the ring
+    let request = AdminRequest::Kick {
+        username: "bob".to_string(),
+    };
+    assert!(matches!(ask(&mut admin, request), AdminReply::Ok { .. }));
+    assert!(bob.read_frame().is_err());
+    assert_eq!(
+        presence(&mut admin),
+        [
+            ("amy".to_string(), Presence::Online),
+            ("bob".to_string(), Presence::Gone)
+        ]
+    );
+
+    let request = AdminRequest::Kick {
+        username: "carl".to_string(),
+    };
+    assert!(matches!(
+        ask(&mut admin, request),
+        AdminReply::Denied { .. }
+    ));
+}
+
+#[test]
+fn caches_are_inspected_and_purged() {
+    let mut gateway = start_gateway();
+    sign_in(&mut connect(&gateway), "bob", Init::relayed());
+
+    let mut amy = connect(&gateway);
+    sign_in(&mut amy, "amy", Init::relayed());
+    for id in ["1", "2", "3"] {
+        send(
+            &mut amy,
+            Message::Send {
+                recipient: "bob".to_string(),
+                sender: "amy".to_string(),
+                id: id.to_string(),
+                body: "secret".to_string(),
+                signature: None,
+            },
+        );
+        assert!(matches!(receive(&mut amy), Message::Ack { .. }));
+    }
+
+    // Sizes and ids are shown, the text never is
+    let mut admin = admin(&gateway);
+    match ask(&mut admin, AdminRequest::Caches) {
+        AdminReply::Caches { caches } => {
+            assert_eq!(caches.len(), 1);
+            assert_eq!((caches[0].messages, caches[0].bytes), (3, 30));
+        }
+        other => panic!("expected caches, got {}", other),
+    }
+    let reply = ask(
+        &mut admin,
+        AdminRequest::Inspect {
+            recipient: "bob".to_string(),
+        },
+    );
+    assert!(!reply.to_string().contains("secret"));
+    assert_eq!(cached_ids(&mut admin, "bob"), ["1", "2", "3"]);
+
+    let purge = |id: Option<&str>| AdminRequest::Purge {
+        recipient: "bob".to_string(),
+        id: id.map(str::to_string),
+    };
+    assert!(matches!(
+        ask(&mut admin, purge(Some("2"))),
+        AdminReply::Ok { .. }
+    ));
+    assert!(matches!(
+        ask(&mut admin, purge(Some("9"))),
+        AdminReply::Denied { .. }
+    ));
+    assert_eq!(cached_ids(&mut admin, "bob"), ["1", "3"]);
+
+    // Purges are journaled like acks, so they outlast a crash
+    match ask(&mut admin, purge(None)) {
+        AdminReply::Ok { detail } => assert!(detail.contains('2'), "{}", detail),
+        other => panic!("expected a purge, got {}", other),
+    }
+    gateway.restart();
+    let mut admin = self::admin(&gateway);
+    assert!(cached_ids(&mut admin, "bob").is_empty());
+}
+
+#[test]
+fn shutdown_stops_the_gateway() {
+    let mut gateway = start_gateway();
+
+    let reply = ask(&mut admin(&gateway), AdminRequest::Shutdown);
+    assert!(matches!(reply, AdminReply::Ok { .. }));
+    assert!(gateway.exits_within(Duration::from_secs(5)));
+}
+
+#[test]
+fn drain_waits_for_the_last_client() {
+    let mut gateway = start_gateway();
+    let mut amy = connect(&gateway);
+    sign_in(&mut amy, "amy", Init::relayed());
+
+    let reply = ask(&mut admin(&gateway), AdminRequest::Drain);
+    assert!(matches!(reply, AdminReply::Ok { .. }));
+
+    // Nobody new gets in once the listener is closed, which happens right
+    // after the reply goes out. Amy is still served
+    let closed = (0..50).any(|_| {
+        thread::sleep(Duration::from_millis(20));
+        TcpStream::connect(&gateway.addr).is_err()
+    });
+    assert!(closed);
+    send(
+        &mut amy,
+        Message::Fetch {
+            username: "amy".to_string(),
+        },
+    );
+    assert!(matches!(receive(&mut amy), Message::Update { .. }));
+    assert!(!gateway.exits_within(Duration::from_millis(500)));
+
+    drop(amy);
+    assert!(gateway.exits_within(Duration::from_secs(15)));
+}
+
+#[test]
+fn the_cli_talks_to_the_admin_channel() {
+    let gateway = start_gateway();
+    let mut amy = connect(&gateway);
+    sign_in(&mut amy, "amy", Init::relayed());
+    let port = gateway.admin_addr.rsplit(':').next().unwrap();
+
+    // It finds the token through the config in the gateway's directory
+    let output = Command::new(env!("CARGO_BIN_EXE_messaging_admin"))
+        .args(["--admin-bind", "127.0.0.1", "--admin-port", port, "users"])
+        .current_dir(&gateway.dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("amy\t127.0.0.1:1\tonline"), "{}", stdout);
+
+    // A token that isn't the gateway's gets nowhere
+    let wrong = gateway.dir.join("wrong_token");
+    fs::write(&wrong, "guess").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_messaging_admin"))
+        .args([
+            "--admin-port",
+            port,
+            "--token-file",
+            wrong.to_str().unwrap(),
+        ])
+        .arg("users")
+        .current_dir(&gateway.dir)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+
+    // Neither does a command it doesn't know
+    let output = Command::new(env!("CARGO_BIN_EXE_messaging_admin"))
+        .args(["--admin-port", port, "reboot"])
+        .current_dir(&gateway.dir)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
@@ -0,0 +1,325 @@
+/*
+ * Helpers shared by the tests that run a real gateway process
+*/
+
+// Not every test file uses every helper
+#![allow(dead_code)]
+
+use messaging_protocol::crypto::{init_proof, SigningPair};
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::{
+    Capability, Message, NetworkParams, StoredMessage, PROTOCOL_VERSION,
+};
+use std::io::{BufRead, BufReader};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{env, fs};
+
+// Every gateway gets a directory of its own and a free port on loopback,
+// so tests can run side by side
+static GATEWAY_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/*
+ * A gateway process that is killed when the test is done with it. Its
+ * config and state live in dir, which goes away with it
+*/
+
+pub struct Gateway {
+    child: Child,
+    args: Vec<String>,
+    pub addr: String,
+    pub admin_addr: String,
+    pub dir: PathBuf,
+}
+
+impl Gateway {
+    /*
+     * Kill the gateway without warning, like a crash would
+     */
+
+    pub fn kill(&mut self) {
+        _ = self.child.kill();
+        _ = self.child.wait();
+    }
+
+    /*
+     * Whether the gateway exits by itself within the timeout
+     */
+
+    pub fn exits_within(&mut self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if let Ok(Some(_)) = self.child.try_wait() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    /*
+     * Crash the gateway and start it again on the state it left behind
+     */
+
+    pub fn restart(&mut self) {
+        self.kill();
+        (self.child, self.addr, self.admin_addr) = spawn(&self.dir, &self.args);
+    }
+}
+
+impl Drop for Gateway {
+    fn drop(&mut self) {
+        self.kill();
+        _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/*
+ * Start the gateway with its default settings
+*/
+
+pub fn start_gateway() -> Gateway {
+    start_gateway_with_config("")
+}
+
+/*
+ * Start the gateway with the given config file contents and no state
+*/
+
+pub fn start_gateway_with_config(config: &str) -> Gateway {
+    start_gateway_with_args(config, &["--bind", "127.0.0.1", "--port", "0"])
+}
+
+/*
+ * Start the gateway with the given config file contents and flags, and no
+ * state. Its addr is the first address it listens on
+*/
+
+pub fn start_gateway_with_args(config: &str, args: &[&str]) -> Gateway {
+    let count = GATEWAY_COUNT.fetch_add(1, Ordering::SeqCst);
+    let dir = env::temp_dir().join(format!("messaging_gateway_{}_{}", process::id(), count));
+    _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("gateway.toml"), config).unwrap();
+
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    let (child, addr, admin_addr) = spawn(&dir, &args);
+    Gateway {
+        child,
+        args,
+        addr,
+        admin_addr,
+        dir,
+    }
+}
+
+/*
+ * Run the gateway in a directory and wait until it says where it and its
+ * admin listener are. The rest of its output is read and thrown away so it
+ * never blocks
+*/
+
+fn spawn(dir: &Path, args: &[String]) -> (Child, String, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_messaging_server"))
+        .args(args)
+        .args(["--admin-port", "0"])
+        .args(["--log-level", "info"])
+        .current_dir(dir)
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("couldn't start the gateway");
+
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    let addr = lines
+        .by_ref()
+        .map_while(Result::ok)
+        .find_map(|line| line.strip_prefix("Listening on ").map(str::to_string))
+        .expect("gateway never came up");
+    let admin_addr = lines
+        .by_ref()
+        .map_while(Result::ok)
+        .find_map(|line| line.strip_prefix("Admin listening on ").map(str::to_string))
+        .expect("gateway never opened its admin listener");
+    thread::spawn(move || lines.for_each(drop));
+
+    (child, addr, admin_addr)
+}
+
+pub fn connect(gateway: &Gateway) -> FramedStream<TcpStream> {
+    let stream = TcpStream::connect(&gateway.addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    FramedStream::new(stream)
+}
+
+/*
+ * Send raw bytes as one frame and return the gateway's reply, if any
+*/
+
+pub fn send_raw(gateway: &Gateway, payload: &[u8]) -> Option<String> {
+    let mut stream = connect(gateway);
+    stream.write_frame(payload).unwrap();
+    stream
+        .read_frame()
+        .ok()
+        .map(|frame| String::from_utf8_lossy(&frame).to_string())
+}
+
+pub fn send(stream: &mut FramedStream<TcpStream>, message: Message) {
+    stream.write_frame(&message.encode()).unwrap();
+}
+
+pub fn receive(stream: &mut FramedStream<TcpStream>) -> Message {
+    Message::decode(&stream.read_frame().unwrap()).unwrap()
+}
+
+/*
+ * What a test client puts in its INIT besides its username. The default
+ * is a current client on loopback that sends directly and caches for
+ * buddies, with no key or identity
+*/
+
+pub struct Init<'a> {
+    pub addr: &'a str,
+    pub version: u32,
+    pub capabilities: Vec<Capability>,
+    pub key: Option<&'a str>,
+    pub identity: Option<&'a SigningPair>,
+}
+
+impl Default for Init<'_> {
+    fn default() -> Self {
+        Init {
+            addr: "127.0.0.1:1",
+            version: PROTOCOL_VERSION,
+            capabilities: vec![Capability::DirectSend, Capability::BuddyCache],
+            key: None,
+            identity: None,
+        }
+    }
+}
+
+impl<'a> Init<'a> {
+    pub fn at(addr: &'a str) -> Self {
+        Init {
+            addr,
+            ..Init::default()
+        }
+    }
+
+    pub fn relayed() -> Self {
+        Init {
+            capabilities: vec![Capability::ServerRelay],
+            ..Init::default()
+        }
+    }
+}
+
+/*
+ * What the gateway hands a client that signs in without a challenge
+*/
+
+pub struct SignedIn {
+    pub params: NetworkParams,
+    pub token_key: Option<String>,
+    pub buddies: Vec<String>,
+    pub pending: Vec<StoredMessage>,
+}
+
+/*
+ * Send an INIT for username and return the gateway's first reply
+*/
+
+pub fn init(stream: &mut FramedStream<TcpStream>, username: &str, init: Init) -> Message {
+    send(
+        stream,
+        Message::Init {
+            username: username.to_string(),
+            addr: init.addr.to_string(),
+            version: init.version,
+            capabilities: init.capabilities,
+            key: init.key.map(str::to_string),
+            identity: init.identity.map(SigningPair::public_hex),
+        },
+    );
+    receive(stream)
+}
+
+/*
+ * Send an INIT that needs no challenge and read the VERSION, BUDDIES and,
+ * for relayed clients, UPDATE that follow it
+*/
+
+pub fn sign_in(stream: &mut FramedStream<TcpStream>, username: &str, init: Init) -> SignedIn {
+    let relayed = init.capabilities.contains(&Capability::ServerRelay);
+    let (params, token_key) = match self::init(stream, username, init) {
+        Message::Version {
+            params, token_key, ..
+        } => (params, token_key),
+        other => panic!("expected a version, got {}", other),
+    };
+    let buddies = match receive(stream) {
+        Message::Buddies { buddies, .. } => buddies,
+        other => panic!("expected buddies, got {}", other),
+    };
+    let mut pending = Vec::new();
+    if relayed {
+        pending = match receive(stream) {
+            Message::Update { messages } => messages,
+            other => panic!("expected pending messages, got {}", other),
+        };
+    }
+    SignedIn {
+        params,
+        token_key,
+        buddies,
+        pending,
+    }
+}
+
+/*
+ * Register username with its key and identity, answering the challenge,
+ * and return the connection it is registered on
+*/
+
+pub fn register(
+    gateway: &Gateway,
+    username: &str,
+    key: &str,
+    identity: &SigningPair,
+) -> FramedStream<TcpStream> {
+    let addr = "127.0.0.1:1";
+    let mut stream = connect(gateway);
+    let reply = init(
+        &mut stream,
+        username,
+        Init {
+            addr,
+            key: Some(key),
+            identity: Some(identity),
+            ..Init::default()
+        },
+    );
+    assert!(matches!(reply, Message::Version { .. }));
+    let nonce = match receive(&mut stream) {
+        Message::Challenge { nonce } => nonce,
+        other => panic!("expected a challenge, got {}", other),
+    };
+
+    let public = identity.public_hex();
+    let signature = identity.sign(&init_proof(&nonce, username, addr, key, &public));
+    send(
+        &mut stream,
+        Message::Prove {
+            username: username.to_string(),
+            signature,
+        },
+    );
+    assert!(matches!(receive(&mut stream), Message::Buddies { .. }));
+    stream
+}
@@ -0,0 +1,174 @@
+mod common;
+
+use common::{
+    connect, receive, send, sign_in, start_gateway, start_gateway_with_args,
+    start_gateway_with_config, Init,
+};
+use handlers::config::Config;
+use handlers::logging::LogLevel;
+use messaging_protocol::framing::{encode_frame, FramedStream};
+use messaging_protocol::message::{Message, NetworkParams};
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::{env, fs, process};
+
+#[test]
+fn network_settings_come_from_the_config() {
+    let gateway = start_gateway_with_config("group_size = 3\nreplication = 2\n");
+
+    let mut streams = Vec::new();
+    for i in 0..5 {
+        let mut stream = connect(&gateway);
+        let addr = format!("10.0.0.{}:8013", i);
+        let params = sign_in(&mut stream, &format!("user{}", i), Init::at(&addr)).params;
+
+        // Settings left out of the file keep their defaults
+        assert_eq!(
+            params,
+            NetworkParams {
+                group_size: 3,
+                replication: 2,
+                ..NetworkParams::default()
+            }
+        );
+        streams.push(stream);
+    }
+
+    send(
+        &mut streams[0],
+        Message::Buddies {
+            username: "user0".to_string(),
+            buddies: Vec::new(),
+        },
+    );
+    match receive(&mut streams[0]) {
+        Message::Buddies { buddies, .. } => assert_eq!(buddies.len(), 3),
+        other => panic!("expected buddies, got {}", other),
+    }
+}
+
+#[test]
+fn impossible_settings_are_rejected() {
+    for config in [
+        "group_size = 0\n",
+        "group_size = 2\nreplication = 3\n",
+        "ring_size = 4096\n",
+        "groupsize = 2\n",
+        "group_size = \"two\"\n",
+        "max_cached_messages = 0\n",
+        "bind = [\"0.0.0.0\", \"127.0.0.1\"]\n",
+        "bind = [\"::1\", \"::1\"]\n",
+    ] {
+        assert!(Config::from_toml(config).is_err(), "{}", config);
+    }
+
+    assert_eq!(Config::from_toml("").unwrap(), Config::default());
+    assert!(Config::from_toml("bind = [\"0.0.0.0\", \"::\"]\n").is_ok());
+}
+
+fn args(list: &[&str]) -> Vec<String> {
+    list.iter().map(|arg| arg.to_string()).collect()
+}
+
+#[test]
+fn command_line_overrides_the_file() {
+    let dir = env::temp_dir().join(format!("messaging_config_{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("custom.toml");
+    fs::write(
+        &path,
+        "group_size = 3\nport = 9000\nbind = [\"0.0.0.0\"]\nstate_dir = \"/var/gateway\"\n",
+    )
+    .unwrap();
+
+    let config = Config::from_args(args(&[
+        "--config",
+        path.to_str().unwrap(),
+        "--port",
+        "9100",
+        "--bind",
+        "127.0.0.1",
+        "--bind",
+        "::1",
+        "--log-level",
+        "debug",
+        "--max-frame-len",
+        "4096",
+    ]))
+    .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    // Flags win, the file fills in the rest
+    assert_eq!(config.group_size, 3);
+    assert_eq!(config.state_dir, "/var/gateway");
+    assert_eq!(config.log_level, LogLevel::Debug);
+    assert_eq!(config.max_frame_len, 4096);
+    let expected: Vec<SocketAddr> = vec![
+        "127.0.0.1:9100".parse().unwrap(),
+        "[::1]:9100".parse().unwrap(),
+    ];
+    assert_eq!(config.listen_addrs(), expected);
+}
+
+#[test]
+fn bad_flags_are_rejected() {
+    for list in [
+        &["--no-such-setting", "1"][..],
+        &["--port"],
+        &["--port", "eighty"],
+        &["--bind", "not-an-address"],
+        &["--log-level", "loud"],
+        &["--events-capacity", "0"],
+        &["--config", "/no/such/gateway.toml"],
+        &["8013"],
+    ] {
+        assert!(Config::from_args(args(list)).is_err(), "{:?}", list);
+    }
+}
+
+#[test]
+fn gateways_run_side_by_side() {
+    let first = start_gateway();
+    let second = start_gateway();
+    assert_ne!(first.addr, second.addr);
+
+    for gateway in [&first, &second] {
+        let mut stream = connect(gateway);
+        sign_in(&mut stream, "amy", Init::at("10.0.0.1:8013"));
+    }
+}
+
+#[test]
+fn frames_over_the_limit_drop_the_connection() {
+    let gateway = start_gateway_with_config("max_frame_len = 64\n");
+
+    let mut stream = connect(&gateway);
+    let body = format!("FETCH {}", "a".repeat(100));
+    stream
+        .get_mut()
+        .write_all(&encode_frame(body.as_bytes()))
+        .unwrap();
+    assert!(stream.read_frame().is_err());
+
+    // Smaller frames are still served
+    let mut stream = connect(&gateway);
+    sign_in(&mut stream, "amy", Init::at("10.0.0.1:8013"));
+}
+
+#[test]
+fn ipv4_and_ipv6_wildcards_share_a_port() {
+    // A port nothing else is using, for both listeners
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+        .to_string();
+    let _gateway =
+        start_gateway_with_args("", &["--bind", "0.0.0.0", "--bind", "::", "--port", &port]);
+
+    for addr in [format!("127.0.0.1:{}", port), format!("[::1]:{}", port)] {
+        let mut stream = FramedStream::new(TcpStream::connect(&addr).unwrap());
+        sign_in(&mut stream, "amy", Init::at("10.0.0.1:8013"));
+    }
+}
@@ -0,0 +1,179 @@
+mod common;
+
+use common::{connect, send_raw, start_gateway, Gateway};
+use messaging_protocol::framing::encode_frame;
+use std::io::Write;
+use std::net::TcpStream;
+
+/*
+ * Every payload should be answered with an error instead of taking the
+ * gateway down
+*/
+
+fn assert_rejected(gateway: &Gateway, payloads: &[&[u8]]) {
+    for payload in payloads {
+        let reply = send_raw(gateway, payload);
+        assert!(
+            reply.as_deref().is_some_and(|r| r.starts_with("404 ")),
+            "{:?} got {:?}",
+            String::from_utf8_lossy(payload),
+            reply
+        );
+        assert_alive(gateway);
+    }
+}
+
+/*
+ * A fresh client can still register after whatever came before
+*/
+
+fn assert_alive(gateway: &Gateway) {
+    let reply = send_raw(
+        gateway,
+        b"INIT probe&&127.0.0.1:1&&2&&direct_send,buddy_cache",
+    );
+    assert!(
+        reply.as_deref().is_some_and(|r| r.starts_with("VERSION ")),
+        "gateway stopped answering, got {:?}",
+        reply
+    );
+}
+
+#[test]
+fn garbage_init_is_rejected() {
+    let gateway = start_gateway();
+
+    assert_rejected(
+        &gateway,
+        &[
+            b"INIT",
+            b"INIT ",
+            b"INIT bob",
+            b"INIT bob&&127.0.0.1:1&&two&&direct_send",
+            b"INIT bob&&127.0.0.1:1&&-1&&direct_send",
+            b"INIT %ZZ&&127.0.0.1:1",
+            b"INIT bob&&%",
+        ],
+    );
+}
+
+#[test]
+fn garbage_send_is_rejected() {
+    let gateway = start_gateway();
+
+    assert_rejected(
+        &gateway,
+        &[
+            b"SEND",
+            b"SEND bob",
+            b"SEND bob;amy",
+            b"SEND bob;amy;id",
+            b"SEND bob;amy;;hi",
+            b"SEND bob;amy;id;%4",
+            b"SEND nobody;amy;id;hi",
+        ],
+    );
+}
+
+#[test]
+fn garbage_ack_is_ignored() {
+    let gateway = start_gateway();
+
+    assert_rejected(&gateway, &[b"ACK", b"ACK bob", b"ACK bob;", b"ACK %G1;id"]);
+
+    // Acking a message nobody has cached needs no reply
+    let mut stream = connect(&gateway);
+    stream.write_frame(b"ACK nobody;id").unwrap();
+    assert_alive(&gateway);
+}
+
+#[test]
+fn garbage_lookups_are_rejected() {
+    let gateway = start_gateway();
+
+    assert_rejected(
+        &gateway,
+        &[
+            b"BUDDIES",
+            b"BUDDIES nobody",
+            b"BUDDIES %%%",
+            b"IP_FETCH",
+            b"IP_FETCH nobody",
+            b"IP_FETCH %",
+        ],
+    );
+}
+
+#[test]
+fn unhandled_and_unknown_codes_are_rejected() {
+    let gateway = start_gateway();
+
+    assert_rejected(
+        &gateway,
+        &[
+            b"",
+            b" ",
+            b"HELLO there",
+            b"\xff\xfe\xfd",
+            b"CACHE bob;amy;id;hi",
+            b"CACHE bob",
+            b"UPDATE x",
+            b"UPDATE amy;id;hi",
+            b"IP_RETRIEVAL",
+            b"IP_RETRIEVAL 1.2.3.4:1",
+            b"404",
+            b"VERSION two",
+            b"REFUSED",
+            b"SHUTDOWN",
+            b"UPDATE_FINGERS a&&b",
+            b"NEW_FINGER",
+            b"UPDATE_GROUP a",
+        ],
+    );
+}
+
+#[test]
+fn broken_frames_only_drop_their_connection() {
+    let gateway = start_gateway();
+
+    // A length far beyond what the gateway is willing to buffer
+    let mut stream = TcpStream::connect(&gateway.addr).unwrap();
+    stream.write_all(&u32::MAX.to_be_bytes()).unwrap();
+    assert_alive(&gateway);
+
+    // Half a frame, then the client hangs up
+    let mut stream = TcpStream::connect(&gateway.addr).unwrap();
+    stream
+        .write_all(&encode_frame(b"INIT bob&&127.0.0.1:1")[..8])
+        .unwrap();
+    drop(stream);
+    assert_alive(&gateway);
+
+    // Half a header, then the client hangs up
+    let mut stream = TcpStream::connect(&gateway.addr).unwrap();
+    stream.write_all(&[0, 0]).unwrap();
+    drop(stream);
+    assert_alive(&gateway);
+
+    // Connect and leave without sending anything
+    drop(TcpStream::connect(&gateway.addr).unwrap());
+    assert_alive(&gateway);
+}
+
+#[test]
+fn returning_user_without_buddies_is_served() {
+    let gateway = start_gateway();
+
+    // A client that can't cache for others never lands in the buddy list,
+    // so its second INIT asks for buddies out of an empty list
+    let init = b"INIT loner&&127.0.0.1:1&&2&&server_relay";
+    assert!(send_raw(&gateway, init).is_some_and(|r| r.starts_with("VERSION ")));
+
+    let mut stream = connect(&gateway);
+    stream.write_frame(init).unwrap();
+    stream.read_frame().unwrap();
+    let buddies = String::from_utf8(stream.read_frame().unwrap()).unwrap();
+    assert_eq!(buddies, "BUDDIES loner");
+
+    assert_alive(&gateway);
+}
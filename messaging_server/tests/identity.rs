@@ -0,0 +1,137 @@
+mod common;
+
+use common::{connect, init, receive, send, start_gateway, Gateway, Init};
+use messaging_protocol::crypto::{init_proof, SigningPair};
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::Message;
+use std::net::TcpStream;
+
+const ADDR: &str = "127.0.0.1:1";
+
+/*
+ * Send an INIT for username, signing with the given identity, and return
+ * the connection with what the gateway said after VERSION
+*/
+
+fn claim(
+    gateway: &Gateway,
+    username: &str,
+    identity: Option<&SigningPair>,
+) -> (FramedStream<TcpStream>, Message) {
+    let mut stream = connect(gateway);
+    let reply = init(
+        &mut stream,
+        username,
+        Init {
+            addr: ADDR,
+            identity,
+            ..Init::default()
+        },
+    );
+    assert!(matches!(reply, Message::Version { .. }));
+    let reply = receive(&mut stream);
+    (stream, reply)
+}
+
+fn nonce(reply: Message) -> String {
+    match reply {
+        Message::Challenge { nonce } => nonce,
+        other => panic!("expected a challenge, got {}", other),
+    }
+}
+
+/*
+ * Answer the challenge, signing with signer, and return the gateway's reply
+*/
+
+fn prove(
+    stream: &mut FramedStream<TcpStream>,
+    username: &str,
+    nonce: &str,
+    identity: &SigningPair,
+    signer: &SigningPair,
+) -> Message {
+    let public = identity.public_hex();
+    let signature = signer.sign(&init_proof(nonce, username, ADDR, "", &public));
+    send(
+        stream,
+        Message::Prove {
+            username: username.to_string(),
+            signature,
+        },
+    );
+    receive(stream)
+}
+
+/*
+ * Register username under identity, signing the challenge properly
+*/
+
+fn register(gateway: &Gateway, username: &str, identity: &SigningPair) -> Message {
+    let (mut stream, reply) = claim(gateway, username, Some(identity));
+    prove(&mut stream, username, &nonce(reply), identity, identity)
+}
+
+#[test]
+fn names_are_bound_to_the_first_identity() {
+    let mut gateway = start_gateway();
+    let amy = SigningPair::generate().unwrap();
+    let mallory = SigningPair::generate().unwrap();
+
+    assert!(matches!(
+        register(&gateway, "amy", &amy),
+        Message::Buddies { .. }
+    ));
+
+    // Neither a client without an identity nor one with another identity
+    // gets the name
+    let (_, reply) = claim(&gateway, "amy", None);
+    assert!(matches!(reply, Message::Refused { .. }), "{}", reply);
+    let (_, reply) = claim(&gateway, "amy", Some(&mallory));
+    assert!(matches!(reply, Message::Refused { .. }), "{}", reply);
+
+    // Amy comes back on every INIT, the binding outlives a crash
+    assert!(matches!(
+        register(&gateway, "amy", &amy),
+        Message::Buddies { .. }
+    ));
+    gateway.restart();
+    let (_, reply) = claim(&gateway, "amy", Some(&mallory));
+    assert!(matches!(reply, Message::Refused { .. }), "{}", reply);
+    assert!(matches!(
+        register(&gateway, "amy", &amy),
+        Message::Buddies { .. }
+    ));
+
+    // Names nobody bound still work without an identity
+    let (_, reply) = claim(&gateway, "bob", None);
+    assert!(matches!(reply, Message::Buddies { .. }), "{}", reply);
+}
+
+#[test]
+fn challenges_need_the_right_signature() {
+    let gateway = start_gateway();
+    let amy = SigningPair::generate().unwrap();
+    let mallory = SigningPair::generate().unwrap();
+
+    // Claiming amy's identity without her secret key
+    let (mut stream, reply) = claim(&gateway, "amy", Some(&amy));
+    let reply = prove(&mut stream, "amy", &nonce(reply), &amy, &mallory);
+    assert!(matches!(reply, Message::Refused { .. }), "{}", reply);
+    assert!(stream.read_frame().is_err());
+
+    // A signature over another nonce is no good either
+    let (mut stream, reply) = claim(&gateway, "amy", Some(&amy));
+    nonce(reply);
+    let reply = prove(&mut stream, "amy", "00", &amy, &amy);
+    assert!(matches!(reply, Message::Refused { .. }), "{}", reply);
+
+    // Nothing was bound along the way, and PROVE without INIT goes nowhere
+    let mut stream = connect(&gateway);
+    let reply = prove(&mut stream, "amy", "00", &amy, &amy);
+    assert!(matches!(reply, Message::NotFound { .. }), "{}", reply);
+    assert!(matches!(
+        register(&gateway, "amy", &mallory),
+        Message::Buddies { .. }
+    ));
+}
@@ -0,0 +1,77 @@
+mod common;
+
+use common::{connect, receive, send, sign_in, start_gateway, Init};
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::Message;
+use std::net::TcpStream;
+
+fn key_of(stream: &mut FramedStream<TcpStream>, username: &str) -> Message {
+    send(
+        stream,
+        Message::KeyFetch {
+            username: username.to_string(),
+        },
+    );
+    receive(stream)
+}
+
+#[test]
+fn registered_keys_are_handed_out_and_kept() {
+    let mut gateway = start_gateway();
+    let key = "ab".repeat(32);
+
+    let mut amy = connect(&gateway);
+    sign_in(
+        &mut amy,
+        "amy",
+        Init {
+            key: Some(&key),
+            ..Init::default()
+        },
+    );
+    let expected = Message::Key {
+        username: "amy".to_string(),
+        key: key.clone(),
+        identity: None,
+    };
+    assert_eq!(key_of(&mut amy, "amy"), expected);
+
+    // Nobody registered a key for these two
+    sign_in(&mut amy, "bob", Init::default());
+    for username in ["bob", "carl"] {
+        assert!(matches!(
+            key_of(&mut amy, username),
+            Message::NotFound { .. }
+        ));
+    }
+
+    // Coming back without a key doesn't forget the old one
+    let mut amy = connect(&gateway);
+    sign_in(&mut amy, "amy", Init::default());
+    assert_eq!(key_of(&mut amy, "amy"), expected);
+
+    // And neither does a crash
+    gateway.restart();
+    let mut stream = connect(&gateway);
+    assert_eq!(key_of(&mut stream, "amy"), expected);
+
+    // A new key replaces the old one
+    sign_in(
+        &mut stream,
+        "amy",
+        Init {
+            key: Some(&"cd".repeat(32)),
+            ..Init::default()
+        },
+    );
+    gateway.restart();
+    let mut stream = connect(&gateway);
+    assert_eq!(
+        key_of(&mut stream, "amy"),
+        Message::Key {
+            username: "amy".to_string(),
+            key: "cd".repeat(32),
+            identity: None,
+        }
+    );
+}
@@ -0,0 +1,113 @@
+mod common;
+
+use common::{connect, receive, register, send, start_gateway, Gateway};
+use messaging_protocol::crypto::{KeyPair, SigningPair};
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::Message;
+use messaging_protocol::ratchet::{prekey_proof, PrekeyBundle};
+use std::net::TcpStream;
+
+fn publish(
+    stream: &mut FramedStream<TcpStream>,
+    signed_prekey: &str,
+    signature: &str,
+    one_time: &[String],
+) -> Message {
+    send(
+        stream,
+        Message::Prekeys {
+            username: "amy".to_string(),
+            signed_prekey: signed_prekey.to_string(),
+            signature: signature.to_string(),
+            one_time: one_time.to_vec(),
+        },
+    );
+    receive(stream)
+}
+
+fn fetch(gateway: &Gateway) -> Message {
+    let mut stream = connect(gateway);
+    send(
+        &mut stream,
+        Message::BundleFetch {
+            username: "amy".to_string(),
+        },
+    );
+    receive(&mut stream)
+}
+
+fn one_time_of(message: Message) -> Option<String> {
+    match message {
+        Message::Bundle { bundle, .. } => bundle.one_time,
+        other => panic!("expected a bundle, got {}", other),
+    }
+}
+
+#[test]
+fn one_time_prekeys_are_handed_out_once() {
+    let mut gateway = start_gateway();
+    let identity = SigningPair::generate().unwrap();
+    let key = KeyPair::generate().unwrap().public_hex();
+    let signed_prekey = KeyPair::generate().unwrap().public_hex();
+    let signature = identity.sign(&prekey_proof(&signed_prekey));
+    let one_time: Vec<String> = (0..3)
+        .map(|_| KeyPair::generate().unwrap().public_hex())
+        .collect();
+
+    // Nothing is published before amy registers
+    assert!(matches!(fetch(&gateway), Message::NotFound { .. }));
+    let mut stranger = connect(&gateway);
+    assert!(matches!(
+        publish(&mut stranger, &signed_prekey, &signature, &one_time),
+        Message::NotFound { .. }
+    ));
+
+    // A signed prekey her identity didn't sign is turned away
+    let mut amy = register(&gateway, "amy", &key, &identity);
+    let forged = SigningPair::generate().unwrap();
+    assert!(matches!(
+        publish(
+            &mut amy,
+            &signed_prekey,
+            &forged.sign(&prekey_proof(&signed_prekey)),
+            &one_time
+        ),
+        Message::NotFound { .. }
+    ));
+
+    let own = PrekeyBundle {
+        identity: identity.public_hex(),
+        key: key.clone(),
+        signed_prekey: signed_prekey.clone(),
+        signature: signature.clone(),
+        one_time: None,
+    };
+    assert_eq!(
+        publish(&mut amy, &signed_prekey, &signature, &one_time),
+        Message::Bundle {
+            username: "amy".to_string(),
+            bundle: own.clone(),
+        }
+    );
+
+    // Every fetch takes one, and a crash doesn't hand it out again
+    assert_eq!(one_time_of(fetch(&gateway)), Some(one_time[0].clone()));
+    gateway.restart();
+    assert_eq!(one_time_of(fetch(&gateway)), Some(one_time[1].clone()));
+    assert_eq!(one_time_of(fetch(&gateway)), Some(one_time[2].clone()));
+    gateway.restart();
+    assert_eq!(
+        fetch(&gateway),
+        Message::Bundle {
+            username: "amy".to_string(),
+            bundle: own,
+        }
+    );
+
+    // A new batch replaces what was left
+    let mut amy = register(&gateway, "amy", &key, &identity);
+    let fresh = vec![KeyPair::generate().unwrap().public_hex()];
+    publish(&mut amy, &signed_prekey, &signature, &fresh);
+    assert_eq!(one_time_of(fetch(&gateway)), Some(fresh[0].clone()));
+    assert_eq!(one_time_of(fetch(&gateway)), None);
+}
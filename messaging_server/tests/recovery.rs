@@ -0,0 +1,228 @@
+mod common;
+
+use common::{
+    connect, receive, send, sign_in, start_gateway, start_gateway_with_config, Gateway, Init,
+};
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::{Capability, Message, StoredMessage};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+fn fetch(stream: &mut FramedStream<TcpStream>, username: &str) -> Vec<StoredMessage> {
+    send(
+        stream,
+        Message::Fetch {
+            username: username.to_string(),
+        },
+    );
+    match receive(stream) {
+        Message::Update { messages } => messages,
+        other => panic!("expected pending messages, got {}", other),
+    }
+}
+
+/*
+ * Sign bob back in without the pending messages being pushed, then fetch
+ * them on that connection
+*/
+
+fn fetch_as_bob(gateway: &Gateway) -> Vec<StoredMessage> {
+    let mut bob = connect(gateway);
+    sign_in(
+        &mut bob,
+        "bob",
+        Init {
+            capabilities: vec![Capability::BuddyCache],
+            ..Init::default()
+        },
+    );
+    fetch(&mut bob, "bob")
+}
+
+fn send_to_bob(stream: &mut FramedStream<TcpStream>, id: &str) {
+    send(
+        stream,
+        Message::Send {
+            recipient: "bob".to_string(),
+            sender: "amy".to_string(),
+            id: id.to_string(),
+            body: format!("message {}", id),
+            signature: None,
+        },
+    );
+    assert!(matches!(receive(stream), Message::Ack { .. }));
+}
+
+fn ids(messages: &[StoredMessage]) -> Vec<&str> {
+    messages.iter().map(|m| m.id.as_str()).collect()
+}
+
+#[test]
+fn registered_users_survive_a_crash() {
+    let mut gateway = start_gateway();
+
+    let mut amy = connect(&gateway);
+    sign_in(&mut amy, "amy", Init::at("10.0.0.1:8013"));
+    let mut bob = connect(&gateway);
+    sign_in(&mut bob, "bob", Init::at("10.0.0.2:8013"));
+
+    // Amy moves before the crash
+    let mut new_amy = connect(&gateway);
+    sign_in(&mut new_amy, "amy", Init::at("10.0.0.9:8013"));
+
+    gateway.restart();
+
+    // Both are still known, amy at her new address
+    let mut stream = connect(&gateway);
+    send(
+        &mut stream,
+        Message::IpFetch {
+            username: "amy".to_string(),
+        },
+    );
+    assert_eq!(
+        receive(&mut stream),
+        Message::IpRetrieval {
+            addr: "10.0.0.9:8013".to_string(),
+        }
+    );
+
+    // And they still hold each other as buddies
+    send(
+        &mut stream,
+        Message::Buddies {
+            username: "bob".to_string(),
+            buddies: Vec::new(),
+        },
+    );
+    match receive(&mut stream) {
+        Message::Buddies { buddies, .. } => {
+            assert_eq!(buddies.len(), 2);
+            assert!(buddies.iter().any(|addr| addr == "10.0.0.9:8013"));
+            assert!(!buddies.iter().any(|addr| addr == "10.0.0.1:8013"));
+        }
+        other => panic!("expected buddies, got {}", other),
+    }
+}
+
+#[test]
+fn acked_messages_survive_a_crash_mid_traffic() {
+    // Snapshot often, so the crash can also land in the middle of one
+    let mut gateway = start_gateway_with_config("snapshot_every = 7\n");
+
+    let mut bob = connect(&gateway);
+    sign_in(&mut bob, "bob", Init::relayed());
+    drop(bob);
+
+    // Amy sends as fast as the gateway acks until it goes down
+    let mut amy = connect(&gateway);
+    sign_in(&mut amy, "amy", Init::relayed());
+    let acked = Arc::new(Mutex::new(Vec::new()));
+    let sender = {
+        let acked = acked.clone();
+        thread::spawn(move || {
+            for i in 0.. {
+                let id = format!("{:06}", i);
+                let message = Message::Send {
+                    recipient: "bob".to_string(),
+                    sender: "amy".to_string(),
+                    id: id.clone(),
+                    body: format!("message {}", id),
+                    signature: None,
+                };
+                if amy.write_frame(&message.encode()).is_err() {
+                    break;
+                }
+                match amy.read_frame().map(|frame| Message::decode(&frame)) {
+                    Ok(Ok(Message::Ack { .. })) => acked.lock().unwrap().push(id),
+                    _ => break,
+                }
+            }
+        })
+    };
+
+    while acked.lock().unwrap().len() < 50 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    gateway.kill();
+    sender.join().unwrap();
+    gateway.restart();
+
+    // Every message the gateway took responsibility for is still there. The
+    // one in flight when it died may or may not have made it
+    let acked = acked.lock().unwrap();
+    let pending = fetch_as_bob(&gateway);
+    assert!(pending.len() == acked.len() || pending.len() == acked.len() + 1);
+    assert_eq!(ids(&pending[..acked.len()]), *acked);
+    for message in &pending {
+        assert_eq!(message.body, format!("message {}", message.id));
+    }
+}
+
+#[test]
+fn torn_journal_line_is_dropped() {
+    let mut gateway = start_gateway();
+    let state = gateway.dir.join("gateway_state");
+
+    let mut bob = connect(&gateway);
+    sign_in(&mut bob, "bob", Init::relayed());
+    drop(bob);
+    let mut amy = connect(&gateway);
+    sign_in(&mut amy, "amy", Init::relayed());
+    send_to_bob(&mut amy, "1");
+
+    // The gateway dies halfway through writing a record and a snapshot
+    gateway.kill();
+    let mut journal = OpenOptions::new()
+        .append(true)
+        .open(state.join("journal.log"))
+        .unwrap();
+    journal.write_all(b"CACHE bob;amy;2;half a mess").unwrap();
+    std::fs::write(state.join("snapshot.log.tmp"), "CACHE bob;amy;3;").unwrap();
+    gateway.restart();
+
+    assert_eq!(ids(&fetch_as_bob(&gateway)), ["1"]);
+
+    // Records written after recovery aren't glued to the torn one
+    let mut amy = connect(&gateway);
+    send_to_bob(&mut amy, "4");
+    gateway.restart();
+
+    assert_eq!(ids(&fetch_as_bob(&gateway)), ["1", "4"]);
+}
+
+#[test]
+fn acks_are_remembered_across_snapshots() {
+    let mut gateway = start_gateway_with_config("snapshot_every = 5\n");
+
+    let mut bob = connect(&gateway);
+    sign_in(&mut bob, "bob", Init::relayed());
+    let mut amy = connect(&gateway);
+    sign_in(&mut amy, "amy", Init::relayed());
+
+    for i in 0..12 {
+        send_to_bob(&mut amy, &format!("{:02}", i));
+        assert!(matches!(receive(&mut bob), Message::Send { .. }));
+    }
+
+    // Bob confirms the even ones, spread over several snapshots
+    for i in (0..12).step_by(2) {
+        send(
+            &mut bob,
+            Message::Ack {
+                username: "bob".to_string(),
+                id: format!("{:02}", i),
+            },
+        );
+    }
+    let pending = fetch(&mut bob, "bob");
+    assert_eq!(ids(&pending), ["01", "03", "05", "07", "09", "11"]);
+
+    gateway.restart();
+
+    assert_eq!(fetch_as_bob(&gateway), pending);
+}
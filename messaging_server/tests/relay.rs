@@ -0,0 +1,208 @@
+mod common;
+
+use common::{connect, receive, send, sign_in, start_gateway, start_gateway_with_config, Init};
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::{Message, StoredMessage};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+fn fetch(stream: &mut FramedStream<TcpStream>, username: &str) -> Vec<StoredMessage> {
+    send(
+        stream,
+        Message::Fetch {
+            username: username.to_string(),
+        },
+    );
+    match receive(stream) {
+        Message::Update { messages } => messages,
+        other => panic!("expected pending messages, got {}", other),
+    }
+}
+
+/*
+ * Send bob a message and return the gateway's reply
+*/
+
+fn send_to_bob(stream: &mut FramedStream<TcpStream>, id: &str, body: &str) -> Message {
+    send(
+        stream,
+        Message::Send {
+            recipient: "bob".to_string(),
+            sender: "amy".to_string(),
+            id: id.to_string(),
+            body: body.to_string(),
+            signature: None,
+        },
+    );
+    receive(stream)
+}
+
+#[test]
+fn cached_messages_are_delivered_until_acked() {
+    let gateway = start_gateway();
+
+    // Bob registers and goes away
+    let mut bob = connect(&gateway);
+    assert!(sign_in(&mut bob, "bob", Init::relayed()).pending.is_empty());
+    drop(bob);
+
+    // Amy sends him two messages with the same text
+    let mut amy = connect(&gateway);
+    sign_in(&mut amy, "amy", Init::relayed());
+    for id in ["1", "2"] {
+        send(
+            &mut amy,
+            Message::Send {
+                recipient: "bob".to_string(),
+                sender: "amy".to_string(),
+                id: id.to_string(),
+                body: "ok".to_string(),
+                signature: None,
+            },
+        );
+        assert_eq!(
+            receive(&mut amy),
+            Message::Ack {
+                username: "bob".to_string(),
+                id: id.to_string(),
+            }
+        );
+    }
+
+    // Both are handed over when bob comes back, and again when he asks
+    let mut bob = connect(&gateway);
+    let pending = sign_in(&mut bob, "bob", Init::relayed()).pending;
+    let ids: Vec<&str> = pending.iter().map(|m| m.id.as_str()).collect();
+    assert_eq!(ids, ["1", "2"]);
+    assert_eq!(fetch(&mut bob, "bob"), pending);
+
+    // Only the acked one goes away
+    send(
+        &mut bob,
+        Message::Ack {
+            username: "bob".to_string(),
+            id: "1".to_string(),
+        },
+    );
+    let pending = fetch(&mut bob, "bob");
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].id, "2");
+}
+
+#[test]
+fn full_caches_turn_messages_away() {
+    let gateway = start_gateway_with_config("max_cached_messages = 2\nmax_cached_bytes = 40\n");
+
+    let mut bob = connect(&gateway);
+    sign_in(&mut bob, "bob", Init::relayed());
+    let mut amy = connect(&gateway);
+    sign_in(&mut amy, "amy", Init::relayed());
+
+    // Too many bytes for bob's cache in one go
+    let reply = send_to_bob(&mut amy, "big", &"x".repeat(40));
+    assert!(matches!(reply, Message::Rejected { id, .. } if id == "big"));
+
+    for id in ["1", "2"] {
+        let reply = send_to_bob(&mut amy, id, "ok");
+        assert!(matches!(reply, Message::Ack { .. }));
+        assert!(matches!(receive(&mut bob), Message::Send { .. }));
+    }
+
+    // A third message is over the count, a resend of one it holds is not
+    match send_to_bob(&mut amy, "3", "ok") {
+        Message::Rejected {
+            username,
+            id,
+            reason,
+        } => {
+            assert_eq!((username.as_str(), id.as_str()), ("bob", "3"));
+            assert!(reason.contains("full"), "{}", reason);
+        }
+        other => panic!("expected a rejection, got {}", other),
+    }
+    assert!(matches!(
+        send_to_bob(&mut amy, "2", "ok"),
+        Message::Ack { .. }
+    ));
+    assert!(matches!(receive(&mut bob), Message::Send { .. }));
+
+    // Once bob acks one there is room again
+    send(
+        &mut bob,
+        Message::Ack {
+            username: "bob".to_string(),
+            id: "1".to_string(),
+        },
+    );
+    assert_eq!(fetch(&mut bob, "bob").len(), 1);
+    assert!(matches!(
+        send_to_bob(&mut amy, "3", "ok"),
+        Message::Ack { .. }
+    ));
+}
+
+#[test]
+fn cached_messages_expire() {
+    let gateway = start_gateway_with_config("cache_ttl = 1\n");
+
+    let mut bob = connect(&gateway);
+    sign_in(&mut bob, "bob", Init::relayed());
+    drop(bob);
+    let mut amy = connect(&gateway);
+    sign_in(&mut amy, "amy", Init::relayed());
+    assert!(matches!(
+        send_to_bob(&mut amy, "1", "ok"),
+        Message::Ack { .. }
+    ));
+    let mut bob = connect(&gateway);
+    assert_eq!(sign_in(&mut bob, "bob", Init::relayed()).pending.len(), 1);
+
+    // The sweeper runs every ten seconds
+    thread::sleep(Duration::from_secs(12));
+    assert!(fetch(&mut bob, "bob").is_empty());
+}
+
+#[test]
+fn only_the_recipient_fetches_and_acks() {
+    let gateway = start_gateway();
+
+    let mut bob = connect(&gateway);
+    sign_in(&mut bob, "bob", Init::relayed());
+    drop(bob);
+    let mut amy = connect(&gateway);
+    sign_in(&mut amy, "amy", Init::relayed());
+    assert!(matches!(
+        send_to_bob(&mut amy, "1", "ok"),
+        Message::Ack { .. }
+    ));
+
+    // Amy is signed in, just not as bob, and a stranger isn't at all
+    let mut stranger = connect(&gateway);
+    for stream in [&mut amy, &mut stranger] {
+        send(
+            stream,
+            Message::Fetch {
+                username: "bob".to_string(),
+            },
+        );
+        let reply = receive(stream);
+        assert!(matches!(reply, Message::NotFound { .. }), "{}", reply);
+
+        send(
+            stream,
+            Message::Ack {
+                username: "bob".to_string(),
+                id: "1".to_string(),
+            },
+        );
+        let reply = receive(stream);
+        assert!(matches!(reply, Message::NotFound { .. }), "{}", reply);
+    }
+
+    // Bob still gets the message
+    let mut bob = connect(&gateway);
+    let pending = sign_in(&mut bob, "bob", Init::relayed()).pending;
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].id, "1");
+}
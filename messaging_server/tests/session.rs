@@ -0,0 +1,196 @@
+mod common;
+
+use common::{connect, receive, send, sign_in, start_gateway, start_gateway_with_config, Init};
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::hash::select_group;
+use messaging_protocol::message::Message;
+use std::net::TcpStream;
+
+#[test]
+fn returning_user_moves_to_its_new_address() {
+    let gateway = start_gateway();
+
+    let mut bob = connect(&gateway);
+    sign_in(&mut bob, "bob", Init::at("10.0.0.2:8013"));
+    let mut amy = connect(&gateway);
+    sign_in(&mut amy, "amy", Init::at("10.0.0.1:8013"));
+
+    // Amy comes back from another network on a new connection
+    let mut new_amy = connect(&gateway);
+    let buddies = sign_in(&mut new_amy, "amy", Init::at("10.0.0.9:8013")).buddies;
+    assert!(!buddies.iter().any(|addr| addr == "10.0.0.1:8013"));
+
+    // Her old session is closed
+    assert!(amy.read_frame().is_err());
+
+    // Lookups hand out the new address
+    send(
+        &mut new_amy,
+        Message::IpFetch {
+            username: "amy".to_string(),
+        },
+    );
+    assert_eq!(
+        receive(&mut new_amy),
+        Message::IpRetrieval {
+            addr: "10.0.0.9:8013".to_string(),
+        }
+    );
+
+    // Bob holds her as a buddy and is told about her new address
+    match receive(&mut bob) {
+        Message::UpdateGroup { members } => {
+            assert!(members.iter().any(|addr| addr == "10.0.0.9:8013"));
+            assert!(!members.iter().any(|addr| addr == "10.0.0.1:8013"));
+        }
+        other => panic!("expected a group update, got {}", other),
+    }
+
+    // Bob's buddy lookups no longer hand out the old address
+    send(
+        &mut bob,
+        Message::Buddies {
+            username: "bob".to_string(),
+            buddies: Vec::new(),
+        },
+    );
+    match receive(&mut bob) {
+        Message::Buddies { buddies, .. } => {
+            assert!(!buddies.iter().any(|addr| addr == "10.0.0.1:8013"))
+        }
+        other => panic!("expected buddies, got {}", other),
+    }
+}
+
+#[test]
+fn relayed_messages_follow_the_new_session() {
+    let gateway = start_gateway();
+
+    let mut amy = connect(&gateway);
+    sign_in(&mut amy, "amy", Init::at("10.0.0.1:8013"));
+    let mut bob = connect(&gateway);
+    sign_in(&mut bob, "bob", Init::at("10.0.0.2:8013"));
+
+    // Bob reconnects from the same address
+    let mut new_bob = connect(&gateway);
+    sign_in(&mut new_bob, "bob", Init::at("10.0.0.2:8013"));
+
+    send(
+        &mut amy,
+        Message::Send {
+            recipient: "bob".to_string(),
+            sender: "amy".to_string(),
+            id: "1".to_string(),
+            body: "hi".to_string(),
+            signature: None,
+        },
+    );
+    assert!(matches!(receive(&mut amy), Message::Ack { .. }));
+    assert!(matches!(receive(&mut new_bob), Message::Send { .. }));
+    assert!(bob.read_frame().is_err());
+}
+
+fn buddies_of(stream: &mut FramedStream<TcpStream>, username: &str) -> Vec<String> {
+    send(
+        stream,
+        Message::Buddies {
+            username: username.to_string(),
+            buddies: Vec::new(),
+        },
+    );
+    match receive(stream) {
+        Message::Buddies { buddies, .. } => buddies,
+        other => panic!("expected buddies, got {}", other),
+    }
+}
+
+#[test]
+fn leaving_user_is_dropped_from_the_ring() {
+    let gateway = start_gateway();
+
+    let mut amy = connect(&gateway);
+    sign_in(&mut amy, "amy", Init::at("10.0.0.1:8013"));
+    let mut bob = connect(&gateway);
+    sign_in(&mut bob, "bob", Init::at("10.0.0.2:8013"));
+
+    // Amy can't sign bob out
+    send(
+        &mut amy,
+        Message::Leave {
+            username: "bob".to_string(),
+        },
+    );
+    assert!(matches!(receive(&mut amy), Message::NotFound { .. }));
+
+    send(
+        &mut bob,
+        Message::Leave {
+            username: "bob".to_string(),
+        },
+    );
+    assert!(bob.read_frame().is_err());
+
+    let buddies = buddies_of(&mut amy, "amy");
+    assert!(!buddies.is_empty());
+    assert!(!buddies.iter().any(|addr| addr == "10.0.0.2:8013"));
+}
+
+#[test]
+fn short_disconnect_keeps_groups() {
+    let gateway = start_gateway();
+
+    let mut amy = connect(&gateway);
+    sign_in(&mut amy, "amy", Init::at("10.0.0.1:8013"));
+    let mut bob = connect(&gateway);
+    sign_in(&mut bob, "bob", Init::at("10.0.0.2:8013"));
+
+    let before = buddies_of(&mut amy, "amy");
+    drop(bob);
+
+    // Bob is within the grace period, so nobody's group moves
+    assert_eq!(buddies_of(&mut amy, "amy"), before);
+}
+
+/*
+ * The first address from the given network whose place in bob's group of
+ * one among bob, carl and it is wanted
+*/
+
+fn address_for_bob(network: &str, wins: bool) -> String {
+    (0..256)
+        .map(|i| format!("{}.{}:8013", network, i))
+        .find(|addr| {
+            let ring = [
+                "10.0.0.2:8013".to_string(),
+                "10.0.0.3:8013".to_string(),
+                addr.clone(),
+            ];
+            (select_group("bob", &ring, 1) == [addr.clone()]) == wins
+        })
+        .unwrap()
+}
+
+#[test]
+fn buddies_that_lose_a_mover_are_told_too() {
+    let gateway = start_gateway_with_config("group_size = 1\nreplication = 1\n");
+    let old = address_for_bob("10.0.1", true);
+    let new = address_for_bob("10.0.2", false);
+
+    let mut bob = connect(&gateway);
+    sign_in(&mut bob, "bob", Init::at("10.0.0.2:8013"));
+    let mut carl = connect(&gateway);
+    sign_in(&mut carl, "carl", Init::at("10.0.0.3:8013"));
+    let mut amy = connect(&gateway);
+    sign_in(&mut amy, "amy", Init::at(&old));
+
+    // Amy's new address doesn't make bob's group, which loses her old one
+    let mut new_amy = connect(&gateway);
+    sign_in(&mut new_amy, "amy", Init::at(&new));
+    match receive(&mut bob) {
+        Message::UpdateGroup { members } => {
+            assert!(!members.contains(&old));
+            assert!(!members.contains(&new));
+        }
+        other => panic!("expected a group update, got {}", other),
+    }
+}
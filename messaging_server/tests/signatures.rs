@@ -0,0 +1,295 @@
+mod common;
+
+use common::{connect, receive, register, send, sign_in, start_gateway, Gateway, Init};
+use messaging_protocol::crypto::{message_proof, KeyPair, SigningPair};
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::Message;
+use messaging_protocol::tokens::{message_digest, today, Blind};
+use std::fs;
+use std::net::TcpStream;
+
+fn send_to_bob(
+    stream: &mut FramedStream<TcpStream>,
+    id: &str,
+    signature: Option<String>,
+) -> Message {
+    send(
+        stream,
+        Message::Send {
+            recipient: "bob".to_string(),
+            sender: "amy".to_string(),
+            id: id.to_string(),
+            body: "sealed".to_string(),
+            signature,
+        },
+    );
+    receive(stream)
+}
+
+#[test]
+fn senders_with_an_identity_sign_what_they_send() {
+    let mut gateway = start_gateway();
+    let amy = SigningPair::generate().unwrap();
+    let mallory = SigningPair::generate().unwrap();
+    let key = KeyPair::generate().unwrap().public_hex();
+    let mut amy_stream = register(&gateway, "amy", &key, &amy);
+    let bob = SigningPair::generate().unwrap();
+    let _bob_stream = register(&gateway, "bob", &key, &bob);
+
+    // The identity is handed out with the key
+    send(
+        &mut amy_stream,
+        Message::KeyFetch {
+            username: "amy".to_string(),
+        },
+    );
+    assert_eq!(
+        receive(&mut amy_stream),
+        Message::Key {
+            username: "amy".to_string(),
+            key: key.clone(),
+            identity: Some(amy.public_hex()),
+        }
+    );
+
+    // Unsigned, signed by someone else or signed for another id
+    let mut stranger = connect(&gateway);
+    let forged = mallory.sign(&message_proof("bob", "amy", "1", "sealed"));
+    let other_id = amy.sign(&message_proof("bob", "amy", "2", "sealed"));
+    for signature in [None, Some(forged), Some(other_id)] {
+        let reply = send_to_bob(&mut stranger, "1", signature);
+        assert!(matches!(reply, Message::Rejected { .. }), "{}", reply);
+    }
+
+    // Signed by amy it goes through, and the signature is kept with it
+    let signature = amy.sign(&message_proof("bob", "amy", "1", "sealed"));
+    let reply = send_to_bob(&mut stranger, "1", Some(signature.clone()));
+    assert!(matches!(reply, Message::Ack { .. }), "{}", reply);
+
+    gateway.restart();
+    let mut bob = register(&gateway, "bob", &key, &bob);
+    send(
+        &mut bob,
+        Message::Fetch {
+            username: "bob".to_string(),
+        },
+    );
+    match receive(&mut bob) {
+        Message::Update { messages } => {
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].signature, Some(signature));
+        }
+        other => panic!("expected pending messages, got {}", other),
+    }
+}
+
+/*
+ * The key the gateway issues delivery tokens with, as it announces it
+*/
+
+fn token_key(gateway: &Gateway) -> String {
+    let mut stream = connect(gateway);
+    let signed_in = sign_in(&mut stream, "carl", Init::default());
+    signed_in
+        .token_key
+        .expect("the gateway announces its token key")
+}
+
+/*
+ * Have the gateway sign blinded nonces on a registered connection
+*/
+
+fn request_tokens(stream: &mut FramedStream<TcpStream>, blinds: &[Blind]) -> Message {
+    send(
+        stream,
+        Message::Tokens {
+            blinded: blinds.iter().map(Blind::blinded_hex).collect(),
+        },
+    );
+    receive(stream)
+}
+
+fn tokens(stream: &mut FramedStream<TcpStream>, key: &str, count: usize) -> Vec<String> {
+    let blinds: Vec<Blind> = (0..count).map(|_| Blind::new(today()).unwrap()).collect();
+    match request_tokens(stream, &blinds) {
+        Message::Issued { tokens } => blinds
+            .iter()
+            .zip(&tokens)
+            .map(|(blind, issued)| blind.finish(key, issued).unwrap())
+            .collect(),
+        other => panic!("expected tokens, got {}", other),
+    }
+}
+
+fn sealed(id: &str, token: Option<&String>) -> Message {
+    Message::Send {
+        recipient: "bob".to_string(),
+        sender: String::new(),
+        id: id.to_string(),
+        body: "s1.sealed".to_string(),
+        signature: token.cloned(),
+    }
+}
+
+fn redeem(gateway: &Gateway, token: &str, id: &str) -> Message {
+    let mut stream = connect(gateway);
+    send(
+        &mut stream,
+        Message::Redeem {
+            token: token.to_string(),
+            digest: message_digest("bob", id, "s1.sealed"),
+        },
+    );
+    receive(&mut stream)
+}
+
+#[test]
+fn sealed_messages_need_a_delivery_token() {
+    let mut gateway = start_gateway();
+    let key = KeyPair::generate().unwrap().public_hex();
+    let amy_identity = SigningPair::generate().unwrap();
+    let mut amy = register(&gateway, "amy", &key, &amy_identity);
+    let bob_identity = SigningPair::generate().unwrap();
+    let mut bob = register(&gateway, "bob", &key, &bob_identity);
+    let token_key = token_key(&gateway);
+
+    // Anyone could send a message that doesn't say who it is from, so
+    // strangers get no tokens and nothing sealed goes through without one
+    let mut stranger = connect(&gateway);
+    let reply = request_tokens(&mut stranger, &[Blind::new(today()).unwrap()]);
+    assert!(matches!(reply, Message::NotFound { .. }), "{}", reply);
+    let made_up = format!("{}.00.{}", today(), token_key);
+    for message in [sealed("1", None), sealed("1", Some(&made_up))] {
+        send(&mut stranger, message);
+        let reply = receive(&mut stranger);
+        assert!(matches!(reply, Message::Rejected { .. }), "{}", reply);
+    }
+
+    // Amy's tokens work on any connection, the one she signed in on never
+    // hears about the message
+    let spent = tokens(&mut amy, &token_key, 2);
+    send(&mut stranger, sealed("2", Some(&spent[0])));
+    assert_eq!(receive(&mut bob), sealed("2", Some(&spent[0])));
+    let reply = receive(&mut stranger);
+    assert!(matches!(reply, Message::Ack { .. }), "{}", reply);
+
+    // A token pays for one message. Sending that one again is fine, it
+    // goes to every buddy too, but another message can't have it
+    send(&mut stranger, sealed("2", Some(&spent[0])));
+    let reply = receive(&mut stranger);
+    assert!(matches!(reply, Message::Ack { .. }), "{}", reply);
+    assert_eq!(
+        redeem(&gateway, &spent[0], "2"),
+        Message::Redeemed {
+            token: spent[0].clone()
+        }
+    );
+    let reply = redeem(&gateway, &spent[0], "3");
+    assert!(matches!(reply, Message::Refused { .. }), "{}", reply);
+
+    // The gateway keeps its token key and what was spent across a restart
+    gateway.restart();
+    let mut stranger = connect(&gateway);
+    send(&mut stranger, sealed("3", Some(&spent[0])));
+    let reply = receive(&mut stranger);
+    assert!(matches!(reply, Message::Rejected { .. }), "{}", reply);
+    send(&mut stranger, sealed("4", Some(&spent[1])));
+    let reply = receive(&mut stranger);
+    assert!(matches!(reply, Message::Ack { .. }), "{}", reply);
+
+    let mut bob = register(&gateway, "bob", &key, &bob_identity);
+    send(
+        &mut bob,
+        Message::Fetch {
+            username: "bob".to_string(),
+        },
+    );
+    match receive(&mut bob) {
+        Message::Update { messages } => {
+            let ids: Vec<(&str, &str)> = messages
+                .iter()
+                .map(|m| (m.sender.as_str(), m.id.as_str()))
+                .collect();
+            assert_eq!(ids, [("", "2"), ("", "4")]);
+        }
+        other => panic!("expected pending messages, got {}", other),
+    }
+}
+
+#[test]
+fn the_gateway_never_holds_a_sender_and_recipient_together() {
+    let mut gateway = start_gateway();
+    let key = KeyPair::generate().unwrap().public_hex();
+    let mut amy = register(&gateway, "amy", &key, &SigningPair::generate().unwrap());
+    let mut bob = register(&gateway, "bob", &key, &SigningPair::generate().unwrap());
+    let token_key = token_key(&gateway);
+
+    // Amy asks for a token on her own connection before she knows what
+    // she will send, all the gateway sees there is a blinded nonce
+    let blind = Blind::new(today()).unwrap();
+    let issued = match request_tokens(&mut amy, std::slice::from_ref(&blind)) {
+        Message::Issued { tokens } => tokens,
+        other => panic!("expected tokens, got {}", other),
+    };
+    let token = blind.finish(&token_key, &issued[0]).unwrap();
+    for part in token.split('.').skip(1) {
+        assert!(!blind.blinded_hex().contains(part) && !issued[0].contains(part));
+    }
+
+    // The message goes out on a connection that never said who it is
+    let mut relay = connect(&gateway);
+    send(&mut relay, sealed("1", Some(&token)));
+    assert!(matches!(receive(&mut relay), Message::Ack { .. }));
+    assert_eq!(receive(&mut bob), sealed("1", Some(&token)));
+
+    // Nothing the gateway keeps ties amy to the message or its token, the
+    // message, its token and bob only ever show up without her
+    gateway.kill();
+    let mut lines = Vec::new();
+    for file in ["journal.log", "snapshot.log"] {
+        let state = fs::read_to_string(gateway.dir.join("gateway_state").join(file)).unwrap();
+        lines.extend(state.lines().map(str::to_string));
+    }
+    let nonce = token.split('.').nth(1).unwrap();
+    assert!(lines.iter().any(|line| line.contains(nonce)));
+    for line in lines.iter().filter(|line| line.contains("amy")) {
+        assert!(!line.contains("bob") && !line.contains(nonce), "{}", line);
+        assert!(!line.contains("s1.sealed"), "{}", line);
+    }
+}
+
+#[test]
+fn tokens_are_handed_out_sparingly() {
+    let gateway = start_gateway();
+    let key = KeyPair::generate().unwrap().public_hex();
+    let mut amy = register(&gateway, "amy", &key, &SigningPair::generate().unwrap());
+
+    // Six batches of twenty is as many as she gets in a minute
+    let batch = vec![Blind::new(today()).unwrap().blinded_hex(); 20];
+    let mut ask = |blinded: &[String]| {
+        send(
+            &mut amy,
+            Message::Tokens {
+                blinded: blinded.to_vec(),
+            },
+        );
+        receive(&mut amy)
+    };
+    for _ in 0..6 {
+        match ask(&batch) {
+            Message::Issued { tokens } => assert_eq!(tokens.len(), 20),
+            other => panic!("expected tokens, got {}", other),
+        }
+    }
+    let reply = ask(&batch[..1]);
+    assert!(matches!(reply, Message::Refused { .. }), "{}", reply);
+
+    // Turning her down doesn't cost her the connection
+    send(
+        &mut amy,
+        Message::KeyFetch {
+            username: "amy".to_string(),
+        },
+    );
+    assert!(matches!(receive(&mut amy), Message::Key { .. }));
+}
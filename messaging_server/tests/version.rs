@@ -0,0 +1,96 @@
+mod common;
+
+use common::{connect, init, start_gateway, Gateway, Init};
+use messaging_protocol::framing::FramedStream;
+use messaging_protocol::message::{Capability, Message, LEGACY_VERSION, PROTOCOL_VERSION};
+use std::io::ErrorKind;
+use std::net::TcpStream;
+
+/*
+ * Open a connection with an INIT at the given version and capabilities
+ * and return it with the gateway's first reply
+*/
+
+fn offer(
+    gateway: &Gateway,
+    version: u32,
+    capabilities: Vec<Capability>,
+) -> (FramedStream<TcpStream>, Message) {
+    let mut stream = connect(gateway);
+    let reply = init(
+        &mut stream,
+        "amy",
+        Init {
+            version,
+            capabilities,
+            ..Init::default()
+        },
+    );
+    (stream, reply)
+}
+
+/*
+ * The gateway hung up once it had its say
+*/
+
+fn assert_closed(stream: &mut FramedStream<TcpStream>) {
+    let err = stream.read_frame().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn newer_clients_are_talked_down_to_our_version() {
+    let gateway = start_gateway();
+    let (_stream, reply) = offer(
+        &gateway,
+        PROTOCOL_VERSION + 5,
+        vec![Capability::ServerRelay, Capability::BuddyCache],
+    );
+
+    match reply {
+        Message::Version { version, .. } => assert_eq!(version, PROTOCOL_VERSION),
+        other => panic!("expected VERSION, got {}", other),
+    }
+}
+
+#[test]
+fn version_1_clients_skip_straight_to_buddies() {
+    let gateway = start_gateway();
+    let (_stream, reply) = offer(&gateway, LEGACY_VERSION, Capability::legacy());
+    assert!(matches!(reply, Message::Buddies { .. }), "{}", reply);
+}
+
+#[test]
+fn clients_too_old_are_refused_and_hung_up_on() {
+    let gateway = start_gateway();
+    let (mut stream, reply) = offer(&gateway, 0, vec![Capability::ServerRelay]);
+
+    assert!(matches!(reply, Message::Refused { .. }), "{}", reply);
+    assert_closed(&mut stream);
+}
+
+#[test]
+fn only_capabilities_both_sides_have_are_agreed() {
+    let gateway = start_gateway();
+    let (_stream, reply) = offer(
+        &gateway,
+        PROTOCOL_VERSION,
+        vec![Capability::DirectSend, Capability::ServerRelay],
+    );
+
+    match reply {
+        Message::Version { capabilities, .. } => {
+            assert_eq!(capabilities, vec![Capability::ServerRelay])
+        }
+        other => panic!("expected VERSION, got {}", other),
+    }
+}
+
+#[test]
+fn clients_with_nothing_in_common_are_refused() {
+    let gateway = start_gateway();
+    let (mut stream, reply) = offer(&gateway, PROTOCOL_VERSION, vec![Capability::DirectSend]);
+
+    assert!(matches!(reply, Message::Refused { .. }), "{}", reply);
+    assert_closed(&mut stream);
+}